@@ -132,7 +132,7 @@ fn dispatch(model_dir: PathBuf, mut requests: UnboundedReceiver<TaskRequest>) {
     // 启动 tokio 运行时
     let runtime = tokio::runtime::Runtime::new().unwrap();
     runtime.block_on(async move {
-        let (service, _handle) = Service::load(model_dir, ());
+        let (service, _handle) = Service::load(model_dir, Default::default());
         let mut session = None;
         while let Some(request) = requests.recv().await {
             match request {
@@ -142,10 +142,13 @@ fn dispatch(model_dir: PathBuf, mut requests: UnboundedReceiver<TaskRequest>) {
                         log::info!("new session");
                         service.launch()
                     });
-                    session.extend(&[Message {
+                    if let Err(e) = session.extend(&[Message {
                         role: "user",
                         content: &content,
-                    }]);
+                    }]) {
+                        log::warn!("chat error: {e}");
+                        continue;
+                    }
                     let mut chat = session.chat();
                     while let Some(piece) = chat.decode().await {
                         if answer.send(piece).is_err() {
@@ -157,7 +160,13 @@ fn dispatch(model_dir: PathBuf, mut requests: UnboundedReceiver<TaskRequest>) {
                 }
                 TaskRequest::Generate(content, answer) => {
                     log::info!("generate: {content}");
-                    let mut generator = service.generate(content, None);
+                    let mut generator = match service.generate(content, None) {
+                        Ok(generator) => generator,
+                        Err(e) => {
+                            log::warn!("generate error: {e}");
+                            continue;
+                        }
+                    };
                     while let Some(piece) = generator.decode().await {
                         if answer.send(piece).is_err() {
                             log::warn!("send error");
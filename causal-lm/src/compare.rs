@@ -0,0 +1,128 @@
+//! 比较两个模型的输出，用于验证新的后端或量化的模型。
+
+use crate::{CausalLM, DecodingMeta, QueryContext};
+use common::{upos, utok};
+use common_devices::argmax;
+use std::iter::zip;
+
+/// 两个模型在一个位置上输出的 logits 的差异。
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PositionDivergence {
+    /// 对应元素之差的最大绝对值。
+    pub max_abs_diff: f32,
+    /// 两个 logits 向量的余弦相似度。
+    pub cosine_similarity: f32,
+    /// 两个模型的最大值是否在同一个 token 上。
+    pub argmax_agree: bool,
+}
+
+impl PositionDivergence {
+    /// 比较同一个位置上的两行 logits。
+    pub fn new(a: &[f32], b: &[f32]) -> Self {
+        assert_eq!(a.len(), b.len());
+        let mut max_abs_diff = 0f32;
+        let (mut dot, mut norm_a, mut norm_b) = (0f64, 0f64, 0f64);
+        for (&x, &y) in zip(a, b) {
+            max_abs_diff = max_abs_diff.max((x - y).abs());
+            let (x, y) = (x as f64, y as f64);
+            dot += x * y;
+            norm_a += x * x;
+            norm_b += y * y;
+        }
+        let norm = (norm_a * norm_b).sqrt();
+        Self {
+            max_abs_diff,
+            cosine_similarity: if norm > 0. { (dot / norm) as _ } else { 1. },
+            argmax_agree: argmax(a) == argmax(b),
+        }
+    }
+}
+
+/// 两个模型在同一段输入上逐个位置的输出差异。
+#[derive(Clone, Default, Debug)]
+pub struct Divergence {
+    /// 每个位置的差异。
+    pub positions: Vec<PositionDivergence>,
+}
+
+impl Divergence {
+    /// 所有位置中最大的元素差。
+    pub fn max_abs_diff(&self) -> f32 {
+        self.positions
+            .iter()
+            .map(|p| p.max_abs_diff)
+            .fold(0., f32::max)
+    }
+
+    /// 所有位置中最小的余弦相似度。
+    pub fn min_cosine_similarity(&self) -> f32 {
+        self.positions
+            .iter()
+            .map(|p| p.cosine_similarity)
+            .fold(1., f32::min)
+    }
+
+    /// 最大值在同一个 token 上的位置的比例。
+    pub fn argmax_agreement(&self) -> f32 {
+        if self.positions.is_empty() {
+            return 1.;
+        }
+        let n = self.positions.iter().filter(|p| p.argmax_agree).count();
+        n as f32 / self.positions.len() as f32
+    }
+}
+
+/// 在 `tokens` 上以教师强制的方式分别运行两个模型，比较每个位置输出的 logits。
+///
+/// 两个模型的词表必须相同。任何一个模型不支持把 logits 拷贝到主存，或 `tokens` 超出缓存容量时返回 `None`。
+pub fn compare_models<A, B>(a: &A, b: &B, tokens: &[utok]) -> Option<Divergence>
+where
+    A: CausalLM,
+    B: CausalLM,
+{
+    let voc = a.architecture().voc;
+    assert_eq!(voc, b.architecture().voc, "models have different vocab");
+    let logits_a = score(a, tokens)?;
+    let logits_b = score(b, tokens)?;
+    Some(Divergence {
+        positions: zip(logits_a.chunks_exact(voc), logits_b.chunks_exact(voc))
+            .map(|(a, b)| PositionDivergence::new(a, b))
+            .collect(),
+    })
+}
+
+/// 一次计算 `tokens` 每个位置的 logits（`tokens.len() x vocab_size`）。
+fn score<M: CausalLM>(model: &M, tokens: &[utok]) -> Option<Vec<f32>> {
+    let mut cache = model.new_cache();
+    let queries = [QueryContext {
+        cache: Some(&mut cache),
+        range: 0..tokens.len() as upos,
+    }];
+    let hidden_state = model
+        .forward(queries, model.token_embed(tokens.iter().copied()))
+        .ok()?;
+    let logits = model.decode([DecodingMeta::all(tokens.len())], hidden_state);
+    model.logits_to_host(&logits)
+}
+
+#[test]
+fn test_position_divergence() {
+    let a = [1., 3., 2.];
+    let same = PositionDivergence::new(&a, &a);
+    assert_eq!(same.max_abs_diff, 0.);
+    assert!((same.cosine_similarity - 1.).abs() < 1e-6);
+    assert!(same.argmax_agree);
+
+    let b = [1., 2., 3.5];
+    let diff = PositionDivergence::new(&a, &b);
+    assert_eq!(diff.max_abs_diff, 1.5);
+    assert!(diff.cosine_similarity < 1.);
+    assert!(!diff.argmax_agree);
+
+    let divergence = Divergence {
+        positions: vec![same, diff],
+    };
+    assert_eq!(divergence.max_abs_diff(), 1.5);
+    assert_eq!(divergence.min_cosine_similarity(), diff.cosine_similarity);
+    assert_eq!(divergence.argmax_agreement(), 0.5);
+}
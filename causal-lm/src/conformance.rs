@@ -0,0 +1,204 @@
+//! 后端实现的一致性测试。
+//!
+//! 新的后端调用 [`run`] 检查 [`CausalLM`] 的实现满足推理调度服务依赖的性质。
+
+use crate::{CacheOverflow, CausalLM, DecodingMeta, QueryContext, SampleArgs, SampleMeta};
+use common::{upos, utok};
+use std::{fmt::Debug, iter::zip};
+use tensor::Tensor;
+
+/// 加载测试模型，以 `tokens` 为提示词检查模型实现的以下性质：
+///
+/// - 相同的输入两次贪心生成的结果相同；
+/// - 复制的缓存与原缓存内容相同，从两者继续推理的结果相同；
+/// - 回退缓存位置后重新计算的结果与一次计算的结果相同；
+/// - 不同长度的请求一起推理的结果与分别推理的结果相同；
+/// - 注意力长度超出缓存容量的查询返回错误。
+///
+/// 提示词至少包含 2 个 token，没有找到测试模型时直接返回。
+pub fn run<M>(meta: M::Meta, tokens: &[utok])
+where
+    M: CausalLM,
+    M::Error: Debug,
+{
+    assert!(
+        tokens.len() >= 2,
+        "conformance test requires at least 2 tokens"
+    );
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    let model = M::load(model_dir, meta).unwrap();
+
+    greedy_determinism(&model, tokens);
+    duplicate(&model, tokens);
+    revert(&model, tokens);
+    ragged_batch(&model, tokens);
+    cache_overflow(&model);
+}
+
+/// 两次从空缓存开始贪心生成，结果相同。
+fn greedy_determinism<M: CausalLM>(model: &M, tokens: &[utok]) {
+    let (a, cache_a) = generate(model, tokens, 8);
+    let (b, cache_b) = generate(model, tokens, 8);
+    assert_eq!(a, b, "greedy generation is not deterministic");
+    let pos = (tokens.len() + a.len() - 1) as upos;
+    assert_cache_close(model, &cache_a, &cache_b, pos, "determinism");
+}
+
+/// 复制的缓存与原缓存内容相同，从两者继续推理的结果相同。
+fn duplicate<M: CausalLM>(model: &M, tokens: &[utok]) {
+    let (next, mut cache) = generate(model, tokens, 1);
+    let pos = tokens.len() as upos;
+    let mut dup = model.duplicate_cache(&cache, pos);
+    assert_cache_close(model, &cache, &dup, pos, "duplicate");
+
+    let a = step(model, vec![(&mut cache, pos, &next[..])]);
+    let b = step(model, vec![(&mut dup, pos, &next[..])]);
+    assert_eq!(a, b, "inference diverges on duplicated cache");
+}
+
+/// 缓存回退到提示词中间，重新计算后半部分，结果与一次计算相同。
+fn revert<M: CausalLM>(model: &M, tokens: &[utok]) {
+    let (expected, full) = generate(model, tokens, 1);
+    let len = tokens.len() as upos;
+    let mid = tokens.len() / 2;
+
+    // 先在提示词之后多推理一步，使回退的位置之后有需要被覆盖的内容
+    let mut cache = model.new_cache();
+    let next = step(model, vec![(&mut cache, 0, tokens)]);
+    step(model, vec![(&mut cache, len, &next[..])]);
+    let next = step(model, vec![(&mut cache, mid as _, &tokens[mid..])]);
+    assert_eq!(next, expected, "inference diverges after revert");
+    assert_cache_close(model, &full, &cache, len, "revert");
+}
+
+/// 不同长度、不同位置的请求一起推理，结果与分别推理相同。
+fn ragged_batch<M: CausalLM>(model: &M, tokens: &[utok]) {
+    let short = &tokens[..tokens.len() / 2];
+    let (expected_long, long_cache) = generate(model, tokens, 2);
+    let (expected_short, short_cache) = generate(model, short, 2);
+
+    let mut cache_long = model.new_cache();
+    let mut cache_short = model.new_cache();
+    // 预填充长度不同的提示词
+    let first = step(
+        model,
+        vec![(&mut cache_long, 0, tokens), (&mut cache_short, 0, short)],
+    );
+    assert_eq!(first, [expected_long[0], expected_short[0]]);
+    // 从不同的位置解码
+    let (pos_long, pos_short) = (tokens.len() as upos, short.len() as upos);
+    let second = step(
+        model,
+        vec![
+            (&mut cache_long, pos_long, &first[..1]),
+            (&mut cache_short, pos_short, &first[1..]),
+        ],
+    );
+    assert_eq!(second, [expected_long[1], expected_short[1]]);
+
+    assert_cache_close(model, &long_cache, &cache_long, pos_long + 1, "batch");
+    assert_cache_close(model, &short_cache, &cache_short, pos_short + 1, "batch");
+}
+
+/// 注意力长度比最大序列长度多 1 的查询返回错误，而不是越界访问缓存。
+fn cache_overflow<M: CausalLM>(model: &M) {
+    let max_seq_len = model.max_seq_len();
+    let mut cache = model.new_cache();
+    let queries = [QueryContext {
+        cache: Some(&mut cache),
+        range: max_seq_len..max_seq_len + 1,
+    }];
+    let ans = model.forward(queries, model.token_embed([model.bos_token()]));
+    assert_eq!(
+        ans.err(),
+        Some(CacheOverflow {
+            att_len: max_seq_len + 1,
+            max_seq_len,
+        }),
+        "query beyond the cache is not rejected"
+    );
+}
+
+/// 从空缓存开始贪心生成 `n` 个 token，返回生成的 token 和缓存。
+fn generate<M: CausalLM>(model: &M, prompt: &[utok], n: usize) -> (Vec<utok>, Tensor<M::Storage>) {
+    let mut cache = model.new_cache();
+    let mut input = prompt.to_vec();
+    let mut pos = 0;
+    let mut ans = Vec::with_capacity(n);
+    for _ in 0..n {
+        let next = step(model, vec![(&mut cache, pos, &input[..])]);
+        pos += input.len() as upos;
+        ans.extend(&next);
+        input = next;
+    }
+    (ans, cache)
+}
+
+/// 一起推理一批请求，每个请求由缓存、查询的起始位置和输入的 token 组成，返回每个请求贪心采样的下一个 token。
+fn step<'a, M: CausalLM>(
+    model: &M,
+    queries: Vec<(&'a mut Tensor<M::Storage>, upos, &[utok])>,
+) -> Vec<utok>
+where
+    M: 'a,
+{
+    let token_embedded = model.token_embed(
+        queries
+            .iter()
+            .flat_map(|(_, _, tokens)| tokens.iter().copied())
+            .collect::<Vec<_>>(),
+    );
+    let decoding = queries
+        .iter()
+        .map(|(_, _, tokens)| DecodingMeta {
+            num_query: tokens.len(),
+            num_decode: 1,
+        })
+        .collect::<Vec<_>>();
+    let args = queries
+        .iter()
+        .map(|_| SampleMeta {
+            num_decode: 1,
+            args: SampleArgs::ARG_MAX,
+            history: &[],
+            suppressed: &[],
+        })
+        .collect::<Vec<_>>();
+    let queries = queries
+        .into_iter()
+        .map(|(cache, pos, tokens)| QueryContext {
+            cache: Some(cache),
+            range: pos..pos + tokens.len() as upos,
+        });
+    let hidden_state = model.forward(queries, token_embedded).unwrap();
+    let logits = model.decode(decoding, hidden_state);
+    // 一步生成多个 token 的模型只取每个请求的第一个 token
+    model
+        .sample(args, logits)
+        .chunks(model.tokens_per_step())
+        .map(|tokens| tokens[0])
+        .collect()
+}
+
+/// 比较两个缓存前 `pos` 个位置的 K-V，允许计算顺序不同带来的误差。
+fn assert_cache_close<M: CausalLM>(
+    model: &M,
+    a: &Tensor<M::Storage>,
+    b: &Tensor<M::Storage>,
+    pos: upos,
+    check: &str,
+) {
+    for layer in 0..model.architecture().nlayers {
+        let kv_a = model.cache_to_host(a, layer, pos);
+        let kv_b = model.cache_to_host(b, layer, pos);
+        for (x, y) in zip(kv_a.physical().iter(), kv_b.physical().iter()) {
+            let (x, y) = (x.to_f32(), y.to_f32());
+            assert!(
+                (x - y).abs() <= 1e-2 + 1e-2 * x.abs(),
+                "{check}: kv cache of layer {layer} mismatch, {x} != {y}"
+            );
+        }
+    }
+}
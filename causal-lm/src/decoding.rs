@@ -13,6 +13,17 @@ pub struct DecodingMeta {
 }
 
 impl DecodingMeta {
+    /// 解码查询中的所有位置，用于教师强制（teacher forcing）。
+    ///
+    /// 每个输入的 token 都得到一行 logits，可以在一次推理中计算已知序列上每个位置的损失。
+    #[inline]
+    pub const fn all(num_query: usize) -> Self {
+        Self {
+            num_query,
+            num_decode: num_query,
+        }
+    }
+
     /// 根据解码元信息移动数据。
     pub fn select<T, B>(
         x: &mut Tensor<T>,
@@ -67,3 +78,14 @@ impl DecodingMeta {
         begin..dst
     }
 }
+
+#[test]
+fn test_select_all() {
+    use digit_layout::types::U8;
+
+    let mut x = Tensor::new(U8, &[5, 2], (0..10).collect::<Vec<u8>>());
+    let decoding = [DecodingMeta::all(2), DecodingMeta::all(3)];
+    let range = DecodingMeta::select(&mut x, decoding, |_, _| unreachable!());
+    assert_eq!(range, 0..5);
+    assert_eq!(x.physical(), &(0..10).collect::<Vec<u8>>());
+}
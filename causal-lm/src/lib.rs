@@ -1,17 +1,23 @@
 #![doc = include_str!("../README.md")]
 #![deny(warnings, missing_docs)]
 
+pub mod conformance;
+
+mod compare;
 mod decoding;
 mod query_context;
+mod sample;
 
-use common::{upos, utok};
-use digit_layout::types::U32;
-use std::path::Path;
-use tensor::{udim, Tensor};
+use common::{f16, upos, utok, Blob};
+use digit_layout::types::{F16, U32};
+use std::{ops::Deref, path::Path};
+use tensor::{reslice, slice, udim, Tensor};
 
+pub use common_devices::SampleStage;
+pub use compare::{compare_models, Divergence, PositionDivergence};
 pub use decoding::DecodingMeta;
-pub use operators::random_sample::SampleArgs;
-pub use query_context::QueryContext;
+pub use query_context::{CacheOverflow, QueryContext};
+pub use sample::{InvalidSampleArgs, SampleArgs};
 
 /// 从文件系统加载的模型。
 pub trait Model: Sized {
@@ -31,6 +37,7 @@ pub trait Model: Sized {
 /// - 复制缓存张量（[`duplicate_cache`](CausalLM::duplicate_cache)）；
 /// - 以及对输入序列计算词嵌入（[`token_embed`](CausalLM::token_embed)）；
 /// - 对词嵌入计算前向传播（[`forward`](CausalLM::forward)）；
+/// - 预填充缓存并返回隐藏状态（[`prefill`](CausalLM::prefill)）；
 /// - 解码词嵌入张量得到概率密度（[`decode`](CausalLM::decode)）；
 /// - 采样概率密度（[`sample`](CausalLM::sample)）；
 ///
@@ -41,6 +48,8 @@ pub trait Model: Sized {
 pub trait CausalLM: Model {
     /// 定义中间变量的存储方式。
     type Storage;
+    /// 模型结构信息。
+    fn architecture(&self) -> ModelInfo;
     /// 最大序列长度。
     fn max_seq_len(&self) -> upos;
     /// 模型定义的句子起始符。
@@ -49,24 +58,62 @@ pub trait CausalLM: Model {
     fn eos_token(&self) -> utok;
     /// 创建一个未填充的缓存张量（`num_layers x 2 x num_kv_head x max_seq_len x head_dim`）。
     fn new_cache(&self) -> Tensor<Self::Storage>;
+    /// 缓存 `len` 个 token 所需的缓存字节数。
+    fn cache_bytes(&self, len: upos) -> usize;
+    /// 释放缓存中前 `pos` 个位置之后不再使用的容量。
+    ///
+    /// 缓存容量固定的后端不做任何事。
+    fn shrink_cache(&self, _cache: &mut Tensor<Self::Storage>, _pos: upos) {}
     /// 复制一个有效长度为 `pos` 的缓存。
     ///
     /// 有效部分：`.., .., .., ..pos, ..`
     fn duplicate_cache(&self, cache: &Tensor<Self::Storage>, pos: upos) -> Tensor<Self::Storage>;
+    /// 将缓存中第 `layer` 层前 `pos` 个位置的 K-V 拷贝到主存（`2 x num_kv_head x pos x head_dim`）。
+    ///
+    /// 加速卡上的模型需要把缓存拷贝回主机，开销很大，仅用于调试和分析。
+    fn cache_to_host(
+        &self,
+        cache: &Tensor<Self::Storage>,
+        layer: usize,
+        pos: upos,
+    ) -> Tensor<Vec<f16>>;
     /// 对所有词执行词嵌入（`num_tokens x hidden_size`）。
     ///
     /// 词嵌入是上下文无关的，对于每个词独立进行，因此多个请求的查询序列可以 flatten 同时计算。
     fn token_embed(&self, queries: impl IntoIterator<Item = utok>) -> Tensor<Self::Storage>;
     /// 对词嵌入张量执行 Transformer 计算（`num_t   okens x hidden_size`）。
     ///
-    /// 需要输入每个请求的上下文。
+    /// 需要输入每个请求的上下文。有请求的注意力长度超出缓存容量时返回错误，不做任何计算。
     fn forward<'a>(
         &self,
         queries: impl IntoIterator<Item = QueryContext<'a, Self::Storage>>,
         token_embedded: Tensor<Self::Storage>,
-    ) -> Tensor<Self::Storage>
+    ) -> Result<Tensor<Self::Storage>, CacheOverflow>
     where
         Self: 'a;
+    /// 对从 `*pos` 开始的 `tokens` 执行词嵌入和前向传播，K-V 填入 `cache`，`*pos` 前进到查询末尾。
+    ///
+    /// 返回隐藏状态（`num_tokens x hidden_size`），不解码也不采样。超出缓存容量时返回错误，`*pos` 不变。
+    fn prefill<'a>(
+        &self,
+        tokens: &[utok],
+        cache: &'a mut Tensor<Self::Storage>,
+        pos: &mut upos,
+    ) -> Result<Tensor<Self::Storage>, CacheOverflow>
+    where
+        Self: 'a,
+    {
+        let token_embedded = self.token_embed(tokens.iter().copied());
+        let range = *pos..*pos + tokens.len() as upos;
+        let end = range.end;
+        let queries = [QueryContext {
+            cache: Some(cache),
+            range,
+        }];
+        let hidden_state = self.forward(queries, token_embedded)?;
+        *pos = end;
+        Ok(hidden_state)
+    }
     /// 对词嵌入张量执行解码计算（`num_decoding_tokens` x `vocab_size`）。
     ///
     /// 每个请求可以独立指定解码 token 的数量。
@@ -76,19 +123,77 @@ pub trait CausalLM: Model {
         hidden_state: Tensor<Self::Storage>,
     ) -> Tensor<Self::Storage>;
     /// 对 logits 进行采样。
-    fn sample(
+    ///
+    /// 每个解码位置依次返回 [`tokens_per_step`](CausalLM::tokens_per_step) 个 token。
+    fn sample<'a>(
         &self,
-        args: impl IntoIterator<Item = SampleMeta>,
+        args: impl IntoIterator<Item = SampleMeta<'a>>,
         logits: Tensor<Self::Storage>,
     ) -> Vec<utok>;
+    /// 检查采样参数是否合法，以及这个模型的 [`sample`](CausalLM::sample) 能否支持。
+    ///
+    /// 默认只检查取值，不支持部分采样方式的模型应当覆盖这个方法。
+    #[inline]
+    fn check_sample_args(&self, args: &SampleArgs) -> Result<(), InvalidSampleArgs> {
+        args.validate()
+    }
+    /// 将 logits 转换为 f32 拷贝到主存（`num_decoding_tokens x vocab_size`），用于计算采样的 token 的对数概率。
+    ///
+    /// 默认不支持，返回 `None`。
+    #[inline]
+    fn logits_to_host(&self, _logits: &Tensor<Self::Storage>) -> Option<Vec<f32>> {
+        None
+    }
+    /// 每个解码位置一步采样得到的 token 数量。
+    ///
+    /// 支持多 token 预测的模型一步可以生成多个 token，它们在下一轮推理中一起填入缓存。
+    #[inline]
+    fn tokens_per_step(&self) -> usize {
+        1
+    }
+}
+
+/// 模型结构信息。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct ModelInfo {
+    /// 层数。
+    pub nlayers: usize,
+    /// 注意力头数。
+    pub nh: usize,
+    /// kv 头数。
+    pub nkvh: usize,
+    /// 隐藏层维度。
+    pub d: usize,
+    /// 前馈网络中间层维度。
+    pub di: usize,
+    /// 词表大小。
+    pub voc: usize,
+    /// 最大上下文长度。
+    pub max_seq_len: usize,
+    /// 混合专家模型的专家数量和每个 token 激活的专家数量，稠密模型为 `None`。
+    pub moe: Option<(usize, usize)>,
 }
 
 /// 解码的要求。
-pub struct SampleMeta {
+pub struct SampleMeta<'a> {
     /// 解码的长度。
     pub num_decode: usize,
-    /// 采样参数。
+    /// 采样参数，只作用于生成的 token。
     pub args: SampleArgs,
+    /// 本次生成中已经出现过的 token，不重复，用于重复惩罚，没有设置重复惩罚时可以为空。
+    ///
+    /// 只包含生成的 token，不包含提示词，与 transformers 同时惩罚提示词和生成的 token 不同。
+    pub history: &'a [utok],
+    /// 禁止采样的 token，例如生成长度达到下限之前的停止 token。
+    pub suppressed: &'a [utok],
+}
+
+impl SampleMeta<'_> {
+    /// 采样前要加到 logits 上的所有偏置，包括采样参数中的偏置和禁止采样的 token，`eos` 是模型的结束符。
+    pub fn biases(&self, eos: utok) -> impl Iterator<Item = (utok, f32)> + '_ {
+        let suppressed = self.suppressed.iter().map(|&t| (t, f32::NEG_INFINITY));
+        self.args.biases(eos).chain(suppressed)
+    }
 }
 
 /// 生成位置张量。
@@ -104,7 +209,34 @@ pub fn pos<'a, S: 'a>(
     Tensor::new(U32, &[ans.len() as _], ans)
 }
 
+/// 从主存中的缓存张量提取第 `layer` 层前 `pos` 个位置的 K-V（`2 x num_kv_head x pos x head_dim`）。
+pub fn cache_layer<T>(cache: &Tensor<T>, layer: usize, pos: upos) -> Tensor<Vec<f16>>
+where
+    T: Deref<Target = [u8]>,
+{
+    let &[nlayers, 2, nkvh, max_seq_len, dh] = cache.shape() else {
+        panic!("invalid cache shape: {:?}", cache.shape())
+    };
+    assert_eq!(cache.data_layout(), F16);
+    assert!(layer < nlayers as usize);
+    assert!(pos <= max_seq_len);
+
+    let src = cache.as_ref().map_physical(|u| &**u).slice(&[
+        slice![=layer],
+        slice![=>],
+        slice![=>],
+        slice![=>pos],
+        slice![=>],
+    ]);
+    let mut dst = Tensor::alloc(F16, src.shape(), Blob::new);
+    src.reform_to(&mut dst);
+    dst.reshape(&[2, nkvh, pos, dh])
+        .map_physical(|b| reslice::<u8, f16>(&b).to_vec())
+}
+
 /// 测试模型实现。
+///
+/// 只检查贪心生成能够进行到结束符，更完整的检查见 [`conformance::run`]。
 pub fn test_impl<M>(meta: M::Meta, prompt: &[utok])
 where
     M: CausalLM,
@@ -134,7 +266,7 @@ where
             cache: Some(&mut cache),
             range: pos..pos + prompt.len() as upos,
         }];
-        let hidden_state = CausalLM::forward(&model, queries, token_embedded);
+        let hidden_state = CausalLM::forward(&model, queries, token_embedded).unwrap();
 
         let decoding = [DecodingMeta {
             num_query: prompt.len(),
@@ -145,6 +277,8 @@ where
         let args = [SampleMeta {
             num_decode: 1,
             args: SampleArgs::ARG_MAX,
+            history: &[],
+            suppressed: &[],
         }];
         let tokens = CausalLM::sample(&model, args, logits);
 
@@ -152,4 +286,34 @@ where
         pos += prompt.len() as upos;
         prompt = tokens;
     }
+
+    let info = model.architecture();
+    let kv = model.cache_to_host(&cache, info.nlayers - 1, pos);
+    assert_eq!(
+        kv.shape(),
+        [2, info.nkvh as udim, pos, (info.d / info.nh) as udim]
+    );
+}
+
+#[test]
+fn test_cache_layer() {
+    let (nlayers, nkvh, max_seq_len, dh) = (3, 2, 8, 4);
+    let len = nlayers * 2 * nkvh * max_seq_len * dh;
+    let data = (0..len).map(|i| f16::from_f32(i as _)).collect::<Vec<_>>();
+    let mut cache = Tensor::alloc(F16, &[nlayers, 2, nkvh, max_seq_len, dh], Blob::new);
+    cache
+        .physical_mut()
+        .copy_from_slice(reslice::<f16, u8>(&data));
+
+    let (layer, pos) = (1, 5);
+    let kv = cache_layer(&cache, layer, pos);
+    assert_eq!(kv.shape(), [2, nkvh, pos, dh]);
+
+    let at = |i: usize, h: usize, p: usize, d: usize| {
+        (((layer * 2 + i) * nkvh as usize + h) * max_seq_len as usize + p) * dh as usize + d
+    };
+    let host = kv.physical();
+    assert_eq!(host[0], data[at(0, 0, 0, 0)]);
+    assert_eq!(host[host.len() - 1], data[at(1, 1, 4, 3)]);
+    assert_eq!(host.len(), (2 * nkvh * pos * dh) as usize);
 }
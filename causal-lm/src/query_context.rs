@@ -1,5 +1,8 @@
 ﻿use common::upos;
-use std::ops::{DerefMut, Range};
+use std::{
+    error, fmt,
+    ops::{DerefMut, Range},
+};
 use tensor::{slice, split, udim, LocalSplitable, Tensor};
 
 /// 查询 Transformer 的的信息。
@@ -26,6 +29,51 @@ impl<'a, Storage> QueryContext<'a, Storage> {
     pub const fn att_len(&self) -> udim {
         self.range.end
     }
+    /// 检查注意力长度是否超出缓存容量。
+    pub fn check_cache(&self) -> Result<(), CacheOverflow> {
+        let Some(cache) = &self.cache else {
+            return Ok(());
+        };
+        let &[_, 2, _, max_seq_len, _] = cache.shape() else {
+            unreachable!()
+        };
+        let att_len = self.att_len();
+        if att_len <= max_seq_len {
+            Ok(())
+        } else {
+            Err(CacheOverflow {
+                att_len,
+                max_seq_len,
+            })
+        }
+    }
+    /// 检查所有查询，返回第一个超出缓存容量的错误。
+    ///
+    /// 前向传播在访问缓存之前调用，越界的查询返回错误而不是写坏内存。
+    pub fn check_all(queries: &[Self]) -> Result<(), CacheOverflow> {
+        queries.iter().try_for_each(Self::check_cache)
+    }
+}
+
+/// 查询的注意力长度超出了缓存容量。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct CacheOverflow {
+    /// 查询的注意力长度。
+    pub att_len: udim,
+    /// 缓存容量。
+    pub max_seq_len: udim,
+}
+
+impl error::Error for CacheOverflow {}
+impl fmt::Display for CacheOverflow {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "attention length {} exceeds cache capacity {}",
+            self.att_len, self.max_seq_len
+        )
+    }
 }
 
 type KVCache<'a, T> = (
@@ -61,3 +109,33 @@ where
         })
     }
 }
+
+#[test]
+fn test_check_cache() {
+    use digit_layout::types::F16;
+
+    let max_seq_len = 16;
+    let mut cache = Tensor::new(F16, &[2, 2, 4, max_seq_len, 8], ());
+    let mut query = |range: Range<upos>| {
+        QueryContext {
+            cache: Some(&mut cache),
+            range,
+        }
+        .check_cache()
+    };
+    assert_eq!(query(0..max_seq_len), Ok(()));
+    assert_eq!(query(max_seq_len - 1..max_seq_len), Ok(()));
+    assert_eq!(
+        query(max_seq_len..max_seq_len + 1),
+        Err(CacheOverflow {
+            att_len: max_seq_len + 1,
+            max_seq_len,
+        })
+    );
+
+    let query = QueryContext::<()> {
+        cache: None,
+        range: 0..max_seq_len + 1,
+    };
+    assert_eq!(query.check_cache(), Ok(()));
+}
@@ -0,0 +1,413 @@
+use common::utok;
+use common_devices::{repetition_penalty, SampleFilter, SampleStage};
+use operators::random_sample;
+use std::{error, fmt};
+
+/// 采样参数。
+///
+/// 采样参数只作用于生成的 token，提示词中的 token 不会被采样。
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SampleArgs {
+    /// 温度，不大于 0 时退化为贪心采样。
+    pub temperature: f32,
+    /// 只从概率最大的 `top_k` 个 token 中采样。
+    pub top_k: usize,
+    /// 只从累积概率不超过 `top_p` 的 token 中采样。
+    pub top_p: f32,
+    /// 只从概率不小于最大概率 `min_p` 倍的 token 中采样，在 `top_k` 之前过滤，为 0 时不过滤。
+    pub min_p: f32,
+    /// 采样前给一个 token 的 logit 加上偏置，为负时降低这个 token 出现的概率。
+    pub logit_bias: Option<(utok, f32)>,
+    /// 采样前给模型结束符的 logit 加上的偏置，为正时鼓励结束生成。
+    pub eos_bias: f32,
+    /// 重复惩罚，本次生成中已经出现过的 token 的 logit 为正时除以这个值，为负时乘以这个值。
+    ///
+    /// 大于 1 时抑制重复，为 1 时不惩罚。
+    pub repetition_penalty: f32,
+    /// 过滤 logits 的顺序，用于对齐其他框架的采样流程。
+    ///
+    /// 目前只有 CPU 上的采样支持默认以外的顺序，其他模型的 [`check_sample_args`](crate::CausalLM::check_sample_args) 返回错误。
+    pub sample_order: [SampleStage; 3],
+    /// 用 Gumbel-max 技巧采样：给过滤后的对数概率加上独立的 Gumbel 噪声后取最大值。
+    ///
+    /// 与按累积概率采样的分布相同，每个 token 的噪声可以并行生成。
+    /// 目前只有 CPU 上的采样支持，其他模型的 [`check_sample_args`](crate::CausalLM::check_sample_args) 返回错误。
+    pub gumbel: bool,
+}
+
+impl SampleArgs {
+    /// 贪心采样，总是选择概率最大的 token。
+    pub const ARG_MAX: Self = Self {
+        temperature: 0.,
+        top_k: usize::MAX,
+        top_p: 1.,
+        min_p: 0.,
+        logit_bias: None,
+        eos_bias: 0.,
+        repetition_penalty: 1.,
+        sample_order: SampleStage::DEFAULT_ORDER,
+        gumbel: false,
+    };
+
+    /// 判断采样结果是否是确定的。
+    #[inline]
+    pub fn is_argmax(&self) -> bool {
+        self.temperature <= 0. || self.top_k < 2 || self.top_p <= 0.
+    }
+
+    /// 采样前需要加到 logits 上的所有偏置，`eos` 是模型的结束符。
+    pub fn biases(self, eos: utok) -> impl Iterator<Item = (utok, f32)> {
+        let eos_bias = (self.eos_bias != 0.).then_some((eos, self.eos_bias));
+        self.logit_bias.into_iter().chain(eos_bias)
+    }
+
+    /// 是否需要重复惩罚。
+    #[inline]
+    pub fn has_repetition_penalty(&self) -> bool {
+        self.repetition_penalty != 1.
+    }
+
+    /// 把重复惩罚换算为加到 logits 上的偏置，`history` 是本次生成中已经出现过的不重复的 token，`logit` 读取 token 原始的 logit。
+    ///
+    /// 不需要重复惩罚时为空。
+    pub fn penalty_biases(self, history: &[utok], logit: impl Fn(utok) -> f32) -> Vec<(utok, f32)> {
+        if !self.has_repetition_penalty() {
+            return vec![];
+        }
+        history
+            .iter()
+            .map(|&token| {
+                let x = logit(token);
+                (token, repetition_penalty(x, self.repetition_penalty) - x)
+            })
+            .collect()
+    }
+
+    /// 按名字获取预设的采样参数，名字不存在时返回 `None`。
+    ///
+    /// - `greedy`、`deterministic`：贪心采样；
+    /// - `precise`：低温度，适合问答和代码；
+    /// - `balanced`：通用的对话参数；
+    /// - `creative`：高温度，适合写作；
+    pub fn preset(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "greedy" | "deterministic" => Some(Self::ARG_MAX),
+            "precise" => Some(Self {
+                temperature: 0.3,
+                top_k: 20,
+                top_p: 0.8,
+                min_p: 0.,
+                logit_bias: None,
+                eos_bias: 0.,
+                repetition_penalty: 1.,
+                sample_order: SampleStage::DEFAULT_ORDER,
+                gumbel: false,
+            }),
+            "balanced" => Some(Self {
+                temperature: 0.7,
+                top_k: 50,
+                top_p: 0.9,
+                min_p: 0.,
+                logit_bias: None,
+                eos_bias: 0.,
+                repetition_penalty: 1.,
+                sample_order: SampleStage::DEFAULT_ORDER,
+                gumbel: false,
+            }),
+            "creative" => Some(Self {
+                temperature: 1.,
+                top_k: 100,
+                top_p: 0.95,
+                min_p: 0.,
+                logit_bias: None,
+                eos_bias: 0.,
+                repetition_penalty: 1.,
+                sample_order: SampleStage::DEFAULT_ORDER,
+                gumbel: false,
+            }),
+            _ => None,
+        }
+    }
+
+    /// 将 `top_k` 限制在词表大小 `voc` 以内。
+    ///
+    /// `top_k` 不小于词表大小时与不限制 `top_k` 等价。
+    #[inline]
+    pub fn clamp_top_k(self, voc: usize) -> Self {
+        Self {
+            top_k: self.top_k.min(voc),
+            ..self
+        }
+    }
+
+    /// 检查参数取值是否合法。
+    ///
+    /// `top_k` 大于词表大小是合法的，采样时会用 [`clamp_top_k`](Self::clamp_top_k) 限制在词表大小以内。
+    pub fn validate(&self) -> Result<(), InvalidSampleArgs> {
+        if !self.temperature.is_finite() {
+            Err(InvalidSampleArgs::Temperature)
+        } else if self.top_k == 0 {
+            Err(InvalidSampleArgs::TopK)
+        } else if !(0. ..=1.).contains(&self.top_p) {
+            Err(InvalidSampleArgs::TopP)
+        } else if !(0. ..=1.).contains(&self.min_p) {
+            Err(InvalidSampleArgs::MinP)
+        } else if !(self.repetition_penalty.is_finite() && self.repetition_penalty > 0.) {
+            Err(InvalidSampleArgs::RepetitionPenalty)
+        } else if SampleStage::DEFAULT_ORDER
+            .iter()
+            .any(|stage| !self.sample_order.contains(stage))
+        {
+            Err(InvalidSampleArgs::SampleOrder)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// 检查参数能否由采样算子完成，除了 [`validate`](Self::validate) 的检查之外，只支持默认的过滤顺序，不支持 Gumbel-max 采样。
+    ///
+    /// 供不支持在主机上按其他顺序过滤的模型实现 [`check_sample_args`](crate::CausalLM::check_sample_args)。
+    pub fn validate_for_operator(&self) -> Result<(), InvalidSampleArgs> {
+        self.validate()?;
+        if self.sample_order != SampleStage::DEFAULT_ORDER {
+            Err(InvalidSampleArgs::UnsupportedSampleOrder)
+        } else if self.gumbel {
+            Err(InvalidSampleArgs::UnsupportedGumbel)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// 不合法的采样参数。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum InvalidSampleArgs {
+    /// 温度不是有限值。
+    Temperature,
+    /// `top_k` 为 0。
+    TopK,
+    /// `top_p` 不在 `[0, 1]` 范围内。
+    TopP,
+    /// `min_p` 不在 `[0, 1]` 范围内。
+    MinP,
+    /// 重复惩罚不是正的有限值。
+    RepetitionPenalty,
+    /// `sample_order` 不是所有过滤步骤的一个排列。
+    SampleOrder,
+    /// 模型的采样不支持默认以外的 `sample_order`。
+    UnsupportedSampleOrder,
+    /// 模型的采样不支持 `gumbel`。
+    UnsupportedGumbel,
+}
+
+impl error::Error for InvalidSampleArgs {}
+impl fmt::Display for InvalidSampleArgs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Temperature => "temperature must be finite",
+            Self::TopK => "top-k must be positive",
+            Self::TopP => "top-p must be in [0, 1]",
+            Self::MinP => "min-p must be in [0, 1]",
+            Self::RepetitionPenalty => "repetition penalty must be positive",
+            Self::SampleOrder => {
+                "sample order must contain each of top-k, temperature and top-p once"
+            }
+            Self::UnsupportedSampleOrder => "sample order is not supported by this backend",
+            Self::UnsupportedGumbel => "gumbel sampling is not supported by this backend",
+        })
+    }
+}
+
+/// 默认使用贪心采样，相同的输入总是得到相同的输出。
+impl Default for SampleArgs {
+    #[inline]
+    fn default() -> Self {
+        Self::ARG_MAX
+    }
+}
+
+impl From<SampleArgs> for random_sample::SampleArgs {
+    #[inline]
+    fn from(args: SampleArgs) -> Self {
+        let SampleArgs {
+            temperature,
+            top_k,
+            top_p,
+            ..
+        } = args;
+        Self {
+            temperature,
+            top_p,
+            top_k,
+        }
+    }
+}
+
+impl From<SampleArgs> for SampleFilter {
+    #[inline]
+    fn from(args: SampleArgs) -> Self {
+        let SampleArgs {
+            temperature,
+            top_k,
+            top_p,
+            min_p,
+            sample_order,
+            ..
+        } = args;
+        Self {
+            order: sample_order,
+            temperature,
+            top_p,
+            min_p,
+            top_k,
+        }
+    }
+}
+
+#[test]
+fn test_default() {
+    let args = SampleArgs::default();
+    assert_eq!(args, SampleArgs::ARG_MAX);
+    assert!(args.is_argmax());
+    assert!(!SampleArgs {
+        temperature: 0.9,
+        top_k: 50,
+        top_p: 0.95,
+        min_p: 0.,
+        logit_bias: None,
+        eos_bias: 0.,
+        repetition_penalty: 1.,
+        sample_order: SampleStage::DEFAULT_ORDER,
+        gumbel: false,
+    }
+    .is_argmax());
+}
+
+#[test]
+fn test_preset() {
+    for name in ["greedy", "deterministic", "precise", "balanced", "creative"] {
+        let args = SampleArgs::preset(name).unwrap();
+        assert_eq!(args.validate(), Ok(()), "{name}");
+    }
+    assert!(SampleArgs::preset("greedy").unwrap().is_argmax());
+    assert!(!SampleArgs::preset("Creative").unwrap().is_argmax());
+    assert_eq!(SampleArgs::preset("unknown"), None);
+
+    let args = SampleArgs {
+        top_p: 1.5,
+        ..SampleArgs::ARG_MAX
+    };
+    assert_eq!(args.validate(), Err(InvalidSampleArgs::TopP));
+
+    for min_p in [-0.1, 1.5, f32::NAN] {
+        let args = SampleArgs {
+            min_p,
+            ..SampleArgs::ARG_MAX
+        };
+        assert_eq!(args.validate(), Err(InvalidSampleArgs::MinP));
+    }
+
+    let args = SampleArgs {
+        sample_order: [SampleStage::TopK, SampleStage::TopP, SampleStage::TopK],
+        ..SampleArgs::ARG_MAX
+    };
+    assert_eq!(args.validate(), Err(InvalidSampleArgs::SampleOrder));
+}
+
+#[test]
+fn test_clamp_top_k() {
+    const VOC: usize = 32000;
+    let args = SampleArgs {
+        temperature: 0.7,
+        top_k: VOC * 2,
+        top_p: 0.9,
+        min_p: 0.,
+        logit_bias: None,
+        eos_bias: 0.,
+        repetition_penalty: 1.,
+        sample_order: SampleStage::DEFAULT_ORDER,
+        gumbel: false,
+    };
+    assert_eq!(args.validate(), Ok(()));
+    let clamped = args.clamp_top_k(VOC);
+    assert_eq!(clamped.top_k, VOC);
+    assert_eq!(clamped.validate(), Ok(()));
+    // 与不限制 top_k 的结果相同
+    let unlimited = SampleArgs {
+        top_k: usize::MAX,
+        ..args
+    };
+    assert_eq!(unlimited.clamp_top_k(VOC), clamped);
+    assert_eq!(clamped.clamp_top_k(VOC), clamped);
+    assert_eq!(args.clamp_top_k(10).top_k, 10);
+}
+
+#[test]
+fn test_validate_for_operator() {
+    use SampleStage::*;
+
+    let args = SampleArgs::preset("balanced").unwrap();
+    assert_eq!(args.validate_for_operator(), Ok(()));
+    let reordered = SampleArgs {
+        sample_order: [Temperature, TopK, TopP],
+        ..args
+    };
+    assert_eq!(reordered.validate(), Ok(()));
+    assert_eq!(
+        reordered.validate_for_operator(),
+        Err(InvalidSampleArgs::UnsupportedSampleOrder)
+    );
+    // 取值不合法时先报告取值错误
+    let invalid = SampleArgs {
+        top_p: 2.,
+        ..reordered
+    };
+    assert_eq!(
+        invalid.validate_for_operator(),
+        Err(InvalidSampleArgs::TopP)
+    );
+    let gumbel = SampleArgs {
+        gumbel: true,
+        ..args
+    };
+    assert_eq!(gumbel.validate(), Ok(()));
+    assert_eq!(
+        gumbel.validate_for_operator(),
+        Err(InvalidSampleArgs::UnsupportedGumbel)
+    );
+}
+
+#[test]
+fn test_repetition_penalty() {
+    let logits = [2f32, -1., 1.9, 0.5];
+    let args = SampleArgs {
+        repetition_penalty: 2.,
+        ..SampleArgs::ARG_MAX
+    };
+    assert_eq!(args.validate(), Ok(()));
+    // 正的 logit 除以惩罚，负的 logit 乘以惩罚
+    let history = [0, 1];
+    let biases = args.penalty_biases(&history, |t| logits[t as usize]);
+    assert_eq!(biases, [(0, -1.), (1, -1.)]);
+    let mut penalized = logits;
+    for (token, bias) in biases {
+        penalized[token as usize] += bias;
+    }
+    assert_eq!(penalized, [1., -2., 1.9, 0.5]);
+    // 惩罚之后概率最大的 token 改变
+    assert_eq!(common_devices::argmax(&logits), 0);
+    assert_eq!(common_devices::argmax(&penalized), 2);
+
+    // 默认不惩罚
+    assert!(!SampleArgs::ARG_MAX.has_repetition_penalty());
+    assert!(SampleArgs::ARG_MAX
+        .penalty_biases(&history, |t| logits[t as usize])
+        .is_empty());
+    for repetition_penalty in [0., -1., f32::INFINITY, f32::NAN] {
+        let args = SampleArgs {
+            repetition_penalty,
+            ..SampleArgs::ARG_MAX
+        };
+        assert_eq!(args.validate(), Err(InvalidSampleArgs::RepetitionPenalty));
+    }
+}
@@ -2,9 +2,13 @@
 
 use minijinja::Environment;
 use serde::Serialize;
-use std::sync::{
-    atomic::{AtomicUsize, Ordering::Relaxed},
-    OnceLock, RwLock,
+use std::{
+    collections::{BTreeMap, HashMap},
+    error, fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering::Relaxed},
+        OnceLock, RwLock,
+    },
 };
 
 #[repr(transparent)]
@@ -16,7 +20,121 @@ pub struct Message<'a> {
     pub content: &'a str,
 }
 
+/// 角色名映射，把调用者使用的角色名转换为模板使用的 `system`、`user`、`assistant`。
+///
+/// 其他约定（如 `human`、`ai`）需要用 [`alias`](Self::alias) 添加。
+/// 默认没有别名的角色（如 `tool`、`developer`）原样交给模板，[`strict`](Self::strict) 的映射拒绝这些角色。
+#[derive(Clone, Default, Debug)]
+pub struct RoleMap {
+    aliases: HashMap<String, String>,
+    strict: bool,
+}
+
+/// 消息的角色不在 [`RoleMap`] 中。
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct UnknownRole(pub String);
+
+impl error::Error for UnknownRole {}
+impl fmt::Display for UnknownRole {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown role \"{}\"", self.0)
+    }
+}
+
+impl RoleMap {
+    /// 只认识模板使用的角色名和添加的别名，其他角色返回错误。
+    pub fn strict() -> Self {
+        Self {
+            aliases: ["system", "user", "assistant"]
+                .into_iter()
+                .map(|role| (role.into(), role.into()))
+                .collect(),
+            strict: true,
+        }
+    }
+
+    /// 将角色名 `name` 映射为模板的角色名 `role`。
+    pub fn alias(mut self, name: impl Into<String>, role: impl Into<String>) -> Self {
+        self.aliases.insert(name.into(), role.into());
+        self
+    }
+
+    /// 查找 `name` 对应的模板角色名，没有别名时原样返回，严格的映射返回错误。
+    pub fn get<'a>(&'a self, name: &'a str) -> Result<&'a str, UnknownRole> {
+        match self.aliases.get(name) {
+            Some(role) => Ok(role.as_str()),
+            None if !self.strict => Ok(name),
+            None => Err(UnknownRole(name.into())),
+        }
+    }
+
+    /// 转换所有消息的角色名，任何一条消息的角色未知时返回错误。
+    pub fn apply<'a>(&'a self, messages: &[Message<'a>]) -> Result<Vec<Message<'a>>, UnknownRole> {
+        messages
+            .iter()
+            .map(|msg| {
+                Ok(Message {
+                    role: self.get(msg.role)?,
+                    content: msg.content,
+                })
+            })
+            .collect()
+    }
+}
+
+/// 内置的对话模板，不需要用户提供 Jinja 源码。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum BuiltinTemplate {
+    /// `<|im_start|>role\n...<|im_end|>` 格式，OpenAI、Qwen 等模型使用。
+    ChatML,
+}
+
+impl BuiltinTemplate {
+    /// 按名字查找内置模板，不区分大小写，名字不存在时返回 `None`。
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "chatml" => Some(Self::ChatML),
+            _ => None,
+        }
+    }
+
+    /// 模板的 Jinja 源码。
+    pub fn source(&self) -> &'static str {
+        match self {
+            Self::ChatML => {
+                "\
+{%- for message in messages -%}
+    {{ '<|im_start|>' + message['role'] + '\n' + message['content'] + '<|im_end|>\n' }}
+{%- endfor -%}
+{%- if add_generation_prompt -%}
+    {{ '<|im_start|>assistant\n' }}
+{%- endif -%}"
+            }
+        }
+    }
+
+    /// 模板插入的特殊 token，需要作为整体编码。
+    pub fn special_tokens(&self) -> &'static [&'static str] {
+        match self {
+            Self::ChatML => &["<|im_start|>", "<|im_end|>"],
+        }
+    }
+
+    /// 标志一条消息结束的特殊 token，生成时遇到它应该停止。
+    pub fn stop_tokens(&self) -> &'static [&'static str] {
+        match self {
+            Self::ChatML => &["<|im_end|>"],
+        }
+    }
+}
+
 impl ChatTemplate {
+    /// 创建内置的对话模板。
+    #[inline]
+    pub fn builtin(template: BuiltinTemplate) -> Self {
+        Self::new(template.source().into())
+    }
+
     pub fn new(template: String) -> Self {
         static NEXT: AtomicUsize = AtomicUsize::new(0);
         let id = NEXT.fetch_add(1, Relaxed).to_string();
@@ -36,6 +154,18 @@ impl ChatTemplate {
         bos_token: &str,
         eos_token: &str,
         add_generation_prompt: bool,
+    ) -> Result<String, minijinja::Error> {
+        self.render_with(messages, bos_token, eos_token, add_generation_prompt, &[])
+    }
+
+    /// 渲染模板，并将 `vars` 作为额外的布尔变量（如 `enable_thinking`）传入模板。
+    pub fn render_with(
+        &self,
+        messages: &[Message<'_>],
+        bos_token: &str,
+        eos_token: &str,
+        add_generation_prompt: bool,
+        vars: &[(&str, bool)],
     ) -> Result<String, minijinja::Error> {
         #[derive(Serialize)]
         struct Args<'a> {
@@ -43,6 +173,8 @@ impl ChatTemplate {
             bos_token: &'a str,
             eos_token: &'a str,
             add_generation_prompt: bool,
+            #[serde(flatten)]
+            vars: BTreeMap<&'a str, bool>,
         }
 
         jinja()
@@ -55,6 +187,7 @@ impl ChatTemplate {
                 bos_token,
                 eos_token,
                 add_generation_prompt,
+                vars: vars.iter().copied().collect(),
             })
     }
 }
@@ -117,3 +250,111 @@ fn test() {
         .unwrap();
     assert_eq!(result, "<用户>Hello, who are you?<AI>");
 }
+
+#[test]
+fn test_enable_thinking() {
+    const QWEN3: &str = "{% for message in messages %}{{ '<|im_start|>' + message['role'] + '\n' + message['content'] + '<|im_end|>\n' }}{% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\n' }}{% if enable_thinking == false %}{{ '<think>\n\n</think>\n\n' }}{% endif %}{% endif %}";
+
+    let template = ChatTemplate::new(QWEN3.into());
+    let messages = [Message {
+        role: "user",
+        content: "Hi",
+    }];
+    let render = |vars: &[(&str, bool)]| {
+        template
+            .render_with(&messages, "", "<|im_end|>", true, vars)
+            .unwrap()
+    };
+
+    let prompt = "<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n";
+    assert_eq!(render(&[]), prompt);
+    assert_eq!(render(&[("enable_thinking", true)]), prompt);
+    assert_eq!(
+        render(&[("enable_thinking", false)]),
+        format!("{prompt}<think>\n\n</think>\n\n")
+    );
+}
+
+#[test]
+fn test_chatml() {
+    assert_eq!(
+        BuiltinTemplate::from_name("ChatML"),
+        Some(BuiltinTemplate::ChatML)
+    );
+    assert_eq!(BuiltinTemplate::from_name("unknown"), None);
+
+    let template = ChatTemplate::builtin(BuiltinTemplate::ChatML);
+    let messages = [
+        Message {
+            role: "system",
+            content: "You are a helpful assistant.",
+        },
+        Message {
+            role: "user",
+            content: "Hi",
+        },
+        Message {
+            role: "assistant",
+            content: "Hello! How can I help you?",
+        },
+        Message {
+            role: "user",
+            content: "Tell me a joke.",
+        },
+    ];
+    let result = template.render(&messages, "", "", true).unwrap();
+    assert_eq!(
+        result,
+        "\
+<|im_start|>system\nYou are a helpful assistant.<|im_end|>\n\
+<|im_start|>user\nHi<|im_end|>\n\
+<|im_start|>assistant\nHello! How can I help you?<|im_end|>\n\
+<|im_start|>user\nTell me a joke.<|im_end|>\n\
+<|im_start|>assistant\n"
+    );
+    let result = template.render(&messages[..2], "", "", false).unwrap();
+    assert!(result.ends_with("Hi<|im_end|>\n"));
+}
+
+#[test]
+fn test_role_map() {
+    let roles = RoleMap::default()
+        .alias("human", "user")
+        .alias("ai", "assistant");
+    assert_eq!(roles.get("user"), Ok("user"));
+    assert_eq!(roles.get("human"), Ok("user"));
+    // 默认没有别名的角色原样交给模板
+    assert_eq!(roles.get("tool"), Ok("tool"));
+    assert_eq!(roles.get("developer"), Ok("developer"));
+    let strict = RoleMap::strict().alias("human", "user");
+    assert_eq!(strict.get("human"), Ok("user"));
+    assert_eq!(strict.get("assistant"), Ok("assistant"));
+    assert_eq!(strict.get("bot"), Err(UnknownRole("bot".into())));
+
+    let messages = roles
+        .apply(&[
+            Message {
+                role: "human",
+                content: "Hi",
+            },
+            Message {
+                role: "ai",
+                content: "Hello",
+            },
+        ])
+        .unwrap();
+    let result = ChatTemplate::builtin(BuiltinTemplate::ChatML)
+        .render(&messages, "", "", false)
+        .unwrap();
+    assert_eq!(
+        result,
+        "<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\nHello<|im_end|>\n"
+    );
+
+    let unknown = [Message {
+        role: "bot",
+        content: "Hi",
+    }];
+    assert!(RoleMap::strict().apply(&unknown).is_err());
+    assert_eq!(RoleMap::default().apply(&unknown).unwrap()[0].role, "bot");
+}
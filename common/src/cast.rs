@@ -0,0 +1,73 @@
+use crate::{bf16, f16, Blob, FileLoadError};
+use digit_layout::{
+    types::{BF16, F16, F32},
+    AsDigit, DigitLayout,
+};
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use std::ops::Deref;
+use tensor::Tensor;
+
+/// 是否支持将数据类型 `from` 转换为 `to`。
+pub fn can_cast(from: DigitLayout, to: DigitLayout) -> bool {
+    from == to || matches!((from, to), (F16 | BF16 | F32, F16 | BF16 | F32))
+}
+
+/// 将 `src` 转换为数据类型 `dt` 的连续张量，数据类型相同时只复制，不支持的转换返回错误。
+///
+/// 先按步长重排为连续存储再逐元素转换，加载时施加的转置等变换得以保留。
+pub fn cast_tensor<P: Deref<Target = [u8]>>(
+    src: &Tensor<P>,
+    dt: DigitLayout,
+) -> Result<Tensor<Blob>, FileLoadError> {
+    if src.data_layout() == dt || !src.is_contiguous() {
+        let mut ans = Tensor::alloc(src.data_layout(), src.shape(), Blob::new);
+        src.reform_to(&mut ans);
+        return if ans.data_layout() == dt {
+            Ok(ans)
+        } else {
+            cast_tensor(&ans, dt)
+        };
+    }
+    Ok(match (src.data_layout(), dt) {
+        (F16, BF16) => typed(src, |x: &f16| bf16::from_f32(x.to_f32())),
+        (F16, F32) => typed(src, |x: &f16| x.to_f32()),
+        (BF16, F16) => typed(src, |x: &bf16| f16::from_f32(x.to_f32())),
+        (BF16, F32) => typed(src, |x: &bf16| x.to_f32()),
+        (F32, F16) => typed(src, |x: &f32| f16::from_f32(*x)),
+        (F32, BF16) => typed(src, |x: &f32| bf16::from_f32(*x)),
+        (from, _) => return Err(FileLoadError::UnsupportedDtype(from)),
+    })
+}
+
+fn typed<P, T, U>(src: &Tensor<P>, cast: impl Fn(&T) -> U + Sync) -> Tensor<Blob>
+where
+    P: Deref<Target = [u8]>,
+    T: AsDigit + Sync,
+    U: AsDigit + Send,
+{
+    use tensor::{reslice, reslice_mut};
+
+    assert_eq!(src.data_layout(), T::LAYOUT);
+    let mut ans = Tensor::alloc(U::LAYOUT, src.shape(), Blob::new);
+
+    reslice(src.as_slice())
+        .par_iter()
+        .zip(reslice_mut(ans.physical_mut()))
+        .for_each(|(src, dst)| *dst = cast(src));
+
+    ans
+}
+
+#[test]
+fn test_can_cast() {
+    use digit_layout::types::U32;
+
+    for from in [F16, BF16, F32] {
+        for to in [F16, BF16, F32] {
+            assert!(can_cast(from, to));
+        }
+        assert!(!can_cast(from, U32));
+        assert!(!can_cast(U32, from));
+    }
+    assert!(can_cast(U32, U32));
+}
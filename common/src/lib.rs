@@ -11,10 +11,12 @@ pub type utok = u32;
 pub type upos = u32;
 
 mod blob;
+mod cast;
 pub mod safe_tensors;
 pub mod test_model;
 
 pub use blob::Blob;
+pub use cast::{can_cast, cast_tensor};
 pub use half::{bf16, f16};
 
 /// 加载 safetensors 文件可能产生的错误。
@@ -24,4 +26,17 @@ pub enum FileLoadError {
     Io(std::io::Error),
     /// Json 解析错误。
     Json(serde_json::Error),
+    /// 严格模式下，模型的数据类型不能直接用于计算。
+    UnsupportedDtype(digit_layout::DigitLayout),
+    /// 不支持的旋转位置编码缩放方式。
+    UnsupportedRopeScaling(String),
+    /// 张量缺失，或者数据类型、形状不符合要求。
+    InvalidTensor(String),
+    /// 加载时指定的量化分组大小与模型保存的不同。
+    Int4GroupMismatch {
+        /// 加载时指定的分组大小。
+        expected: usize,
+        /// 模型保存的分组大小。
+        found: usize,
+    },
 }
@@ -1,17 +1,22 @@
 ﻿//! safetensors 文件的加载和访问。
 
-use crate::FileLoadError::{self, Io, Json};
+use crate::{
+    Blob,
+    FileLoadError::{self, Io, Json},
+};
+use digit_layout::DigitLayout;
 use memmap2::Mmap;
 use std::{
     collections::{hash_map, HashMap},
     fs::File,
-    io::{Error as IoError, ErrorKind::NotFound},
+    io::{self, BufWriter, Error as IoError, ErrorKind::NotFound, Write},
     mem::size_of_val,
     ops::Deref,
     path::Path,
     pin::Pin,
     sync::Arc,
 };
+use tensor::Tensor;
 
 pub use safetensors::{tensor::TensorInfo, Dtype};
 
@@ -285,6 +290,86 @@ pub struct SafeTensorsHeaderMetadata {
     pub format: String,
 }
 
+/// 按顺序将 `tensors` 写入 safetensors 文件，可用于生成任意命名的权重文件。
+///
+/// 每个张量按自身的形状连续写入，按步长转置或切分的张量先重排为连续存储。
+pub fn write_safetensors<P: Deref<Target = [u8]>>(
+    path: impl AsRef<Path>,
+    tensors: &[(String, Tensor<P>)],
+) -> io::Result<()> {
+    let mut offset = 0usize;
+    let header = SafeTensorsHeader {
+        tensors: tensors
+            .iter()
+            .map(|(name, tensor)| {
+                let info = TensorInfo {
+                    dtype: convert(tensor.data_layout()),
+                    shape: tensor.shape().iter().map(|&d| d as _).collect(),
+                    data_offsets: {
+                        let start = offset;
+                        offset += tensor.bytes_size();
+                        (start, offset)
+                    },
+                };
+                (name.clone(), info)
+            })
+            .collect(),
+        metadata: SafeTensorsHeaderMetadata {
+            format: "rs".into(),
+        },
+    };
+
+    let header = {
+        let str = serde_json::to_string(&header)?;
+        let len = str.len();
+        const ALIGN: usize = std::mem::size_of::<usize>();
+        let aligned = (len + ALIGN - 1) & !(ALIGN - 1);
+
+        let mut buffer = Vec::with_capacity(aligned);
+        let mut write = BufWriter::new(&mut buffer);
+        write.write_all(&(aligned as u64).to_le_bytes())?;
+        write.write_all(str.as_bytes())?;
+        for _ in len..aligned {
+            write.write_all(&[32])?;
+        }
+        drop(write);
+        buffer
+    };
+
+    let mut file = File::create(path)?;
+    file.write_all(&header)?;
+    for (_, tensor) in tensors {
+        if tensor.is_contiguous() {
+            file.write_all(tensor.as_slice())?;
+        } else {
+            let mut ans = Tensor::alloc(tensor.data_layout(), tensor.shape(), Blob::new);
+            tensor.reform_to(&mut ans);
+            file.write_all(ans.as_slice())?;
+        }
+    }
+    Ok(())
+}
+
+fn convert(dtype: DigitLayout) -> Dtype {
+    use digit_layout::types::*;
+    match dtype {
+        BOOL => Dtype::BOOL,
+        U8 => Dtype::U8,
+        I8 => Dtype::I8,
+        I16 => Dtype::I16,
+        U16 => Dtype::U16,
+        F16 => Dtype::F16,
+        BF16 => Dtype::BF16,
+        I32 => Dtype::I32,
+        U32 => Dtype::U32,
+        F32 => Dtype::F32,
+        F64 => Dtype::F64,
+        I64 => Dtype::I64,
+        U64 => Dtype::U64,
+        _ => todo!(),
+    }
+}
+
 fn load_header(file: &Mmap) -> Result<SafeTensorsHeader, FileLoadError> {
     let header_len = unsafe { *file.as_ptr().cast::<u64>() };
     let header = &file[size_of_val(&header_len)..][..header_len as _];
@@ -6,9 +6,15 @@ macro_rules! slice {
 }
 
 mod gather;
+mod quant;
+mod rope;
+mod softcap;
 
 use common::{f16, utok};
-use common_devices::{Operators, SliceOn};
+use common_devices::{
+    argmax, filter_logits, gumbel_max, pick, Operators, SampleFilter, SampleRng, SampleStage,
+    SliceOn,
+};
 use digit_layout::types::F16;
 use operators::{
     fuesd_softmax::common_cpu as softmax,
@@ -20,13 +26,19 @@ use operators::{
     rope::common_cpu as rope,
     Operator, QueueOf,
 };
-use std::ops::{Deref, DerefMut};
-use tensor::Tensor;
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tensor::{udim, Tensor};
 
 pub extern crate tensor;
 
 pub use common_devices::{Kernels, KernelsA, KernelsB};
 pub use operators::common_cpu::{Handle as Cpu, ThisThread};
+pub use quant::Int4Matrix;
+pub use rope::RopeTable;
 
 pub struct CpuKernels {
     reform: reform::Operator,
@@ -36,10 +48,19 @@ pub struct CpuKernels {
     softmax: softmax::Operator,
     mlp: mlp::Operator,
     sample: random_sample::Operator,
+    /// 按非默认顺序过滤时在主机上采样使用的随机数发生器。
+    rng: Mutex<SampleRng>,
 }
 
 impl CpuKernels {
+    /// 采样一个 token，`temperature` 不大于 0 时总是返回概率最大的 token，多个最大值相等时返回序号最小的。
+    ///
+    /// `top_k` 超过词表大小时限制在词表大小以内。
     pub fn sample(&self, temperature: f32, top_p: f32, top_k: usize, logits: &[f16]) -> utok {
+        // 算子规约的顺序不保证平局时的选择，贪心采样直接在主机上计算
+        if temperature <= 0. || top_k < 2 || top_p <= 0. {
+            return argmax(logits) as _;
+        }
         let mut kv_pair = KVPair::new(0, f16::ZERO);
         let mut args = Args::<Cpu>::new(F16, logits.len());
         args.kv_pair_base = &mut kv_pair as *mut _ as _;
@@ -47,11 +68,80 @@ impl CpuKernels {
         args.detail = SampleArgs {
             temperature,
             top_p,
-            top_k,
+            top_k: top_k.min(logits.len()),
         };
         self.sample.launch(&args, &ThisThread).unwrap();
         kv_pair.idx() as _
     }
+
+    /// 给 `biases` 指定的 token 的 logit 加上偏置后采样一个 token。
+    pub fn sample_with_bias(
+        &self,
+        temperature: f32,
+        top_p: f32,
+        top_k: usize,
+        biases: impl IntoIterator<Item = (utok, f32)>,
+        logits: &[f16],
+    ) -> utok {
+        let mut biases = biases.into_iter().peekable();
+        if biases.peek().is_none() {
+            return self.sample(temperature, top_p, top_k, logits);
+        }
+        let mut biased = logits.to_vec();
+        for (token, bias) in biases {
+            let x = &mut biased[token as usize];
+            *x = f16::from_f32(x.to_f32() + bias);
+        }
+        self.sample(temperature, top_p, top_k, &biased)
+    }
+}
+
+impl CpuKernels {
+    /// 给 `biases` 指定的 token 的 logit 加上偏置后，按 `filter` 依次过滤并采样一个 token。
+    ///
+    /// `min_p` 大于 0 时先保留概率不小于最大概率 `min_p` 倍的 token，再按 `order` 过滤。
+    /// `order` 为默认顺序且 `min_p` 为 0 时与 [`sample_with_bias`](Self::sample_with_bias) 相同。
+    pub fn sample_ordered(
+        &self,
+        filter: SampleFilter,
+        biases: impl IntoIterator<Item = (utok, f32)>,
+        logits: &[f16],
+    ) -> utok {
+        let SampleFilter {
+            temperature,
+            top_p,
+            top_k,
+            ..
+        } = filter;
+        // 采样算子不支持 min-p，需要时在主机上过滤
+        if filter.order == SampleStage::DEFAULT_ORDER && filter.min_p <= 0. {
+            return self.sample_with_bias(temperature, top_p, top_k, biases, logits);
+        }
+        let candidates = filter_logits(filter, &biased_f32(biases, logits));
+        pick(&candidates, self.rng.lock().unwrap().next_f32())
+    }
+
+    /// 给 `biases` 指定的 token 的 logit 加上偏置后，按 `filter` 依次过滤，用 Gumbel-max 技巧采样一个 token。
+    ///
+    /// 采样的分布与 [`sample_ordered`](Self::sample_ordered) 相同。
+    pub fn sample_gumbel(
+        &self,
+        filter: SampleFilter,
+        biases: impl IntoIterator<Item = (utok, f32)>,
+        logits: &[f16],
+    ) -> utok {
+        let candidates = filter_logits(filter, &biased_f32(biases, logits));
+        gumbel_max(&candidates, &mut self.rng.lock().unwrap())
+    }
+}
+
+/// 把 `logits` 转换为 f32 并加上 `biases` 指定的偏置。
+fn biased_f32(biases: impl IntoIterator<Item = (utok, f32)>, logits: &[f16]) -> Vec<f32> {
+    let mut logits = logits.iter().map(|x| x.to_f32()).collect::<Vec<_>>();
+    for (token, bias) in biases {
+        logits[token as usize] += bias;
+    }
+    logits
 }
 
 impl Default for CpuKernels {
@@ -64,6 +154,11 @@ impl Default for CpuKernels {
             softmax: softmax::Operator::new(&Cpu),
             mlp: mlp::Operator::new(&Cpu),
             sample: random_sample::Operator::new(&Cpu),
+            rng: Mutex::new(SampleRng::new(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_nanos() as _),
+            )),
         }
     }
 }
@@ -121,4 +216,148 @@ impl KernelsB for CpuKernels {
     {
         gather::gather(x, table, tokens);
     }
+
+    fn softcap<T>(&self, x: &mut Tensor<T>, cap: f32, _queue: &QueueOf<Self::Handle>)
+    where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+    {
+        softcap::softcap(x, cap);
+    }
+
+    fn attention_f32<O, Q, K, V>(
+        &self,
+        o: &mut Tensor<O>,
+        q: &Tensor<Q>,
+        k: &Tensor<K>,
+        v: &Tensor<V>,
+        scale: f32,
+        softcap: Option<f32>,
+        mask: impl Fn(udim, udim) -> bool,
+        _queue: &QueueOf<Self::Handle>,
+    ) where
+        O: DerefMut<Target = SliceOn<Self::Handle>>,
+        Q: Deref<Target = SliceOn<Self::Handle>>,
+        K: Deref<Target = SliceOn<Self::Handle>>,
+        V: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        common_devices::masked_attention_f32(o, q, k, v, scale, softcap, mask);
+    }
+}
+
+#[test]
+fn test_sample_argmax() {
+    let logits = [0.1f32, 2.5, -1., 2.4, 0.].map(f16::from_f32).to_vec();
+    let kernels = CpuKernels::default();
+    for _ in 0..8 {
+        assert_eq!(kernels.sample(0., 1., usize::MAX, &logits), 1);
+    }
+}
+
+#[test]
+fn test_sample_argmax_tie() {
+    let logits = [0.1f32, 2.5, -1., 2.5, 2.5].map(f16::from_f32).to_vec();
+    let kernels = CpuKernels::default();
+    for _ in 0..8 {
+        assert_eq!(kernels.sample(0., 1., usize::MAX, &logits), 1);
+        assert_eq!(kernels.sample(1., 1., 1, &logits), 1);
+    }
+    // 偏置使后面的 token 与最大值相等时仍然选择序号最小的
+    let order = [
+        SampleStage::Temperature,
+        SampleStage::TopK,
+        SampleStage::TopP,
+    ];
+    let biased = Some((0, 2.4));
+    assert_eq!(
+        kernels.sample_with_bias(0., 1., usize::MAX, biased, &logits),
+        0
+    );
+    let filter = SampleFilter {
+        order,
+        ..SampleFilter::new(0., 1., usize::MAX)
+    };
+    assert_eq!(kernels.sample_ordered(filter, None, &logits), 1);
+}
+
+#[test]
+fn test_sample_with_bias() {
+    // token 2 是概率最大的换行符
+    let logits = [0.1f32, 0.3, 5., -0.2, 0.].map(f16::from_f32).to_vec();
+    let kernels = CpuKernels::default();
+    let count = |bias| {
+        (0..64)
+            .filter(|_| kernels.sample_with_bias(1., 1., usize::MAX, bias, &logits) == 2)
+            .count()
+    };
+    assert!(count(Some((2, -10.))) < count(None));
+    // 贪心采样时偏置同样生效
+    assert_eq!(
+        kernels.sample_with_bias(0., 1., usize::MAX, None, &logits),
+        2
+    );
+    assert_ne!(
+        kernels.sample_with_bias(0., 1., usize::MAX, Some((2, -10.)), &logits),
+        2
+    );
+}
+
+#[test]
+fn test_sample_ordered() {
+    use SampleStage::*;
+
+    // 原始分布中 token 0 的概率超过 top_p，先截断时总是采样到 token 0
+    let logits = [4f32, 2., 1., 0.].map(f16::from_f32).to_vec();
+    let kernels = CpuKernels::default();
+    let sample = |order| {
+        let filter = SampleFilter {
+            order,
+            ..SampleFilter::new(4., 0.8, usize::MAX)
+        };
+        (0..64)
+            .map(|_| kernels.sample_ordered(filter, None, &logits))
+            .collect::<Vec<_>>()
+    };
+    assert!(sample([TopK, TopP, Temperature]).iter().all(|&t| t == 0));
+    let tokens = sample([Temperature, TopP, TopK]);
+    assert!(tokens.iter().all(|&t| t < 3));
+    assert!(tokens.iter().any(|&t| t != 0));
+    // 默认顺序下 min-p 同样生效，token 1 的概率只有最大值的 0.14 倍
+    let min_p = |min_p| {
+        let filter = SampleFilter {
+            min_p,
+            ..SampleFilter::new(1., 1., usize::MAX)
+        };
+        kernels.sample_ordered(filter, None, &logits)
+    };
+    assert!((0..64).all(|_| min_p(0.2) == 0));
+    assert!((0..64).any(|_| min_p(0.) != 0));
+}
+
+#[test]
+fn test_sample_gumbel() {
+    let logits = [0.1f32, 2.5, -1., 2.4, 0.].map(f16::from_f32).to_vec();
+    let kernels = CpuKernels::default();
+    // 贪心采样总是选择最大值，top_k 限制候选范围
+    assert_eq!(
+        kernels.sample_gumbel(SampleFilter::new(0., 1., 2), None, &logits),
+        1
+    );
+    let tokens = (0..64)
+        .map(|_| kernels.sample_gumbel(SampleFilter::new(1., 1., 2), None, &logits))
+        .collect::<Vec<_>>();
+    assert!(tokens.iter().all(|&t| matches!(t, 1 | 3)));
+    assert!(tokens.contains(&1) && tokens.contains(&3));
+}
+
+#[test]
+fn test_sample_top_k() {
+    let logits = [0.1f32, 12., -1., 2.4, 0.].map(f16::from_f32).to_vec();
+    let voc = logits.len();
+    let kernels = CpuKernels::default();
+    // 超过词表大小的 top_k 不会越界，且与不限制 top_k 的结果相同
+    for top_k in [voc, voc * 2, usize::MAX] {
+        for _ in 0..8 {
+            assert_eq!(kernels.sample(1., 0.5, top_k, &logits), 1);
+        }
+    }
 }
@@ -0,0 +1,332 @@
+use common::{f16, Blob};
+use digit_layout::types::F16;
+use std::ops::{Deref, DerefMut};
+use tensor::{reslice_mut, udim, Tensor};
+
+/// 4 位分组量化的矩阵，打包格式与 GPTQ 相同。
+///
+/// 逻辑形状为 `[k, n]`，沿 `k` 方向每 `group` 行共享一组缩放和零点，反量化为 `(q - zero) * scale`。
+///
+/// - `qweight` 形状为 `[k / 8, n]`，每个 `u32` 沿 `k` 方向打包 8 个值，低位在前；
+/// - `qzeros` 形状为 `[k / group, n / 8]`，每个 `u32` 沿 `n` 方向打包 8 个零点，低位在前；
+/// - `scales` 形状为 `[k / group, n]`。
+pub struct Int4Matrix {
+    k: usize,
+    n: usize,
+    group: usize,
+    qweight: Vec<u32>,
+    qzeros: Vec<u32>,
+    scales: Vec<f16>,
+}
+
+impl Int4Matrix {
+    /// 从 GPTQ 格式的打包数据构造。
+    pub fn new(
+        k: usize,
+        n: usize,
+        group: usize,
+        qweight: Vec<u32>,
+        qzeros: Vec<u32>,
+        scales: Vec<f16>,
+    ) -> Self {
+        assert!(group > 0 && group % 8 == 0 && k % group == 0 && n % 8 == 0);
+        assert_eq!(qweight.len(), k / 8 * n);
+        assert_eq!(qzeros.len(), k / group * n / 8);
+        assert_eq!(scales.len(), k / group * n);
+        Self {
+            k,
+            n,
+            group,
+            qweight,
+            qzeros,
+            scales,
+        }
+    }
+
+    /// 将形状为 `[k, n]` 的 f16 矩阵按 `group` 行一组量化，矩阵不必连续。
+    pub fn quantize<T>(w: &Tensor<T>, group: usize) -> Self
+    where
+        T: Deref<Target = [u8]>,
+    {
+        assert_eq!(w.data_layout(), F16);
+        let &[k, n] = w.shape() else {
+            panic!("quantize requires a 2d tensor")
+        };
+        let (k, n) = (k as usize, n as usize);
+        assert!(group > 0 && k % group == 0);
+        let &[sk, sn] = w.strides() else {
+            unreachable!()
+        };
+        let base = w.base().cast::<f16>();
+        let at = |i: usize, j: usize| {
+            let offset = i as isize * sk as isize + j as isize * sn as isize;
+            unsafe { *base.offset(offset) }.to_f32()
+        };
+
+        let mut qweight = vec![0; k / 8 * n];
+        let mut qzeros = vec![0; k / group * n / 8];
+        let mut scales = vec![f16::ZERO; k / group * n];
+        for g in 0..k / group {
+            let rows = g * group..(g + 1) * group;
+            for j in 0..n {
+                // 量化范围总是包含 0，使 0 可以精确表示
+                let (min, max) = rows
+                    .clone()
+                    .map(|i| at(i, j))
+                    .fold((0f32, 0f32), |(min, max), x| (min.min(x), max.max(x)));
+                let scale = match f16::from_f32((max - min) / 15.) {
+                    s if s > f16::ZERO => s,
+                    _ => f16::ONE,
+                };
+                let s = scale.to_f32();
+                let zero = (-min / s).round().clamp(0., 15.) as u32;
+                scales[g * n + j] = scale;
+                qzeros[g * n / 8 + j / 8] |= zero << (j % 8 * 4);
+                for i in rows.clone() {
+                    let q = ((at(i, j) / s).round() + zero as f32).clamp(0., 15.) as u32;
+                    qweight[i / 8 * n + j] |= q << (i % 8 * 4);
+                }
+            }
+        }
+        Self::new(k, n, group, qweight, qzeros, scales)
+    }
+
+    /// 逻辑形状 `[k, n]`。
+    #[inline]
+    pub fn shape(&self) -> [usize; 2] {
+        [self.k, self.n]
+    }
+
+    /// 共享缩放和零点的行数。
+    #[inline]
+    pub fn group(&self) -> usize {
+        self.group
+    }
+
+    /// 打包的量化值，形状为 `[k / 8, n]`。
+    #[inline]
+    pub fn qweight(&self) -> &[u32] {
+        &self.qweight
+    }
+
+    /// 打包的零点，形状为 `[k / group, n / 8]`。
+    #[inline]
+    pub fn qzeros(&self) -> &[u32] {
+        &self.qzeros
+    }
+
+    /// 缩放，形状为 `[k / group, n]`。
+    #[inline]
+    pub fn scales(&self) -> &[f16] {
+        &self.scales
+    }
+
+    /// 打包存储占用的字节数。
+    #[inline]
+    pub fn nbytes(&self) -> usize {
+        (self.qweight.len() + self.qzeros.len()) * size_of::<u32>()
+            + self.scales.len() * size_of::<f16>()
+    }
+
+    /// 第 `i` 行第 `j` 列的量化值。
+    #[inline]
+    pub fn q(&self, i: usize, j: usize) -> u8 {
+        ((self.qweight[i / 8 * self.n + j] >> (i % 8 * 4)) & 0xf) as _
+    }
+
+    /// 第 `g` 组第 `j` 列的零点。
+    #[inline]
+    pub fn zero(&self, g: usize, j: usize) -> u8 {
+        ((self.qzeros[g * self.n / 8 + j / 8] >> (j % 8 * 4)) & 0xf) as _
+    }
+
+    /// 第 `g` 组第 `j` 列的缩放。
+    #[inline]
+    pub fn scale(&self, g: usize, j: usize) -> f16 {
+        self.scales[g * self.n + j]
+    }
+
+    /// 反量化第 `i` 行第 `j` 列的元素。
+    #[inline]
+    pub fn get(&self, i: usize, j: usize) -> f32 {
+        let g = i / self.group;
+        (self.q(i, j) as f32 - self.zero(g, j) as f32) * self.scale(g, j).to_f32()
+    }
+
+    /// 计算 `y = beta * y + alpha * x w`，`x` 形状为 `[m, k]`，`y` 形状为 `[m, n]`，都是 f16 且不必连续。
+    ///
+    /// 每次只反量化一组 `group` 行权重，与 `x` 对应的列相乘累加，不展开整个矩阵。
+    pub fn mat_mul<T, U>(&self, y: &mut Tensor<T>, beta: f32, x: &Tensor<U>, alpha: f32)
+    where
+        T: DerefMut<Target = [u8]>,
+        U: Deref<Target = [u8]>,
+    {
+        assert_eq!(x.data_layout(), F16);
+        assert_eq!(y.data_layout(), F16);
+        let (&[m, k], &[my, n]) = (x.shape(), y.shape()) else {
+            panic!("mat_mul requires 2d tensors")
+        };
+        assert_eq!(
+            [m as usize, k as usize, n as usize],
+            [my as usize, self.k, self.n]
+        );
+        let m = m as usize;
+        let (&[sxm, sxk], &[sym, syn]) = (x.strides(), y.strides()) else {
+            unreachable!()
+        };
+
+        let xb = x.base().cast::<f16>();
+        let x_at = |i: usize, p: usize| {
+            let offset = i as isize * sxm as isize + p as isize * sxk as isize;
+            unsafe { *xb.offset(offset) }.to_f32()
+        };
+        let mut acc = vec![0f32; m * self.n];
+        let mut tile = vec![0f32; self.group * self.n];
+        for g in 0..self.k / self.group {
+            let rows = g * self.group;
+            for (r, w) in tile.chunks_exact_mut(self.n).enumerate() {
+                for (j, w) in w.iter_mut().enumerate() {
+                    *w = self.get(rows + r, j);
+                }
+            }
+            for (i, acc) in acc.chunks_exact_mut(self.n).enumerate() {
+                for (r, w) in tile.chunks_exact(self.n).enumerate() {
+                    let a = x_at(i, rows + r);
+                    for (acc, w) in acc.iter_mut().zip(w) {
+                        *acc += a * w;
+                    }
+                }
+            }
+        }
+
+        let yb = y.base_mut().cast::<f16>();
+        for (i, acc) in acc.chunks_exact(self.n).enumerate() {
+            for (j, acc) in acc.iter().enumerate() {
+                let offset = i as isize * sym as isize + j as isize * syn as isize;
+                let y = unsafe { &mut *yb.offset(offset) };
+                let old = if beta == 0. { 0. } else { beta * y.to_f32() };
+                *y = f16::from_f32(old + alpha * acc);
+            }
+        }
+    }
+
+    /// 反量化为形状为 `[k, n]` 的连续 f16 矩阵。
+    pub fn dequantize(&self) -> Tensor<Blob> {
+        let mut ans = Tensor::alloc(F16, &[self.k as udim, self.n as udim], Blob::new);
+        let data: &mut [f16] = reslice_mut(ans.physical_mut());
+        for (i, row) in data.chunks_exact_mut(self.n).enumerate() {
+            for (j, x) in row.iter_mut().enumerate() {
+                *x = f16::from_f32(self.get(i, j));
+            }
+        }
+        ans
+    }
+}
+
+#[test]
+fn test() {
+    use tensor::reslice;
+
+    let (k, n, group) = (32, 16, 16);
+    // 按 GPTQ 格式手工打包
+    let q = |i: usize, j: usize| ((i * 3 + j * 5) % 16) as u32;
+    let zero = |g: usize, j: usize| ((g * 7 + j) % 16) as u32;
+    let scale = |g: usize, j: usize| f16::from_f32((g * n + j + 1) as f32 / 64.);
+    let mut qweight = vec![0; k / 8 * n];
+    let mut qzeros = vec![0; k / group * n / 8];
+    let mut scales = vec![f16::ZERO; k / group * n];
+    for i in 0..k {
+        for j in 0..n {
+            qweight[i / 8 * n + j] |= q(i, j) << (i % 8 * 4);
+        }
+    }
+    for g in 0..k / group {
+        for j in 0..n {
+            qzeros[g * n / 8 + j / 8] |= zero(g, j) << (j % 8 * 4);
+            scales[g * n + j] = scale(g, j);
+        }
+    }
+    let m = Int4Matrix::new(k, n, group, qweight, qzeros, scales);
+    assert_eq!(m.shape(), [k, n]);
+    assert_eq!(
+        m.nbytes(),
+        k * n / 2 + k / group * n / 2 + k / group * n * 2
+    );
+
+    let w = m.dequantize();
+    let data: &[f16] = reslice(w.as_slice());
+    for i in 0..k {
+        for j in 0..n {
+            let g = i / group;
+            assert_eq!(m.q(i, j) as u32, q(i, j));
+            assert_eq!(m.zero(g, j) as u32, zero(g, j));
+            assert_eq!(m.scale(g, j), scale(g, j));
+            let ans = (q(i, j) as f32 - zero(g, j) as f32) * scale(g, j).to_f32();
+            assert_eq!(data[i * n + j], f16::from_f32(ans));
+        }
+    }
+
+    // 量化转置存储的矩阵，误差不超过半个量化步长
+    let mut x = Tensor::alloc(F16, &[n as _, k as _], Blob::new);
+    let src: &mut [f16] = reslice_mut(x.physical_mut());
+    for (i, x) in src.iter_mut().enumerate() {
+        *x = f16::from_f32(((i * 37 % 101) as f32 / 50. - 1.) * (i % 3 + 1) as f32);
+    }
+    let x = x.transpose(&[1, 0]);
+    let m = Int4Matrix::quantize(&x, group);
+    let src: &[f16] = reslice(x.physical());
+    for i in 0..k {
+        for j in 0..n {
+            let s = m.scale(i / group, j).to_f32();
+            let a = src[j * k + i].to_f32();
+            let b = m.get(i, j);
+            assert!((a - b).abs() <= s * 0.51 + 1e-3, "{a} != {b}");
+        }
+    }
+}
+
+#[test]
+fn test_mat_mul() {
+    use tensor::reslice;
+
+    let (m, k, n, group) = (3, 32, 16, 16);
+    let mut w = Tensor::alloc(F16, &[k as _, n as _], Blob::new);
+    for (i, x) in reslice_mut::<u8, f16>(w.physical_mut())
+        .iter_mut()
+        .enumerate()
+    {
+        *x = f16::from_f32((i * 29 % 31) as f32 / 16. - 1.);
+    }
+    let q = Int4Matrix::quantize(&w, group);
+    let w = q.dequantize();
+    let w: &[f16] = reslice(w.as_slice());
+
+    // x 转置存储，y 带有初始值
+    let mut x = Tensor::alloc(F16, &[k as _, m as _], Blob::new);
+    for (i, x) in reslice_mut::<u8, f16>(x.physical_mut())
+        .iter_mut()
+        .enumerate()
+    {
+        *x = f16::from_f32((i * 7 % 13) as f32 / 8. - 0.75);
+    }
+    let x = x.transpose(&[1, 0]);
+    let mut y = Tensor::alloc(F16, &[m as _, n as _], Blob::new);
+    reslice_mut::<u8, f16>(y.physical_mut()).fill(f16::ONE);
+    q.mat_mul(&mut y, 0.5, &x, 2.);
+
+    let xs: &[f16] = reslice(x.physical());
+    let ys: &[f16] = reslice(y.as_slice());
+    for i in 0..m {
+        for j in 0..n {
+            let dot = (0..k)
+                .map(|p| xs[p * m + i].to_f32() * w[p * n + j].to_f32())
+                .sum::<f32>();
+            let expected = 0.5 + 2. * dot;
+            let ans = ys[i * n + j].to_f32();
+            assert!(
+                (ans - expected).abs() < 1e-2 * expected.abs().max(1.),
+                "{ans} != {expected}"
+            );
+        }
+    }
+}
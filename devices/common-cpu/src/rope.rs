@@ -0,0 +1,127 @@
+use common::{f16, upos};
+use digit_layout::types::F16;
+use std::ops::{Deref, DerefMut};
+use tensor::{reslice, Tensor};
+
+/// 预先计算的旋转位置编码表。
+///
+/// 保存 `[0, max_seq_len)` 每个位置上各个频率的 `(sin, cos)`，推理时按位置查表，避免重复计算三角函数。
+pub struct RopeTable {
+    dh: usize,
+    sin_cos: Vec<(f32, f32)>,
+}
+
+impl RopeTable {
+    /// 根据 `theta` 为维度 `dh` 的头计算 `max_seq_len` 个位置的编码表。
+    pub fn new(theta: f32, dh: usize, max_seq_len: usize) -> Self {
+        assert_eq!(dh % 2, 0);
+        let freqs = (0..dh / 2)
+            .map(|k| theta.powf(-((2 * k) as f32) / dh as f32))
+            .collect::<Vec<_>>();
+        Self::from_freqs(&freqs, 1., max_seq_len)
+    }
+
+    /// 由每对维度的旋转频率计算 `max_seq_len` 个位置的编码表，`sin` 和 `cos` 整体乘以 `mscale`。
+    ///
+    /// 用于缩放过频率的旋转位置编码，头的维度是频率数量的 2 倍。
+    pub fn from_freqs(freqs: &[f32], mscale: f32, max_seq_len: usize) -> Self {
+        let sin_cos = (0..max_seq_len)
+            .flat_map(|pos| {
+                freqs.iter().map(move |freq| {
+                    let (sin, cos) = (pos as f32 * freq).sin_cos();
+                    (sin * mscale, cos * mscale)
+                })
+            })
+            .collect();
+        Self {
+            dh: freqs.len() * 2,
+            sin_cos,
+        }
+    }
+
+    /// 编码表覆盖的位置数量。
+    #[inline]
+    pub fn max_seq_len(&self) -> usize {
+        self.sin_cos.len() / (self.dh / 2)
+    }
+
+    /// 判断编码表是否覆盖 `pos` 中的所有位置。
+    #[inline]
+    pub fn covers<U>(&self, pos: &Tensor<U>) -> bool
+    where
+        U: Deref<Target = [u8]>,
+    {
+        let pos: &[upos] = reslice(pos.as_slice());
+        pos.iter().all(|&p| (p as usize) < self.max_seq_len())
+    }
+
+    /// 对形状为 `[seq_len, nh, dh]` 的 `t` 原地应用旋转位置编码。
+    pub fn apply<T, U>(&self, t: &mut Tensor<T>, pos: &Tensor<U>)
+    where
+        T: DerefMut<Target = [u8]>,
+        U: Deref<Target = [u8]>,
+    {
+        assert_eq!(t.data_layout(), F16);
+        let &[seq_len, nh, dh] = t.shape() else {
+            panic!("rope requires a 3d tensor")
+        };
+        assert_eq!(dh as usize, self.dh);
+        let strides = t.strides();
+        assert_eq!(strides[2], 1);
+        let (s0, s1) = (strides[0] as isize, strides[1] as isize);
+
+        let pos: &[upos] = reslice(pos.as_slice());
+        assert_eq!(pos.len(), seq_len as usize);
+        let base = t.base_mut().cast::<f16>();
+        for (i, &p) in pos.iter().enumerate() {
+            let table = &self.sin_cos[p as usize * self.dh / 2..][..self.dh / 2];
+            for h in 0..nh as isize {
+                let offset = i as isize * s0 + h * s1;
+                let head = unsafe { std::slice::from_raw_parts_mut(base.offset(offset), self.dh) };
+                for (pair, &(sin, cos)) in head.chunks_exact_mut(2).zip(table) {
+                    let a = pair[0].to_f32();
+                    let b = pair[1].to_f32();
+                    pair[0] = f16::from_f32(a * cos - b * sin);
+                    pair[1] = f16::from_f32(a * sin + b * cos);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test() {
+    use crate::{CpuKernels, KernelsA, ThisThread};
+    use common::Blob;
+    use digit_layout::types::U32;
+    use tensor::reslice_mut;
+
+    const THETA: f32 = 1e4;
+    let (seq_len, nh, dh) = (7, 3, 16);
+
+    let mut t = Tensor::alloc(F16, &[seq_len, nh, dh], Blob::new);
+    for (i, x) in reslice_mut::<u8, f16>(t.as_mut_slice())
+        .iter_mut()
+        .enumerate()
+    {
+        *x = f16::from_f32(((i * 37 % 101) as f32 / 50.) - 1.);
+    }
+    let mut pos = Tensor::alloc(U32, &[seq_len], Blob::new);
+    let positions = [0, 1, 2, 5, 30, 62, 63];
+    reslice_mut::<u8, upos>(pos.as_mut_slice()).copy_from_slice(&positions);
+
+    let table = RopeTable::new(THETA, dh as _, 64);
+    assert_eq!(table.max_seq_len(), 64);
+    assert!(table.covers(&pos));
+
+    let mut cached = Tensor::alloc(F16, &[seq_len, nh, dh], Blob::new);
+    cached.as_mut_slice().copy_from_slice(t.as_slice());
+    table.apply(&mut cached, &pos);
+    CpuKernels::default().rope(&mut t, &pos, THETA, &ThisThread);
+
+    let cached: &[f16] = reslice(cached.as_slice());
+    let computed: &[f16] = reslice(t.as_slice());
+    for (a, b) in cached.iter().zip(computed) {
+        assert!((a.to_f32() - b.to_f32()).abs() < 1e-2, "{a} != {b}");
+    }
+}
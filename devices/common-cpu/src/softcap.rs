@@ -0,0 +1,45 @@
+﻿use common::f16;
+use digit_layout::types::F16;
+use std::ops::DerefMut;
+use tensor::{reslice_mut, Tensor};
+
+pub fn softcap<T>(x: &mut Tensor<T>, cap: f32)
+where
+    T: DerefMut<Target = [u8]>,
+{
+    assert_eq!(x.data_layout(), F16);
+    assert!(x.is_contiguous());
+
+    let x: &mut [f16] = reslice_mut(x.as_mut_slice());
+    for x in x {
+        *x = f16::from_f32(cap * (x.to_f32() / cap).tanh());
+    }
+}
+
+#[test]
+fn test() {
+    use common::Blob;
+
+    const CAP: f32 = 30.;
+    let src = [0., 1., -1., 50., -50., 1000., -1000.];
+
+    let mut x = Tensor::alloc(F16, &[src.len() as _], Blob::new);
+    for (dst, src) in reslice_mut::<u8, f16>(x.as_mut_slice()).iter_mut().zip(src) {
+        *dst = f16::from_f32(src);
+    }
+    softcap(&mut x, CAP);
+
+    let ans: &[f16] = tensor::reslice(x.as_slice());
+    let ans = ans.iter().map(|x| x.to_f32()).collect::<Vec<_>>();
+    // 零点不变，小值近似不变
+    assert_eq!(ans[0], 0.);
+    assert!((ans[1] - 1.).abs() < 1e-2);
+    assert!((ans[2] + 1.).abs() < 1e-2);
+    // 大值被截断到 cap 以内，并趋近 cap
+    for &y in &ans[3..] {
+        assert!(y.abs() <= CAP);
+    }
+    assert!(ans[3] < 50. && ans[3] > 25.);
+    assert!((ans[5] - CAP).abs() < 1e-1);
+    assert!((ans[6] + CAP).abs() < 1e-1);
+}
@@ -0,0 +1,351 @@
+use common::{f16, upos};
+use digit_layout::types::F16;
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+use tensor::{idim, reslice, reslice_mut, udim, Tensor};
+
+/// 注意力掩码，决定上下文中每个位置能看到哪些位置。
+#[derive(Clone, Default)]
+pub enum AttentionMask {
+    /// 因果掩码，每个位置只能看到自己和之前的位置。
+    #[default]
+    Causal,
+    /// 前缀语言模型掩码，前 `n` 个位置之间双向可见，之后的位置保持因果。
+    ///
+    /// 前缀需要在同一次推理中完整填充，否则前缀中的位置看不到尚未填充的部分。
+    PrefixLm(upos),
+    /// 自定义掩码，`f(i, j)` 表示位置 `i` 能否看到位置 `j`，可用于文档打包等场景。
+    Custom(Arc<dyn Fn(upos, upos) -> bool + Send + Sync>),
+}
+
+impl AttentionMask {
+    /// 判断位置 `i` 能否看到位置 `j`。
+    pub fn visible(&self, i: upos, j: upos) -> bool {
+        match self {
+            Self::Causal => j <= i,
+            &Self::PrefixLm(n) => j <= i || (i < n && j < n),
+            Self::Custom(f) => f(i, j),
+        }
+    }
+
+    /// 文档打包的块对角掩码，依次排列的 `lens` 个文档各自保持因果，文档之间互不可见。
+    ///
+    /// 超出所有文档的位置视为一个新的文档。
+    pub fn packed(lens: &[upos]) -> Self {
+        let ends = lens
+            .iter()
+            .scan(0, |end, &len| {
+                *end += len;
+                Some(*end)
+            })
+            .collect::<Vec<_>>();
+        let doc = move |i: upos| ends.partition_point(|&end| end <= i);
+        Self::Custom(Arc::new(move |i, j| j <= i && doc(i) == doc(j)))
+    }
+
+    /// 是否为因果掩码。
+    #[inline]
+    pub fn is_causal(&self) -> bool {
+        matches!(self, Self::Causal)
+    }
+}
+
+impl fmt::Debug for AttentionMask {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Causal => write!(f, "Causal"),
+            Self::PrefixLm(n) => write!(f, "PrefixLm({n})"),
+            Self::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+/// 在主机上计算因果注意力，分数、softmax 和加权求和都以 f32 累加。
+///
+/// `o`、`q` 形状为 `[nh, seq_len, dh]`，`k`、`v` 形状为 `[nkvh, att_len, dh]`，存储均为 f16。
+pub fn attention_f32<O, Q, K, V>(
+    o: &mut Tensor<O>,
+    q: &Tensor<Q>,
+    k: &Tensor<K>,
+    v: &Tensor<V>,
+    scale: f32,
+    softcap: Option<f32>,
+) where
+    O: DerefMut<Target = [u8]>,
+    Q: Deref<Target = [u8]>,
+    K: Deref<Target = [u8]>,
+    V: Deref<Target = [u8]>,
+{
+    // 因果掩码：第 i 个查询只能看到前 att_len - seq_len + i + 1 个 token
+    let offset = k.shape()[1] - q.shape()[1];
+    masked_attention_f32(o, q, k, v, scale, softcap, |i, j| j <= offset + i);
+}
+
+/// 在主机上计算注意力，`mask(i, j)` 表示第 `i` 个查询能否看到第 `j` 个键，被遮盖的分数在 softmax 之前置为 -inf。
+///
+/// 形状要求同 [`attention_f32`]，所有键都被遮盖的查询输出 0。
+pub fn masked_attention_f32<O, Q, K, V>(
+    o: &mut Tensor<O>,
+    q: &Tensor<Q>,
+    k: &Tensor<K>,
+    v: &Tensor<V>,
+    scale: f32,
+    softcap: Option<f32>,
+    mask: impl Fn(udim, udim) -> bool,
+) where
+    O: DerefMut<Target = [u8]>,
+    Q: Deref<Target = [u8]>,
+    K: Deref<Target = [u8]>,
+    V: Deref<Target = [u8]>,
+{
+    for t in [
+        o.data_layout(),
+        q.data_layout(),
+        k.data_layout(),
+        v.data_layout(),
+    ] {
+        assert_eq!(t, F16);
+    }
+    let &[nh, seq_len, dh] = q.shape() else {
+        panic!()
+    };
+    let &[nkvh, att_len, dh_] = k.shape() else {
+        panic!()
+    };
+    assert_eq!(o.shape(), q.shape());
+    assert_eq!(v.shape(), k.shape());
+    assert_eq!(dh, dh_);
+    assert_eq!(nh % nkvh, 0);
+    assert!(seq_len <= att_len);
+    let head_group = nh / nkvh;
+
+    let o_idx = Index::new(o);
+    let q_idx = Index::new(q);
+    let k_idx = Index::new(k);
+    let v_idx = Index::new(v);
+    let q_: &[f16] = reslice(q.physical());
+    let k_: &[f16] = reslice(k.physical());
+    let v_: &[f16] = reslice(v.physical());
+    let o_: &mut [f16] = reslice_mut(o.physical_mut());
+
+    let mut att = vec![0f32; att_len as usize];
+    let mut sum = vec![0f32; dh as usize];
+    for h in 0..nh {
+        let kvh = h / head_group;
+        for i in 0..seq_len {
+            for (j, a) in (0..).zip(&mut att) {
+                if !mask(i, j) {
+                    *a = f32::NEG_INFINITY;
+                    continue;
+                }
+                let mut dot = 0f32;
+                for l in 0..dh {
+                    dot += q_[q_idx.at(h, i, l)].to_f32() * k_[k_idx.at(kvh, j, l)].to_f32();
+                }
+                *a = dot * scale;
+                if let Some(cap) = softcap {
+                    *a = cap * (*a / cap).tanh();
+                }
+            }
+
+            let max = att.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            if max == f32::NEG_INFINITY {
+                for l in 0..dh {
+                    o_[o_idx.at(h, i, l)] = f16::ZERO;
+                }
+                continue;
+            }
+            let mut total = 0f32;
+            for a in &mut att {
+                *a = (*a - max).exp();
+                total += *a;
+            }
+
+            sum.fill(0.);
+            for (j, a) in (0..).zip(&att) {
+                if *a == 0. {
+                    continue;
+                }
+                let p = a / total;
+                for (l, s) in (0..).zip(&mut sum) {
+                    *s += p * v_[v_idx.at(kvh, j, l)].to_f32();
+                }
+            }
+            for (l, s) in (0..).zip(&sum) {
+                o_[o_idx.at(h, i, l)] = f16::from_f32(*s);
+            }
+        }
+    }
+}
+
+/// 以 f16 元素为单位计算三维张量的下标。
+struct Index([idim; 4]);
+
+impl Index {
+    fn new<T>(t: &Tensor<T>) -> Self {
+        Self(t.pattern().try_into().unwrap())
+    }
+
+    #[inline]
+    fn at(&self, i: udim, j: udim, k: udim) -> usize {
+        let [s0, s1, s2, offset] = self.0;
+        (offset + i as idim * s0 + j as idim * s1 + k as idim * s2) as usize
+    }
+}
+
+#[test]
+fn test_attention_f32() {
+    use common::Blob;
+
+    // 伪随机数，使测试结果稳定
+    let mut seed = 0x2545_f491u32;
+    let mut rand = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        (seed as f64 / u32::MAX as f64) * 2. - 1.
+    };
+
+    const DH: usize = 64;
+    let scale = (DH as f32).sqrt().recip();
+    let q = (0..DH).map(|_| rand()).collect::<Vec<_>>();
+    let kv_len = 4096;
+    let k = (0..kv_len * DH).map(|_| rand() * 0.5).collect::<Vec<_>>();
+    let v = (0..kv_len * DH).map(|_| rand()).collect::<Vec<_>>();
+
+    let tensor = |data: &[f64], shape: &[udim]| {
+        let mut t = Tensor::alloc(F16, shape, Blob::new);
+        for (dst, &src) in reslice_mut::<u8, f16>(t.as_mut_slice())
+            .iter_mut()
+            .zip(data)
+        {
+            *dst = f16::from_f64(src);
+        }
+        t
+    };
+    let f16_data = |data: &[f64]| data.iter().map(|&x| f16::from_f64(x)).collect::<Vec<_>>();
+
+    // 以 f64 计算的参考结果
+    let reference = |len: usize| {
+        let q = f16_data(&q);
+        let k = f16_data(&k[..len * DH]);
+        let v = f16_data(&v[..len * DH]);
+        let att = k
+            .chunks(DH)
+            .map(|k| {
+                let dot = q.iter().zip(k).map(|(q, k)| q.to_f64() * k.to_f64());
+                dot.sum::<f64>() * scale as f64
+            })
+            .collect::<Vec<_>>();
+        let max = att.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let att = att.iter().map(|a| (a - max).exp()).collect::<Vec<_>>();
+        let total = att.iter().sum::<f64>();
+        (0..DH)
+            .map(|l| {
+                let weighted = att.iter().zip(v.chunks(DH));
+                weighted.map(|(a, v)| a / total * v[l].to_f64()).sum()
+            })
+            .collect::<Vec<f64>>()
+    };
+    // 全程以 f16 累加的结果
+    let accumulate_f16 = |len: usize| {
+        let q = f16_data(&q);
+        let k = f16_data(&k[..len * DH]);
+        let v = f16_data(&v[..len * DH]);
+        let att = k
+            .chunks(DH)
+            .map(|k| {
+                let dot = q.iter().zip(k).fold(f16::ZERO, |acc, (&q, &k)| acc + q * k);
+                dot * f16::from_f32(scale)
+            })
+            .collect::<Vec<_>>();
+        let max = att.iter().copied().fold(f16::NEG_INFINITY, f16::max);
+        let att = att
+            .iter()
+            .map(|&a| f16::from_f32((a - max).to_f32().exp()))
+            .collect::<Vec<_>>();
+        let total = att.iter().fold(f16::ZERO, |acc, &a| acc + a);
+        (0..DH)
+            .map(|l| {
+                let weighted = att.iter().zip(v.chunks(DH));
+                let o = weighted.fold(f16::ZERO, |acc, (&a, v)| acc + a / total * v[l]);
+                o.to_f64()
+            })
+            .collect::<Vec<f64>>()
+    };
+    let accumulate_f32 = |len: usize| {
+        let q = tensor(&q, &[1, 1, DH as _]);
+        let k = tensor(&k[..len * DH], &[1, len as _, DH as _]);
+        let v = tensor(&v[..len * DH], &[1, len as _, DH as _]);
+        let mut o = Tensor::alloc(F16, &[1, 1, DH as _], Blob::new);
+        attention_f32(&mut o, &q, &k, &v, scale, None);
+        let o: &[f16] = reslice(o.as_slice());
+        o.iter().map(|x| x.to_f64()).collect::<Vec<_>>()
+    };
+    // 相对于参考结果最大值的误差
+    let error = |a: &[f64], b: &[f64]| {
+        let max = b.iter().map(|b| b.abs()).fold(0., f64::max);
+        let diff = a.iter().zip(b).map(|(a, b)| (a - b).abs());
+        diff.fold(0., f64::max) / max
+    };
+
+    let mut f16_errors = vec![];
+    let mut f32_errors = vec![];
+    for len in [16, 256, kv_len] {
+        let reference = reference(len);
+        f16_errors.push(error(&accumulate_f16(len), &reference));
+        f32_errors.push(error(&accumulate_f32(len), &reference));
+    }
+    // f16 累加的误差随序列长度增长
+    assert!(f16_errors[0] < f16_errors[2]);
+    // f32 累加的结果只有输出舍入误差，且在长序列上更接近参考结果
+    assert!(f32_errors.iter().all(|&e| e < 1e-3));
+    assert!(f32_errors[2] < f16_errors[2]);
+}
+
+#[test]
+fn test_masked_attention() {
+    use common::Blob;
+
+    // k 全为 0 时注意力在可见位置上均匀分布，输出为可见位置 v 的平均值
+    let (seq_len, dh) = (4, 8);
+    let zeros = |len| {
+        let mut t = Tensor::alloc(F16, &[1, len, dh], Blob::new);
+        t.as_mut_slice().fill(0);
+        t
+    };
+    let q = zeros(seq_len);
+    let k = zeros(seq_len);
+    let mut v = Tensor::alloc(F16, &[1, seq_len, dh], Blob::new);
+    for (i, x) in reslice_mut::<u8, f16>(v.as_mut_slice())
+        .iter_mut()
+        .enumerate()
+    {
+        *x = f16::from_f32((i / dh as usize) as _);
+    }
+    let attend = |mask: AttentionMask| {
+        let mut o = Tensor::alloc(F16, &[1, seq_len, dh], Blob::new);
+        masked_attention_f32(&mut o, &q, &k, &v, 1., None, |i, j| mask.visible(i, j));
+        let o: &[f16] = reslice(o.as_slice());
+        o.chunks(dh as _)
+            .map(|row| row[0].to_f32())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(attend(AttentionMask::Causal), [0., 0.5, 1., 1.5]);
+    // 前缀中的位置双向可见，之后的位置保持因果
+    assert_eq!(attend(AttentionMask::PrefixLm(2)), [0.5, 0.5, 1., 1.5]);
+    // 每两个位置为一篇文档，文档之间互不可见
+    let packed = AttentionMask::Custom(Arc::new(|i, j| j <= i && i / 2 == j / 2));
+    assert_eq!(attend(packed), [0., 0.5, 2., 2.5]);
+    assert_eq!(attend(AttentionMask::packed(&[2, 2])), [0., 0.5, 2., 2.5]);
+    assert_eq!(attend(AttentionMask::packed(&[1, 3])), [0., 1., 1.5, 2.]);
+
+    let mut o = Tensor::alloc(F16, &[1, seq_len, dh], Blob::new);
+    attention_f32(&mut o, &q, &k, &v, 1., None);
+    let o: &[f16] = reslice(o.as_slice());
+    assert_eq!(o[dh as usize * 3].to_f32(), 1.5);
+}
@@ -1,7 +1,16 @@
+mod attention;
+mod sample;
+
 use common::utok;
 use operators::{fuesd_softmax, mat_mul, mlp, reform, rms_norm, rope, Handle, Operator, QueueOf};
 use std::ops::{Deref, DerefMut};
-use tensor::Tensor;
+use tensor::{udim, Tensor};
+
+pub use attention::{attention_f32, masked_attention_f32, AttentionMask};
+pub use sample::{
+    argmax, filter_logits, gumbel_max, min_p_threshold, pick, repetition_penalty, sample_f32,
+    SampleFilter, SampleRng, SampleStage,
+};
 
 pub type SliceOn<H> = [<H as Handle>::Byte];
 
@@ -98,6 +107,32 @@ pub trait KernelsB {
         T: DerefMut<Target = SliceOn<Self::Handle>>,
         U: Deref<Target = [u8]>,
         I: IntoIterator<Item = utok>;
+
+    /// 原地执行软截断 `x = cap * tanh(x / cap)`。
+    fn softcap<T>(&self, x: &mut Tensor<T>, cap: f32, queue: &QueueOf<Self::Handle>)
+    where
+        T: DerefMut<Target = SliceOn<Self::Handle>>;
+
+    /// 以 f32 精度累加计算注意力，结果写入 `o`。
+    ///
+    /// `o`、`q` 形状为 `[nh, seq_len, dh]`，`k`、`v` 形状为 `[nkvh, att_len, dh]`，
+    /// `mask(i, j)` 表示第 `i` 个查询能否看到第 `j` 个键。
+    #[allow(clippy::too_many_arguments)]
+    fn attention_f32<O, Q, K, V>(
+        &self,
+        o: &mut Tensor<O>,
+        q: &Tensor<Q>,
+        k: &Tensor<K>,
+        v: &Tensor<V>,
+        scale: f32,
+        softcap: Option<f32>,
+        mask: impl Fn(udim, udim) -> bool,
+        queue: &QueueOf<Self::Handle>,
+    ) where
+        O: DerefMut<Target = SliceOn<Self::Handle>>,
+        Q: Deref<Target = SliceOn<Self::Handle>>,
+        K: Deref<Target = SliceOn<Self::Handle>>,
+        V: Deref<Target = SliceOn<Self::Handle>>;
 }
 
 pub trait Kernels<H: Handle>: KernelsA<Handle = H> + KernelsB<Handle = H> {}
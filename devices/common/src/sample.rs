@@ -0,0 +1,359 @@
+use common::utok;
+use std::cmp::Ordering;
+
+/// 可复现的伪随机数发生器，相同的种子产生相同的序列。
+#[derive(Clone, Debug)]
+pub struct SampleRng(u64);
+
+impl SampleRng {
+    /// 以 `seed` 为种子创建发生器。
+    #[inline]
+    pub fn new(seed: u64) -> Self {
+        // xorshift 的状态不能为 0
+        Self(seed ^ 0x9e37_79b9_7f4a_7c15)
+    }
+
+    /// 生成 `[0, 1)` 区间的随机数。
+    pub fn next_f32(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+/// 最大值的序号，多个最大值相等时取序号最小的一个，NaN 被忽略，空的输入返回 0。
+///
+/// 量化模型的 logits 经常出现相等的最大值，所有后端的贪心采样都以此打破平局，保证结果可复现。
+pub fn argmax<T: PartialOrd + Copy>(logits: &[T]) -> usize {
+    let mut best = None;
+    for (i, &x) in logits.iter().enumerate() {
+        match best {
+            // 相等时保留序号较小的
+            Some((_, max)) if x <= max => {}
+            _ if x.partial_cmp(&x).is_none() => {}
+            _ => best = Some((i, x)),
+        }
+    }
+    best.map_or(0, |(i, _)| i)
+}
+
+/// 对一个已经出现过的 token 的 logit 施加重复惩罚，为正时除以 `penalty`，否则乘以 `penalty`。
+///
+/// `penalty` 大于 1 时总是降低这个 token 的概率。
+#[inline]
+pub fn repetition_penalty(logit: f32, penalty: f32) -> f32 {
+    if logit > 0. {
+        logit / penalty
+    } else {
+        logit * penalty
+    }
+}
+
+/// min-p 过滤保留的 logit 下限，`max` 是最大的 logit。
+///
+/// 以温度缩放后的概率不小于最大概率的 `min_p` 倍的 token 被保留，`min_p` 为 0 时下限为负无穷。
+#[inline]
+pub fn min_p_threshold(temperature: f32, min_p: f32, max: f32) -> f32 {
+    max + temperature * min_p.ln()
+}
+
+/// 采样前依次作用于 logits 的过滤步骤。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum SampleStage {
+    /// 只保留概率最大的 `top_k` 个 token。
+    TopK,
+    /// 用温度缩放 logits。
+    Temperature,
+    /// 只保留累积概率达到 `top_p` 之前的 token。
+    TopP,
+}
+
+impl SampleStage {
+    /// 默认的过滤顺序，与 [`sample_f32`] 和采样算子相同。
+    pub const DEFAULT_ORDER: [Self; 3] = [Self::TopK, Self::Temperature, Self::TopP];
+}
+
+/// 采样前过滤 logits 的参数。
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SampleFilter {
+    /// 过滤步骤的顺序。
+    pub order: [SampleStage; 3],
+    /// 温度，不大于 0 时退化为贪心采样。
+    pub temperature: f32,
+    /// 只保留累积概率达到 `top_p` 之前的 token。
+    pub top_p: f32,
+    /// 只保留概率不小于最大概率 `min_p` 倍的 token，为 0 时不过滤。
+    pub min_p: f32,
+    /// 只保留概率最大的 `top_k` 个 token。
+    pub top_k: usize,
+}
+
+impl SampleFilter {
+    /// 按默认顺序过滤，不做 min-p 过滤。
+    #[inline]
+    pub const fn new(temperature: f32, top_p: f32, top_k: usize) -> Self {
+        Self {
+            order: SampleStage::DEFAULT_ORDER,
+            temperature,
+            top_p,
+            min_p: 0.,
+            top_k,
+        }
+    }
+
+    /// 判断采样结果是否是确定的。
+    #[inline]
+    pub fn is_argmax(&self) -> bool {
+        self.temperature <= 0. || self.top_k < 2 || self.top_p <= 0.
+    }
+}
+
+/// 按 `filter.order` 依次过滤 `logits`，返回候选 token 及其归一化的概率，按概率降序排列。
+///
+/// `top_p` 按过滤到这一步时的分布计算累积概率，因此温度在 `top_p` 之前或之后会得到不同的候选集合。
+/// `min_p` 在所有步骤之前按温度缩放后的分布过滤，不受 `order` 影响。
+/// 贪心采样时只返回概率最大的 token。
+pub fn filter_logits(filter: SampleFilter, logits: &[f32]) -> Vec<(utok, f32)> {
+    let desc = |a: &f32, b: &f32| b.partial_cmp(a).unwrap_or(Ordering::Equal);
+    if filter.is_argmax() {
+        return vec![(argmax(logits) as _, 1.)];
+    }
+    let SampleFilter {
+        order,
+        temperature,
+        top_p,
+        min_p,
+        top_k,
+    } = filter;
+
+    let mut candidates = (0..logits.len())
+        .map(|i| (i as utok, logits[i]))
+        .collect::<Vec<_>>();
+    candidates.sort_by(|a, b| desc(&a.1, &b.1).then(a.0.cmp(&b.0)));
+    // 先按 min-p 过滤，再由 top-k 限制数量，最大的 token 总是保留
+    if min_p > 0. {
+        let threshold = min_p_threshold(temperature, min_p, candidates[0].1);
+        let kept = candidates
+            .iter()
+            .take_while(|&&(_, x)| x >= threshold)
+            .count();
+        candidates.truncate(kept.max(1));
+    }
+    for stage in order {
+        match stage {
+            SampleStage::TopK => candidates.truncate(top_k),
+            SampleStage::Temperature => candidates.iter_mut().for_each(|(_, x)| *x /= temperature),
+            SampleStage::TopP => {
+                let probs = softmax(&candidates);
+                // 保留累积概率达到 top_p 之前的 token，至少保留一个
+                let mut kept = 0;
+                let mut cumulative = 0.;
+                for p in probs {
+                    if kept > 0 && cumulative >= top_p {
+                        break;
+                    }
+                    cumulative += p;
+                    kept += 1;
+                }
+                candidates.truncate(kept);
+            }
+        }
+    }
+    let probs = softmax(&candidates);
+    candidates
+        .into_iter()
+        .zip(probs)
+        .map(|((i, _), p)| (i, p))
+        .collect()
+}
+
+/// 从 [`filter_logits`] 得到的候选中采样，`random` 为 `[0, 1)` 区间的随机数。
+pub fn pick(candidates: &[(utok, f32)], random: f32) -> utok {
+    let mut acc = 0.;
+    for &(i, p) in candidates {
+        acc += p;
+        if acc > random {
+            return i;
+        }
+    }
+    candidates.last().map_or(0, |&(i, _)| i)
+}
+
+/// 用 Gumbel-max 技巧从 [`filter_logits`] 得到的候选中采样。
+///
+/// 给每个候选的对数概率加上独立的 Gumbel 噪声后取最大值，与 [`pick`] 的分布相同，每个候选消耗 `rng` 的一个随机数。
+pub fn gumbel_max(candidates: &[(utok, f32)], rng: &mut SampleRng) -> utok {
+    let mut best = None;
+    for &(i, p) in candidates {
+        // 随机数可能为 0，避免对 0 取对数
+        let u = rng.next_f32().max(f32::MIN_POSITIVE);
+        let score = p.ln() - (-u.ln()).ln();
+        match best {
+            Some((_, max)) if score <= max => {}
+            _ => best = Some((i, score)),
+        }
+    }
+    best.map_or(0, |(i, _)| i)
+}
+
+/// 对降序排列的候选计算归一化的概率。
+fn softmax(candidates: &[(utok, f32)]) -> Vec<f32> {
+    let max = candidates.first().map_or(0., |&(_, x)| x);
+    let exp = candidates
+        .iter()
+        .map(|&(_, x)| (x - max).exp())
+        .collect::<Vec<_>>();
+    let total = exp.iter().sum::<f32>();
+    exp.into_iter().map(|x| x / total).collect()
+}
+
+/// 在主机上以 f32 精度按默认顺序过滤并采样，`random` 为 `[0, 1)` 区间的随机数，相同的输入总是得到相同的结果。
+#[inline]
+pub fn sample_f32(temperature: f32, top_p: f32, top_k: usize, logits: &[f32], random: f32) -> utok {
+    let filter = SampleFilter::new(temperature, top_p, top_k);
+    pick(&filter_logits(filter, logits), random)
+}
+
+#[test]
+fn test_sample_f32() {
+    let logits = [0.1f32, 2.5, -1., 2.4, 0.];
+    // 贪心采样总是选择最大值
+    for random in [0., 0.5, 0.99] {
+        assert_eq!(sample_f32(0., 1., usize::MAX, &logits, random), 1);
+    }
+    // top_k 限制候选范围
+    let mut rng = SampleRng::new(0);
+    for _ in 0..64 {
+        let token = sample_f32(1., 1., 2, &logits, rng.next_f32());
+        assert!(matches!(token, 1 | 3));
+    }
+    // 相同的种子得到相同的结果
+    let sample = |seed| {
+        let mut rng = SampleRng::new(seed);
+        (0..64)
+            .map(|_| sample_f32(1., 0.9, usize::MAX, &logits, rng.next_f32()))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(sample(42), sample(42));
+    assert_ne!(sample(42), sample(43));
+}
+
+#[test]
+fn test_sample_order() {
+    use SampleStage::*;
+
+    let logits = [4f32, 2., 1., 0.];
+    let probs = |order| {
+        let filter = SampleFilter {
+            order,
+            ..SampleFilter::new(4., 0.8, usize::MAX)
+        };
+        filter_logits(filter, &logits)
+    };
+
+    // 先截断：原始分布中最大的 token 概率约为 0.83，已经超过 top_p，只保留一个
+    let top_p_first = probs([TopK, TopP, Temperature]);
+    assert_eq!(top_p_first, [(0, 1.)]);
+
+    // 先升温：分布变平为约 [0.41, 0.25, 0.19, 0.15]，保留前三个后重新归一化为约 [0.48, 0.29, 0.23]
+    let default = probs(SampleStage::DEFAULT_ORDER);
+    let tokens = default.iter().map(|&(i, _)| i).collect::<Vec<_>>();
+    assert_eq!(tokens, [0, 1, 2]);
+    for (&(_, p), expected) in default.iter().zip([0.481, 0.292, 0.227]) {
+        assert!((p - expected).abs() < 1e-3, "{p} != {expected}");
+    }
+
+    // 默认顺序与 sample_f32 的结果一致
+    let mut rng = SampleRng::new(7);
+    for _ in 0..64 {
+        let random = rng.next_f32();
+        assert_eq!(
+            pick(&default, random),
+            sample_f32(4., 0.8, usize::MAX, &logits, random)
+        );
+    }
+    assert_eq!(pick(&top_p_first, 0.99), 0);
+}
+
+#[test]
+fn test_argmax_tie() {
+    let logits = [0.5f32, 2., f32::NAN, 2., -1., 2.];
+    assert_eq!(argmax(&logits), 1);
+    assert_eq!(argmax(&[f32::NAN, 1., 1.]), 1);
+    assert_eq!(argmax::<f32>(&[]), 0);
+    // 贪心采样遇到相等的最大值总是选择序号最小的 token
+    for random in [0., 0.5, 0.99] {
+        assert_eq!(sample_f32(0., 1., usize::MAX, &logits, random), 1);
+    }
+    assert_eq!(
+        filter_logits(SampleFilter::new(1., 1., 1), &logits),
+        [(1, 1.)]
+    );
+}
+
+#[test]
+fn test_gumbel_max() {
+    let logits = [2f32, 1., 0.5, -1., 0.];
+    for (temperature, top_k) in [(1., usize::MAX), (0.8, 3)] {
+        let candidates = filter_logits(SampleFilter::new(temperature, 1., top_k), &logits);
+        // 多个种子上的采样频率与过滤后的概率一致
+        let mut counts = [0usize; 5];
+        for seed in 0..200 {
+            let mut rng = SampleRng::new(seed);
+            for _ in 0..100 {
+                counts[gumbel_max(&candidates, &mut rng) as usize] += 1;
+            }
+        }
+        let total = counts.iter().sum::<usize>() as f32;
+        for (i, &count) in counts.iter().enumerate() {
+            let p = candidates
+                .iter()
+                .find(|&&(token, _)| token as usize == i)
+                .map_or(0., |&(_, p)| p);
+            let freq = count as f32 / total;
+            assert!((freq - p).abs() < 0.02, "token {i}: {freq} != {p}");
+        }
+    }
+    // 相同的种子得到相同的结果
+    let candidates = filter_logits(SampleFilter::new(1., 1., usize::MAX), &logits);
+    let sample = |seed| {
+        let mut rng = SampleRng::new(seed);
+        (0..64)
+            .map(|_| gumbel_max(&candidates, &mut rng))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(sample(42), sample(42));
+}
+
+#[test]
+fn test_min_p() {
+    let dist = [0.5f32, 0.2, 0.15, 0.1, 0.05];
+    let logits = dist.map(f32::ln);
+    let filter = |temperature, min_p, top_k| {
+        let filter = SampleFilter {
+            min_p,
+            ..SampleFilter::new(temperature, 1., top_k)
+        };
+        filter_logits(filter, &logits)
+    };
+    let tokens =
+        |candidates: &[(utok, f32)]| candidates.iter().map(|&(i, _)| i).collect::<Vec<_>>();
+
+    // min_p 为 0 时不过滤
+    assert_eq!(tokens(&filter(1., 0., usize::MAX)), [0, 1, 2, 3, 4]);
+    // 下限为 0.25 * 0.5 = 0.125，保留 [0.5, 0.2, 0.15]，重新归一化为 [0.588, 0.235, 0.176]
+    let kept = filter(1., 0.25, usize::MAX);
+    assert_eq!(tokens(&kept), [0, 1, 2]);
+    for (&(_, p), expected) in kept.iter().zip([0.588, 0.235, 0.176]) {
+        assert!((p - expected).abs() < 1e-3, "{p} != {expected}");
+    }
+    // 下限为 0.25，只保留最大的 token
+    assert_eq!(filter(1., 0.5, usize::MAX), [(0, 1.)]);
+    // 先 min-p 再 top-k
+    assert_eq!(tokens(&filter(1., 0.25, 2)), [0, 1]);
+    assert_eq!(tokens(&filter(1., 0.25, 4)), [0, 1, 2]);
+    // 温度为 2 时相对最大值的概率为 sqrt(p / 0.5) = [1, 0.632, 0.548, 0.447, 0.316]
+    assert_eq!(tokens(&filter(2., 0.5, usize::MAX)), [0, 1, 2]);
+    assert_eq!(tokens(&filter(2., 0.3, usize::MAX)), [0, 1, 2, 3, 4]);
+}
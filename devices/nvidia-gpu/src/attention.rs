@@ -0,0 +1,218 @@
+use digit_layout::types::{F16, F32};
+use operators::{
+    cuda::{params, DevByte, Stream},
+    nvidia_gpu::{Handle as Gpu, ModuleBox},
+};
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+use tensor::{idim, udim, Tensor};
+
+const CODE: &str = r#"
+#include <cuda_fp16.h>
+
+// 块内规约，线程数是 2 的幂，返回前同步，shared 可以在下一次规约中复用
+__device__ float reduce(float *shared, float x, bool max) {
+    shared[threadIdx.x] = x;
+    __syncthreads();
+    for (unsigned int s = blockDim.x / 2; s > 0; s >>= 1) {
+        if (threadIdx.x < s) {
+            float y = shared[threadIdx.x + s];
+            shared[threadIdx.x] = max ? fmaxf(shared[threadIdx.x], y) : shared[threadIdx.x] + y;
+        }
+        __syncthreads();
+    }
+    float ans = shared[0];
+    __syncthreads();
+    return ans;
+}
+
+// 每个线程块处理一个头的一个查询，分数暂存在 att 中，以 f32 累加
+// strides 依次为 o、q、k、v 三个维度的步长，以元素为单位
+extern "C" __global__ void attention_f32(
+    half *o,
+    half const *q,
+    half const *k,
+    half const *v,
+    unsigned char const *mask,
+    float *att,
+    long long const *strides,
+    unsigned int seq_len,
+    unsigned int att_len,
+    unsigned int dh,
+    unsigned int head_group,
+    float scale,
+    float softcap) {
+    extern __shared__ float shared[];
+    long long const *s = strides;
+    unsigned int h = blockIdx.x / seq_len;
+    unsigned int i = blockIdx.x % seq_len;
+    unsigned int kvh = h / head_group;
+
+    half *o_ = o + h * s[0] + i * s[1];
+    half const *q_ = q + h * s[3] + i * s[4];
+    half const *k_ = k + kvh * s[6];
+    half const *v_ = v + kvh * s[9];
+    unsigned char const *mask_ = mask + (size_t) i * att_len;
+    float *att_ = att + (size_t) blockIdx.x * att_len;
+
+    float local = -INFINITY;
+    for (unsigned int j = threadIdx.x; j < att_len; j += blockDim.x) {
+        float x = -INFINITY;
+        if (mask_[j]) {
+            float dot = 0;
+            for (unsigned int l = 0; l < dh; ++l) {
+                dot += __half2float(q_[l * s[5]]) * __half2float(k_[j * s[7] + l * s[8]]);
+            }
+            x = dot * scale;
+            if (softcap > 0) {
+                x = softcap * tanhf(x / softcap);
+            }
+        }
+        att_[j] = x;
+        local = fmaxf(local, x);
+    }
+    float max = reduce(shared, local, true);
+    // 所有键都被遮盖时输出 0
+    if (max == -INFINITY) {
+        for (unsigned int l = threadIdx.x; l < dh; l += blockDim.x) {
+            o_[l * s[2]] = __float2half(0);
+        }
+        return;
+    }
+
+    local = 0;
+    for (unsigned int j = threadIdx.x; j < att_len; j += blockDim.x) {
+        float x = expf(att_[j] - max);
+        att_[j] = x;
+        local += x;
+    }
+    float total = reduce(shared, local, false);
+
+    for (unsigned int l = threadIdx.x; l < dh; l += blockDim.x) {
+        float sum = 0;
+        for (unsigned int j = 0; j < att_len; ++j) {
+            float a = att_[j];
+            if (a != 0) {
+                sum += a / total * __half2float(v_[j * s[10] + l * s[11]]);
+            }
+        }
+        o_[l * s[2]] = __float2half(sum);
+    }
+}
+"#;
+
+/// 每个线程块的线程数，必须是 2 的幂。
+const BLOCK_SIZE: u32 = 256;
+
+/// 以 f32 累加的注意力，支持任意掩码。
+pub struct AttentionKernel(Arc<ModuleBox>);
+
+impl AttentionKernel {
+    pub fn new(handle: &Gpu) -> Self {
+        let cc = handle.device().compute_capability();
+        Self(handle.compile_kernel("infinilm-attention", cc, || CODE.into()))
+    }
+
+    /// 计算注意力，`mask(i, j)` 表示第 `i` 个查询能否看到第 `j` 个键，与 [`common_devices::masked_attention_f32`] 相同。
+    ///
+    /// 掩码在主机上展开后拷贝到设备上，分数、softmax 和加权求和都在设备上以 f32 计算，不需要同步。
+    #[allow(clippy::too_many_arguments)]
+    pub fn launch<O, Q, K, V>(
+        &self,
+        o: &mut Tensor<O>,
+        q: &Tensor<Q>,
+        k: &Tensor<K>,
+        v: &Tensor<V>,
+        scale: f32,
+        softcap: Option<f32>,
+        mask: impl Fn(udim, udim) -> bool,
+        stream: &Stream,
+    ) where
+        O: DerefMut<Target = [DevByte]>,
+        Q: Deref<Target = [DevByte]>,
+        K: Deref<Target = [DevByte]>,
+        V: Deref<Target = [DevByte]>,
+    {
+        for t in [
+            o.data_layout(),
+            q.data_layout(),
+            k.data_layout(),
+            v.data_layout(),
+        ] {
+            assert_eq!(t, F16);
+        }
+        let &[nh, seq_len, dh] = q.shape() else {
+            panic!()
+        };
+        let &[nkvh, att_len, dh_] = k.shape() else {
+            panic!()
+        };
+        assert_eq!(o.shape(), q.shape());
+        assert_eq!(v.shape(), k.shape());
+        assert_eq!(dh, dh_);
+        assert_eq!(nh % nkvh, 0);
+        assert!(seq_len <= att_len);
+        if nh == 0 || seq_len == 0 {
+            return;
+        }
+
+        let mask = (0..seq_len)
+            .flat_map(|i| (0..att_len).map(move |j| (i, j)))
+            .map(|(i, j)| mask(i, j) as u8)
+            .collect::<Vec<_>>();
+        // 步长以元素为单位，偏移折算到基址上
+        let mut strides = Vec::with_capacity(12);
+        let mut base = |pattern: &[idim], ptr: usize| {
+            let &[s0, s1, s2, offset] = pattern else {
+                panic!()
+            };
+            strides.extend([s0, s1, s2].map(|s| s as i64));
+            (ptr as isize + offset as isize * F16.nbytes() as isize) as usize
+        };
+        let o_ptr = o.physical_mut().as_mut_ptr() as usize;
+        let o_ptr = base(o.pattern(), o_ptr);
+        let q_ptr = base(q.pattern(), q.physical().as_ptr() as _);
+        let k_ptr = base(k.pattern(), k.physical().as_ptr() as _);
+        let v_ptr = base(v.pattern(), v.physical().as_ptr() as _);
+
+        let num_blocks = nh * seq_len;
+        let mask = stream.from_host(&mask);
+        let strides = stream.from_host(&strides);
+        let att = stream.malloc::<f32>(num_blocks as usize * att_len as usize);
+
+        let mask_ptr = mask.as_ptr();
+        let att_ptr = att.as_ptr();
+        let strides_ptr = strides.as_ptr();
+        let head_group = nh / nkvh;
+        let softcap = softcap.unwrap_or(0.);
+        let params = params![
+            o_ptr,
+            q_ptr,
+            k_ptr,
+            v_ptr,
+            mask_ptr,
+            att_ptr,
+            strides_ptr,
+            seq_len,
+            att_len,
+            dh,
+            head_group,
+            scale,
+            softcap
+        ];
+        let shared = BLOCK_SIZE as usize * F32.nbytes();
+        self.0.launch(
+            c"attention_f32",
+            num_blocks,
+            BLOCK_SIZE,
+            params.as_ptr(),
+            shared,
+            stream,
+        );
+        mask.drop_on(stream);
+        strides.drop_on(stream);
+        att.drop_on(stream);
+    }
+}
@@ -1,11 +1,16 @@
 ﻿#![cfg(detected_cuda)]
 
+mod attention;
 mod gather;
+mod logits;
 
 use common::{f16, utok};
 use common_devices::{Operators, SliceOn};
 use cuda::{AsRaw, Device};
-use digit_layout::types::{F16, U32};
+use digit_layout::{
+    types::{F16, F32, U32},
+    DigitLayout,
+};
 use operators::{
     cuda::{memcpy_d2h, DevByte, DevMem, Stream},
     dyn_,
@@ -20,11 +25,12 @@ use operators::{
 };
 use std::{
     collections::HashMap,
+    iter::zip,
     ops::{Deref, DerefMut},
     ptr::{null, null_mut},
 };
 
-pub use common_devices::{Kernels, KernelsA, KernelsB};
+pub use common_devices::{Kernels, KernelsA, KernelsB, SampleRng};
 pub use operators::{cuda, nvidia_gpu::Handle as Gpu};
 pub use tensor::{reslice, reslice_mut, slice, split, udim, LocalSplitable, Tensor};
 
@@ -41,6 +47,9 @@ struct Internal {
     softmax: softmax::Operator,
     mlp: mlp::Operator,
     random_sample: random_sample::Operator,
+    random_sample_f32: random_sample::Operator,
+    logits: logits::LogitsKernels,
+    attention: attention::AttentionKernel,
 }
 
 impl Internal {
@@ -106,10 +115,12 @@ impl Internal {
         })
         .unwrap();
 
-        let mut random_sample = random_sample::Operator::new(handle);
-        random_sample
-            .scheme(&operators::random_sample::Args::new(F16, voc))
-            .unwrap();
+        let random_sample = |dt| {
+            let mut op = random_sample::Operator::new(handle);
+            op.scheme(&operators::random_sample::Args::new(dt, voc))
+                .unwrap();
+            op
+        };
 
         Self {
             mat_mul,
@@ -118,7 +129,10 @@ impl Internal {
             reform,
             softmax,
             mlp,
-            random_sample,
+            random_sample: random_sample(F16),
+            random_sample_f32: random_sample(F32),
+            logits: logits::LogitsKernels::new(handle),
+            attention: attention::AttentionKernel::new(handle),
         }
     }
 }
@@ -142,45 +156,164 @@ impl NvidiaKernels {
         self.0.get(&unsafe { queue.ctx().dev().as_raw() }).unwrap()
     }
 
+    /// 采样的工作空间，f16 和 f32 的 logits 共用，按两者中较大的分配。
     pub fn sample_workspace<'ctx>(&self, queue: &QueueOf<'ctx, Gpu>) -> DevMem<'ctx> {
-        self.get(queue).random_sample.workspace(queue)
+        let internal = self.get(queue);
+        let f16 = internal.random_sample.workspace(queue);
+        let f32 = internal.random_sample_f32.workspace(queue);
+        if f16.len() >= f32.len() {
+            f32.drop_on(queue);
+            f16
+        } else {
+            f16.drop_on(queue);
+            f32
+        }
     }
 
+    /// 从数据类型为 `dt` 的 `logits` 中采样，支持 f16 和 f32。
     pub fn sample(
         &self,
         voc_size: usize,
-        args: impl IntoIterator<Item = SampleArgs>,
+        dt: DigitLayout,
+        args: impl IntoIterator<Item = impl Into<SampleArgs>>,
         logits: &[DevByte],
         workspace: &mut [DevByte],
         stream: &Stream,
     ) -> Vec<utok> {
-        let random_sample = &self.get(stream).random_sample;
+        let internal = self.get(stream);
+        let random_sample = match dt {
+            F16 => &internal.random_sample,
+            F32 => &internal.random_sample_f32,
+            _ => panic!("sampling from {dt} logits is not supported"),
+        };
+        let rows = logits;
         let logits = logits.as_ptr();
 
-        let details = args.into_iter().collect::<Vec<_>>();
+        let details = args.into_iter().map(Into::into).collect::<Vec<_>>();
+        let greedy = details.iter().map(is_greedy).collect::<Vec<_>>();
         let kv_pair_size = KVPair::<()>::LAYOUT.nbytes();
         let mut kv_pairs = stream.malloc::<u8>(details.len() * kv_pair_size);
-
-        let mut args = operators::random_sample::Args::<Gpu>::new(F16, voc_size);
+        // 算子规约的顺序不保证平局时的选择，贪心采样的行由 argmax 算子计算，取序号最小的最大值
+        let mut indices = stream.malloc::<u32>(details.len());
+        internal.logits.argmax(
+            voc_size,
+            dt,
+            greedy.iter().copied(),
+            rows,
+            &mut indices,
+            stream,
+        );
+
+        let mut args = operators::random_sample::Args::<Gpu>::new(dt, voc_size);
         args.workspace = Workspace {
             ptr: workspace.as_mut_ptr(),
             len: workspace.len(),
         };
         for (i, detail) in details.iter().enumerate() {
+            if greedy[i] {
+                continue;
+            }
             args.kv_pair_base = unsafe { kv_pairs.as_mut_ptr().add(i * kv_pair_size) };
-            args.data_base = unsafe { logits.add(i * voc_size * F16.nbytes()) };
-            args.detail = *detail;
+            args.data_base = unsafe { logits.add(i * voc_size * dt.nbytes()) };
+            // top_k 超过词表大小时限制在词表大小以内
+            args.detail = SampleArgs {
+                top_k: detail.top_k.min(voc_size),
+                ..*detail
+            };
             random_sample.launch(&args, stream).unwrap();
         }
 
-        let mut host = vec![KVPair::new(0, f16::ZERO); details.len()];
+        let mut host_indices = vec![0u32; details.len()];
         stream.synchronize();
-        memcpy_d2h(&mut host, &kv_pairs);
+        let host = match dt {
+            F16 => kv_pair_indices(&kv_pairs, details.len(), f16::ZERO),
+            _ => kv_pair_indices(&kv_pairs, details.len(), 0f32),
+        };
+        memcpy_d2h(&mut host_indices, &indices);
+        kv_pairs.drop_on(stream);
+        indices.drop_on(stream);
+
+        zip(greedy, zip(host, host_indices))
+            .map(|(greedy, (kv, idx))| if greedy { idx } else { kv })
+            .collect()
+    }
+
+    /// 给每行 `logits` 中 `biases` 指定的 token 的 logit 加上偏置，`logits` 的数据类型为 `dt`。
+    ///
+    /// 在设备上计算，不需要同步。
+    #[inline]
+    pub fn logit_bias(
+        &self,
+        voc_size: usize,
+        dt: DigitLayout,
+        biases: impl IntoIterator<Item = impl IntoIterator<Item = (utok, f32)>>,
+        logits: &mut [DevByte],
+        stream: &Stream,
+    ) {
+        self.get(stream)
+            .logits
+            .logit_bias(voc_size, dt, biases, logits, stream);
+    }
+
+    /// 对每行 `logits` 中 `history` 出现过的 token 施加重复惩罚，每行为 `(penalty, history)`，`logits` 的数据类型为 `dt`。
+    ///
+    /// `history` 中的 token 不重复，在设备上计算，不需要同步。
+    #[inline]
+    pub fn repetition_penalty<'a>(
+        &self,
+        voc_size: usize,
+        dt: DigitLayout,
+        penalties: impl IntoIterator<Item = (f32, &'a [utok])>,
+        logits: &mut [DevByte],
+        stream: &Stream,
+    ) {
+        self.get(stream)
+            .logits
+            .repetition_penalty(voc_size, dt, penalties, logits, stream);
+    }
 
-        host.into_iter().map(|kv| kv.idx() as _).collect()
+    /// 把每行 `logits` 中概率小于最大概率 `min_p` 倍的 token 的 logit 置为负无穷，每行为 `(temperature, min_p)`。
+    ///
+    /// 在 [`sample`](Self::sample) 之前调用，采样时先按 min-p 过滤再由 top-k 限制数量。
+    /// 贪心采样或 `min_p` 为 0 的行不修改，在设备上计算，不需要同步。
+    #[inline]
+    pub fn min_p(
+        &self,
+        voc_size: usize,
+        dt: DigitLayout,
+        args: impl IntoIterator<Item = (f32, f32)>,
+        logits: &mut [DevByte],
+        stream: &Stream,
+    ) {
+        self.get(stream)
+            .logits
+            .min_p(voc_size, dt, args, logits, stream);
+    }
+
+    /// 把连续的 f16 张量 `x` 转换为 f32 写入 `y`，在设备上计算，不需要同步。
+    #[inline]
+    pub fn upcast<T, U>(&self, y: &mut Tensor<T>, x: &Tensor<U>, stream: &Stream)
+    where
+        T: DerefMut<Target = [DevByte]>,
+        U: Deref<Target = [DevByte]>,
+    {
+        self.get(stream).logits.upcast(y, x, stream);
     }
 }
 
+/// 把设备上 `n` 个值类型与 `zero` 相同的键值对拷贝到主机，返回其中的序号。
+fn kv_pair_indices<T: Copy>(kv_pairs: &[DevByte], n: usize, zero: T) -> Vec<utok> {
+    let mut host = vec![KVPair::new(0, zero); n];
+    memcpy_d2h(&mut host, kv_pairs);
+    host.into_iter().map(|kv| kv.idx() as _).collect()
+}
+
+/// 贪心采样的参数，与采样算子的判断相同。
+#[inline]
+fn is_greedy(args: &SampleArgs) -> bool {
+    args.temperature <= 0. || args.top_k < 2 || args.top_p <= 0.
+}
+
 impl Kernels<Gpu> for NvidiaKernels {}
 
 impl Operators for NvidiaKernels {
@@ -239,6 +372,34 @@ impl KernelsB for NvidiaKernels {
     {
         gather::gather(x, table, tokens, queue);
     }
+
+    fn softcap<T>(&self, x: &mut Tensor<T>, cap: f32, queue: &QueueOf<Self::Handle>)
+    where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+    {
+        self.get(queue).logits.softcap(x, cap, queue);
+    }
+
+    fn attention_f32<O, Q, K, V>(
+        &self,
+        o: &mut Tensor<O>,
+        q: &Tensor<Q>,
+        k: &Tensor<K>,
+        v: &Tensor<V>,
+        scale: f32,
+        softcap: Option<f32>,
+        mask: impl Fn(udim, udim) -> bool,
+        queue: &QueueOf<Self::Handle>,
+    ) where
+        O: DerefMut<Target = SliceOn<Self::Handle>>,
+        Q: Deref<Target = SliceOn<Self::Handle>>,
+        K: Deref<Target = SliceOn<Self::Handle>>,
+        V: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        self.get(queue)
+            .attention
+            .launch(o, q, k, v, scale, softcap, mask, queue);
+    }
 }
 
 pub fn synchronize() {
@@ -251,3 +412,217 @@ pub fn synchronize() {
             .apply(|ctx| ctx.synchronize());
     }
 }
+
+#[test]
+fn test_sample_f32() {
+    use common_devices::{filter_logits, SampleFilter};
+
+    if let Err(cuda::NoDevice) = cuda::init() {
+        return;
+    }
+    const VOC: usize = 4096;
+    let device = cuda::Device::new(0);
+    let kernels = NvidiaKernels::new(&[device], 2048, VOC);
+
+    let mut rng = SampleRng::new(0);
+    let logits = (0..2 * VOC)
+        .map(|_| rng.next_f32() * 8.)
+        .collect::<Vec<_>>();
+    let args = [
+        SampleArgs {
+            temperature: 0.,
+            top_p: 1.,
+            top_k: usize::MAX,
+        },
+        SampleArgs {
+            temperature: 0.8,
+            top_p: 0.9,
+            top_k: 50,
+        },
+    ];
+
+    // 主机上按相同的参数过滤得到的候选作为参考
+    let candidates = args
+        .iter()
+        .zip(logits.chunks_exact(VOC))
+        .map(|(a, logits)| {
+            let filter = SampleFilter::new(a.temperature, a.top_p, a.top_k);
+            filter_logits(filter, logits)
+                .into_iter()
+                .map(|(t, _)| t)
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(candidates[0].len(), 1);
+
+    device.retain_primary().apply(|ctx| {
+        let stream = ctx.stream();
+        let logits = stream.from_host(&logits);
+        let mut workspace = kernels.sample_workspace(&stream);
+        for _ in 0..16 {
+            let sampled = kernels.sample(VOC, F32, args, &logits, &mut workspace, &stream);
+            // 贪心采样的结果与主机相同，随机采样的结果总在主机过滤的候选之中
+            assert_eq!(sampled[0], candidates[0][0]);
+            assert!(candidates[1].contains(&sampled[1]));
+        }
+    });
+}
+
+#[test]
+fn test_sample_argmax_tie() {
+    if let Err(cuda::NoDevice) = cuda::init() {
+        return;
+    }
+    const VOC: usize = 1024;
+    let device = cuda::Device::new(0);
+    let kernels = NvidiaKernels::new(&[device], 2048, VOC);
+
+    // 相等的最大值分布在不同线程处理的位置上，总是选择序号最小的
+    let mut logits = vec![f16::ZERO; 2 * VOC];
+    for i in [700, 300, 301, 999, VOC + 5, VOC + 1000] {
+        logits[i] = f16::from_f32(3.);
+    }
+    logits[VOC + 6] = f16::NAN;
+    let args = [SampleArgs {
+        temperature: 0.,
+        top_p: 1.,
+        top_k: usize::MAX,
+    }; 2];
+    let sampled = device.retain_primary().apply(|ctx| {
+        let stream = ctx.stream();
+        let logits = stream.from_host(&logits);
+        let mut workspace = kernels.sample_workspace(&stream);
+        kernels.sample(VOC, F16, args, &logits, &mut workspace, &stream)
+    });
+    assert_eq!(sampled, [300, 5]);
+}
+
+#[test]
+fn test_min_p() {
+    if let Err(cuda::NoDevice) = cuda::init() {
+        return;
+    }
+    const VOC: usize = 5;
+    let device = cuda::Device::new(0);
+    let kernels = NvidiaKernels::new(&[device], 2048, VOC);
+
+    let dist = [0.5f32, 0.2, 0.15, 0.1, 0.05];
+    let logits = [dist, dist, dist]
+        .concat()
+        .into_iter()
+        .map(f32::ln)
+        .collect::<Vec<_>>();
+    // 第一行保留 [0.5, 0.2, 0.15]，贪心采样和 min_p 为 0 的行不修改
+    let args = [(1., 0.25), (0., 0.25), (1., 0.)];
+    let mut host = vec![0f32; logits.len()];
+    device.retain_primary().apply(|ctx| {
+        let stream = ctx.stream();
+        let mut logits = stream.from_host(&logits);
+        kernels.min_p(VOC, F32, args, &mut logits, &stream);
+        memcpy_d2h(&mut host, &logits);
+    });
+    let kept = |row: &[f32]| row.iter().filter(|x| x.is_finite()).count();
+    assert_eq!(kept(&host[..VOC]), 3);
+    assert_eq!(&host[..3], &logits[..3]);
+    assert_eq!(host[VOC..], logits[VOC..]);
+}
+
+#[test]
+fn test_logit_bias() {
+    if let Err(cuda::NoDevice) = cuda::init() {
+        return;
+    }
+    const VOC: usize = 4;
+    let device = cuda::Device::new(0);
+    let kernels = NvidiaKernels::new(&[device], 2048, VOC);
+
+    let logits = [2f32, -1., 0.5, 1., 2., -1., 0.5, 1.].map(f16::from_f32);
+    let mut host = [f16::ZERO; 2 * VOC];
+    device.retain_primary().apply(|ctx| {
+        let stream = ctx.stream();
+        let mut logits = stream.from_host(&logits);
+        // 同一个 token 的偏置累加
+        let biases = [vec![(2, 0.5), (3, -1.), (2, 0.5)], vec![]];
+        kernels.logit_bias(VOC, F16, biases, &mut logits, &stream);
+        let history: [&[utok]; 2] = [&[], &[0, 1]];
+        let penalties = [(2., history[0]), (2., history[1])];
+        kernels.repetition_penalty(VOC, F16, penalties, &mut logits, &stream);
+        memcpy_d2h(&mut host, &logits);
+    });
+    let expected = [2f32, -1., 1.5, 0., 1., -2., 0.5, 1.].map(f16::from_f32);
+    assert_eq!(host, expected);
+}
+
+#[test]
+fn test_softcap() {
+    if let Err(cuda::NoDevice) = cuda::init() {
+        return;
+    }
+    let device = cuda::Device::new(0);
+    let kernels = NvidiaKernels::new(&[device], 2048, 4);
+
+    let x = [0f32, 1., -30., 100., 1e4, -1e4];
+    let mut host = [0f32; 6];
+    device.retain_primary().apply(|ctx| {
+        let stream = ctx.stream();
+        let mut x = Tensor::new(F32, &[2, 3], stream.from_host(&x));
+        kernels.softcap(&mut x, 30., &stream);
+        memcpy_d2h(&mut host, x.physical());
+    });
+    for (y, x) in host.iter().zip(x) {
+        assert!((y - 30. * (x / 30.).tanh()).abs() < 1e-4, "{y} {x}");
+        assert!(y.abs() <= 30.);
+    }
+}
+
+#[test]
+fn test_attention_f32() {
+    if let Err(cuda::NoDevice) = cuda::init() {
+        return;
+    }
+    const NH: udim = 4;
+    const NKVH: udim = 2;
+    const SEQ: udim = 3;
+    const ATT: udim = 7;
+    const DH: udim = 16;
+    let device = cuda::Device::new(0);
+    let kernels = NvidiaKernels::new(&[device], 2048, 4);
+
+    let mut rng = SampleRng::new(0);
+    let mut random = |n: udim| {
+        (0..n)
+            .map(|_| f16::from_f32(rng.next_f32() * 2. - 1.))
+            .collect::<Vec<_>>()
+    };
+    let q = random(NH * SEQ * DH);
+    let k = random(NKVH * ATT * DH);
+    let v = random(NKVH * ATT * DH);
+    // 滑动窗口，第一个查询的键全部被遮盖
+    let mask = |i: udim, j: udim| i > 0 && j + 3 > i + ATT - SEQ && j <= i + ATT - SEQ;
+
+    let mut expected = vec![f16::ZERO; q.len()];
+    common_devices::masked_attention_f32(
+        &mut Tensor::new(F16, &[NH, SEQ, DH], reslice_mut(&mut expected)),
+        &Tensor::new(F16, &[NH, SEQ, DH], reslice(&q)),
+        &Tensor::new(F16, &[NKVH, ATT, DH], reslice(&k)),
+        &Tensor::new(F16, &[NKVH, ATT, DH], reslice(&v)),
+        0.25,
+        Some(5.),
+        mask,
+    );
+
+    let mut host = vec![f16::ZERO; q.len()];
+    device.retain_primary().apply(|ctx| {
+        let stream = ctx.stream();
+        let mut o = Tensor::new(F16, &[NH, SEQ, DH], stream.malloc::<f16>(host.len()));
+        let q = Tensor::new(F16, &[NH, SEQ, DH], stream.from_host(&q));
+        let k = Tensor::new(F16, &[NKVH, ATT, DH], stream.from_host(&k));
+        let v = Tensor::new(F16, &[NKVH, ATT, DH], stream.from_host(&v));
+        kernels.attention_f32(&mut o, &q, &k, &v, 0.25, Some(5.), mask, &stream);
+        memcpy_d2h(&mut host, o.physical());
+    });
+    for (a, b) in host.iter().zip(&expected) {
+        assert!((a.to_f32() - b.to_f32()).abs() < 1e-3, "{a} != {b}");
+    }
+    assert!(host[..DH as usize].iter().all(|x| *x == f16::ZERO));
+}
@@ -0,0 +1,516 @@
+use common::utok;
+use common_devices::min_p_threshold;
+use digit_layout::{
+    types::{F16, F32, U32},
+    DigitLayout,
+};
+use operators::{
+    cuda::{params, DevByte, Stream},
+    nvidia_gpu::{Handle as Gpu, ModuleBox},
+};
+use std::{
+    ffi::CStr,
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+use tensor::Tensor;
+
+const CODE: &str = r#"
+#include <cuda_fp16.h>
+
+__device__ float load(half const *x) { return __half2float(*x); }
+__device__ float load(float const *x) { return *x; }
+__device__ void store(half *x, float v) { *x = __float2half(v); }
+__device__ void store(float *x, float v) { *x = v; }
+
+// 每个线程块处理一行，一行中的 token 不重复，各线程之间没有冲突
+template<class T>
+__device__ void repetition_penalty(
+    T *logits,
+    unsigned int voc,
+    unsigned int const *rows,
+    unsigned int const *offsets,
+    unsigned int const *tokens,
+    float const *penalties) {
+    unsigned int r = blockIdx.x;
+    T *row = logits + (size_t) rows[r] * voc;
+    float penalty = penalties[r];
+    for (unsigned int i = offsets[r] + threadIdx.x; i < offsets[r + 1]; i += blockDim.x) {
+        T *x = row + tokens[i];
+        float v = load(x);
+        store(x, v > 0 ? v / penalty : v * penalty);
+    }
+}
+
+extern "C" __global__ void repetition_penalty_f16(
+    half *logits,
+    unsigned int voc,
+    unsigned int const *rows,
+    unsigned int const *offsets,
+    unsigned int const *tokens,
+    float const *penalties) {
+    repetition_penalty(logits, voc, rows, offsets, tokens, penalties);
+}
+
+extern "C" __global__ void repetition_penalty_f32(
+    float *logits,
+    unsigned int voc,
+    unsigned int const *rows,
+    unsigned int const *offsets,
+    unsigned int const *tokens,
+    float const *penalties) {
+    repetition_penalty(logits, voc, rows, offsets, tokens, penalties);
+}
+
+// 每个线程处理一个元素
+template<class T>
+__device__ void softcap(T *x, size_t n, float cap) {
+    size_t i = (size_t) blockIdx.x * blockDim.x + threadIdx.x;
+    if (i < n) {
+        store(x + i, cap * tanhf(load(x + i) / cap));
+    }
+}
+
+extern "C" __global__ void softcap_f16(half *x, size_t n, float cap) {
+    softcap(x, n, cap);
+}
+
+extern "C" __global__ void softcap_f32(float *x, size_t n, float cap) {
+    softcap(x, n, cap);
+}
+
+// 每个线程转换一个元素
+extern "C" __global__ void upcast(float *y, half const *x, size_t n) {
+    size_t i = (size_t) blockIdx.x * blockDim.x + threadIdx.x;
+    if (i < n) {
+        y[i] = __half2float(x[i]);
+    }
+}
+
+// 每个线程块处理一行，一行中的 token 不重复，各线程之间没有冲突
+template<class T>
+__device__ void logit_bias(
+    T *logits,
+    unsigned int voc,
+    unsigned int const *rows,
+    unsigned int const *offsets,
+    unsigned int const *tokens,
+    float const *biases) {
+    unsigned int r = blockIdx.x;
+    T *row = logits + (size_t) rows[r] * voc;
+    for (unsigned int i = offsets[r] + threadIdx.x; i < offsets[r + 1]; i += blockDim.x) {
+        T *x = row + tokens[i];
+        store(x, load(x) + biases[i]);
+    }
+}
+
+extern "C" __global__ void logit_bias_f16(
+    half *logits,
+    unsigned int voc,
+    unsigned int const *rows,
+    unsigned int const *offsets,
+    unsigned int const *tokens,
+    float const *biases) {
+    logit_bias(logits, voc, rows, offsets, tokens, biases);
+}
+
+extern "C" __global__ void logit_bias_f32(
+    float *logits,
+    unsigned int voc,
+    unsigned int const *rows,
+    unsigned int const *offsets,
+    unsigned int const *tokens,
+    float const *biases) {
+    logit_bias(logits, voc, rows, offsets, tokens, biases);
+}
+
+// 每个线程块处理一行，规约得到最大值后把低于下限的 logit 置为负无穷，线程数是 2 的幂
+template<class T>
+__device__ void min_p(
+    T *logits,
+    unsigned int voc,
+    unsigned int const *rows,
+    float const *offsets) {
+    extern __shared__ float max[];
+    T *row = logits + (size_t) rows[blockIdx.x] * voc;
+    float local = -INFINITY;
+    for (unsigned int i = threadIdx.x; i < voc; i += blockDim.x) {
+        local = fmaxf(local, load(row + i));
+    }
+    max[threadIdx.x] = local;
+    __syncthreads();
+    for (unsigned int s = blockDim.x / 2; s > 0; s >>= 1) {
+        if (threadIdx.x < s) {
+            max[threadIdx.x] = fmaxf(max[threadIdx.x], max[threadIdx.x + s]);
+        }
+        __syncthreads();
+    }
+    float threshold = max[0] + offsets[blockIdx.x];
+    for (unsigned int i = threadIdx.x; i < voc; i += blockDim.x) {
+        if (load(row + i) < threshold) {
+            store(row + i, -INFINITY);
+        }
+    }
+}
+
+extern "C" __global__ void min_p_f16(
+    half *logits,
+    unsigned int voc,
+    unsigned int const *rows,
+    float const *offsets) {
+    min_p(logits, voc, rows, offsets);
+}
+
+extern "C" __global__ void min_p_f32(
+    float *logits,
+    unsigned int voc,
+    unsigned int const *rows,
+    float const *offsets) {
+    min_p(logits, voc, rows, offsets);
+}
+
+// 每个线程块处理一行，多个最大值相等时取序号最小的一个，NaN 被忽略，与主机上的 argmax 相同
+template<class T>
+__device__ void argmax(
+    T const *logits,
+    unsigned int voc,
+    unsigned int const *rows,
+    unsigned int *indices) {
+    extern __shared__ float values[];
+    unsigned int *args = (unsigned int *) (values + blockDim.x);
+    T const *row = logits + (size_t) rows[blockIdx.x] * voc;
+    // 序号为 voc 表示还没有找到
+    float best = -INFINITY;
+    unsigned int arg = voc;
+    for (unsigned int i = threadIdx.x; i < voc; i += blockDim.x) {
+        float x = load(row + i);
+        if (!isnan(x) && (arg == voc || x > best)) {
+            best = x;
+            arg = i;
+        }
+    }
+    values[threadIdx.x] = best;
+    args[threadIdx.x] = arg;
+    __syncthreads();
+    for (unsigned int s = blockDim.x / 2; s > 0; s >>= 1) {
+        if (threadIdx.x < s) {
+            float x = values[threadIdx.x + s];
+            unsigned int i = args[threadIdx.x + s];
+            unsigned int j = args[threadIdx.x];
+            if (i != voc && (j == voc || x > values[threadIdx.x] || (x == values[threadIdx.x] && i < j))) {
+                values[threadIdx.x] = x;
+                args[threadIdx.x] = i;
+            }
+        }
+        __syncthreads();
+    }
+    if (threadIdx.x == 0) {
+        indices[rows[blockIdx.x]] = args[0] == voc ? 0 : args[0];
+    }
+}
+
+extern "C" __global__ void argmax_f16(
+    half const *logits,
+    unsigned int voc,
+    unsigned int const *rows,
+    unsigned int *indices) {
+    argmax(logits, voc, rows, indices);
+}
+
+extern "C" __global__ void argmax_f32(
+    float const *logits,
+    unsigned int voc,
+    unsigned int const *rows,
+    unsigned int *indices) {
+    argmax(logits, voc, rows, indices);
+}
+"#;
+
+/// 每个线程块的线程数，必须是 2 的幂。
+const BLOCK_SIZE: u32 = 256;
+
+/// 直接在设备上修改 logits 的算子，避免把 logits 拷贝到主机上。
+pub struct LogitsKernels(Arc<ModuleBox>);
+
+impl LogitsKernels {
+    pub fn new(handle: &Gpu) -> Self {
+        let cc = handle.device().compute_capability();
+        Self(handle.compile_kernel("infinilm-logits", cc, || CODE.into()))
+    }
+
+    /// 对每行 `logits` 中 `history` 的 token 施加重复惩罚，每行为 `(penalty, history)`，`history` 不重复。
+    ///
+    /// 需要惩罚的行在一次启动中完成，只向设备拷贝 token 列表。
+    pub fn repetition_penalty<'a>(
+        &self,
+        voc_size: usize,
+        dt: DigitLayout,
+        penalties: impl IntoIterator<Item = (f32, &'a [utok])>,
+        logits: &mut [DevByte],
+        stream: &Stream,
+    ) {
+        let mut rows = Vec::<u32>::new();
+        let mut offsets = vec![0u32];
+        let mut tokens = Vec::<utok>::new();
+        let mut factors = Vec::<f32>::new();
+        for (i, (penalty, history)) in penalties.into_iter().enumerate() {
+            if penalty != 1. && !history.is_empty() {
+                rows.push(i as _);
+                tokens.extend_from_slice(history);
+                offsets.push(tokens.len() as _);
+                factors.push(penalty);
+            }
+        }
+        if rows.is_empty() {
+            return;
+        }
+        assert!(logits.len() >= (*rows.last().unwrap() as usize + 1) * voc_size * dt.nbytes());
+
+        let name: &CStr = match dt {
+            F16 => c"repetition_penalty_f16",
+            F32 => c"repetition_penalty_f32",
+            _ => panic!("unsupported logits dtype {dt:?}"),
+        };
+        let num_rows = rows.len() as u32;
+        let rows = stream.from_host(&rows);
+        let offsets = stream.from_host(&offsets);
+        let tokens = stream.from_host(&tokens);
+        let factors = stream.from_host(&factors);
+
+        // 参数传递的是变量的地址，不能直接使用临时值
+        let logits_ptr = logits.as_mut_ptr();
+        let voc = voc_size as u32;
+        let rows_ptr = rows.as_ptr();
+        let offsets_ptr = offsets.as_ptr();
+        let tokens_ptr = tokens.as_ptr();
+        let factors_ptr = factors.as_ptr();
+        let params = params![
+            logits_ptr,
+            voc,
+            rows_ptr,
+            offsets_ptr,
+            tokens_ptr,
+            factors_ptr
+        ];
+        self.0
+            .launch(name, num_rows, BLOCK_SIZE, params.as_ptr(), 0, stream);
+        rows.drop_on(stream);
+        offsets.drop_on(stream);
+        tokens.drop_on(stream);
+        factors.drop_on(stream);
+    }
+
+    /// 对连续的张量 `x` 逐元素软截断，`x = cap * tanh(x / cap)`。
+    pub fn softcap<T>(&self, x: &mut Tensor<T>, cap: f32, stream: &Stream)
+    where
+        T: DerefMut<Target = [DevByte]>,
+    {
+        assert!(x.is_contiguous());
+
+        let dt = x.data_layout();
+        let name: &CStr = match dt {
+            F16 => c"softcap_f16",
+            F32 => c"softcap_f32",
+            _ => panic!("softcap does not support {dt:?}"),
+        };
+        let offset = x.bytes_offset() as usize;
+        let len = x.bytes_size();
+        let n = len / dt.nbytes();
+        if n == 0 {
+            return;
+        }
+
+        let x_ptr = x.physical_mut()[offset..][..len].as_mut_ptr();
+        let n_ = n as u64;
+        let params = params![x_ptr, n_, cap];
+        let grid = n.div_ceil(BLOCK_SIZE as usize) as u32;
+        self.0
+            .launch(name, grid, BLOCK_SIZE, params.as_ptr(), 0, stream);
+    }
+
+    /// 把连续的 f16 张量 `x` 转换为 f32 写入形状相同的连续张量 `y`。
+    pub fn upcast<T, U>(&self, y: &mut Tensor<T>, x: &Tensor<U>, stream: &Stream)
+    where
+        T: DerefMut<Target = [DevByte]>,
+        U: Deref<Target = [DevByte]>,
+    {
+        assert_eq!(x.data_layout(), F16);
+        assert_eq!(y.data_layout(), F32);
+        assert_eq!(x.shape(), y.shape());
+        assert!(x.is_contiguous() && y.is_contiguous());
+
+        let n = x.bytes_size() / F16.nbytes();
+        if n == 0 {
+            return;
+        }
+        let y_offset = y.bytes_offset() as usize;
+        let x_offset = x.bytes_offset() as usize;
+        let y_ptr = y.physical_mut()[y_offset..].as_mut_ptr();
+        let x_ptr = x.physical()[x_offset..].as_ptr();
+        let n_ = n as u64;
+        let params = params![y_ptr, x_ptr, n_];
+        let grid = n.div_ceil(BLOCK_SIZE as usize) as u32;
+        self.0
+            .launch(c"upcast", grid, BLOCK_SIZE, params.as_ptr(), 0, stream);
+    }
+
+    /// 给每行 `logits` 中 `biases` 指定的 token 的 logit 加上偏置，同一个 token 的多个偏置累加。
+    ///
+    /// 有偏置的行在一次启动中完成，只向设备拷贝 token 和偏置列表。
+    pub fn logit_bias(
+        &self,
+        voc_size: usize,
+        dt: DigitLayout,
+        biases: impl IntoIterator<Item = impl IntoIterator<Item = (utok, f32)>>,
+        logits: &mut [DevByte],
+        stream: &Stream,
+    ) {
+        let mut rows = Vec::<u32>::new();
+        let mut offsets = vec![0u32];
+        let mut tokens = Vec::<utok>::new();
+        let mut values = Vec::<f32>::new();
+        for (i, row) in biases.into_iter().enumerate() {
+            let mut row = row.into_iter().collect::<Vec<_>>();
+            if row.is_empty() {
+                continue;
+            }
+            // 合并同一个 token 的偏置，使一行中的 token 不重复
+            row.sort_by_key(|&(token, _)| token);
+            let start = tokens.len();
+            for (token, bias) in row {
+                if tokens.len() > start && tokens.last() == Some(&token) {
+                    *values.last_mut().unwrap() += bias;
+                } else {
+                    tokens.push(token);
+                    values.push(bias);
+                }
+            }
+            rows.push(i as _);
+            offsets.push(tokens.len() as _);
+        }
+        if rows.is_empty() {
+            return;
+        }
+        assert!(logits.len() >= (*rows.last().unwrap() as usize + 1) * voc_size * dt.nbytes());
+        assert!(tokens.iter().all(|&t| (t as usize) < voc_size));
+
+        let name: &CStr = match dt {
+            F16 => c"logit_bias_f16",
+            F32 => c"logit_bias_f32",
+            _ => panic!("unsupported logits dtype {dt:?}"),
+        };
+        let num_rows = rows.len() as u32;
+        let rows = stream.from_host(&rows);
+        let offsets = stream.from_host(&offsets);
+        let tokens = stream.from_host(&tokens);
+        let values = stream.from_host(&values);
+
+        let logits_ptr = logits.as_mut_ptr();
+        let voc = voc_size as u32;
+        let rows_ptr = rows.as_ptr();
+        let offsets_ptr = offsets.as_ptr();
+        let tokens_ptr = tokens.as_ptr();
+        let values_ptr = values.as_ptr();
+        let params = params![
+            logits_ptr,
+            voc,
+            rows_ptr,
+            offsets_ptr,
+            tokens_ptr,
+            values_ptr
+        ];
+        self.0
+            .launch(name, num_rows, BLOCK_SIZE, params.as_ptr(), 0, stream);
+        rows.drop_on(stream);
+        offsets.drop_on(stream);
+        tokens.drop_on(stream);
+        values.drop_on(stream);
+    }
+
+    /// 把每行 `logits` 中概率小于最大概率 `min_p` 倍的 token 的 logit 置为负无穷，每行为 `(temperature, min_p)`。
+    ///
+    /// 贪心采样或 `min_p` 为 0 的行不修改，需要过滤的行在一次启动中完成。
+    pub fn min_p(
+        &self,
+        voc_size: usize,
+        dt: DigitLayout,
+        args: impl IntoIterator<Item = (f32, f32)>,
+        logits: &mut [DevByte],
+        stream: &Stream,
+    ) {
+        let mut rows = Vec::<u32>::new();
+        let mut offsets = Vec::<f32>::new();
+        for (i, (temperature, min_p)) in args.into_iter().enumerate() {
+            if temperature > 0. && min_p > 0. {
+                rows.push(i as _);
+                // 下限相对最大值的偏移
+                offsets.push(min_p_threshold(temperature, min_p, 0.));
+            }
+        }
+        if rows.is_empty() {
+            return;
+        }
+        assert!(logits.len() >= (*rows.last().unwrap() as usize + 1) * voc_size * dt.nbytes());
+
+        let name: &CStr = match dt {
+            F16 => c"min_p_f16",
+            F32 => c"min_p_f32",
+            _ => panic!("unsupported logits dtype {dt:?}"),
+        };
+        let num_rows = rows.len() as u32;
+        let rows = stream.from_host(&rows);
+        let offsets = stream.from_host(&offsets);
+
+        let logits_ptr = logits.as_mut_ptr();
+        let voc = voc_size as u32;
+        let rows_ptr = rows.as_ptr();
+        let offsets_ptr = offsets.as_ptr();
+        let params = params![logits_ptr, voc, rows_ptr, offsets_ptr];
+        let shared = BLOCK_SIZE as usize * F32.nbytes();
+        self.0
+            .launch(name, num_rows, BLOCK_SIZE, params.as_ptr(), shared, stream);
+        rows.drop_on(stream);
+        offsets.drop_on(stream);
+    }
+
+    /// 对 `greedy` 为真的每行 `logits` 求最大值的序号，写入 `indices` 的对应位置，其他位置不修改。
+    ///
+    /// 多个最大值相等时取序号最小的一个，与主机上的 [`common_devices::argmax`] 相同。
+    pub fn argmax(
+        &self,
+        voc_size: usize,
+        dt: DigitLayout,
+        greedy: impl IntoIterator<Item = bool>,
+        logits: &[DevByte],
+        indices: &mut [DevByte],
+        stream: &Stream,
+    ) {
+        let rows = (0..)
+            .zip(greedy)
+            .filter_map(|(i, greedy)| greedy.then_some(i))
+            .collect::<Vec<u32>>();
+        let Some(&last) = rows.last() else {
+            return;
+        };
+        assert!(logits.len() >= (last as usize + 1) * voc_size * dt.nbytes());
+        assert!(indices.len() >= (last as usize + 1) * U32.nbytes());
+
+        let name: &CStr = match dt {
+            F16 => c"argmax_f16",
+            F32 => c"argmax_f32",
+            _ => panic!("unsupported logits dtype {dt:?}"),
+        };
+        let num_rows = rows.len() as u32;
+        let rows = stream.from_host(&rows);
+
+        let logits_ptr = logits.as_ptr();
+        let voc = voc_size as u32;
+        let rows_ptr = rows.as_ptr();
+        let indices_ptr = indices.as_mut_ptr();
+        let params = params![logits_ptr, voc, rows_ptr, indices_ptr];
+        let shared = BLOCK_SIZE as usize * (F32.nbytes() + U32.nbytes());
+        self.0
+            .launch(name, num_rows, BLOCK_SIZE, params.as_ptr(), shared, stream);
+        rows.drop_on(stream);
+    }
+}
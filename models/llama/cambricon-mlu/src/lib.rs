@@ -2,8 +2,10 @@
 
 mod resource;
 
-use causal_lm::{CausalLM, DecodingMeta, Model, QueryContext, SampleMeta};
-use common::{upos, utok, FileLoadError};
+use causal_lm::{
+    CacheOverflow, CausalLM, DecodingMeta, Model, ModelInfo, QueryContext, SampleMeta,
+};
+use common::{f16, upos, utok, FileLoadError};
 use common_cn::Tensor;
 use std::path::Path;
 
@@ -24,6 +26,10 @@ impl Model for Transformer {
 impl CausalLM for Transformer {
     type Storage = Cache;
 
+    fn architecture(&self) -> ModelInfo {
+        todo!()
+    }
+
     fn max_seq_len(&self) -> upos {
         todo!()
     }
@@ -36,10 +42,23 @@ impl CausalLM for Transformer {
         todo!()
     }
 
+    fn cache_bytes(&self, _len: upos) -> usize {
+        todo!()
+    }
+
     fn duplicate_cache(&self, _cache: &Tensor<Self::Storage>, _pos: upos) -> Tensor<Self::Storage> {
         todo!()
     }
 
+    fn cache_to_host(
+        &self,
+        _cache: &Tensor<Self::Storage>,
+        _layer: usize,
+        _pos: upos,
+    ) -> Tensor<Vec<f16>> {
+        todo!()
+    }
+
     fn token_embed(&self, _queries: impl IntoIterator<Item = utok>) -> Tensor<Self::Storage> {
         todo!()
     }
@@ -48,7 +67,7 @@ impl CausalLM for Transformer {
         &self,
         _queries: impl IntoIterator<Item = QueryContext<'a, Self::Storage>>,
         _token_embedded: Tensor<Self::Storage>,
-    ) -> Tensor<Self::Storage>
+    ) -> Result<Tensor<Self::Storage>, CacheOverflow>
     where
         Self: 'a,
     {
@@ -63,9 +82,9 @@ impl CausalLM for Transformer {
         todo!()
     }
 
-    fn sample(
+    fn sample<'a>(
         &self,
-        _args: impl IntoIterator<Item = SampleMeta>,
+        _args: impl IntoIterator<Item = SampleMeta<'a>>,
         _logits: Tensor<Self::Storage>,
     ) -> Vec<utok> {
         todo!()
@@ -0,0 +1,370 @@
+use common::utok;
+use common_cpu::{
+    tensor::{udim, Tensor},
+    Cpu, CpuKernels, Kernels, KernelsA, KernelsB, RopeTable, ThisThread,
+};
+use llama::{QueueOf, SliceOn};
+use std::{
+    ops::{Deref, DerefMut},
+    sync::OnceLock,
+};
+
+/// CPU 上的计算内核。
+///
+/// 实现这个 trait 可以用其他的矩阵乘或注意力实现替换默认的 [`CpuKernels`]。
+/// 所有方法都有委托给 [`CpuKernels`] 的默认实现，只需要覆盖想替换的算子。
+pub trait CpuBackend: Send + Sync {
+    /// 把 `src` 按 `dst` 的布局复制到 `dst`。
+    fn reform(&self, dst: &mut Tensor<&mut [u8]>, src: &Tensor<&[u8]>) {
+        CpuBackend::reform(default_kernels(), dst, src)
+    }
+
+    /// 以权重 `w` 对 `x` 做均方根归一化，结果写入 `y`。
+    fn rms_norm(
+        &self,
+        y: &mut Tensor<&mut [u8]>,
+        x: &Tensor<&[u8]>,
+        w: &Tensor<&[u8]>,
+        epsilon: f32,
+    ) {
+        CpuBackend::rms_norm(default_kernels(), y, x, w, epsilon)
+    }
+
+    /// 按 `pos` 中的位置对 `t` 原地应用旋转位置编码。
+    fn rope(&self, t: &mut Tensor<&mut [u8]>, pos: &Tensor<&[u8]>, theta: f32) {
+        CpuBackend::rope(default_kernels(), t, pos, theta)
+    }
+
+    /// 使用预先计算的编码表对 `t` 原地应用旋转位置编码，调用方保证 `table` 覆盖 `pos`。
+    fn rope_table(&self, t: &mut Tensor<&mut [u8]>, pos: &Tensor<&[u8]>, table: &RopeTable) {
+        CpuBackend::rope_table(default_kernels(), t, pos, table)
+    }
+
+    /// 计算 `c = beta * c + alpha * a x b`。
+    fn mat_mul(
+        &self,
+        c: &mut Tensor<&mut [u8]>,
+        beta: f32,
+        a: &Tensor<&[u8]>,
+        b: &Tensor<&[u8]>,
+        alpha: f32,
+    ) {
+        CpuBackend::mat_mul(default_kernels(), c, beta, a, b, alpha)
+    }
+
+    /// 对注意力分数 `att` 原地做因果 softmax。
+    fn softmax(&self, att: &mut Tensor<&mut [u8]>) {
+        CpuBackend::softmax(default_kernels(), att)
+    }
+
+    /// 计算门控前馈网络，结果累加到 `x`，`gate_up` 是中间结果的工作空间。
+    #[allow(clippy::too_many_arguments)]
+    fn mlp(
+        &self,
+        x: &mut Tensor<&mut [u8]>,
+        x1: &Tensor<&[u8]>,
+        gate_up: &mut Tensor<&mut [u8]>,
+        w_gate_up: &Tensor<&[u8]>,
+        w_down: &Tensor<&[u8]>,
+        down_alpha: f32,
+        down_bias: bool,
+    ) {
+        CpuBackend::mlp(
+            default_kernels(),
+            x,
+            x1,
+            gate_up,
+            w_gate_up,
+            w_down,
+            down_alpha,
+            down_bias,
+        )
+    }
+
+    /// 从词表 `table` 中取出 `tokens` 对应的行写入 `x`。
+    fn gather(&self, x: &mut Tensor<&mut [u8]>, table: &Tensor<&[u8]>, tokens: &[utok]) {
+        CpuBackend::gather(default_kernels(), x, table, tokens)
+    }
+
+    /// 对 `x` 原地做 `cap * tanh(x / cap)` 软截断。
+    fn softcap(&self, x: &mut Tensor<&mut [u8]>, cap: f32) {
+        CpuBackend::softcap(default_kernels(), x, cap)
+    }
+
+    /// 以 f32 精度计算注意力，`mask` 返回 `false` 的位置不参与计算。
+    #[allow(clippy::too_many_arguments)]
+    fn attention_f32(
+        &self,
+        o: &mut Tensor<&mut [u8]>,
+        q: &Tensor<&[u8]>,
+        k: &Tensor<&[u8]>,
+        v: &Tensor<&[u8]>,
+        scale: f32,
+        softcap: Option<f32>,
+        mask: &dyn Fn(udim, udim) -> bool,
+    ) {
+        CpuBackend::attention_f32(default_kernels(), o, q, k, v, scale, softcap, mask)
+    }
+}
+
+/// [`CpuBackend`] 默认实现委托的内核。
+fn default_kernels() -> &'static CpuKernels {
+    static KERNELS: OnceLock<CpuKernels> = OnceLock::new();
+    KERNELS.get_or_init(Default::default)
+}
+
+impl CpuBackend for CpuKernels {
+    #[inline]
+    fn reform(&self, dst: &mut Tensor<&mut [u8]>, src: &Tensor<&[u8]>) {
+        KernelsA::reform(self, dst, src, &ThisThread)
+    }
+    #[inline]
+    fn rms_norm(
+        &self,
+        y: &mut Tensor<&mut [u8]>,
+        x: &Tensor<&[u8]>,
+        w: &Tensor<&[u8]>,
+        epsilon: f32,
+    ) {
+        KernelsA::rms_norm(self, y, x, w, epsilon, &ThisThread)
+    }
+    #[inline]
+    fn rope(&self, t: &mut Tensor<&mut [u8]>, pos: &Tensor<&[u8]>, theta: f32) {
+        KernelsA::rope(self, t, pos, theta, &ThisThread)
+    }
+    #[inline]
+    fn rope_table(&self, t: &mut Tensor<&mut [u8]>, pos: &Tensor<&[u8]>, table: &RopeTable) {
+        table.apply(t, pos)
+    }
+    #[inline]
+    fn mat_mul(
+        &self,
+        c: &mut Tensor<&mut [u8]>,
+        beta: f32,
+        a: &Tensor<&[u8]>,
+        b: &Tensor<&[u8]>,
+        alpha: f32,
+    ) {
+        KernelsA::mat_mul(self, c, beta, a, b, alpha, &ThisThread)
+    }
+    #[inline]
+    fn softmax(&self, att: &mut Tensor<&mut [u8]>) {
+        KernelsA::softmax(self, att, &ThisThread)
+    }
+    #[inline]
+    fn mlp(
+        &self,
+        x: &mut Tensor<&mut [u8]>,
+        x1: &Tensor<&[u8]>,
+        gate_up: &mut Tensor<&mut [u8]>,
+        w_gate_up: &Tensor<&[u8]>,
+        w_down: &Tensor<&[u8]>,
+        down_alpha: f32,
+        down_bias: bool,
+    ) {
+        KernelsA::mlp(
+            self,
+            x,
+            x1,
+            gate_up,
+            w_gate_up,
+            w_down,
+            down_alpha,
+            down_bias,
+            &ThisThread,
+        )
+    }
+    #[inline]
+    fn gather(&self, x: &mut Tensor<&mut [u8]>, table: &Tensor<&[u8]>, tokens: &[utok]) {
+        KernelsB::gather(self, x, table, tokens.iter().copied(), &ThisThread)
+    }
+    #[inline]
+    fn softcap(&self, x: &mut Tensor<&mut [u8]>, cap: f32) {
+        KernelsB::softcap(self, x, cap, &ThisThread)
+    }
+    #[inline]
+    fn attention_f32(
+        &self,
+        o: &mut Tensor<&mut [u8]>,
+        q: &Tensor<&[u8]>,
+        k: &Tensor<&[u8]>,
+        v: &Tensor<&[u8]>,
+        scale: f32,
+        softcap: Option<f32>,
+        mask: &dyn Fn(udim, udim) -> bool,
+    ) {
+        KernelsB::attention_f32(self, o, q, k, v, scale, softcap, mask, &ThisThread)
+    }
+}
+
+/// 把 [`CpuBackend`] 包装为计算流使用的 [`Kernels<Cpu>`]。
+pub(crate) struct DynKernels(pub Box<dyn CpuBackend>);
+
+#[inline]
+fn view<T: Deref<Target = [u8]>>(t: &Tensor<T>) -> Tensor<&[u8]> {
+    t.as_ref().map_physical(|u| &**u)
+}
+
+#[inline]
+fn view_mut<T: DerefMut<Target = [u8]>>(t: &mut Tensor<T>) -> Tensor<&mut [u8]> {
+    t.as_mut().map_physical(|u| &mut **u)
+}
+
+impl DynKernels {
+    /// 使用预先计算的编码表应用旋转位置编码，见 [`CpuBackend::rope_table`]。
+    pub fn rope_table<T, U>(&self, t: &mut Tensor<T>, pos: &Tensor<U>, table: &RopeTable)
+    where
+        T: DerefMut<Target = SliceOn<Cpu>>,
+        U: Deref<Target = SliceOn<Cpu>>,
+    {
+        self.0.rope_table(&mut view_mut(t), &view(pos), table)
+    }
+}
+
+impl Kernels<Cpu> for DynKernels {}
+
+impl KernelsA for DynKernels {
+    type Handle = Cpu;
+
+    fn reform<T, U>(&self, dst: &mut Tensor<T>, src: &Tensor<U>, _queue: &QueueOf<Self::Handle>)
+    where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        U: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        self.0.reform(&mut view_mut(dst), &view(src))
+    }
+
+    fn rms_norm<T, U, V>(
+        &self,
+        y: &mut Tensor<T>,
+        x: &Tensor<U>,
+        w: &Tensor<V>,
+        epsilon: f32,
+        _queue: &QueueOf<Self::Handle>,
+    ) where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        U: Deref<Target = SliceOn<Self::Handle>>,
+        V: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        self.0
+            .rms_norm(&mut view_mut(y), &view(x), &view(w), epsilon)
+    }
+
+    fn rope<T, U>(
+        &self,
+        t: &mut Tensor<T>,
+        pos: &Tensor<U>,
+        theta: f32,
+        _queue: &QueueOf<Self::Handle>,
+    ) where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        U: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        self.0.rope(&mut view_mut(t), &view(pos), theta)
+    }
+
+    fn mat_mul<T, U, V>(
+        &self,
+        c: &mut Tensor<T>,
+        beta: f32,
+        a: &Tensor<U>,
+        b: &Tensor<V>,
+        alpha: f32,
+        _queue: &QueueOf<Self::Handle>,
+    ) where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        U: Deref<Target = SliceOn<Self::Handle>>,
+        V: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        self.0
+            .mat_mul(&mut view_mut(c), beta, &view(a), &view(b), alpha)
+    }
+
+    fn softmax<T>(&self, att: &mut Tensor<T>, _queue: &QueueOf<Self::Handle>)
+    where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+    {
+        self.0.softmax(&mut view_mut(att))
+    }
+
+    fn mlp<M0, M1, C0, C1, C2>(
+        &self,
+        x: &mut Tensor<M0>,
+        x1: &Tensor<C0>,
+        gate_up: &mut Tensor<M1>,
+        w_gate_up: &Tensor<C1>,
+        w_down: &Tensor<C2>,
+        down_alpha: f32,
+        down_bias: bool,
+        _queue: &QueueOf<Self::Handle>,
+    ) where
+        M0: DerefMut<Target = SliceOn<Self::Handle>>,
+        M1: DerefMut<Target = SliceOn<Self::Handle>>,
+        C0: Deref<Target = SliceOn<Self::Handle>>,
+        C1: Deref<Target = SliceOn<Self::Handle>>,
+        C2: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        self.0.mlp(
+            &mut view_mut(x),
+            &view(x1),
+            &mut view_mut(gate_up),
+            &view(w_gate_up),
+            &view(w_down),
+            down_alpha,
+            down_bias,
+        )
+    }
+}
+
+impl KernelsB for DynKernels {
+    type Handle = Cpu;
+
+    fn gather<T, U, I>(
+        &self,
+        x: &mut Tensor<T>,
+        table: &Tensor<U>,
+        tokens: I,
+        _queue: &QueueOf<Self::Handle>,
+    ) where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        U: Deref<Target = [u8]>,
+        I: IntoIterator<Item = utok>,
+    {
+        let tokens = tokens.into_iter().collect::<Vec<_>>();
+        self.0.gather(&mut view_mut(x), &view(table), &tokens)
+    }
+
+    fn softcap<T>(&self, x: &mut Tensor<T>, cap: f32, _queue: &QueueOf<Self::Handle>)
+    where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+    {
+        self.0.softcap(&mut view_mut(x), cap)
+    }
+
+    fn attention_f32<O, Q, K, V>(
+        &self,
+        o: &mut Tensor<O>,
+        q: &Tensor<Q>,
+        k: &Tensor<K>,
+        v: &Tensor<V>,
+        scale: f32,
+        softcap: Option<f32>,
+        mask: impl Fn(udim, udim) -> bool,
+        _queue: &QueueOf<Self::Handle>,
+    ) where
+        O: DerefMut<Target = SliceOn<Self::Handle>>,
+        Q: Deref<Target = SliceOn<Self::Handle>>,
+        K: Deref<Target = SliceOn<Self::Handle>>,
+        V: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        self.0.attention_f32(
+            &mut view_mut(o),
+            &view(q),
+            &view(k),
+            &view(v),
+            scale,
+            softcap,
+            &mask,
+        )
+    }
+}
@@ -1,33 +1,496 @@
-use causal_lm::{CausalLM, DecodingMeta, Model, QueryContext, SampleMeta};
-use common::{f16, upos, utok, Blob, FileLoadError};
+mod backend;
+#[cfg(test)]
+mod test_storage;
+
+use backend::DynKernels;
+use causal_lm::{
+    CacheOverflow, CausalLM, DecodingMeta, Model, ModelInfo, QueryContext, SampleMeta,
+};
+use common::{f16, safe_tensors::SafeTensors, upos, utok, Blob, FileLoadError};
 use common_cpu::{
     tensor::{reslice, slice, udim, Tensor},
-    CpuKernels, Kernels, KernelsA, KernelsB, ThisThread,
+    CpuKernels, Int4Matrix, Kernels, KernelsA, KernelsB, RopeTable, ThisThread,
+};
+use digit_layout::{
+    types::{F16, I32},
+    DigitLayout,
 };
 use llama::{
-    ComputeConst, ComputeStream, Handle, InferenceConfig, LayerStorage, QueueOf, SliceOn, Storage,
-    Weight,
+    AttentionMask, ComputeConst, ComputeStream, ForwardArgs, ForwardError, Handle, InferenceConfig,
+    Int4Tensors, LayerStorage, Projection, QueueOf, SliceOn, Storage, Weight,
 };
-use std::{iter::repeat, ops::Deref, path::Path, slice::from_raw_parts};
+use std::{
+    error, fmt, io,
+    iter::repeat,
+    ops::{Deref, DerefMut},
+    path::Path,
+    slice::from_raw_parts,
+};
+
+pub use backend::CpuBackend;
 
 pub struct Transformer {
     s: Storage,
-    kernels: CpuKernels,
+    int4: Vec<Int4Layer>,
+    kernels: DynKernels,
+    /// 采样使用的内核，不随计算后端替换。
+    sampler: CpuKernels,
+    rope: Option<RopeTable>,
+    attn_f32: bool,
+    /// 每次前向计算都剪枝的注意力头。
+    pruned_heads: Vec<(usize, udim)>,
+    cache_growth: CacheGrowth,
+}
+
+/// 模型加载参数。
+#[derive(Clone, Copy, Default, Debug)]
+pub struct ModelLoadMeta {
+    /// 严格数据类型模式，模型不是 f16 时报错而不是自动转换。
+    pub strict_dtype: bool,
+    /// 常驻内存的层数。
+    ///
+    /// 前 `resident_layers` 层的权重复制到内存中，其余层保留在文件映射中，计算时按需加载。
+    pub resident_layers: usize,
+    /// 加载时预先计算旋转位置编码表，推理时查表而不是重复计算三角函数。
+    ///
+    /// 模型配置了旋转位置编码的缩放时总是使用编码表。
+    pub rope_table: bool,
+    /// 加载时将矩阵权重转置后连续存储，避免计算时按步长访问转置的权重。
+    pub pretranspose: bool,
+    /// 以 4 位分组量化存储注意力和 MLP 的投影矩阵，每 `int4_group` 个输入通道共享一组缩放和零点。
+    ///
+    /// 量化之后不再保留原始权重，计算时逐组反量化并与输入相乘累加。
+    /// 加载 [`quantize_and_save`](Transformer::quantize_and_save) 保存的模型时总是使用保存的量化权重，这个设置与保存的分组不同时返回错误。
+    pub int4_group: Option<usize>,
+    /// 只加载和计算前 `num_layers_override` 层，模型结构完整但输出没有意义，用于快速的冒烟测试。
+    pub num_layers_override: Option<usize>,
+    /// 缓存的增长策略。
+    pub cache_growth: CacheGrowth,
+}
+
+/// 缓存的增长策略。
+#[derive(Clone, Copy, PartialEq, Default, Debug)]
+pub enum CacheGrowth {
+    /// 创建时分配最大序列长度的全部容量。
+    #[default]
+    Preallocate,
+    /// 创建时分配 `initial` 个位置，容量不足时扩大到 `factor` 倍，重新分配并复制已有的内容。
+    Geometric { initial: udim, factor: f32 },
+}
+
+impl CacheGrowth {
+    /// 新缓存的容量。
+    fn initial(self, max: udim) -> udim {
+        match self {
+            Self::Preallocate => max,
+            Self::Geometric { initial, .. } => initial.clamp(1, max),
+        }
+    }
+
+    /// 容纳 `len` 个位置需要扩大到的容量，当前容量 `capacity` 足够或不能再扩大时返回 `None`。
+    fn grow(self, capacity: udim, len: udim, max: udim) -> Option<udim> {
+        let Self::Geometric { factor, .. } = self else {
+            return None;
+        };
+        if len <= capacity || capacity >= max {
+            return None;
+        }
+        let mut ans = capacity.max(1);
+        while ans < len {
+            ans = ((ans as f32 * factor).ceil() as udim).max(ans + 1);
+        }
+        Some(ans.min(max))
+    }
+
+    /// 只需要容纳 `len` 个位置时可以缩小到的容量，即从初始容量按策略扩大到足够的容量，不能缩小时返回 `None`。
+    fn shrink(self, capacity: udim, len: udim, max: udim) -> Option<udim> {
+        let Self::Geometric { .. } = self else {
+            return None;
+        };
+        let initial = self.initial(max);
+        let ans = self.grow(initial, len, max).unwrap_or(initial);
+        (ans < capacity).then_some(ans)
+    }
+}
+
+/// 模型的量化方式。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum QuantConfig {
+    /// 4 位分组量化，与 [`ModelLoadMeta::int4_group`] 相同，每 `group` 个输入通道共享一组缩放和零点。
+    Int4 { group: usize },
+}
+
+/// 4 位量化的层投影矩阵。
+struct Int4Layer {
+    att_qkv: Int4Matrix,
+    att_o: Int4Matrix,
+    mlp_gate_up: Int4Matrix,
+    mlp_down: Int4Matrix,
+}
+
+impl Int4Layer {
+    fn quantize(layer: &LayerStorage<Weight>, group: usize) -> Self {
+        Self {
+            att_qkv: Int4Matrix::quantize(&layer.att_qkv, group),
+            att_o: Int4Matrix::quantize(&layer.att_o, group),
+            mlp_gate_up: Int4Matrix::quantize(&layer.mlp_gate_up, group),
+            mlp_down: Int4Matrix::quantize(&layer.mlp_down, group),
+        }
+    }
+
+    /// 投影矩阵 `proj` 的量化权重。
+    fn matrix(&self, proj: Projection) -> &Int4Matrix {
+        match proj {
+            Projection::AttQkv => &self.att_qkv,
+            Projection::AttO => &self.att_o,
+            Projection::MlpGateUp => &self.mlp_gate_up,
+            Projection::MlpDown => &self.mlp_down,
+        }
+    }
+
+    /// 以 `group` 分组重新量化。
+    fn requantize(&self, group: usize) -> Self {
+        let requantize = |m: &Int4Matrix| Int4Matrix::quantize(&m.dequantize(), group);
+        Self {
+            att_qkv: requantize(&self.att_qkv),
+            att_o: requantize(&self.att_o),
+            mlp_gate_up: requantize(&self.mlp_gate_up),
+            mlp_down: requantize(&self.mlp_down),
+        }
+    }
+
+    /// 读取保存的第 `i` 层量化的投影矩阵，缺失或不符合要求时返回错误。
+    fn load(model: &SafeTensors, i: usize) -> Result<Self, FileLoadError> {
+        let matrix = |name: &str| -> Result<_, FileLoadError> {
+            let prefix = format!("model.layers.{i}.{name}");
+            let saved = llama::load_int4(model, &prefix)?.ok_or_else(|| {
+                FileLoadError::InvalidTensor(format!("missing tensor: {prefix}.qweight"))
+            })?;
+            // 文件中的数据不保证对齐，逐个读取
+            let words = |data: &[u8]| {
+                data.chunks_exact(4)
+                    .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+                    .collect()
+            };
+            let scales = saved
+                .scales
+                .chunks_exact(2)
+                .map(|b| f16::from_le_bytes(b.try_into().unwrap()))
+                .collect();
+            Ok(Int4Matrix::new(
+                saved.k,
+                saved.n,
+                saved.group,
+                words(saved.qweight),
+                words(saved.qzeros),
+                scales,
+            ))
+        };
+        Ok(Self {
+            att_qkv: matrix("self_attn.qkv_proj")?,
+            att_o: matrix("self_attn.o_proj")?,
+            mlp_gate_up: matrix("mlp.gate_up_proj")?,
+            mlp_down: matrix("mlp.down_proj")?,
+        })
+    }
+
+    /// 保存的张量，依次为 qkv、o、gate_up、down。
+    fn tensors(&self) -> [Int4Tensors; 4] {
+        fn tensor(dt: DigitLayout, shape: [usize; 2], bytes: &[u8]) -> Tensor<Weight> {
+            let mut t = Tensor::alloc(dt, &shape.map(|d| d as udim), Blob::new);
+            t.physical_mut().copy_from_slice(bytes);
+            t.map_physical(Weight::from)
+        }
+        [
+            &self.att_qkv,
+            &self.att_o,
+            &self.mlp_gate_up,
+            &self.mlp_down,
+        ]
+        .map(|m| {
+            let [k, n] = m.shape();
+            let groups = k / m.group();
+            Int4Tensors {
+                qweight: tensor(I32, [k / 8, n], reslice(m.qweight())),
+                qzeros: tensor(I32, [groups, n / 8], reslice(m.qzeros())),
+                scales: tensor(F16, [groups, n], reslice(m.scales())),
+            }
+        })
+    }
 }
 
 impl Model for Transformer {
-    type Meta = ();
+    type Meta = ModelLoadMeta;
     type Error = FileLoadError;
 
     #[inline]
-    fn load(model_dir: impl AsRef<Path>, _meta: Self::Meta) -> Result<Self, Self::Error> {
+    fn load(model_dir: impl AsRef<Path>, meta: Self::Meta) -> Result<Self, Self::Error> {
+        // 保存的量化模型直接读取打包的权重，与保存之前的量化完全相同，不再保留反量化的投影矩阵
+        let (mut s, packed) = llama::Storage::load_safetensors_packed(model_dir)?;
+        if let Some(n) = meta.num_layers_override {
+            s.truncate_layers(n);
+        }
+        let mut s = s.cast_for_compute(F16, meta.strict_dtype)?;
+        let int4 = match (packed, meta.int4_group) {
+            (Some(model), group) => {
+                let int4 = (0..s.layers.len())
+                    .map(|i| Int4Layer::load(&model, i))
+                    .collect::<Result<Vec<_>, _>>()?;
+                // 保存的量化模型不会重新量化，指定的分组必须与保存的相同
+                match (group, int4.first()) {
+                    (Some(expected), Some(first)) if first.att_qkv.group() != expected => {
+                        return Err(FileLoadError::Int4GroupMismatch {
+                            expected,
+                            found: first.att_qkv.group(),
+                        })
+                    }
+                    _ => int4,
+                }
+            }
+            // 量化之后不再保留原始的投影矩阵
+            (None, Some(group)) => s
+                .layers
+                .iter_mut()
+                .map(|layer| {
+                    let int4 = Int4Layer::quantize(layer, group);
+                    *layer = layer.without_projections();
+                    int4
+                })
+                .collect(),
+            (None, None) => vec![],
+        };
+        for layer in s.layers.iter_mut().take(meta.resident_layers) {
+            *layer = layer.resident();
+        }
+        if meta.pretranspose {
+            // 量化的投影矩阵计算时从打包的权重反量化，不需要转置
+            if int4.is_empty() {
+                for layer in &mut s.layers {
+                    *layer = layer.pretransposed();
+                }
+            }
+            s.lm_head = llama::contiguous(&s.lm_head);
+        }
+        let rope = (meta.rope_table || s.config.rope_scaling.is_some()).then(|| {
+            let config = &s.config;
+            let dh = (config.d / config.nh) as usize;
+            let max_seq_len = config.max_seq_len as usize;
+            match config.rope_scaling {
+                Some(scaling) => RopeTable::from_freqs(
+                    &scaling.freqs(config.theta, dh),
+                    scaling.mscale(),
+                    max_seq_len,
+                ),
+                None => RopeTable::new(config.theta, dh, max_seq_len),
+            }
+        });
         Ok(Self {
-            s: llama::Storage::load_safetensors(model_dir)?,
-            kernels: Default::default(),
+            s,
+            int4,
+            kernels: DynKernels(Box::<CpuKernels>::default()),
+            sampler: Default::default(),
+            rope,
+            attn_f32: false,
+            pruned_heads: vec![],
+            cache_growth: meta.cache_growth,
         })
     }
 }
 
+/// 打包推理的第 `index` 个序列长度为 `len`，超过了模型的最大长度 `max`。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct SequenceTooLong {
+    pub index: usize,
+    pub len: usize,
+    pub max: usize,
+}
+
+impl error::Error for SequenceTooLong {}
+impl fmt::Display for SequenceTooLong {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Self { index, len, max } = self;
+        write!(
+            f,
+            "sequence {index} has {len} tokens, exceeding the limit of {max}"
+        )
+    }
+}
+
+impl Transformer {
+    /// 设置是否以 f32 精度计算注意力，可以提高长上下文中的数值稳定性。
+    #[inline]
+    pub fn set_attention_f32(&mut self, enabled: bool) {
+        self.attn_f32 = enabled;
+    }
+
+    /// 替换计算使用的内核，例如使用其他的矩阵乘实现，默认为 [`CpuKernels`]。
+    ///
+    /// 采样不受影响。
+    #[inline]
+    pub fn set_backend(&mut self, backend: Box<dyn CpuBackend>) {
+        self.kernels = DynKernels(backend);
+    }
+
+    /// 设置每次前向计算都剪枝的注意力头，每一项为 `(层序号, 头序号)`，用于分析每个头的贡献。
+    ///
+    /// 替换之前的设置，传入空列表恢复所有头。有头超出范围时返回错误，不改变设置。
+    pub fn set_pruned_heads(&mut self, heads: Vec<(usize, udim)>) -> Result<(), ForwardError> {
+        llama::check_pruned_heads(&heads, self.s.layers.len(), self.s.config.nh)?;
+        self.pruned_heads = heads;
+        Ok(())
+    }
+
+    /// 以 `args` 指定的注意力掩码等参数前向计算，例如前缀语言模型或文档打包的掩码，其他同 [`CausalLM::forward`]。
+    ///
+    /// 剪枝的注意力头超出范围时返回错误。
+    pub fn forward_with<'a>(
+        &self,
+        queries: impl IntoIterator<Item = QueryContext<'a, Blob>>,
+        token_embedded: Tensor<Blob>,
+        args: &ForwardArgs,
+    ) -> Result<Tensor<Blob>, ForwardError> {
+        // 缓存容量不足时先按增长策略扩大
+        let queries = queries.into_iter().map(|mut query| {
+            let len = query.att_len();
+            if let Some(cache) = query.cache.as_deref_mut() {
+                self.reserve_cache(cache, len);
+            }
+            query
+        });
+        <Self as ComputeStream>::forward_with(self, queries, token_embedded, args)
+    }
+
+    /// 计算多个互相独立的序列每个位置的 logits，返回每个序列的 logits（`len x vocab_size`）。
+    ///
+    /// 依次把序列打包到总长度不超过 `max_tokens` 的批次中，每个批次在一个缓存上推理一次，
+    /// 以文档打包的掩码隔开各个序列，每个序列的位置从 0 开始，比逐个序列推理的吞吐量更高。
+    /// 打包时注意力以 f32 精度计算。有序列超过模型的最大长度时返回错误，不做任何推理。
+    pub fn score_packed(
+        &self,
+        sequences: &[&[utok]],
+        max_tokens: usize,
+    ) -> Result<Vec<Vec<f32>>, SequenceTooLong> {
+        let max = self.s.config.max_seq_len as usize;
+        if let Some((index, seq)) = sequences.iter().enumerate().find(|(_, s)| s.len() > max) {
+            let len = seq.len();
+            return Err(SequenceTooLong { index, len, max });
+        }
+        let max_tokens = max_tokens.clamp(1, max);
+        let mut ans = Vec::with_capacity(sequences.len());
+        let mut rest = sequences;
+        while !rest.is_empty() {
+            // 至少放入一个序列
+            let mut total = 0;
+            let mut n = 0;
+            for seq in rest {
+                if n > 0 && total + seq.len() > max_tokens {
+                    break;
+                }
+                total += seq.len();
+                n += 1;
+            }
+            let (pack, tail) = rest.split_at(n);
+            rest = tail;
+            ans.extend(self.score_pack(pack, total));
+        }
+        Ok(ans)
+    }
+
+    /// 在一个缓存上推理打包的 `pack`，总长度为 `total`。
+    fn score_pack(&self, pack: &[&[utok]], total: usize) -> Vec<Vec<f32>> {
+        let voc = self.s.config.voc as usize;
+        if total == 0 {
+            return vec![vec![]; pack.len()];
+        }
+        let lens = pack.iter().map(|s| s.len() as upos).collect::<Vec<_>>();
+        let args = ForwardArgs {
+            attn_mask: AttentionMask::packed(&lens),
+            pos: Some(lens.iter().flat_map(|&len| 0..len).collect()),
+            ..Default::default()
+        };
+        let mut cache = self.new_cache();
+        let queries = [QueryContext {
+            cache: Some(&mut cache),
+            range: 0..total as upos,
+        }];
+        let tokens = pack.iter().flat_map(|s| s.iter().copied());
+        // 打包的总长度不超过最大序列长度
+        let hidden_state = self
+            .forward_with(queries, self.token_embed(tokens), &args)
+            .unwrap();
+        let logits = self.decode([DecodingMeta::all(total)], hidden_state);
+
+        let logits = self.logits_to_host(&logits).unwrap();
+        let mut logits = &logits[..];
+        pack.iter()
+            .map(|s| {
+                let (head, tail) = logits.split_at(s.len() * voc);
+                logits = tail;
+                head.to_vec()
+            })
+            .collect()
+    }
+
+    /// 按 `config` 量化加载的模型，保存到 `dir`，保存的模型可以直接加载。
+    ///
+    /// 加载时已经以相同的方式量化的模型直接保存量化的权重，加载保存的模型与量化之后的模型计算结果相同。
+    pub fn quantize_and_save(&self, dir: impl AsRef<Path>, config: QuantConfig) -> io::Result<()> {
+        let QuantConfig::Int4 { group } = config;
+        let quantized;
+        let int4 = match &*self.int4 {
+            [first, ..] if first.att_qkv.group() == group => &self.int4,
+            // 已经量化的模型没有保留原始的投影矩阵，从反量化的权重重新量化
+            [_, ..] => {
+                quantized = self
+                    .int4
+                    .iter()
+                    .map(|layer| layer.requantize(group))
+                    .collect::<Vec<_>>();
+                &quantized
+            }
+            [] => {
+                quantized = self
+                    .s
+                    .layers
+                    .iter()
+                    .map(|layer| Int4Layer::quantize(layer, group))
+                    .collect::<Vec<_>>();
+                &quantized
+            }
+        };
+        let layers = int4.iter().map(Int4Layer::tensors).collect::<Vec<_>>();
+        self.s.save_int4(dir, group, &layers)
+    }
+
+    /// 按增长策略扩大缓存，使其能容纳 `len` 个位置，已有的内容复制到新的缓存。
+    fn reserve_cache(&self, cache: &mut Tensor<Blob>, len: udim) {
+        let capacity = cache.shape()[3];
+        let max = self.s.config.max_seq_len;
+        let Some(grown) = self.cache_growth.grow(capacity, len, max) else {
+            return;
+        };
+        self.resize_cache(cache, grown, capacity);
+    }
+
+    /// 重新分配容量为 `capacity` 的缓存，复制前 `len` 个位置的内容。
+    fn resize_cache(&self, cache: &mut Tensor<Blob>, capacity: udim, len: udim) {
+        let mut ans = self.s.config.new_cache_with_capacity(capacity, Blob::new);
+        let slice = [
+            slice![=>],
+            slice![=>],
+            slice![=>],
+            slice![=>len],
+            slice![=>],
+        ];
+        cache
+            .as_ref()
+            .slice(&slice)
+            .map_physical(|u| &**u)
+            .reform_to(&mut ans.as_mut().slice(&slice).map_physical(|u| &mut **u));
+        *cache = ans;
+    }
+}
+
 impl ComputeStream for Transformer {
     type Handle = common_cpu::Cpu;
     type Storage = Blob;
@@ -65,6 +528,9 @@ impl ComputeStream for Transformer {
             di: self.s.config.di,
             epsilon: self.s.config.epsilon,
             theta: self.s.config.theta,
+            attn_softcap: self.s.config.attn_logit_softcap,
+            sliding_window: self.s.config.sliding_window,
+            attn_f32: self.attn_f32,
         }
     }
 
@@ -75,6 +541,96 @@ impl ComputeStream for Transformer {
         println!("{tensor}");
     }
 
+    fn rope<T, U>(&self, t: &mut Tensor<T>, pos: &Tensor<U>, theta: f32)
+    where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        U: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        match &self.rope {
+            Some(table) if table.covers(pos) => self.kernels.rope_table(t, pos, table),
+            _ => self.kernels.rope(t, pos, theta, &ThisThread),
+        }
+    }
+
+    #[inline]
+    fn can_prune_heads(&self) -> bool {
+        true
+    }
+
+    #[inline]
+    fn pruned_heads(&self) -> &[(usize, udim)] {
+        &self.pruned_heads
+    }
+
+    fn prune_heads<T>(&self, o: &mut Tensor<T>, heads: &[udim])
+    where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+    {
+        let &[nt, _, dh] = o.shape() else {
+            unreachable!()
+        };
+        let mut zeros = Tensor::alloc(o.data_layout(), &[nt, 1, dh], Blob::new);
+        zeros.physical_mut().fill(0);
+        for &h in heads {
+            let mut o =
+                o.as_mut()
+                    .map_physical(|u| &mut **u)
+                    .slice(&[slice![=>], slice![=h], slice![=>]]);
+            self.kernels.reform(&mut o, &zeros, &ThisThread);
+        }
+    }
+
+    fn project<T, U, V>(
+        &self,
+        layer: usize,
+        proj: Projection,
+        y: &mut Tensor<T>,
+        beta: f32,
+        x: &Tensor<U>,
+        w: &Tensor<V>,
+        alpha: f32,
+    ) where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        U: Deref<Target = SliceOn<Self::Handle>>,
+        V: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        match self.int4.get(layer) {
+            Some(q) => q.matrix(proj).mat_mul(y, beta, x, alpha),
+            None => self.kernels.mat_mul(y, beta, x, w, alpha, &ThisThread),
+        }
+    }
+
+    fn mlp<M0, M1, C0, C1, C2>(
+        &self,
+        layer: usize,
+        x: &mut Tensor<M0>,
+        x1: &Tensor<C0>,
+        gate_up: &mut Tensor<M1>,
+        w_gate_up: &Tensor<C1>,
+        w_down: &Tensor<C2>,
+    ) where
+        M0: DerefMut<Target = SliceOn<Self::Handle>>,
+        M1: DerefMut<Target = SliceOn<Self::Handle>>,
+        C0: Deref<Target = SliceOn<Self::Handle>>,
+        C1: Deref<Target = SliceOn<Self::Handle>>,
+        C2: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        let Some(q) = self.int4.get(layer) else {
+            self.kernels
+                .mlp(x, x1, gate_up, w_gate_up, w_down, 1., true, &ThisThread);
+            return;
+        };
+        // 量化的投影矩阵不能使用融合的内核，分步计算
+        q.mlp_gate_up.mat_mul(gate_up, 0., x1, 1.);
+        let di = gate_up.shape()[1] / 2;
+        swiglu(gate_up);
+        let gate = gate_up
+            .as_ref()
+            .map_physical(|u| &**u)
+            .slice(&[slice![=>], slice![=> di]]);
+        q.mlp_down.mat_mul(x, 1., &gate, 1.);
+    }
+
     #[inline]
     fn layers(
         &self,
@@ -83,11 +639,38 @@ impl ComputeStream for Transformer {
     }
 }
 
+/// 对形状为 `[nt, di + di]` 的 `gate_up` 原地计算 `gate = silu(gate) * up`。
+fn swiglu<T: DerefMut<Target = [u8]>>(gate_up: &mut Tensor<T>) {
+    assert_eq!(gate_up.data_layout(), F16);
+    let &[nt, di2] = gate_up.shape() else {
+        panic!("gate_up must be 2d")
+    };
+    let &[sr, sc] = gate_up.strides() else {
+        unreachable!()
+    };
+    let di = di2 / 2;
+    let base = gate_up.base_mut().cast::<f16>();
+    for i in 0..nt {
+        for j in 0..di {
+            let at = |j: udim| unsafe {
+                base.offset(i as isize * sr as isize + j as isize * sc as isize)
+            };
+            let (gate, up) = unsafe { (&mut *at(j), (*at(j + di)).to_f32()) };
+            let g = gate.to_f32();
+            *gate = f16::from_f32(g / (1. + (-g).exp()) * up);
+        }
+    }
+}
+
+/// 量化的投影矩阵是占位，计算时由 [`ComputeStream::project`] 使用量化的权重。
 struct LlamaLayer<'a>(&'a LayerStorage<Weight>);
 
 impl<'a> llama::LLamaLayer for LlamaLayer<'a> {
     type Byte = u8;
-    type Storage<'m> = Weight where Self: 'm;
+    type Storage<'m>
+        = Weight
+    where
+        Self: 'm;
 
     #[inline]
     fn att_layernorm(&self) -> Tensor<Self::Storage<'_>> {
@@ -113,11 +696,23 @@ impl<'a> llama::LLamaLayer for LlamaLayer<'a> {
     fn mlp_down(&self) -> Tensor<Self::Storage<'_>> {
         self.0.mlp_down.clone()
     }
+    #[inline]
+    fn att_q_norm(&self) -> Option<Tensor<Self::Storage<'_>>> {
+        self.0.att_q_norm.clone()
+    }
+    #[inline]
+    fn att_k_norm(&self) -> Option<Tensor<Self::Storage<'_>>> {
+        self.0.att_k_norm.clone()
+    }
 }
 
 impl CausalLM for Transformer {
     type Storage = Blob;
 
+    #[inline]
+    fn architecture(&self) -> ModelInfo {
+        self.s.config.architecture()
+    }
     #[inline]
     fn max_seq_len(&self) -> upos {
         self.s.config.max_seq_len
@@ -132,7 +727,19 @@ impl CausalLM for Transformer {
     }
     #[inline]
     fn new_cache(&self) -> Tensor<Self::Storage> {
-        self.s.config.new_cache(Blob::new)
+        let capacity = self.cache_growth.initial(self.s.config.max_seq_len);
+        self.s.config.new_cache_with_capacity(capacity, Blob::new)
+    }
+    #[inline]
+    fn cache_bytes(&self, len: upos) -> usize {
+        self.s.config.cache_bytes(len)
+    }
+    fn shrink_cache(&self, cache: &mut Tensor<Self::Storage>, pos: upos) {
+        let capacity = cache.shape()[3];
+        let max = self.s.config.max_seq_len;
+        if let Some(shrunk) = self.cache_growth.shrink(capacity, pos, max) {
+            self.resize_cache(cache, shrunk, pos);
+        }
     }
     #[inline]
     fn duplicate_cache(&self, cache: &Tensor<Self::Storage>, pos: upos) -> Tensor<Self::Storage> {
@@ -142,6 +749,16 @@ impl CausalLM for Transformer {
         })
     }
 
+    #[inline]
+    fn cache_to_host(
+        &self,
+        cache: &Tensor<Self::Storage>,
+        layer: usize,
+        pos: upos,
+    ) -> Tensor<Vec<f16>> {
+        causal_lm::cache_layer(cache, layer, pos)
+    }
+
     fn token_embed(&self, queries: impl IntoIterator<Item = utok>) -> Tensor<Self::Storage> {
         let dt = self.s.config.dt;
         let d = self.s.config.d;
@@ -159,8 +776,9 @@ impl CausalLM for Transformer {
         &self,
         queries: impl IntoIterator<Item = QueryContext<'a, Self::Storage>>,
         token_embedded: Tensor<Self::Storage>,
-    ) -> Tensor<Self::Storage> {
-        <Self as ComputeStream>::forward(self, queries, token_embedded)
+    ) -> Result<Tensor<Self::Storage>, CacheOverflow> {
+        self.forward_with(queries, token_embedded, &Default::default())
+            .map_err(ForwardError::cache_overflow)
     }
 
     fn decode(
@@ -192,39 +810,1034 @@ impl CausalLM for Transformer {
             .rms_norm(&mut x, &x_, lm_layernorm, epsilon, self.queue());
         self.kernels()
             .mat_mul(&mut logits, 0., &x, lm_head, 1., self.queue());
+        if let Some(cap) = self.s.config.final_logit_softcap {
+            self.kernels().softcap(&mut logits, cap, self.queue());
+        }
 
         logits
     }
 
-    fn sample(
+    fn sample<'a>(
         &self,
-        args: impl IntoIterator<Item = SampleMeta>,
+        args: impl IntoIterator<Item = SampleMeta<'a>>,
         logits: Tensor<Self::Storage>,
     ) -> Vec<utok> {
         let &[_, voc] = logits.shape() else { panic!() };
         let logits: &[f16] = reslice(logits.as_slice());
-        args.into_iter()
-            .flat_map(|meta| repeat(meta.args).take(meta.num_decode))
+        let eos = self.s.config.eos_token;
+        let metas = args.into_iter().collect::<Vec<_>>();
+        metas
+            .iter()
+            .flat_map(|meta| repeat(meta).take(meta.num_decode))
             .enumerate()
-            .map(|(i, args)| {
-                self.kernels.sample(
-                    args.temperature,
-                    args.top_p,
-                    args.top_k,
-                    &common_cpu::slice!(logits; voc; [i]),
-                )
+            .map(|(i, meta)| {
+                let args = meta.args;
+                let logits = &common_cpu::slice!(logits; voc; [i]);
+                // 重复惩罚换算为偏置，与其他偏置一起加到 logits 上
+                let penalties =
+                    args.penalty_biases(meta.history, |token| logits[token as usize].to_f32());
+                let biases = meta.biases(eos).chain(penalties);
+                if args.gumbel {
+                    self.sampler.sample_gumbel(args.into(), biases, logits)
+                } else {
+                    self.sampler.sample_ordered(args.into(), biases, logits)
+                }
             })
             .collect()
     }
+
+    fn logits_to_host(&self, logits: &Tensor<Self::Storage>) -> Option<Vec<f32>> {
+        let logits: &[f16] = reslice(logits.as_slice());
+        Some(logits.iter().map(|x| x.to_f32()).collect())
+    }
 }
 
 #[test]
 fn test_infer() {
     causal_lm::test_impl::<Transformer>(
-        (),
+        Default::default(),
+        &[
+            29966, 29989, 1792, 29989, 29958, 13, 29903, 388, 376, 18567, 29908, 304, 592, 21106,
+            29879, 5299, 29989, 465, 22137, 29989, 29958, 13,
+        ],
+    );
+}
+
+#[test]
+fn test_conformance() {
+    causal_lm::conformance::run::<Transformer>(
+        Default::default(),
         &[
             29966, 29989, 1792, 29989, 29958, 13, 29903, 388, 376, 18567, 29908, 304, 592, 21106,
             29879, 5299, 29989, 465, 22137, 29989, 29958, 13,
         ],
     );
 }
+
+#[test]
+fn test_prefill() {
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    let model = Transformer::load(model_dir, Default::default()).unwrap();
+    let d = model.architecture().d as udim;
+    let tokens = [29966, 29989, 1792, 29989, 29958, 13];
+
+    let mut cache = model.new_cache();
+    let mut pos = 0;
+    let full = model.prefill(&tokens, &mut cache, &mut pos).unwrap();
+    assert_eq!(full.shape(), [tokens.len() as udim, d]);
+    assert_eq!(pos, tokens.len() as upos);
+
+    // 分两次预填充，第二次使用第一次填入缓存的 K-V
+    let mut cache = model.new_cache();
+    let mut pos = 0;
+    let head = model.prefill(&tokens[..4], &mut cache, &mut pos).unwrap();
+    assert_eq!(head.shape(), [4, d]);
+    assert_eq!(pos, 4);
+    let tail = model.prefill(&tokens[4..], &mut cache, &mut pos).unwrap();
+    assert_eq!(tail.shape(), [2, d]);
+    assert_eq!(pos, 6);
+
+    let full: &[f16] = reslice(full.as_slice());
+    let tail: &[f16] = reslice(tail.as_slice());
+    for (a, b) in full[4 * d as usize..].iter().zip(tail) {
+        let (a, b) = (a.to_f32(), b.to_f32());
+        assert!((a - b).abs() <= 5e-2 * a.abs().max(1.), "{a} != {b}");
+    }
+}
+
+#[test]
+fn test_num_layers_override() {
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    let meta = ModelLoadMeta {
+        num_layers_override: Some(2),
+        ..Default::default()
+    };
+    let model = Transformer::load(model_dir, meta).unwrap();
+    let info = model.architecture();
+    assert_eq!(info.nlayers, 2);
+    assert_eq!(model.s.layers.len(), 2);
+
+    let mut cache = model.new_cache();
+    assert_eq!(cache.shape()[0], 2);
+    let tokens = [29966, 29989, 1792];
+    let mut pos = 0;
+    let hidden_state = model.prefill(&tokens, &mut cache, &mut pos).unwrap();
+    assert_eq!(hidden_state.shape(), [tokens.len() as udim, info.d as udim]);
+
+    let decoding = [DecodingMeta {
+        num_query: tokens.len(),
+        num_decode: 1,
+    }];
+    let logits = model.decode(decoding, hidden_state);
+    let args = [SampleMeta {
+        num_decode: 1,
+        args: Default::default(),
+        history: &[],
+        suppressed: &[],
+    }];
+    let next = model.sample(args, logits);
+    assert_eq!(next.len(), 1);
+    assert!((next[0] as usize) < info.voc);
+}
+
+#[test]
+fn test_architecture() {
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    let model = Transformer::load(model_dir, Default::default()).unwrap();
+    let config = &model.s.config;
+    let info = model.architecture();
+    assert_eq!(info.nlayers, config.nlayers as usize);
+    assert_eq!(info.nh, config.nh as usize);
+    assert_eq!(info.nkvh, config.nkvh as usize);
+    assert_eq!(info.d, config.d as usize);
+    assert_eq!(info.di, config.di as usize);
+    assert_eq!(info.voc, config.voc as usize);
+    assert_eq!(info.max_seq_len, model.max_seq_len() as usize);
+    assert_eq!(info.moe, None);
+}
+
+#[test]
+fn test_qk_norm() {
+    use common_cpu::tensor::{reslice, reslice_mut};
+
+    const NT: usize = 2;
+    const NH: usize = 2;
+    const DH: usize = 4;
+    const EPSILON: f32 = 1e-5;
+    // 每行之后留出空位，模拟从 qkv 中切分出的 q
+    const ROW: usize = NH * DH + DH;
+
+    let x = (0..NT * NH * DH)
+        .map(|i| (i as f32 - 7.) / 4.)
+        .collect::<Vec<_>>();
+    let w = [0.5f32, 1., 1.5, 2.];
+
+    let mut q = Tensor::alloc(F16, &[NT as _, ROW as _], Blob::new);
+    let data: &mut [f16] = reslice_mut(q.physical_mut());
+    for t in 0..NT {
+        for j in 0..NH * DH {
+            data[t * ROW + j] = f16::from_f32(x[t * NH * DH + j]);
+        }
+    }
+    let mut q = q
+        .slice(&[slice![=>], slice![=> NH * DH]])
+        .reshape(&[NT as _, NH as _, DH as _]);
+    let mut w_ = Tensor::alloc(F16, &[DH as _], Blob::new);
+    for (dst, src) in reslice_mut::<u8, f16>(w_.physical_mut()).iter_mut().zip(w) {
+        *dst = f16::from_f32(src);
+    }
+
+    llama::head_norm(&CpuKernels::default(), &mut q, &w_, EPSILON, &ThisThread);
+
+    let data: &[f16] = reslice(q.physical());
+    for t in 0..NT {
+        for h in 0..NH {
+            let head = &x[(t * NH + h) * DH..][..DH];
+            let rms = (head.iter().map(|x| x * x).sum::<f32>() / DH as f32 + EPSILON).sqrt();
+            for j in 0..DH {
+                let ans = head[j] / rms * w[j];
+                let y = data[t * ROW + h * DH + j].to_f32();
+                assert!((y - ans).abs() < 1e-2, "{y} != {ans}");
+            }
+        }
+    }
+}
+
+#[test]
+fn test_tied_lm_head() {
+    use test_storage::weight;
+
+    let (voc, d) = (8, 4);
+    // 词嵌入与输出层绑定，保存时不包含 lm_head
+    let config = test_storage::config(voc, 0, 1, d, d);
+    let mut storage = test_storage::storage(config, |shape, _| weight(shape, |_| 1.));
+    storage.embed_tokens = weight(&[voc, d], |i| i as f32 / 32.);
+    storage.lm_head = storage.embed_tokens.clone().transpose(&[1, 0]);
+    let dir = std::env::temp_dir().join("llama-cpu-test-tied-lm-head");
+    storage.save(&dir).unwrap();
+
+    let model = Transformer::load(&dir, Default::default()).unwrap();
+    assert_eq!(model.s.lm_head.shape(), [d, voc]);
+
+    let hidden_state = model.token_embed([3, 5]);
+    let decoding = [DecodingMeta {
+        num_query: 2,
+        num_decode: 2,
+    }];
+    let logits = model.decode(decoding, hidden_state);
+    assert_eq!(logits.shape(), [2, voc]);
+
+    drop(model);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_resident_layers() {
+    use causal_lm::QueryContext;
+
+    let config = test_storage::config(8, 2, 1, 4, 4);
+    let storage = test_storage::storage(config, |shape, _| {
+        test_storage::weight(shape, |i| (i % 7) as f32 / 8. - 0.375)
+    });
+    let dir = std::env::temp_dir().join("llama-cpu-test-resident-layers");
+    storage.save(&dir).unwrap();
+
+    let forward = |resident_layers| {
+        let meta = ModelLoadMeta {
+            resident_layers,
+            ..Default::default()
+        };
+        let model = Transformer::load(&dir, meta).unwrap();
+        let resident = model
+            .s
+            .layers
+            .iter()
+            .map(|l| l.att_qkv.physical().is_resident())
+            .collect::<Vec<_>>();
+
+        let mut cache = model.new_cache();
+        let queries = [QueryContext {
+            cache: Some(&mut cache),
+            range: 0..3,
+        }];
+        let x = CausalLM::forward(&model, queries, model.token_embed([3, 5, 7])).unwrap();
+        (resident, x.as_slice().to_vec())
+    };
+    // 按需加载的层与常驻的层计算结果相同
+    let (on_demand, x_on_demand) = forward(0);
+    let (partial, x_partial) = forward(1);
+    let (resident, x_resident) = forward(usize::MAX);
+    assert_eq!(on_demand, [false, false]);
+    assert_eq!(partial, [true, false]);
+    assert_eq!(resident, [true, true]);
+    assert_eq!(x_on_demand, x_resident);
+    assert_eq!(x_partial, x_resident);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_sliding_window_chunked() {
+    use causal_lm::QueryContext;
+    use llama::SlidingWindow;
+
+    let save = |name: &str, sliding_window| {
+        let mut config = test_storage::config(8, 2, 1, 4, 4);
+        config.sliding_window = sliding_window;
+        let storage = test_storage::storage(config, |shape, _| {
+            test_storage::weight(shape, |i| (i % 7) as f32 / 8. - 0.375)
+        });
+        let dir = std::env::temp_dir().join(name);
+        storage.save(&dir).unwrap();
+        dir
+    };
+    let window = SlidingWindow {
+        size: 3,
+        pattern: None,
+        full_layers: 0,
+    };
+    let windowed = save("llama-cpu-test-sliding-window", Some(window));
+    let full = save("llama-cpu-test-sliding-window-full", None);
+
+    // 提示词长于窗口，按 `chunk` 分块预填充，返回每个位置的隐藏状态
+    let prompt = [3, 5, 7, 1, 4, 6, 2, 0];
+    let forward = |dir: &Path, chunk: usize| {
+        let model = Transformer::load(dir, Default::default()).unwrap();
+        let mut cache = model.new_cache();
+        let mut x = vec![];
+        for (i, tokens) in prompt.chunks(chunk).enumerate() {
+            let start = (i * chunk) as upos;
+            let queries = [QueryContext {
+                cache: Some(&mut cache),
+                range: start..start + tokens.len() as upos,
+            }];
+            let embedded = model.token_embed(tokens.iter().copied());
+            let hidden_state = CausalLM::forward(&model, queries, embedded).unwrap();
+            let hidden_state: &[f16] = reslice(hidden_state.as_slice());
+            x.extend(hidden_state.iter().map(|x| x.to_f32()));
+        }
+        x
+    };
+    let close = |a: &[f32], b: &[f32]| a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-2);
+    // 逐个解码只截取缓存，分块和完整预填充的每个位置按窗口计算掩码，结果应该相同
+    let decode = forward(&windowed, 1);
+    assert!(close(&forward(&windowed, 3), &decode));
+    assert!(close(&forward(&windowed, prompt.len()), &decode));
+    // 窗口之外的位置确实被遮住
+    assert!(!close(&forward(&full, prompt.len()), &decode));
+
+    std::fs::remove_dir_all(&windowed).unwrap();
+    std::fs::remove_dir_all(&full).unwrap();
+}
+
+#[test]
+fn test_teacher_forcing() {
+    use causal_lm::QueryContext;
+
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    let model = Transformer::load(model_dir, Default::default()).unwrap();
+    let prompt = [29966, 29989, 1792, 29989, 29958, 13];
+
+    let mut cache = model.new_cache();
+    let queries = [QueryContext {
+        cache: Some(&mut cache),
+        range: 0..prompt.len() as upos,
+    }];
+    let hidden_state = CausalLM::forward(&model, queries, model.token_embed(prompt)).unwrap();
+    let logits = model.decode([DecodingMeta::all(prompt.len())], hidden_state);
+    // 每个输入的 token 对应一行 logits
+    assert_eq!(logits.shape(), [prompt.len() as udim, model.s.config.voc]);
+}
+
+#[test]
+fn test_attention_mask() {
+    use causal_lm::QueryContext;
+
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    let model = Transformer::load(model_dir, Default::default()).unwrap();
+    let forward = |attn_mask, prompt: [utok; 6]| {
+        let mut cache = model.new_cache();
+        let queries = [QueryContext {
+            cache: Some(&mut cache),
+            range: 0..prompt.len() as upos,
+        }];
+        let args = ForwardArgs {
+            attn_mask,
+            ..Default::default()
+        };
+        let hidden_state = model
+            .forward_with(queries, model.token_embed(prompt), &args)
+            .unwrap();
+        let logits = model.decode([DecodingMeta::all(prompt.len())], hidden_state);
+        let logits: &[f16] = reslice(logits.as_slice());
+        logits
+            .chunks(model.s.config.voc as _)
+            .map(<[_]>::to_vec)
+            .collect::<Vec<_>>()
+    };
+    let prompt = [29966, 29989, 1792, 29989, 29958, 13];
+    let mut changed_prefix = prompt;
+    changed_prefix[3] = 1;
+    let mut changed_suffix = prompt;
+    changed_suffix[5] = 1;
+
+    // 因果掩码下，修改后面的 token 不影响前面的位置
+    let causal = forward(AttentionMask::Causal, prompt);
+    assert_eq!(
+        forward(AttentionMask::Causal, changed_prefix)[..3],
+        causal[..3]
+    );
+    // 前缀中的位置能看到整个前缀
+    let prefix_lm = forward(AttentionMask::PrefixLm(4), prompt);
+    assert_ne!(
+        forward(AttentionMask::PrefixLm(4), changed_prefix)[0],
+        prefix_lm[0]
+    );
+    // 前缀之后的位置保持因果
+    assert_eq!(
+        forward(AttentionMask::PrefixLm(4), changed_suffix)[..5],
+        prefix_lm[..5]
+    );
+}
+
+#[test]
+fn test_rope_table() {
+    use causal_lm::QueryContext;
+
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    let prompt = [29966, 29989, 1792, 29989, 29958, 13];
+    let forward = |rope_table| {
+        let meta = ModelLoadMeta {
+            rope_table,
+            ..Default::default()
+        };
+        let model = Transformer::load(&model_dir, meta).unwrap();
+        assert_eq!(model.rope.is_some(), rope_table);
+
+        let mut cache = model.new_cache();
+        let queries = [QueryContext {
+            cache: Some(&mut cache),
+            range: 0..prompt.len() as upos,
+        }];
+        let hidden_state = CausalLM::forward(&model, queries, model.token_embed(prompt)).unwrap();
+        let logits = model.decode([DecodingMeta::all(prompt.len())], hidden_state);
+        let logits: &[f16] = reslice(logits.as_slice());
+        logits.to_vec()
+    };
+    // 查表与即时计算的结果只有舍入误差
+    let cached = forward(true);
+    let computed = forward(false);
+    assert_eq!(cached.len(), computed.len());
+    for (a, b) in cached.iter().zip(&computed) {
+        assert!((a.to_f32() - b.to_f32()).abs() < 0.1, "{a} != {b}");
+    }
+}
+
+#[test]
+fn test_pretranspose() {
+    use causal_lm::QueryContext;
+
+    let d = 4;
+    let config = test_storage::config(8, 1, 1, d, 4);
+    let storage = test_storage::storage(config, |shape, _| {
+        test_storage::weight(shape, |i| (i % 5) as f32 / 8. - 0.25)
+    });
+    let dir = std::env::temp_dir().join("llama-cpu-test-pretranspose");
+    storage.save(&dir).unwrap();
+
+    let infer = |pretranspose| {
+        let meta = ModelLoadMeta {
+            pretranspose,
+            ..Default::default()
+        };
+        let model = Transformer::load(&dir, meta).unwrap();
+        let layer = &model.s.layers[0];
+        assert_eq!(layer.att_qkv.is_contiguous(), pretranspose);
+        assert_eq!(layer.mlp_down.is_contiguous(), pretranspose);
+        assert_eq!(layer.att_qkv.shape(), [d, d + d + d]);
+
+        let mut cache = model.new_cache();
+        let queries = [QueryContext {
+            cache: Some(&mut cache),
+            range: 0..3,
+        }];
+        let x = CausalLM::forward(&model, queries, model.token_embed([3, 5, 7])).unwrap();
+        let logits = model.decode([DecodingMeta::all(3)], x);
+        let logits: &[f16] = reslice(logits.as_slice());
+        logits.to_vec()
+    };
+    // 预先转置的权重与按步长转置的权重计算结果相同
+    let transposed = infer(false);
+    let pretransposed = infer(true);
+    for (a, b) in transposed.iter().zip(&pretransposed) {
+        assert!((a.to_f32() - b.to_f32()).abs() < 1e-3, "{a} != {b}");
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_forward_cache_overflow() {
+    use causal_lm::QueryContext;
+
+    let config = test_storage::config(8, 1, 1, 4, 4);
+    let max = config.max_seq_len;
+    let storage = test_storage::storage(config, |shape, _| {
+        test_storage::weight(shape, |i| (i % 5) as f32 / 8. - 0.25)
+    });
+    let dir = std::env::temp_dir().join("llama-cpu-test-forward-cache-overflow");
+    storage.save(&dir).unwrap();
+
+    let growth = [
+        CacheGrowth::Preallocate,
+        CacheGrowth::Geometric {
+            initial: 4,
+            factor: 2.,
+        },
+    ];
+    for cache_growth in growth {
+        let meta = ModelLoadMeta {
+            cache_growth,
+            ..Default::default()
+        };
+        let model = Transformer::load(&dir, meta).unwrap();
+        let mut cache = model.new_cache();
+        // 最后一个位置正好用满缓存
+        let queries = [QueryContext {
+            cache: Some(&mut cache),
+            range: max - 1..max,
+        }];
+        assert!(CausalLM::forward(&model, queries, model.token_embed([3])).is_ok());
+        // 多出一个位置时返回错误而不是越界访问缓存
+        let queries = [QueryContext {
+            cache: Some(&mut cache),
+            range: max..max + 1,
+        }];
+        let ans = CausalLM::forward(&model, queries, model.token_embed([3]));
+        assert_eq!(
+            ans.err(),
+            Some(CacheOverflow {
+                att_len: max + 1,
+                max_seq_len: max,
+            })
+        );
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_cast_and_save() {
+    use digit_layout::types::BF16;
+
+    // 所有权重的值在 bf16 中都能精确表示，往返转换不改变数值
+    let config = test_storage::config(8, 1, 1, 4, 6);
+    let storage = test_storage::storage(config, |shape, i| {
+        test_storage::weight(shape, |j| ((j * 3 + i) % 7) as f32 / 8. - 0.375)
+    });
+    let dir = std::env::temp_dir().join("llama-cpu-test-cast-and-save");
+    storage.save(dir.join("f16")).unwrap();
+
+    // 按逻辑形状连续排列的所有权重
+    let weights = |s: &Storage| {
+        let mut ans = vec![&s.embed_tokens, &s.lm_layernorm, &s.lm_head];
+        for l in &s.layers {
+            ans.extend([
+                &l.att_layernorm,
+                &l.att_qkv,
+                &l.att_o,
+                &l.mlp_layernorm,
+                &l.mlp_gate_up,
+                &l.mlp_down,
+            ]);
+        }
+        ans.into_iter()
+            .map(|t| (t.shape().to_vec(), llama::contiguous(t).as_slice().to_vec()))
+            .collect::<Vec<_>>()
+    };
+
+    for pretranspose in [false, true] {
+        let meta = ModelLoadMeta {
+            pretranspose,
+            ..Default::default()
+        };
+        let model = Transformer::load(dir.join("f16"), meta).unwrap();
+        let Transformer { s, .. } = Transformer::load(dir.join("f16"), meta).unwrap();
+        s.cast(BF16).unwrap().save(dir.join("bf16")).unwrap();
+
+        // 保存的是转换后的权重，转换回 f16 与原始的权重完全相同
+        let saved = Storage::load_safetensors(dir.join("bf16")).unwrap();
+        assert_eq!(saved.config.dt, BF16);
+        assert_eq!(saved.layers[0].att_qkv.data_layout(), BF16);
+        assert_eq!(weights(&saved.cast(F16).unwrap()), weights(&storage));
+
+        let reloaded = Transformer::load(dir.join("bf16"), Default::default()).unwrap();
+        let divergence = causal_lm::compare_models(&model, &reloaded, &[3, 5, 7]).unwrap();
+        assert_eq!(divergence.max_abs_diff(), 0.);
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_separate_gate_up() {
+    use std::iter::zip;
+
+    let (d, di, n) = (4, 6, 3);
+    let weight = |shape: &[udim], seed: usize| {
+        test_storage::weight(shape, |i| ((i * 7 + seed) % 11) as f32 / 8. - 0.625)
+    };
+    let gate = weight(&[di, d], 1);
+    let up = weight(&[di, d], 2);
+    let down = weight(&[d, di], 3);
+
+    // 先保存融合的权重以生成配置，再用 HF 格式分离存储的 gate/up 覆盖
+    let seeds = [9, 4, 5, 6, 7, 8, 3, 10, 11];
+    let config = test_storage::config(8, 1, 1, d, di);
+    let storage = test_storage::storage(config, |shape, i| weight(shape, seeds[i]));
+    let dir = std::env::temp_dir().join("llama-cpu-test-separate-gate-up");
+    storage.save(&dir).unwrap();
+    let layer = &storage.layers[0];
+    let name = |name: &str| format!("model.layers.0.{name}.weight");
+    #[rustfmt::skip]
+    let tensors = [
+        ("model.embed_tokens.weight".into()  , storage.embed_tokens.clone()),
+        (name("input_layernorm")             , layer.att_layernorm.clone()),
+        (name("self_attn.qkv_proj")          , layer.att_qkv.clone().transpose(&[1, 0])),
+        (name("self_attn.o_proj")            , layer.att_o.clone().transpose(&[1, 0])),
+        (name("post_attention_layernorm")    , layer.mlp_layernorm.clone()),
+        (name("mlp.gate_proj")               , gate.clone()),
+        (name("mlp.up_proj")                 , up.clone()),
+        (name("mlp.down_proj")               , down.clone()),
+        ("model.norm.weight".into()          , storage.lm_layernorm.clone()),
+        ("lm_head.weight".into()             , storage.lm_head.clone().transpose(&[1, 0])),
+    ];
+    common::safe_tensors::write_safetensors(dir.join("model.safetensors"), &tensors).unwrap();
+    let loaded = Storage::load_safetensors(&dir).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // 融合的权重依次是 gate 和 up
+    let layer = &loaded.layers[0];
+    let gate_up = layer.mlp_gate_up.clone().transpose(&[1, 0]);
+    assert_eq!(gate_up.shape(), [di + di, d]);
+    let fused: &[f16] = reslice(gate_up.physical());
+    let gate: &[f16] = reslice(gate.physical());
+    let up: &[f16] = reslice(up.physical());
+    assert_eq!(fused, [gate, up].concat());
+
+    // mlp 的输出与主机上按定义计算的结果一致
+    let x1 = weight(&[n, d], 12);
+    let mut x = Tensor::alloc(F16, &[n, d], Blob::new);
+    x.physical_mut().fill(0);
+    let mut buf = Tensor::alloc(F16, &[n, di + di], Blob::new);
+    KernelsA::mlp(
+        &CpuKernels::default(),
+        &mut x,
+        &x1,
+        &mut buf,
+        &layer.mlp_gate_up,
+        &layer.mlp_down,
+        1.,
+        true,
+        &ThisThread,
+    );
+
+    let down: &[f16] = reslice(down.physical());
+    let x1: &[f16] = reslice(x1.physical());
+    let dot = |a: &[f16], b: &[f16]| zip(a, b).map(|(a, b)| a.to_f32() * b.to_f32()).sum::<f32>();
+    let mut expected = vec![0f32; (n * d) as usize];
+    for (x1, y) in zip(x1.chunks(d as _), expected.chunks_mut(d as _)) {
+        let act = zip(gate.chunks(d as _), up.chunks(d as _))
+            .map(|(g, u)| {
+                let g = dot(g, x1);
+                f16::from_f32(g / (1. + (-g).exp()) * dot(u, x1))
+            })
+            .collect::<Vec<_>>();
+        for (y, w) in zip(y, down.chunks(di as _)) {
+            *y = dot(&act, w);
+        }
+    }
+    let x: &[f16] = reslice(x.as_slice());
+    for (a, b) in zip(x, &expected) {
+        assert!(
+            (a.to_f32() - b).abs() < 1e-2 * b.abs().max(1.),
+            "{a} != {b}"
+        );
+    }
+}
+
+#[test]
+fn test_int4() {
+    use causal_lm::QueryContext;
+
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    let prompt = [29966, 29989, 1792, 29989, 29958, 13];
+    let forward = |int4_group| {
+        let meta = ModelLoadMeta {
+            int4_group,
+            ..Default::default()
+        };
+        let model = Transformer::load(&model_dir, meta).unwrap();
+        if int4_group.is_some() {
+            assert_eq!(model.int4.len(), model.s.layers.len());
+            let q = &model.int4[0].att_qkv;
+            let [k, n] = q.shape();
+            assert!(q.nbytes() * 3 < k * n * 2);
+            // 量化之后不再保留 f16 的投影矩阵
+            let w = &model.s.layers[0].att_qkv;
+            assert!(w.physical().len() < w.bytes_size());
+        }
+
+        let mut cache = model.new_cache();
+        let queries = [QueryContext {
+            cache: Some(&mut cache),
+            range: 0..prompt.len() as upos,
+        }];
+        let hidden_state = CausalLM::forward(&model, queries, model.token_embed(prompt)).unwrap();
+        let logits = model.decode([DecodingMeta::all(prompt.len())], hidden_state);
+        let logits: &[f16] = reslice(logits.as_slice());
+        logits.iter().map(|x| x.to_f32()).collect::<Vec<_>>()
+    };
+    // 量化模型的 logits 与 f16 模型方向一致
+    let a = forward(None);
+    let b = forward(Some(128));
+    assert_eq!(a.len(), b.len());
+    let dot = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+    let cos = dot(&a, &b) / (dot(&a, &a) * dot(&b, &b)).sqrt();
+    assert!(cos > 0.95, "cosine similarity {cos}");
+}
+
+#[test]
+fn test_head_mask() {
+    use causal_lm::QueryContext;
+
+    let (nh, dh) = (2, 2);
+    let d = nh * dh;
+    let seeds = [2, 3, 4, 1, 5, 6, 7, 8, 9];
+    let load = |zeroed: Option<udim>| {
+        // 输出投影中第 `zeroed` 个头对应的输入通道置零，相当于这个头没有贡献
+        let config = test_storage::config(8, 1, nh, d, 4);
+        let storage = test_storage::storage(config, |shape, k| {
+            test_storage::weight(shape, |i| {
+                if k == 3 && zeroed.is_some_and(|h| i as udim % d / dh == h) {
+                    0.
+                } else {
+                    ((i * 5 + seeds[k]) % 9) as f32 / 8. - 0.5
+                }
+            })
+        });
+        let dir = std::env::temp_dir().join("llama-cpu-test-head-mask");
+        storage.save(&dir).unwrap();
+        let model = Transformer::load(&dir, Default::default()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        model
+    };
+    let forward = |model: &Transformer, pruned_heads| {
+        let mut cache = model.new_cache();
+        let queries = [QueryContext {
+            cache: Some(&mut cache),
+            range: 0..3,
+        }];
+        let args = ForwardArgs {
+            pruned_heads,
+            ..Default::default()
+        };
+        let x = model
+            .forward_with(queries, model.token_embed([3, 5, 7]), &args)
+            .unwrap();
+        x.as_slice().to_vec()
+    };
+    let model = load(None);
+    let full = forward(&model, vec![]);
+    for h in 0..nh {
+        // 剪枝的头没有贡献，其他头的贡献不变
+        let masked = forward(&model, vec![(0, h)]);
+        assert_ne!(masked, full);
+        assert_eq!(masked, forward(&load(Some(h)), vec![]));
+        // 剪枝只作用于传入参数的这一次计算
+        assert_eq!(forward(&model, vec![]), full);
+    }
+
+    // 模型设置的剪枝作用于每一次计算，与传入参数的剪枝效果相同
+    let mut model = model;
+    model.set_pruned_heads(vec![(0, 1)]).unwrap();
+    assert_eq!(forward(&model, vec![]), forward(&load(Some(1)), vec![]));
+    model.set_pruned_heads(vec![]).unwrap();
+    assert_eq!(forward(&model, vec![]), full);
+
+    // 超出范围的层或头返回错误，不改变设置，也不做任何计算
+    let out_of_range = ForwardError::PrunedHeadOutOfRange { layer: 0, head: nh };
+    assert_eq!(model.set_pruned_heads(vec![(0, nh)]), Err(out_of_range));
+    assert_eq!(
+        model.set_pruned_heads(vec![(1, 0)]),
+        Err(ForwardError::PrunedHeadOutOfRange { layer: 1, head: 0 })
+    );
+    assert_eq!(forward(&model, vec![]), full);
+    let mut cache = model.new_cache();
+    let queries = [QueryContext {
+        cache: Some(&mut cache),
+        range: 0..3,
+    }];
+    let args = ForwardArgs {
+        pruned_heads: vec![(0, nh)],
+        ..Default::default()
+    };
+    assert_eq!(
+        model
+            .forward_with(queries, model.token_embed([3, 5, 7]), &args)
+            .err(),
+        Some(out_of_range)
+    );
+}
+
+#[test]
+fn test_cache_growth() {
+    let growth = CacheGrowth::Geometric {
+        initial: 4,
+        factor: 2.,
+    };
+    assert_eq!(growth.initial(1024), 4);
+    assert_eq!(growth.grow(4, 4, 1024), None);
+    assert_eq!(growth.grow(4, 5, 1024), Some(8));
+    assert_eq!(growth.grow(4, 17, 1024), Some(32));
+    assert_eq!(growth.grow(512, 1000, 600), Some(600));
+    assert_eq!(growth.grow(600, 1000, 600), None);
+    assert_eq!(CacheGrowth::Preallocate.initial(1024), 1024);
+    assert_eq!(CacheGrowth::Preallocate.grow(4, 5, 1024), None);
+    // 缩小到足够容纳剩余位置的最小容量
+    assert_eq!(growth.shrink(32, 3, 1024), Some(4));
+    assert_eq!(growth.shrink(32, 9, 1024), Some(16));
+    assert_eq!(growth.shrink(32, 17, 1024), None);
+    assert_eq!(CacheGrowth::Preallocate.shrink(1024, 3, 1024), None);
+
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    let mut model = Transformer::load(model_dir, Default::default()).unwrap();
+    let tokens = [29966, 29989, 1792, 29989, 29958, 13];
+    // 分两次预填充，记录第一次之后的缓存容量
+    let prefill = |model: &Transformer| {
+        let mut cache = model.new_cache();
+        let mut pos = 0;
+        model.prefill(&tokens[..3], &mut cache, &mut pos).unwrap();
+        let capacity = cache.shape()[3];
+        let x = model.prefill(&tokens[3..], &mut cache, &mut pos).unwrap();
+        (cache, capacity, x)
+    };
+    let (expected, capacity, x0) = prefill(&model);
+    assert_eq!(capacity, model.s.config.max_seq_len);
+
+    // 第二次预填充跨越了容量边界，缓存重新分配
+    model.cache_growth = growth;
+    let (cache, capacity, x1) = prefill(&model);
+    assert_eq!(capacity, 4);
+    assert_eq!(cache.shape()[3], 8);
+
+    let close = |a: &[f16], b: &[f16]| {
+        assert_eq!(a.len(), b.len());
+        for (a, b) in a.iter().zip(b) {
+            let (a, b) = (a.to_f32(), b.to_f32());
+            assert!((a - b).abs() <= 5e-2 * a.abs().max(1.), "{a} != {b}");
+        }
+    };
+    close(reslice(x0.as_slice()), reslice(x1.as_slice()));
+    for layer in 0..model.architecture().nlayers {
+        let a = model.cache_to_host(&expected, layer, tokens.len() as _);
+        let b = model.cache_to_host(&cache, layer, tokens.len() as _);
+        close(a.physical(), b.physical());
+    }
+}
+
+#[test]
+fn test_compare_models() {
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    let model = Transformer::load(model_dir, Default::default()).unwrap();
+    let tokens = [29966, 29989, 1792, 29989, 29958, 13];
+    let divergence = causal_lm::compare_models(&model, &model, &tokens).unwrap();
+    assert_eq!(divergence.positions.len(), tokens.len());
+    assert_eq!(divergence.max_abs_diff(), 0.);
+    assert!((divergence.min_cosine_similarity() - 1.).abs() < 1e-6);
+    assert_eq!(divergence.argmax_agreement(), 1.);
+}
+
+#[test]
+fn test_backend() {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering::Relaxed},
+        Arc,
+    };
+
+    /// 委托给默认内核并统计矩阵乘和查表旋转位置编码次数的后端。
+    struct Counting(CpuKernels, Arc<[AtomicUsize; 2]>);
+
+    impl CpuBackend for Counting {
+        fn mat_mul(
+            &self,
+            c: &mut Tensor<&mut [u8]>,
+            beta: f32,
+            a: &Tensor<&[u8]>,
+            b: &Tensor<&[u8]>,
+            alpha: f32,
+        ) {
+            self.1[0].fetch_add(1, Relaxed);
+            CpuBackend::mat_mul(&self.0, c, beta, a, b, alpha)
+        }
+        fn rope_table(&self, t: &mut Tensor<&mut [u8]>, pos: &Tensor<&[u8]>, table: &RopeTable) {
+            self.1[1].fetch_add(1, Relaxed);
+            CpuBackend::rope_table(&self.0, t, pos, table)
+        }
+    }
+
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    let meta = ModelLoadMeta {
+        rope_table: true,
+        ..Default::default()
+    };
+    let model = Transformer::load(&model_dir, meta).unwrap();
+    let mut custom = Transformer::load(&model_dir, meta).unwrap();
+    let count = Arc::new([AtomicUsize::new(0), AtomicUsize::new(0)]);
+    custom.set_backend(Box::new(Counting(Default::default(), count.clone())));
+
+    // 委托给默认内核的后端输出完全相同
+    let tokens = [29966, 29989, 1792, 29989, 29958, 13];
+    let divergence = causal_lm::compare_models(&model, &custom, &tokens).unwrap();
+    assert_eq!(divergence.max_abs_diff(), 0.);
+    assert_eq!(divergence.argmax_agreement(), 1.);
+    assert!(count[0].load(Relaxed) > 0);
+    // 编码表也经过后端
+    assert!(count[1].load(Relaxed) > 0);
+}
+
+#[test]
+fn test_score_packed() {
+    use causal_lm::PositionDivergence;
+    use std::iter::zip;
+
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    let mut model = Transformer::load(model_dir, Default::default()).unwrap();
+    let voc = model.s.config.voc as usize;
+    let sequences: [&[utok]; 3] = [
+        &[29966, 29989, 1792, 29989, 29958, 13],
+        &[1, 15043, 29892],
+        &[29871, 13, 2277, 29937, 29871],
+    ];
+    // 一次打包所有序列，或者前两个序列打包一次、最后一个序列单独推理
+    let packed = model.score_packed(&sequences, usize::MAX).unwrap();
+    let split = model.score_packed(&sequences, 9).unwrap();
+    assert_eq!(packed.len(), sequences.len());
+    assert_eq!(split.len(), sequences.len());
+
+    // 超长的序列返回错误
+    let max = model.s.config.max_seq_len as usize;
+    let long = vec![1; max + 1];
+    assert_eq!(
+        model.score_packed(&[sequences[0], &long], usize::MAX),
+        Err(SequenceTooLong {
+            index: 1,
+            len: max + 1,
+            max
+        })
+    );
+
+    // 单独推理每个序列，打包的掩码以 f32 精度计算，单独推理也使用 f32 精度
+    model.set_attention_f32(true);
+    for (i, seq) in sequences.into_iter().enumerate() {
+        let mut cache = model.new_cache();
+        let queries = [QueryContext {
+            cache: Some(&mut cache),
+            range: 0..seq.len() as upos,
+        }];
+        let hidden_state =
+            CausalLM::forward(&model, queries, model.token_embed(seq.iter().copied())).unwrap();
+        let logits = model.decode([DecodingMeta::all(seq.len())], hidden_state);
+        let alone = model.logits_to_host(&logits).unwrap();
+        // 每个序列的位置从 0 开始，与单独推理只有批次不同带来的舍入误差
+        for packed in [&packed[i], &split[i]] {
+            assert_eq!(packed.len(), seq.len() * voc);
+            for (a, b) in zip(alone.chunks_exact(voc), packed.chunks_exact(voc)) {
+                let divergence = PositionDivergence::new(a, b);
+                assert!(divergence.argmax_agree);
+                assert!(divergence.cosine_similarity > 0.999);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_quantize_and_save() {
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    let quantized = Transformer::load(
+        &model_dir,
+        ModelLoadMeta {
+            int4_group: Some(64),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let dir = std::env::temp_dir().join("llama-cpu-test-quantize-and-save");
+    let model = Transformer::load(&model_dir, Default::default()).unwrap();
+    model
+        .quantize_and_save(&dir, QuantConfig::Int4 { group: 64 })
+        .unwrap();
+
+    // 重新加载的模型读取保存的量化权重，与加载时量化的模型完全相同
+    let reloaded = Transformer::load(&dir, Default::default()).unwrap();
+    assert_eq!(reloaded.int4.len(), reloaded.s.layers.len());
+    // 不保留反量化的投影矩阵
+    for layer in &reloaded.s.layers {
+        assert!(layer.att_qkv.physical().len() < layer.att_qkv.bytes_size());
+        assert!(layer.mlp_down.physical().len() < layer.mlp_down.bytes_size());
+    }
+    assert_eq!(reloaded.int4[0].att_qkv.group(), 64);
+    // 指定的分组与保存的不同时不会静默地忽略
+    let mismatch = Transformer::load(
+        &dir,
+        ModelLoadMeta {
+            int4_group: Some(32),
+            ..Default::default()
+        },
+    );
+    assert!(matches!(
+        mismatch,
+        Err(FileLoadError::Int4GroupMismatch {
+            expected: 32,
+            found: 64
+        })
+    ));
+    assert_eq!(
+        reloaded.int4[0].att_qkv.qweight(),
+        quantized.int4[0].att_qkv.qweight()
+    );
+    let tokens = [29966, 29989, 1792, 29989, 29958, 13];
+    let divergence = causal_lm::compare_models(&quantized, &reloaded, &tokens).unwrap();
+    assert_eq!(divergence.max_abs_diff(), 0.);
+
+    // 保存的文件中不再有原始的投影矩阵
+    let saved = SafeTensors::load_from_dir(&dir).unwrap();
+    assert!(!saved.contains("model.layers.0.self_attn.qkv_proj.weight"));
+    assert!(saved.contains("model.layers.0.self_attn.qkv_proj.qweight"));
+    drop(saved);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,72 @@
+//! 测试使用的合成小模型。
+
+use common::{f16, Blob};
+use common_cpu::tensor::{reslice_mut, udim, Tensor};
+use digit_layout::types::F16;
+use llama::{InferenceConfig, LayerStorage, Storage, Weight};
+
+/// 形状为 `shape` 的 f16 权重，第 `i` 个元素为 `f(i)`。
+pub fn weight(shape: &[udim], f: impl Fn(usize) -> f32) -> Tensor<Weight> {
+    let mut t = Tensor::alloc(F16, shape, Blob::new);
+    for (i, x) in reslice_mut::<u8, f16>(t.physical_mut())
+        .iter_mut()
+        .enumerate()
+    {
+        *x = f16::from_f32(f(i));
+    }
+    t.map_physical(Weight::from)
+}
+
+/// `nlayers` 层、`nh` 个头的小模型配置，不分组查询，其他配置取常用的值。
+pub fn config(voc: udim, nlayers: udim, nh: udim, d: udim, di: udim) -> InferenceConfig {
+    InferenceConfig {
+        dt: F16,
+        voc,
+        nlayers,
+        nh,
+        nkvh: nh,
+        d,
+        dkv: d,
+        di,
+        max_seq_len: 16,
+        bos_token: 1,
+        eos_token: 2,
+        epsilon: 1e-5,
+        theta: 1e4,
+        attn_logit_softcap: None,
+        final_logit_softcap: None,
+        sliding_window: None,
+        rope_scaling: None,
+    }
+}
+
+/// 按 `config` 生成模型，每个权重由 `weight(shape, index)` 生成。
+///
+/// `index` 依次为 embed_tokens、att_layernorm、att_qkv、att_o、mlp_layernorm、mlp_gate_up、mlp_down、lm_layernorm、lm_head 的序号 0 到 8，
+/// 投影矩阵以 HF 的 `[out, in]` 形状生成，转置后存储。
+pub fn storage(
+    config: InferenceConfig,
+    weight: impl Fn(&[udim], usize) -> Tensor<Weight>,
+) -> Storage {
+    let InferenceConfig {
+        voc, d, dkv, di, ..
+    } = config;
+    Storage {
+        embed_tokens: weight(&[voc, d], 0),
+        layers: (0..config.nlayers)
+            .map(|_| LayerStorage {
+                att_layernorm: weight(&[d], 1),
+                att_qkv: weight(&[d + dkv + dkv, d], 2).transpose(&[1, 0]),
+                att_o: weight(&[d, d], 3).transpose(&[1, 0]),
+                mlp_layernorm: weight(&[d], 4),
+                mlp_gate_up: weight(&[di + di, d], 5).transpose(&[1, 0]),
+                mlp_down: weight(&[d, di], 6).transpose(&[1, 0]),
+                att_q_norm: None,
+                att_k_norm: None,
+            })
+            .collect(),
+        lm_layernorm: weight(&[d], 7),
+        lm_head: weight(&[voc, d], 8).transpose(&[1, 0]),
+        config,
+    }
+}
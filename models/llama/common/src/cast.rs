@@ -1,63 +1,143 @@
 ﻿use crate::{InferenceConfig, LayerStorage, Storage, Weight};
-use common::{bf16, f16, Blob};
-use digit_layout::{
-    types::{BF16, F16, F32},
-    AsDigit, DigitLayout,
-};
-use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use common::{cast_tensor, FileLoadError};
+use digit_layout::DigitLayout;
 use tensor::Tensor;
 
 impl Storage {
-    pub fn cast(self, dt: DigitLayout) -> Self {
+    /// 将模型转换为数据类型 `dt`，不支持的转换返回错误。
+    pub fn cast(self, dt: DigitLayout) -> Result<Self, FileLoadError> {
         if self.config.dt == dt {
-            return self;
+            return Ok(self);
         }
-        Self {
+        Ok(Self {
             config: InferenceConfig { dt, ..self.config },
-            embed_tokens: cast(self.embed_tokens, dt),
+            embed_tokens: cast(self.embed_tokens, dt)?,
             layers: self
                 .layers
                 .into_iter()
-                .map(|l| LayerStorage {
-                    att_layernorm: cast(l.att_layernorm, dt),
-                    att_qkv: cast(l.att_qkv, dt),
-                    att_o: cast(l.att_o, dt),
-                    mlp_layernorm: cast(l.mlp_layernorm, dt),
-                    mlp_gate_up: cast(l.mlp_gate_up, dt),
-                    mlp_down: cast(l.mlp_down, dt),
+                .map(|l| {
+                    Ok(LayerStorage {
+                        att_layernorm: cast(l.att_layernorm, dt)?,
+                        att_qkv: cast(l.att_qkv, dt)?,
+                        att_o: cast(l.att_o, dt)?,
+                        mlp_layernorm: cast(l.mlp_layernorm, dt)?,
+                        mlp_gate_up: cast(l.mlp_gate_up, dt)?,
+                        mlp_down: cast(l.mlp_down, dt)?,
+                        att_q_norm: l.att_q_norm.map(|t| cast(t, dt)).transpose()?,
+                        att_k_norm: l.att_k_norm.map(|t| cast(t, dt)).transpose()?,
+                    })
                 })
-                .collect(),
-            lm_layernorm: cast(self.lm_layernorm, dt),
-            lm_head: cast(self.lm_head, dt),
+                .collect::<Result<_, FileLoadError>>()?,
+            lm_layernorm: cast(self.lm_layernorm, dt)?,
+            lm_head: cast(self.lm_head, dt)?,
+        })
+    }
+
+    /// 将模型转换为计算使用的数据类型 `dt`。
+    ///
+    /// 严格模式下不做转换，模型数据类型与 `dt` 不同时返回错误，避免精度被静默改变。
+    pub fn cast_for_compute(self, dt: DigitLayout, strict: bool) -> Result<Self, FileLoadError> {
+        if strict && self.config.dt != dt {
+            Err(FileLoadError::UnsupportedDtype(self.config.dt))
+        } else {
+            self.cast(dt)
         }
     }
 }
 
-fn cast(src: Tensor<Weight>, dt: DigitLayout) -> Tensor<Weight> {
-    match (src.data_layout(), dt) {
-        (F16, BF16) => typed(src, |x: &f16| bf16::from_f32(x.to_f32())),
-        (F16, F32) => typed(src, |x: &f16| x.to_f32()),
-        (BF16, F16) => typed(src, |x: &bf16| f16::from_f32(x.to_f32())),
-        (BF16, F32) => typed(src, |x: &bf16| x.to_f32()),
-        (F32, F16) => typed(src, |x: &f32| f16::from_f32(*x)),
-        (F32, BF16) => typed(src, |x: &f32| bf16::from_f32(*x)),
-        _ => todo!(),
+fn cast(src: Tensor<Weight>, dt: DigitLayout) -> Result<Tensor<Weight>, FileLoadError> {
+    if src.data_layout() == dt {
+        return Ok(src);
     }
+    cast_tensor(&src, dt).map(|t| t.map_physical(Weight::from))
 }
 
-fn typed<T: AsDigit + Sync, U: AsDigit + Send>(
-    src: Tensor<Weight>,
-    cast: impl Fn(&T) -> U + Sync,
-) -> Tensor<Weight> {
-    use tensor::{reslice, reslice_mut};
+#[test]
+fn test_cast_for_compute() {
+    use common::Blob;
+    use digit_layout::types::{BF16, F16};
+
+    let config = InferenceConfig {
+        dt: BF16,
+        voc: 4,
+        nlayers: 0,
+        nh: 1,
+        nkvh: 1,
+        d: 2,
+        dkv: 2,
+        di: 4,
+        max_seq_len: 8,
+        bos_token: 1,
+        eos_token: 2,
+        epsilon: 1e-5,
+        theta: 1e4,
+        attn_logit_softcap: None,
+        final_logit_softcap: None,
+        sliding_window: None,
+        rope_scaling: None,
+    };
+    let weight =
+        |shape: &[tensor::udim]| Tensor::alloc(BF16, shape, Blob::new).map_physical(Weight::from);
+    let storage = || Storage {
+        config: config.clone(),
+        embed_tokens: weight(&[4, 2]),
+        layers: vec![],
+        lm_layernorm: weight(&[2]),
+        lm_head: weight(&[4, 2]),
+    };
 
-    assert_eq!(src.data_layout(), T::LAYOUT);
-    let mut ans = Tensor::alloc(U::LAYOUT, src.shape(), Blob::new);
+    // 严格模式下不支持的类型报错而不是转换
+    assert!(matches!(
+        storage().cast_for_compute(F16, true),
+        Err(FileLoadError::UnsupportedDtype(dt)) if dt == BF16
+    ));
+    // 类型受支持时严格模式正常加载
+    let strict = storage().cast_for_compute(BF16, true).unwrap();
+    assert_eq!(strict.config.dt, BF16);
+    // 非严格模式自动转换
+    let cast = storage().cast_for_compute(F16, false).unwrap();
+    assert_eq!(cast.config.dt, F16);
+    assert_eq!(cast.lm_head.data_layout(), F16);
+}
+
+#[test]
+fn test_cast_transposed() {
+    use crate::contiguous;
+    use common::{bf16, f16, Blob};
+    use digit_layout::types::{BF16, F16, U32};
+    use tensor::reslice;
 
-    reslice(src.physical())
-        .par_iter()
-        .zip(reslice_mut(ans.physical_mut()))
-        .for_each(|(src, dst)| *dst = cast(src));
+    let values = |t: &Tensor<Weight>| {
+        let t = contiguous(t);
+        match t.data_layout() {
+            F16 => reslice::<u8, f16>(t.as_slice())
+                .iter()
+                .map(|x| x.to_f32())
+                .collect::<Vec<_>>(),
+            BF16 => reslice::<u8, bf16>(t.as_slice())
+                .iter()
+                .map(|x| x.to_f32())
+                .collect(),
+            _ => unreachable!(),
+        }
+    };
+    // 以 [3, 2] 存储、转置为 [2, 3] 使用的权重
+    let data = (0..6).map(|x| bf16::from_f32(x as f32)).collect::<Vec<_>>();
+    let mut blob = Blob::new(data.len() * BF16.nbytes());
+    blob.copy_from_slice(reslice(&data));
+    let weight = Tensor::new(BF16, &[3, 2], Weight::from(blob)).transpose(&[1, 0]);
+    let expected = [0., 2., 4., 1., 3., 5.];
+    assert_eq!(values(&weight), expected);
 
-    ans.map_physical(|b| b.into())
+    // 转换保持逻辑上的元素顺序，往返转换不改变数值
+    let half = cast(weight.clone(), F16).unwrap();
+    assert_eq!(half.shape(), &[2, 3]);
+    assert_eq!(values(&half), expected);
+    let back = cast(half, BF16).unwrap();
+    assert_eq!(values(&back), expected);
+    // 不支持的转换返回错误
+    assert!(matches!(
+        cast(weight, U32),
+        Err(FileLoadError::UnsupportedDtype(dt)) if dt == BF16
+    ));
 }
@@ -1,8 +1,14 @@
-﻿use causal_lm::QueryContext;
-use common_devices::{Kernels, KernelsA, SliceOn};
+﻿use causal_lm::{CacheOverflow, QueryContext};
+use common::upos;
+use common_devices::{AttentionMask, Kernels, KernelsA, KernelsB, SliceOn};
+use digit_layout::types::U32;
 use itertools::izip;
 use operators::{Handle, QueueOf};
-use std::ops::{Deref, DerefMut};
+use std::{
+    error, fmt,
+    ops::{Deref, DerefMut},
+    slice::from_raw_parts,
+};
 use tensor::{slice, split, udim, LocalSplitable, Tensor};
 
 pub trait ComputeStream {
@@ -27,19 +33,121 @@ pub trait ComputeStream {
     where
         T: Deref<Target = SliceOn<Self::Handle>>;
 
+    /// 对形状为 `[seq_len, nh, dh]` 的 `t` 原地应用旋转位置编码。
+    fn rope<T, U>(&self, t: &mut Tensor<T>, pos: &Tensor<U>, theta: f32)
+    where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        U: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        self.kernels().rope(t, pos, theta, self.queue());
+    }
+
+    /// 是否支持剪枝注意力头，支持的后端覆盖 [`prune_heads`](Self::prune_heads) 并返回 `true`。
+    ///
+    /// 不支持时有头需要剪枝的前向计算返回 [`ForwardError::PruningUnsupported`]。
+    #[inline]
+    fn can_prune_heads(&self) -> bool {
+        false
+    }
+
+    /// 模型设置的被剪枝的注意力头，作用于每一次前向计算，每一项为 `(层序号, 头序号)`。
+    ///
+    /// 设置时应当以 [`check_pruned_heads`] 检查范围。
+    #[inline]
+    fn pruned_heads(&self) -> &[(usize, udim)] {
+        &[]
+    }
+
+    /// 将注意力头 `heads` 的输出置零，`o` 的形状为 `[nt, nh, dh]`。
+    ///
+    /// 只在 [`can_prune_heads`](Self::can_prune_heads) 返回 `true` 时调用。
+    fn prune_heads<T>(&self, _o: &mut Tensor<T>, _heads: &[udim])
+    where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+    {
+    }
+
+    /// 计算 `y = beta * y + alpha * x w`，`w` 为第 `layer` 层的投影矩阵 `proj`。
+    ///
+    /// 默认直接以 `w` 做矩阵乘，以其他形式（例如量化）保存投影矩阵的后端可以覆盖。
+    #[allow(clippy::too_many_arguments)]
+    fn project<T, U, V>(
+        &self,
+        _layer: usize,
+        _proj: Projection,
+        y: &mut Tensor<T>,
+        beta: f32,
+        x: &Tensor<U>,
+        w: &Tensor<V>,
+        alpha: f32,
+    ) where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        U: Deref<Target = SliceOn<Self::Handle>>,
+        V: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        self.kernels().mat_mul(y, beta, x, w, alpha, self.queue());
+    }
+
+    /// 计算第 `layer` 层的 MLP 并累加到 `x` 上，`gate_up` 是形状为 `[nt, di + di]` 的工作空间。
+    ///
+    /// 默认使用融合的 MLP 内核，覆盖 [`project`](Self::project) 的后端通常需要一并覆盖。
+    fn mlp<M0, M1, C0, C1, C2>(
+        &self,
+        _layer: usize,
+        x: &mut Tensor<M0>,
+        x1: &Tensor<C0>,
+        gate_up: &mut Tensor<M1>,
+        w_gate_up: &Tensor<C1>,
+        w_down: &Tensor<C2>,
+    ) where
+        M0: DerefMut<Target = SliceOn<Self::Handle>>,
+        M1: DerefMut<Target = SliceOn<Self::Handle>>,
+        C0: Deref<Target = SliceOn<Self::Handle>>,
+        C1: Deref<Target = SliceOn<Self::Handle>>,
+        C2: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        self.kernels()
+            .mlp(x, x1, gate_up, w_gate_up, w_down, 1., true, self.queue());
+    }
+
     fn layers(
         &self,
     ) -> impl Iterator<Item = impl LLamaLayer<Byte = <Self::Handle as Handle>::Byte>>;
 
+    #[inline]
     fn forward<'q>(
+        &self,
+        queries: impl IntoIterator<Item = QueryContext<'q, Self::Storage>>,
+        token_embedded: Tensor<Self::Storage>,
+    ) -> Result<Tensor<Self::Storage>, CacheOverflow>
+    where
+        Self::Storage: 'q,
+    {
+        self.forward_with(queries, token_embedded, &ForwardArgs::default())
+            .map_err(ForwardError::cache_overflow)
+    }
+
+    /// 以 `args` 指定的注意力掩码等参数前向计算，参数只作用于这一次计算。
+    ///
+    /// 有查询超出缓存容量，或者剪枝的注意力头超出范围、后端不支持剪枝时返回错误，不做任何计算。
+    fn forward_with<'q>(
         &self,
         queries: impl IntoIterator<Item = QueryContext<'q, Self::Storage>>,
         mut token_embedded: Tensor<Self::Storage>,
-    ) -> Tensor<Self::Storage>
+        args: &ForwardArgs,
+    ) -> Result<Tensor<Self::Storage>, ForwardError>
     where
         Self::Storage: 'q,
     {
         let mut queries = queries.into_iter().collect::<Vec<_>>();
+        QueryContext::check_all(&queries)?;
+        let pruned_heads = [self.pruned_heads(), &args.pruned_heads[..]].concat();
+        if !pruned_heads.is_empty() {
+            if !self.can_prune_heads() {
+                return Err(ForwardError::PruningUnsupported);
+            }
+            check_pruned_heads(&pruned_heads, self.layers().count(), self.constant().nh)?;
+        }
         let mut nt = 0;
         let mut max_seq_len = 0;
         let mut max_att_len = 0;
@@ -61,7 +169,17 @@ pub trait ComputeStream {
             di,
             epsilon,
             theta,
+            attn_softcap,
+            sliding_window,
+            attn_f32,
         } = self.constant();
+        let ForwardArgs {
+            attn_mask,
+            pos,
+            pruned_heads: _,
+        } = args;
+        // 融合的 softmax 只支持因果掩码，其他掩码以 f32 精度计算注意力
+        let attn_f32 = attn_f32 || !attn_mask.is_causal();
         let dt = token_embedded.data_layout();
         let d = token_embedded.shape()[1];
         let dh = d / nh;
@@ -78,7 +196,13 @@ pub trait ComputeStream {
 
         let mut q_buf = self.malloc((nh * max_seq_len * dh) as usize * dt.nbytes());
         let mut att_buf = self.malloc((nh * max_seq_len * max_att_len) as usize * dt.nbytes());
-        let pos = causal_lm::pos(&queries, nt);
+        let pos = match pos {
+            Some(pos) => {
+                assert_eq!(pos.len(), nt as usize, "positions mismatch the tokens");
+                Tensor::new(U32, &[nt], pos.clone())
+            }
+            None => causal_lm::pos(&queries, nt),
+        };
         let pos = pos.as_ref().map_physical(|u| self.map_pos(u));
 
         for (layer, params) in self.layers().enumerate() {
@@ -87,8 +211,8 @@ pub trait ComputeStream {
 
             self.kernels()
                 .rms_norm(&mut x1, &x, &params.att_layernorm(), epsilon, queue);
-            self.kernels()
-                .mat_mul(&mut qkv, 0., &x1, &params.att_qkv(), 1., queue);
+            let w = params.att_qkv();
+            self.project(layer, Projection::AttQkv, &mut qkv, 0., &x1, &w, 1.);
 
             let (q, k, v) = split!(qkv; [1]: d, dkv, dkv);
             let mut q = q.reshape(&[nt, nh, dh]);
@@ -96,14 +220,21 @@ pub trait ComputeStream {
             let v = v.reshape(&[nt, nkvh, dh]);
             let o = x1.reshape(&[nt, nh, dh]);
 
-            self.kernels().rope(&mut q, &pos, theta, queue);
-            self.kernels().rope(&mut k, &pos, theta, queue);
+            if let Some(w) = params.att_q_norm() {
+                head_norm(self.kernels(), &mut q, &w, epsilon, queue);
+            }
+            if let Some(w) = params.att_k_norm() {
+                head_norm(self.kernels(), &mut k, &w, epsilon, queue);
+            }
+            self.rope(&mut q, &pos, theta);
+            self.rope(&mut k, &pos, theta);
 
             let q = q.transpose(&[1, 0, 2]).split(1, &seq_len);
             let k = k.transpose(&[1, 0, 2]).split(1, &seq_len);
             let v = v.transpose(&[1, 0, 2]).split(1, &seq_len);
             let o = o.transpose(&[1, 0, 2]).split(1, &seq_len);
 
+            let window = sliding_window.and_then(|w| w.window(layer));
             for (query, q, k, v, mut o) in izip!(&mut queries, q, k, v, o) {
                 let pos = query.pos();
                 let seq_len = query.seq_len();
@@ -120,8 +251,12 @@ pub trait ComputeStream {
                     continue;
                 };
 
-                let slice_cat = &[slice![=>], slice![pos =>=> seq_len], slice![=>]];
-                let slice_att = &[slice![=>], slice![      => att_len], slice![=>]];
+                let start = attention_start(window, att_len, seq_len);
+                // 窗口截断了部分查询位置能看到的缓存时，逐个位置按窗口计算掩码
+                let masked = window_masked(window, att_len, seq_len);
+                let att_len = att_len - start;
+                let slice_cat = &[slice![=>], slice![pos   =>=> seq_len], slice![=>]];
+                let slice_att = &[slice![=>], slice![start =>=> att_len], slice![=>]];
                 let shape_q0 = &[nkvh * head_group, seq_len, dh];
                 let shape_q1 = &[nkvh, head_group * seq_len, dh];
                 let shape_att0 = &[nkvh, head_group * seq_len, att_len];
@@ -134,38 +269,75 @@ pub trait ComputeStream {
                 self.kernels().reform(&mut k_cat, &k, queue);
                 self.kernels().reform(&mut v_cat, &v, queue);
 
-                let q_att = q_att.reshape(shape_q1);
-                let k_att = k_cache.slice(slice_att).transpose(&[0, 2, 1]);
+                let k_att = k_cache.slice(slice_att);
                 let v_att = v_cache.slice(slice_att);
+                if attn_f32 || masked {
+                    self.kernels().attention_f32(
+                        &mut o,
+                        &q_att,
+                        &k_att,
+                        &v_att,
+                        head_div,
+                        attn_softcap,
+                        |i, j| {
+                            let (i, j) = (pos + i, start + j);
+                            attn_mask.visible(i, j) && in_window(window, i, j)
+                        },
+                        queue,
+                    );
+                } else {
+                    let q_att = q_att.reshape(shape_q1);
+                    let k_att = k_att.transpose(&[0, 2, 1]);
 
-                let mut att = Tensor::new(dt, shape_att0, &mut att_buf[..]);
-                self.kernels()
-                    .mat_mul(&mut att, 0., &q_att, &k_att, head_div, queue);
-                let mut att = att.reshape(shape_att1);
-                self.kernels().softmax(&mut att, queue);
-                let mut x2 = q_att;
-                self.kernels()
-                    .mat_mul(&mut x2, 0., &att.reshape(shape_att0), &v_att, 1., queue);
+                    let mut att = Tensor::new(dt, shape_att0, &mut att_buf[..]);
+                    self.kernels()
+                        .mat_mul(&mut att, 0., &q_att, &k_att, head_div, queue);
+                    if let Some(cap) = attn_softcap {
+                        self.kernels().softcap(&mut att, cap, queue);
+                    }
+                    let mut att = att.reshape(shape_att1);
+                    self.kernels().softmax(&mut att, queue);
+                    let mut x2 = q_att;
+                    self.kernels().mat_mul(
+                        &mut x2,
+                        0.,
+                        &att.reshape(shape_att0),
+                        &v_att,
+                        1.,
+                        queue,
+                    );
 
-                self.kernels().reform(&mut o, &x2.reshape(shape_q0), queue);
+                    self.kernels().reform(&mut o, &x2.reshape(shape_q0), queue);
+                }
             }
 
             let (mut x1, gate_up) = split!(state_buf.as_mut().map_physical(|u| LocalSplitable::from(&mut **u)); [1]: d, reusing);
             let mut gate_up = gate_up.slice(&[slice![=>], slice![=> di + di]]);
 
-            self.kernels()
-                .mat_mul(&mut x, 1., &x1, &params.att_o(), 1., queue);
+            let heads = pruned_heads
+                .iter()
+                .filter(|&&(l, _)| l == layer)
+                .map(|&(_, h)| h)
+                .collect::<Vec<_>>();
+            if !heads.is_empty() {
+                let mut o = x1
+                    .as_mut()
+                    .map_physical(|u| &mut **u)
+                    .reshape(&[nt, nh, dh]);
+                self.prune_heads(&mut o, &heads);
+            }
+
+            let w = params.att_o();
+            self.project(layer, Projection::AttO, &mut x, 1., &x1, &w, 1.);
             self.kernels()
                 .rms_norm(&mut x1, &x, &params.mlp_layernorm(), epsilon, queue);
-            self.kernels().mlp(
+            self.mlp(
+                layer,
                 &mut x,
                 &x1,
                 &mut gate_up,
                 &params.mlp_gate_up(),
                 &params.mlp_down(),
-                1.,
-                true,
-                queue,
             );
         }
         self.free_pos(pos.take_physical());
@@ -173,7 +345,7 @@ pub trait ComputeStream {
         self.free(q_buf);
         self.free(att_buf);
         drop(x);
-        token_embedded
+        Ok(token_embedded)
     }
 }
 
@@ -183,6 +355,140 @@ pub struct ComputeConst {
     pub di: udim,
     pub epsilon: f32,
     pub theta: f32,
+    pub attn_softcap: Option<f32>,
+    pub sliding_window: Option<SlidingWindow>,
+    /// 以 f32 精度计算注意力，缓存仍以原类型存储。
+    pub attn_f32: bool,
+}
+
+/// 一次前向计算的参数，默认为因果掩码的普通推理。
+#[derive(Clone, Default, Debug)]
+pub struct ForwardArgs {
+    /// 注意力掩码，以 token 在缓存中的位置判断可见性，非因果掩码总是以 f32 精度计算注意力。
+    pub attn_mask: AttentionMask,
+    /// 每个 token 在旋转位置编码中的位置，默认为 token 在缓存中的位置。
+    ///
+    /// 例如打包的多个文档各自从 0 开始编码位置。
+    pub pos: Option<Vec<upos>>,
+    /// 被剪枝的注意力头，每一项为 `(层序号, 头序号)`，这些头的输出在输出投影之前置零，用于分析每个头的贡献。
+    ///
+    /// 与模型设置的 [`ComputeStream::pruned_heads`] 一起生效。
+    pub pruned_heads: Vec<(usize, udim)>,
+}
+
+/// 检查被剪枝的注意力头 `heads` 都在 `nlayers` 层、每层 `nh` 个头的范围内。
+pub fn check_pruned_heads(
+    heads: &[(usize, udim)],
+    nlayers: usize,
+    nh: udim,
+) -> Result<(), ForwardError> {
+    match heads
+        .iter()
+        .find(|&&(layer, head)| layer >= nlayers || head >= nh)
+    {
+        Some(&(layer, head)) => Err(ForwardError::PrunedHeadOutOfRange { layer, head }),
+        None => Ok(()),
+    }
+}
+
+/// 前向计算的错误，发生错误时不做任何计算。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum ForwardError {
+    /// 查询的注意力长度超出了缓存容量。
+    CacheOverflow(CacheOverflow),
+    /// 被剪枝的注意力头的层序号或头序号超出范围。
+    PrunedHeadOutOfRange { layer: usize, head: udim },
+    /// 后端不支持剪枝注意力头。
+    PruningUnsupported,
+}
+
+impl ForwardError {
+    /// 取出缓存容量不足的错误。
+    ///
+    /// 用于不带剪枝参数的前向计算，模型设置的剪枝在设置时已经检查过，只可能超出缓存容量。
+    pub fn cache_overflow(self) -> CacheOverflow {
+        match self {
+            Self::CacheOverflow(e) => e,
+            e => unreachable!("{e}"),
+        }
+    }
+}
+
+impl From<CacheOverflow> for ForwardError {
+    #[inline]
+    fn from(e: CacheOverflow) -> Self {
+        Self::CacheOverflow(e)
+    }
+}
+
+impl error::Error for ForwardError {}
+impl fmt::Display for ForwardError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::CacheOverflow(e) => write!(f, "{e}"),
+            Self::PrunedHeadOutOfRange { layer, head } => {
+                write!(f, "pruned head {head} of layer {layer} out of range")
+            }
+            Self::PruningUnsupported => write!(f, "pruning attention heads is not supported"),
+        }
+    }
+}
+
+/// 滑动窗口注意力配置。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SlidingWindow {
+    /// 窗口长度。
+    pub size: udim,
+    /// 局部层与全局层交替的周期，与 transformers 的 `sliding_window_pattern` 相同，
+    /// 每个周期的最后一层，即 `layer + 1` 为周期整数倍的层使用全局注意力。
+    ///
+    /// 为 `None` 时所有层都使用滑动窗口。
+    pub pattern: Option<udim>,
+    /// 前 `full_layers` 层使用全局注意力，对应 transformers 的 `max_window_layers`。
+    pub full_layers: udim,
+}
+
+impl SlidingWindow {
+    /// 第 `layer` 层的窗口长度，全局注意力层返回 `None`。
+    pub fn window(&self, layer: usize) -> Option<udim> {
+        match self.pattern {
+            _ if layer < self.full_layers as usize => None,
+            Some(p) if p > 0 && (layer + 1) % p as usize == 0 => None,
+            _ => Some(self.size),
+        }
+    }
+}
+
+/// 计算注意力在缓存中的起始位置，即本次查询中第一个位置在窗口内能看到的最早位置。
+#[inline]
+pub fn attention_start(window: Option<udim>, att_len: udim, seq_len: udim) -> udim {
+    match window {
+        Some(window) => (att_len + 1).saturating_sub(window + seq_len),
+        None => 0,
+    }
+}
+
+/// 本次查询的各个位置能看到的范围是否不同，即从 [`attention_start`] 开始截取缓存不足以实现窗口。
+///
+/// 只查询一个位置，或者最后一个位置仍然能看到缓存的开头时，截取缓存之后只需要因果掩码。
+#[inline]
+pub fn window_masked(window: Option<udim>, att_len: udim, seq_len: udim) -> bool {
+    window.is_some_and(|window| seq_len > 1 && att_len > window)
+}
+
+/// 位置 `i` 能否在窗口内看到位置 `j`，与 transformers 相同，窗口包括 `i` 在内共 `window` 个位置。
+#[inline]
+pub fn in_window(window: Option<udim>, i: udim, j: udim) -> bool {
+    !window.is_some_and(|window| j + window <= i)
+}
+
+/// 层中的投影矩阵。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Projection {
+    AttQkv,
+    AttO,
+    MlpGateUp,
+    MlpDown,
 }
 
 pub trait LLamaLayer {
@@ -197,4 +503,74 @@ pub trait LLamaLayer {
     fn mlp_layernorm(&self) -> Tensor<Self::Storage<'_>>;
     fn mlp_gate_up(&self) -> Tensor<Self::Storage<'_>>;
     fn mlp_down(&self) -> Tensor<Self::Storage<'_>>;
+    fn att_q_norm(&self) -> Option<Tensor<Self::Storage<'_>>>;
+    fn att_k_norm(&self) -> Option<Tensor<Self::Storage<'_>>>;
+}
+
+/// 对 `[nt, nh, dh]` 形状的张量逐头原地执行 rms 归一化，用于 QK-Norm。
+pub fn head_norm<K, T, U>(
+    kernels: &K,
+    t: &mut Tensor<T>,
+    w: &Tensor<U>,
+    epsilon: f32,
+    queue: &QueueOf<K::Handle>,
+) where
+    K: KernelsA,
+    T: DerefMut<Target = SliceOn<K::Handle>>,
+    U: Deref<Target = SliceOn<K::Handle>>,
+{
+    let &[nt, nh, dh] = t.shape() else {
+        panic!("shape error")
+    };
+    for h in 0..nh {
+        let mut y = t
+            .as_mut()
+            .map_physical(|u| &mut **u)
+            .slice(&[slice![=>], slice![=h], slice![=>]])
+            .reshape(&[nt, dh]);
+        // 复制一个 y 以实现原地归一化
+        let x = y
+            .as_ref()
+            .map_physical(|u| unsafe { from_raw_parts(u.as_ptr(), u.len()) });
+        kernels.rms_norm(&mut y, &x, w, epsilon, queue);
+    }
+}
+
+#[test]
+fn test_sliding_window_pattern() {
+    let window = SlidingWindow {
+        size: 4,
+        pattern: Some(2),
+        full_layers: 0,
+    };
+    for layer in 0..6 {
+        let w = window.window(layer);
+        // 第 1、3、5 层使用全局注意力
+        if layer % 2 == 1 {
+            assert_eq!(w, None);
+            assert_eq!(attention_start(w, 10, 1), 0);
+            assert!(!window_masked(w, 10, 8));
+        } else {
+            assert_eq!(w, Some(4));
+            assert_eq!(attention_start(w, 10, 1), 6);
+            assert_eq!(attention_start(w, 3, 1), 0);
+            // 第一个查询位置 2 能看到位置 0，最后一个位置 9 只能看到 6 之后的位置
+            assert_eq!(attention_start(w, 10, 8), 0);
+            assert!(window_masked(w, 10, 8));
+            assert!(!window_masked(w, 10, 1));
+            assert!(!window_masked(w, 4, 4));
+            assert!(in_window(w, 9, 6) && !in_window(w, 9, 5));
+        }
+    }
+
+    // 前两层使用全局注意力
+    let window = SlidingWindow {
+        size: 4,
+        pattern: None,
+        full_layers: 2,
+    };
+    for layer in 0..6 {
+        let expected = if layer < 2 { None } else { Some(4) };
+        assert_eq!(window.window(layer), expected);
+    }
 }
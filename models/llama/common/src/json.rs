@@ -1,4 +1,5 @@
-﻿use common::utok;
+﻿use crate::{RopeScaling, SlidingWindow};
+use common::{utok, FileLoadError};
 use digit_layout::{
     types::{BF16, F16, F32},
     DigitLayout,
@@ -7,7 +8,13 @@ use digit_layout::{
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub(crate) struct ConfigJson {
     pub bos_token_id: utok,
-    pub eos_token_id: utok,
+    /// 部分模型未设置结束符，此时与起始符相同；以列表给出多个结束符时取第一个。
+    #[serde(
+        default,
+        deserialize_with = "first_token",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub eos_token_id: Option<utok>,
     pub hidden_size: usize,
     pub intermediate_size: usize,
     pub max_position_embeddings: usize,
@@ -19,10 +26,191 @@ pub(crate) struct ConfigJson {
     pub rms_norm_eps: f32,
     #[serde(default = "default_rope_theta")]
     pub rope_theta: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attn_logit_softcapping: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub final_logit_softcapping: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub use_sliding_window: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sliding_window: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sliding_window_pattern: Option<usize>,
+    /// 前 `max_window_layers` 层使用全局注意力。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_window_layers: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rope_scaling: Option<RopeScalingJson>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quantization_config: Option<QuantizationJson>,
+    /// 用于识别没有 `sliding_window_pattern` 但交替使用滑动窗口的模型，不保存。
+    #[serde(default, skip_serializing)]
+    pub model_type: Option<String>,
     pub torch_dtype: String,
 }
 
+/// 解析单个 token 或者 token 的列表，列表取第一个。
+fn first_token<'de, D>(deserializer: D) -> Result<Option<utok>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::Deserialize;
+
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(utok),
+        Many(Vec<utok>),
+    }
+    Ok(match Option::<OneOrMany>::deserialize(deserializer)? {
+        Some(OneOrMany::One(token)) => Some(token),
+        Some(OneOrMany::Many(tokens)) => tokens.first().copied(),
+        None => None,
+    })
+}
+
+/// [`Storage::save_int4`](crate::Storage::save_int4) 保存的量化方式的名字。
+///
+/// 打包方式是本项目自己的，没有 GPTQ 的 `g_idx` 等字段，不能用其他框架的 GPTQ 加载器读取。
+pub(crate) const INT4_QUANT_METHOD: &str = "infinilm_int4";
+
+/// `config.json` 中的 `quantization_config` 对象，记录投影矩阵的量化方式。
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub(crate) struct QuantizationJson {
+    pub quant_method: String,
+    pub bits: usize,
+    pub group_size: usize,
+}
+
+/// `config.json` 中的 `rope_scaling` 对象。
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug)]
+pub(crate) struct RopeScalingJson {
+    #[serde(default)]
+    pub rope_type: Option<String>,
+    /// 旧版本的配置以 `type` 表示类型，新版本重新保存的配置中两者可能同时存在。
+    #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+    pub ty: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub factor: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_max_position_embeddings: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub low_freq_factor: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub high_freq_factor: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub beta_fast: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub beta_slow: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attention_factor: Option<f32>,
+}
+
+impl From<RopeScaling> for RopeScalingJson {
+    fn from(scaling: RopeScaling) -> Self {
+        match scaling {
+            RopeScaling::Linear { factor } => Self {
+                rope_type: Some("linear".into()),
+                factor: Some(factor),
+                ..Default::default()
+            },
+            RopeScaling::Yarn {
+                factor,
+                original_max_seq_len,
+                beta_fast,
+                beta_slow,
+                attention_factor,
+            } => Self {
+                rope_type: Some("yarn".into()),
+                factor: Some(factor),
+                original_max_position_embeddings: Some(original_max_seq_len as _),
+                beta_fast: Some(beta_fast),
+                beta_slow: Some(beta_slow),
+                attention_factor: Some(attention_factor),
+                ..Default::default()
+            },
+            RopeScaling::Llama3 {
+                factor,
+                low_freq_factor,
+                high_freq_factor,
+                original_max_seq_len,
+            } => Self {
+                rope_type: Some("llama3".into()),
+                factor: Some(factor),
+                original_max_position_embeddings: Some(original_max_seq_len as _),
+                low_freq_factor: Some(low_freq_factor),
+                high_freq_factor: Some(high_freq_factor),
+                ..Default::default()
+            },
+        }
+    }
+}
+
 impl ConfigJson {
+    /// 滑动窗口注意力的配置，与 transformers 相同，`use_sliding_window` 为 `false` 时不使用滑动窗口。
+    ///
+    /// Mistral 等模型的配置没有 `use_sliding_window`，设置了 `sliding_window` 即使用滑动窗口；
+    /// Qwen2 等模型的配置总是包含 `use_sliding_window`，并以 `max_window_layers` 指定使用全局注意力的层。
+    /// Gemma-3 以 `sliding_window_pattern` 指定周期，Gemma-2 的配置没有周期，固定为局部层与全局层逐层交替。
+    pub fn sliding_window(&self) -> Option<SlidingWindow> {
+        if self.use_sliding_window == Some(false) {
+            return None;
+        }
+        let pattern = match self.sliding_window_pattern {
+            Some(p) => Some(p),
+            None if self.model_type.as_deref() == Some("gemma2") => Some(2),
+            None => None,
+        };
+        self.sliding_window.map(|size| SlidingWindow {
+            size: size as _,
+            pattern: pattern.map(|p| p as _),
+            full_layers: self.max_window_layers.unwrap_or(0) as _,
+        })
+    }
+
+    /// 按 `rope_type` 构造旋转位置编码的缩放方式，缺省的参数取 transformers 的默认值。
+    ///
+    /// 没有 `rope_type` 时使用 `type`，不支持的类型返回错误。
+    /// `dynamic` 的缩放随当前序列长度变化，不能预先计算为固定的频率，同样返回错误。
+    pub fn rope_scaling(&self) -> Result<Option<RopeScaling>, FileLoadError> {
+        let Some(json) = &self.rope_scaling else {
+            return Ok(None);
+        };
+        let Some(ty) = json.rope_type.as_ref().or(json.ty.as_ref()) else {
+            return Ok(None);
+        };
+        let factor = json.factor.unwrap_or(1.);
+        let original_max_seq_len = json
+            .original_max_position_embeddings
+            .unwrap_or(self.max_position_embeddings) as _;
+        let scaling = match ty.as_str() {
+            "default" => None,
+            "linear" => Some(RopeScaling::Linear { factor }),
+            "yarn" => Some(RopeScaling::Yarn {
+                factor,
+                original_max_seq_len,
+                beta_fast: json.beta_fast.unwrap_or(32.),
+                beta_slow: json.beta_slow.unwrap_or(1.),
+                // 与 transformers 相同，不放大时不缩放注意力
+                attention_factor: json.attention_factor.unwrap_or_else(|| {
+                    if factor <= 1. {
+                        1.
+                    } else {
+                        0.1 * factor.ln() + 1.
+                    }
+                }),
+            }),
+            "llama3" => Some(RopeScaling::Llama3 {
+                factor,
+                low_freq_factor: json.low_freq_factor.unwrap_or(1.),
+                high_freq_factor: json.high_freq_factor.unwrap_or(4.),
+                original_max_seq_len,
+            }),
+            _ => return Err(FileLoadError::UnsupportedRopeScaling(ty.clone())),
+        };
+        Ok(scaling)
+    }
+
     pub fn data_layout(&self) -> DigitLayout {
         match self.torch_dtype.as_str() {
             "float16" => F16,
@@ -51,3 +239,208 @@ const fn default_rms_norm_eps() -> f32 {
 const fn default_rope_theta() -> f32 {
     1e4
 }
+
+/// 以一个小模型的配置为基础，用 `fields` 中的字段覆盖或补充后解析。
+#[cfg(test)]
+fn test_config(fields: serde_json::Value) -> ConfigJson {
+    let mut json = serde_json::json!({
+        "bos_token_id": 1,
+        "hidden_size": 64,
+        "intermediate_size": 128,
+        "max_position_embeddings": 4096,
+        "num_attention_heads": 4,
+        "num_hidden_layers": 2,
+        "num_key_value_heads": 4,
+        "vocab_size": 32,
+        "torch_dtype": "float16"
+    });
+    if let serde_json::Value::Object(fields) = fields {
+        json.as_object_mut().unwrap().extend(fields);
+    }
+    serde_json::from_value(json).unwrap()
+}
+
+#[test]
+fn test_rope_scaling() {
+    let parse = |rope_scaling: &str| {
+        let rope_scaling = serde_json::from_str::<serde_json::Value>(rope_scaling).unwrap();
+        test_config(serde_json::json!({ "rope_scaling": rope_scaling }))
+            .rope_scaling()
+            .unwrap()
+    };
+
+    assert_eq!(parse("null"), None);
+    assert_eq!(parse(r#"{"rope_type": "default"}"#), None);
+    assert_eq!(
+        parse(r#"{"rope_type": "linear", "factor": 2.0}"#),
+        Some(RopeScaling::Linear { factor: 2. })
+    );
+    // 旧版本的配置以 `type` 表示类型
+    assert_eq!(
+        parse(r#"{"type": "linear", "factor": 4.0}"#),
+        Some(RopeScaling::Linear { factor: 4. })
+    );
+    // 同时存在时以 `rope_type` 为准
+    assert_eq!(
+        parse(r#"{"type": "linear", "rope_type": "llama3", "factor": 8.0}"#),
+        Some(RopeScaling::Llama3 {
+            factor: 8.,
+            low_freq_factor: 1.,
+            high_freq_factor: 4.,
+            original_max_seq_len: 4096,
+        })
+    );
+    assert_eq!(
+        parse(r#"{"rope_type": "yarn", "factor": 4.0, "original_max_position_embeddings": 1024}"#),
+        Some(RopeScaling::Yarn {
+            factor: 4.,
+            original_max_seq_len: 1024,
+            beta_fast: 32.,
+            beta_slow: 1.,
+            attention_factor: 0.1 * 4f32.ln() + 1.,
+        })
+    );
+    // 不放大时注意力不缩放
+    for factor in ["0.5", "1.0"] {
+        let json = format!(r#"{{"rope_type": "yarn", "factor": {factor}}}"#);
+        assert!(matches!(
+            parse(&json),
+            Some(RopeScaling::Yarn { attention_factor, .. }) if attention_factor == 1.
+        ));
+    }
+    assert_eq!(
+        parse(
+            r#"{
+                "rope_type": "llama3",
+                "factor": 8.0,
+                "low_freq_factor": 1.0,
+                "high_freq_factor": 4.0,
+                "original_max_position_embeddings": 8192
+            }"#
+        ),
+        Some(RopeScaling::Llama3 {
+            factor: 8.,
+            low_freq_factor: 1.,
+            high_freq_factor: 4.,
+            original_max_seq_len: 8192,
+        })
+    );
+
+    // 保存后重新解析得到相同的缩放方式
+    let scaling = RopeScaling::Yarn {
+        factor: 4.,
+        original_max_seq_len: 2048,
+        beta_fast: 16.,
+        beta_slow: 2.,
+        attention_factor: 1.5,
+    };
+    let json = serde_json::to_string(&RopeScalingJson::from(scaling)).unwrap();
+    assert_eq!(parse(&json), Some(scaling));
+}
+
+#[test]
+fn test_unsupported_rope_scaling() {
+    let config = |ty: &str| {
+        test_config(serde_json::json!({ "rope_scaling": { "rope_type": ty, "type": ty } }))
+    };
+    // 不支持的类型返回错误而不是让加载崩溃
+    assert!(matches!(
+        config("longrope").rope_scaling(),
+        Err(FileLoadError::UnsupportedRopeScaling(ty)) if ty == "longrope"
+    ));
+    // 随序列长度变化的 NTK 缩放同样不支持
+    assert!(matches!(
+        config("dynamic").rope_scaling(),
+        Err(FileLoadError::UnsupportedRopeScaling(ty)) if ty == "dynamic"
+    ));
+}
+
+#[test]
+fn test_sliding_window() {
+    use serde_json::json;
+
+    let parse = |fields| test_config(fields).sliding_window();
+
+    // Mistral 的配置没有 `use_sliding_window`
+    assert_eq!(
+        parse(json!({ "sliding_window": 4096 })),
+        Some(SlidingWindow {
+            size: 4096,
+            pattern: None,
+            full_layers: 0,
+        })
+    );
+    // Qwen2 默认不使用滑动窗口
+    assert_eq!(
+        parse(
+            json!({ "use_sliding_window": false, "sliding_window": 4096, "max_window_layers": 28 })
+        ),
+        None
+    );
+    assert_eq!(
+        parse(
+            json!({ "use_sliding_window": true, "sliding_window": 4096, "max_window_layers": 1 })
+        ),
+        Some(SlidingWindow {
+            size: 4096,
+            pattern: None,
+            full_layers: 1,
+        })
+    );
+}
+
+#[test]
+fn test_gemma_sliding_window() {
+    // gemma-3-1b-it 的 config.json，每 6 层的最后一层使用全局注意力
+    let gemma3 = r#"{
+        "architectures": ["Gemma3ForCausalLM"],
+        "attention_bias": false,
+        "attention_dropout": 0.0,
+        "attn_logit_softcapping": null,
+        "bos_token_id": 2,
+        "cache_implementation": "hybrid",
+        "eos_token_id": [1, 106],
+        "final_logit_softcapping": null,
+        "head_dim": 256,
+        "hidden_activation": "gelu_pytorch_tanh",
+        "hidden_size": 1152,
+        "initializer_range": 0.02,
+        "intermediate_size": 6912,
+        "max_position_embeddings": 32768,
+        "model_type": "gemma3_text",
+        "num_attention_heads": 4,
+        "num_hidden_layers": 26,
+        "num_key_value_heads": 1,
+        "pad_token_id": 0,
+        "query_pre_attn_scalar": 256,
+        "rms_norm_eps": 1e-06,
+        "rope_local_base_freq": 10000,
+        "rope_scaling": null,
+        "rope_theta": 1000000,
+        "sliding_window": 512,
+        "sliding_window_pattern": 6,
+        "torch_dtype": "bfloat16",
+        "transformers_version": "4.50.0.dev0",
+        "use_cache": true,
+        "vocab_size": 262144
+    }"#;
+    let config = serde_json::from_str::<ConfigJson>(gemma3).unwrap();
+    assert_eq!(config.eos_token_id, Some(1));
+    let window = config.sliding_window().unwrap();
+    let global = (0..config.num_hidden_layers)
+        .filter(|&layer| window.window(layer).is_none())
+        .collect::<Vec<_>>();
+    assert_eq!(global, [5, 11, 17, 23]);
+    assert_eq!(window.window(0), Some(512));
+
+    // Gemma-2 的配置没有周期，奇数层使用全局注意力
+    let gemma2 = test_config(serde_json::json!({
+        "model_type": "gemma2",
+        "num_hidden_layers": 4,
+        "sliding_window": 4096,
+        "cache_implementation": "hybrid"
+    }));
+    let window = gemma2.sliding_window().unwrap();
+    let windows = (0..4).map(|layer| window.window(layer)).collect::<Vec<_>>();
+    assert_eq!(windows, [Some(4096), None, Some(4096), None]);
+}
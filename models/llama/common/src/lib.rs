@@ -2,16 +2,24 @@ mod cast;
 mod compute;
 mod json;
 mod load;
+mod rope;
 mod save;
 
+use causal_lm::ModelInfo;
 use common::{safe_tensors::SharedTensor, upos, utok, Blob};
 use digit_layout::DigitLayout;
 use std::{ops::Deref, sync::Arc};
 use tensor::{slice, udim, Tensor};
 
-pub use common_devices::SliceOn;
-pub use compute::{ComputeConst, ComputeStream, LLamaLayer};
+pub use common_devices::{AttentionMask, SliceOn};
+pub use compute::{
+    attention_start, check_pruned_heads, head_norm, in_window, window_masked, ComputeConst,
+    ComputeStream, ForwardArgs, ForwardError, LLamaLayer, Projection, SlidingWindow,
+};
+pub use load::{load_int4, SavedInt4};
 pub use operators::{Handle, QueueOf};
+pub use rope::RopeScaling;
+pub use save::Int4Tensors;
 
 pub struct Storage {
     pub config: InferenceConfig,
@@ -22,6 +30,16 @@ pub struct Storage {
     pub lm_head: Tensor<Weight>,
 }
 
+impl Storage {
+    /// 只保留前 `n` 层，得到结构完整但输出没有意义的模型，用于快速的冒烟测试。
+    ///
+    /// `n` 不小于层数时不改变模型。
+    pub fn truncate_layers(&mut self, n: usize) {
+        self.layers.truncate(n);
+        self.config.nlayers = self.layers.len() as _;
+    }
+}
+
 pub struct LayerStorage<T> {
     pub att_layernorm: Tensor<T>,
     pub att_qkv: Tensor<T>,
@@ -29,14 +47,20 @@ pub struct LayerStorage<T> {
     pub mlp_layernorm: Tensor<T>,
     pub mlp_gate_up: Tensor<T>,
     pub mlp_down: Tensor<T>,
+    /// QK-Norm 的 q 归一化权重，只有部分模型具有。
+    pub att_q_norm: Option<Tensor<T>>,
+    /// QK-Norm 的 k 归一化权重，只有部分模型具有。
+    pub att_k_norm: Option<Tensor<T>>,
 }
 
 impl<T> LayerStorage<T> {
     pub fn map<U>(&self, mut f: impl FnMut(&T) -> U) -> LayerStorage<U> {
         macro_rules! map {
-            ($($ident:ident)+) => {
+            ($($ident:ident)+; $($optional:ident)+) => {
                 LayerStorage {$(
                     $ident: self.$ident.as_ref().map_physical(&mut f),
+                )+$(
+                    $optional: self.$optional.as_ref().map(|t| t.as_ref().map_physical(&mut f)),
                 )+}
             };
         }
@@ -47,8 +71,75 @@ impl<T> LayerStorage<T> {
             mlp_layernorm
             mlp_gate_up
             mlp_down
+            ;
+            att_q_norm
+            att_k_norm
+        }
+    }
+}
+
+impl LayerStorage<Weight> {
+    /// 将层权重复制到内存中常驻，计算时不再从文件映射按需加载。
+    pub fn resident(&self) -> Self {
+        self.map(|w| match w {
+            Weight::SafeTensor(tensor) => {
+                let mut blob = Blob::new(tensor.len());
+                blob.copy_from_slice(tensor);
+                blob.into()
+            }
+            Weight::Blob(_) => w.clone(),
+        })
+    }
+
+    /// 将矩阵权重转置后连续存储到内存中，计算时不再通过步长访问转置的权重。
+    ///
+    /// 归一化权重不受影响。
+    pub fn pretransposed(&self) -> Self {
+        Self {
+            att_layernorm: self.att_layernorm.clone(),
+            att_qkv: contiguous(&self.att_qkv),
+            att_o: contiguous(&self.att_o),
+            mlp_layernorm: self.mlp_layernorm.clone(),
+            mlp_gate_up: contiguous(&self.mlp_gate_up),
+            mlp_down: contiguous(&self.mlp_down),
+            att_q_norm: self.att_q_norm.clone(),
+            att_k_norm: self.att_k_norm.clone(),
         }
     }
+
+    /// 以全零占位替换投影矩阵，释放原来的权重，用于以其他形式（例如量化）保存投影矩阵的后端。
+    ///
+    /// 归一化权重不受影响。
+    pub fn without_projections(&self) -> Self {
+        let zeros = |t: &Tensor<Weight>| zeros(t.data_layout(), t.shape());
+        Self {
+            att_layernorm: self.att_layernorm.clone(),
+            att_qkv: zeros(&self.att_qkv),
+            att_o: zeros(&self.att_o),
+            mlp_layernorm: self.mlp_layernorm.clone(),
+            mlp_gate_up: zeros(&self.mlp_gate_up),
+            mlp_down: zeros(&self.mlp_down),
+            att_q_norm: self.att_q_norm.clone(),
+            att_k_norm: self.att_k_norm.clone(),
+        }
+    }
+}
+
+/// 形状为 `shape` 的全零占位，所有元素共享同一个零，不随形状大小占用内存，不能用于计算。
+pub fn zeros(dt: DigitLayout, shape: &[udim]) -> Tensor<Weight> {
+    let mut zero = Tensor::alloc(dt, &[1], Blob::new);
+    zero.physical_mut().fill(0);
+    zero.broadcast(shape).map_physical(Weight::from)
+}
+
+/// 将权重按逻辑形状连续存储到内存中，已经连续的权重不复制。
+pub fn contiguous(t: &Tensor<Weight>) -> Tensor<Weight> {
+    if t.is_contiguous() {
+        return t.clone();
+    }
+    let mut ans = Tensor::alloc(t.data_layout(), t.shape(), Blob::new);
+    t.reform_to(&mut ans);
+    ans.map_physical(Weight::from)
 }
 
 #[derive(Clone, Debug)]
@@ -66,23 +157,59 @@ pub struct InferenceConfig {
     pub eos_token: utok,
     pub epsilon: f32,
     pub theta: f32,
+    /// 注意力分数的软截断上限。
+    pub attn_logit_softcap: Option<f32>,
+    /// 输出 logits 的软截断上限。
+    pub final_logit_softcap: Option<f32>,
+    /// 滑动窗口注意力。
+    pub sliding_window: Option<SlidingWindow>,
+    /// 旋转位置编码的缩放，目前只有 CPU 后端支持，其他后端加载配置了缩放的模型时返回错误。
+    pub rope_scaling: Option<RopeScaling>,
 }
 
 impl InferenceConfig {
+    #[inline]
     pub fn new_cache<S>(&self, f: impl FnOnce(usize) -> S) -> Tensor<S> {
+        self.new_cache_with_capacity(self.max_seq_len, f)
+    }
+
+    /// 创建可以容纳 `capacity` 个位置的缓存。
+    pub fn new_cache_with_capacity<S>(
+        &self,
+        capacity: udim,
+        f: impl FnOnce(usize) -> S,
+    ) -> Tensor<S> {
         Tensor::alloc(
             self.dt,
-            &[
-                self.nlayers,
-                2,
-                self.nkvh,
-                self.max_seq_len,
-                self.d / self.nh,
-            ],
+            &[self.nlayers, 2, self.nkvh, capacity, self.d / self.nh],
             f,
         )
     }
 
+    /// 模型结构信息。
+    pub fn architecture(&self) -> ModelInfo {
+        ModelInfo {
+            nlayers: self.nlayers as _,
+            nh: self.nh as _,
+            nkvh: self.nkvh as _,
+            d: self.d as _,
+            di: self.di as _,
+            voc: self.voc as _,
+            max_seq_len: self.max_seq_len as _,
+            moe: None,
+        }
+    }
+
+    /// 缓存 `len` 个 token 所需的字节数。
+    pub fn cache_bytes(&self, len: upos) -> usize {
+        let dh = self.d / self.nh;
+        [self.nlayers, 2, self.nkvh, len, dh]
+            .iter()
+            .map(|&d| d as usize)
+            .product::<usize>()
+            * self.dt.nbytes()
+    }
+
     pub fn duplicate_cache<S>(
         cache: &Tensor<S>,
         pos: upos,
@@ -128,6 +255,14 @@ impl From<Blob> for Weight {
     }
 }
 
+impl Weight {
+    /// 权重是否常驻内存。
+    #[inline]
+    pub fn is_resident(&self) -> bool {
+        matches!(self, Self::Blob(_))
+    }
+}
+
 impl Deref for Weight {
     type Target = [u8];
     #[inline]
@@ -138,3 +273,39 @@ impl Deref for Weight {
         }
     }
 }
+
+#[test]
+fn test_cache_bytes() {
+    use digit_layout::types::F16;
+
+    let config = InferenceConfig {
+        dt: F16,
+        voc: 32000,
+        nlayers: 22,
+        nh: 32,
+        nkvh: 4,
+        d: 2048,
+        dkv: 256,
+        di: 5632,
+        max_seq_len: 2048,
+        bos_token: 1,
+        eos_token: 2,
+        epsilon: 1e-5,
+        theta: 1e4,
+        attn_logit_softcap: None,
+        final_logit_softcap: None,
+        sliding_window: None,
+        rope_scaling: None,
+    };
+    let cache = config.new_cache(|len| len);
+    assert_eq!(cache.bytes_size(), *cache.physical());
+    assert_eq!(config.cache_bytes(config.max_seq_len), *cache.physical());
+    assert_eq!(config.cache_bytes(1024) * 2, *cache.physical());
+
+    let info = config.architecture();
+    assert_eq!(info.nlayers, 22);
+    assert_eq!((info.nh, info.nkvh), (32, 4));
+    assert_eq!((info.d, info.di), (2048, 5632));
+    assert_eq!((info.voc, info.max_seq_len), (32000, 2048));
+    assert_eq!(info.moe, None);
+}
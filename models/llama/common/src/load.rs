@@ -1,18 +1,54 @@
-﻿use crate::{json::ConfigJson, InferenceConfig, LayerStorage, Storage, Weight};
+﻿use crate::{
+    json::{ConfigJson, INT4_QUANT_METHOD},
+    zeros, InferenceConfig, LayerStorage, Storage, Weight,
+};
 use common::{
+    f16,
     safe_tensors::{Dtype, SafeTensors},
     Blob,
-    FileLoadError::{self, Io, Json},
+    FileLoadError::{self, InvalidTensor, Io, Json},
 };
-use digit_layout::DigitLayout;
+use digit_layout::{types::F16, DigitLayout};
 use std::{fs::File, path::Path, pin::Pin, sync::Arc};
-use tensor::{udim, Shape, Tensor};
+use tensor::{reslice_mut, udim, Shape, Tensor};
 
 impl Storage {
+    /// 加载模型，[`save_int4`](Self::save_int4) 保存的量化的投影矩阵反量化为 f16。
+    #[inline]
     pub fn load_safetensors(model_dir: impl AsRef<Path>) -> Result<Self, FileLoadError> {
-        let config = File::open(model_dir.as_ref().join("config.json")).map_err(Io)?;
+        Self::load(model_dir.as_ref(), false).map(|(storage, _)| storage)
+    }
+
+    /// 加载模型，[`save_int4`](Self::save_int4) 保存的量化的投影矩阵不反量化，同时返回打包的权重所在的文件，由后端直接读取。
+    ///
+    /// 此时 `layers` 中量化的投影矩阵是形状正确的全零占位，不占用内存，也不能用于计算。
+    /// 没有量化的模型返回 `None`，与 [`load_safetensors`](Self::load_safetensors) 相同。
+    #[inline]
+    pub fn load_safetensors_packed(
+        model_dir: impl AsRef<Path>,
+    ) -> Result<(Self, Option<Pin<Arc<SafeTensors>>>), FileLoadError> {
+        Self::load(model_dir.as_ref(), true)
+    }
+
+    fn load(
+        model_dir: &Path,
+        packed: bool,
+    ) -> Result<(Self, Option<Pin<Arc<SafeTensors>>>), FileLoadError> {
+        let config = File::open(model_dir.join("config.json")).map_err(Io)?;
         let config: ConfigJson = serde_json::from_reader(&config).map_err(Json)?;
         let model = SafeTensors::load_from_dir(model_dir)?.share();
+        let quantized = config
+            .quantization_config
+            .as_ref()
+            .is_some_and(|q| q.quant_method == INT4_QUANT_METHOD);
+        let int4 = match (quantized, packed) {
+            (false, _) => Int4::None,
+            (true, false) => Int4::Dequantize,
+            (true, true) => Int4::Packed,
+        };
+        let projection = |model: &Pin<Arc<SafeTensors>>, prefix: &str, dt, shape: [udim; 2]| {
+            load_projection(model, prefix, dt, shape, int4)
+        };
 
         let dt = config.data_layout();
         let voc = config.vocab_size as udim;
@@ -23,7 +59,7 @@ impl Storage {
         let dkv = dh * nkvh;
         let di = config.intermediate_size as udim;
 
-        Ok(Self {
+        let storage = Self {
             config: InferenceConfig {
                 dt,
                 voc,
@@ -35,61 +71,91 @@ impl Storage {
                 di,
                 max_seq_len: config.max_position_embeddings as _,
                 bos_token: config.bos_token_id,
-                eos_token: config.eos_token_id,
+                eos_token: config.eos_token_id.unwrap_or(config.bos_token_id),
                 epsilon: config.rms_norm_eps,
                 theta: config.rope_theta,
+                attn_logit_softcap: config.attn_logit_softcapping,
+                final_logit_softcap: config.final_logit_softcapping,
+                sliding_window: config.sliding_window(),
+                rope_scaling: config.rope_scaling()?,
             },
 
             embed_tokens: tensor(&model, "model.embed_tokens.weight", dt, [voc, d]),
             layers: (0..config.num_hidden_layers)
-                .map(|l| {
+                .map(|l| -> Result<_, FileLoadError> {
                     let name = |name: &str| format!("model.layers.{l}.{name}.weight");
-                    LayerStorage {
+                    let proj = |name: &str| format!("model.layers.{l}.{name}");
+                    // 分离存储的 q/k 投影需要为 rope 重排，归一化权重也随之重排
+                    let permute = !contains_projection(&model, &proj("self_attn.qkv_proj"));
+                    Ok(LayerStorage {
                         att_layernorm: tensor(&model, &name("input_layernorm"), dt, [d]),
                         att_qkv: {
-                            let qkv = name("self_attn.qkv_proj");
-                            if model.contains(&qkv) {
-                                tensor(&model, &qkv, dt, [d + dkv + dkv, d])
+                            let qkv = proj("self_attn.qkv_proj");
+                            if !permute {
+                                projection(&model, &qkv, dt, [d + dkv + dkv, d])?
                             } else {
                                 let sq = &[nh, 2, dh / 2, d];
                                 let skv = &[nkvh, 2, dh / 2, d];
                                 let perm = &[0, 2, 1, 3];
 
-                                let q = tensor(&model, &name("self_attn.q_proj"), dt, [d, d])
+                                let q = projection(&model, &proj("self_attn.q_proj"), dt, [d, d])?
                                     .reshape(sq)
                                     .transpose(perm);
-                                let k = tensor(&model, &name("self_attn.k_proj"), dt, [dkv, d])
-                                    .reshape(skv)
-                                    .transpose(perm);
-                                let v = tensor(&model, &name("self_attn.v_proj"), dt, [dkv, d])
-                                    .reshape(skv);
+                                let k =
+                                    projection(&model, &proj("self_attn.k_proj"), dt, [dkv, d])?
+                                        .reshape(skv)
+                                        .transpose(perm);
+                                let v =
+                                    projection(&model, &proj("self_attn.v_proj"), dt, [dkv, d])?
+                                        .reshape(skv);
                                 concat0(&[q, k, v]).reshape(&[d + dkv + dkv, d])
                             }
                         }
                         .transpose(&[1, 0]),
-                        att_o: tensor(&model, &name("self_attn.o_proj"), dt, [d, d])
+                        att_o: projection(&model, &proj("self_attn.o_proj"), dt, [d, d])?
                             .transpose(&[1, 0]),
                         mlp_layernorm: tensor(&model, &name("post_attention_layernorm"), dt, [d]),
                         mlp_gate_up: {
-                            let gate_up = name("mlp.gate_up_proj");
-                            if model.contains(&gate_up) {
-                                tensor(&model, &gate_up, dt, [di + di, d])
+                            let gate_up = proj("mlp.gate_up_proj");
+                            if contains_projection(&model, &gate_up) {
+                                projection(&model, &gate_up, dt, [di + di, d])?
                             } else {
                                 concat0(&[
-                                    tensor(&model, &name("mlp.gate_proj"), dt, [di, d]),
-                                    tensor(&model, &name("mlp.up_proj"), dt, [di, d]),
+                                    projection(&model, &proj("mlp.gate_proj"), dt, [di, d])?,
+                                    projection(&model, &proj("mlp.up_proj"), dt, [di, d])?,
                                 ])
                             }
                         }
                         .transpose(&[1, 0]),
-                        mlp_down: tensor(&model, &name("mlp.down_proj"), dt, [d, di])
+                        mlp_down: projection(&model, &proj("mlp.down_proj"), dt, [d, di])?
                             .transpose(&[1, 0]),
-                    }
+                        att_q_norm: head_norm(&model, &name("self_attn.q_norm"), dt, dh, permute),
+                        att_k_norm: head_norm(&model, &name("self_attn.k_norm"), dt, dh, permute),
+                    })
                 })
-                .collect(),
+                .collect::<Result<_, _>>()?,
             lm_layernorm: tensor(&model, "model.norm.weight", dt, [d]),
-            lm_head: tensor(&model, "lm_head.weight", dt, [voc, d]).transpose(&[1, 0]),
-        })
+            lm_head: {
+                // 词嵌入与输出层绑定的模型不单独存储 lm_head
+                let name = if model.contains("lm_head.weight") {
+                    "lm_head.weight"
+                } else {
+                    "model.embed_tokens.weight"
+                };
+                tensor(&model, name, dt, [voc, d]).transpose(&[1, 0])
+            },
+        };
+        Ok((storage, (int4 == Int4::Packed).then_some(model)))
+    }
+
+    /// 不支持旋转位置编码缩放的后端在部署前调用，模型配置了缩放时返回错误，避免静默地得到错误的输出。
+    pub fn check_rope_scaling(&self) -> Result<(), FileLoadError> {
+        match self.config.rope_scaling {
+            Some(scaling) => Err(FileLoadError::UnsupportedRopeScaling(format!(
+                "{scaling:?}"
+            ))),
+            None => Ok(()),
+        }
     }
 }
 
@@ -110,6 +176,156 @@ fn tensor<const N: usize>(
     Tensor::new(dt, &shape, Weight::SafeTensor(shared))
 }
 
+/// 量化保存的投影矩阵的加载方式。
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Int4 {
+    /// 模型没有量化。
+    None,
+    /// 反量化为 f16。
+    Dequantize,
+    /// 不反量化，以全零占位。
+    Packed,
+}
+
+/// 是否存在名为 `prefix` 的投影矩阵，原始的或量化的。
+fn contains_projection(model: &SafeTensors, prefix: &str) -> bool {
+    model.contains(&format!("{prefix}.weight")) || model.contains(&format!("{prefix}.qweight"))
+}
+
+/// [`save_int4`](Storage::save_int4) 保存的一个投影矩阵，逻辑形状为 `[k, n]`，张量的含义见 [`Int4Tensors`](crate::Int4Tensors)。
+pub struct SavedInt4<'a> {
+    pub k: usize,
+    pub n: usize,
+    pub group: usize,
+    pub qweight: &'a [u8],
+    pub qzeros: &'a [u8],
+    pub scales: &'a [u8],
+}
+
+/// 读取名为 `prefix` 的量化保存的投影矩阵，检查数据类型和形状，没有量化保存时返回 `None`。
+pub fn load_int4<'a>(
+    model: &'a SafeTensors,
+    prefix: &str,
+) -> Result<Option<SavedInt4<'a>>, FileLoadError> {
+    if !model.contains(&format!("{prefix}.qweight")) {
+        return Ok(None);
+    }
+    let get = |suffix: &str, dtype: Dtype| {
+        let name = format!("{prefix}.{suffix}");
+        match model.get(&name) {
+            Some(t) if t.dtype == dtype => Ok(t),
+            Some(t) => Err(InvalidTensor(format!(
+                "{name} must be {dtype:?}, found {:?}",
+                t.dtype
+            ))),
+            None => Err(InvalidTensor(format!("missing tensor: {name}"))),
+        }
+    };
+    let qweight = get("qweight", Dtype::I32)?;
+    let qzeros = get("qzeros", Dtype::I32)?;
+    let scales = get("scales", Dtype::F16)?;
+    let invalid = || {
+        InvalidTensor(format!(
+            "invalid int4 shapes of {prefix}: qweight {:?}, qzeros {:?}, scales {:?}",
+            qweight.shape, qzeros.shape, scales.shape
+        ))
+    };
+    let (&[k8, n], &[groups, n_]) = (qweight.shape, scales.shape) else {
+        return Err(invalid());
+    };
+    let k = k8 * 8;
+    if groups == 0
+        || n_ != n
+        || n % 8 != 0
+        || k % groups != 0
+        || k / groups % 8 != 0
+        || qzeros.shape != [groups, n / 8]
+    {
+        return Err(invalid());
+    }
+    Ok(Some(SavedInt4 {
+        k,
+        n,
+        group: k / groups,
+        qweight: qweight.data,
+        qzeros: qzeros.data,
+        scales: scales.data,
+    }))
+}
+
+/// 加载名为 `prefix` 的形状为 `[n, k]` 的投影矩阵，4 位分组量化保存的矩阵按 `int4` 反量化或占位。
+fn load_projection(
+    model: &Pin<Arc<SafeTensors>>,
+    prefix: &str,
+    dt: DigitLayout,
+    shape: [udim; 2],
+    int4: Int4,
+) -> Result<Tensor<Weight>, FileLoadError> {
+    let saved = match int4 {
+        Int4::None => None,
+        _ => load_int4(model, prefix)?,
+    };
+    let Some(saved) = saved else {
+        return Ok(tensor(model, &format!("{prefix}.weight"), dt, shape));
+    };
+    if dt != F16 {
+        return Err(InvalidTensor(format!(
+            "quantized model must be f16, found {dt:?}"
+        )));
+    }
+    let [n, k] = shape.map(|d| d as usize);
+    if [saved.n, saved.k] != [n, k] {
+        return Err(InvalidTensor(format!(
+            "{prefix} must be [{n}, {k}], found [{}, {}]",
+            saved.n, saved.k
+        )));
+    }
+    if int4 == Int4::Packed {
+        return Ok(zeros(F16, &shape));
+    }
+    let SavedInt4 {
+        group,
+        qweight,
+        qzeros,
+        scales,
+        ..
+    } = saved;
+
+    // 文件中的数据不保证对齐，逐个读取
+    let word = |data: &[u8], i: usize| u32::from_le_bytes(data[i * 4..][..4].try_into().unwrap());
+    let scale = |i: usize| f16::from_le_bytes(scales[i * 2..][..2].try_into().unwrap());
+    let mut ans = Tensor::alloc(F16, &shape, Blob::new);
+    let data: &mut [f16] = reslice_mut(ans.physical_mut());
+    for i in 0..k {
+        let g = i / group;
+        for j in 0..n {
+            let q = (word(qweight, i / 8 * n + j) >> (i % 8 * 4)) & 0xf;
+            let zero = (word(qzeros, g * n / 8 + j / 8) >> (j % 8 * 4)) & 0xf;
+            let x = (q as f32 - zero as f32) * scale(g * n + j).to_f32();
+            data[j * k + i] = f16::from_f32(x);
+        }
+    }
+    Ok(ans.map_physical(Weight::from))
+}
+
+fn head_norm(
+    model: &Pin<Arc<SafeTensors>>,
+    name: &str,
+    dt: DigitLayout,
+    dh: udim,
+    permute: bool,
+) -> Option<Tensor<Weight>> {
+    if !model.contains(name) {
+        return None;
+    }
+    let w = tensor(model, name, dt, [dh]);
+    Some(if permute {
+        concat0(&[w.reshape(&[2, dh / 2]).transpose(&[1, 0])]).reshape(&[dh])
+    } else {
+        w
+    })
+}
+
 fn concat0(tensors: &[Tensor<Weight>]) -> Tensor<Weight> {
     assert!(tensors
         .windows(2)
@@ -162,3 +378,112 @@ fn test_load() {
         println!("load: {:?}", time.elapsed());
     };
 }
+
+#[test]
+fn test_separate_projections() {
+    use common::{f16, safe_tensors::write_safetensors};
+    use digit_layout::types::F16;
+    use tensor::{reslice, reslice_mut};
+
+    let (voc, nh, nkvh, dh, di) = (8, 2, 1, 4, 6);
+    let (d, dkv) = (nh * dh, nkvh * dh);
+    // 每个元素取不同的值，便于检查拼接和重排的位置
+    let mut next = 0;
+    let mut weight = |shape: &[udim]| {
+        let mut t = Tensor::alloc(F16, shape, Blob::new);
+        for x in reslice_mut::<u8, f16>(t.physical_mut()) {
+            *x = f16::from_f32(next as _);
+            next += 1;
+        }
+        t.map_physical(Weight::from)
+    };
+    let embed_tokens = weight(&[voc, d]);
+    let att_layernorm = weight(&[d]);
+    let q = weight(&[d, d]);
+    let k = weight(&[dkv, d]);
+    let v = weight(&[dkv, d]);
+    let o = weight(&[d, d]);
+    let mlp_layernorm = weight(&[d]);
+    let gate = weight(&[di, d]);
+    let up = weight(&[di, d]);
+    let down = weight(&[d, di]);
+    let lm_layernorm = weight(&[d]);
+    let lm_head = weight(&[voc, d]);
+
+    // 先保存融合的权重以生成配置，再用 HF 格式分离存储的权重覆盖
+    let storage = Storage {
+        config: InferenceConfig {
+            dt: F16,
+            voc,
+            nlayers: 1,
+            nh,
+            nkvh,
+            d,
+            dkv,
+            di,
+            max_seq_len: 16,
+            bos_token: 1,
+            eos_token: 2,
+            epsilon: 1e-5,
+            theta: 1e4,
+            attn_logit_softcap: None,
+            final_logit_softcap: None,
+            sliding_window: None,
+            rope_scaling: None,
+        },
+        embed_tokens: embed_tokens.clone(),
+        layers: vec![LayerStorage {
+            att_layernorm: att_layernorm.clone(),
+            att_qkv: concat0(&[q.clone(), k.clone(), v.clone()]).transpose(&[1, 0]),
+            att_o: o.clone().transpose(&[1, 0]),
+            mlp_layernorm: mlp_layernorm.clone(),
+            mlp_gate_up: concat0(&[gate.clone(), up.clone()]).transpose(&[1, 0]),
+            mlp_down: down.clone().transpose(&[1, 0]),
+            att_q_norm: None,
+            att_k_norm: None,
+        }],
+        lm_layernorm: lm_layernorm.clone(),
+        lm_head: lm_head.clone().transpose(&[1, 0]),
+    };
+    let dir = std::env::temp_dir().join("llama-test-separate-projections");
+    storage.save(&dir).unwrap();
+    let name = |name: &str| format!("model.layers.0.{name}.weight");
+    #[rustfmt::skip]
+    let tensors = [
+        ("model.embed_tokens.weight".into()  , embed_tokens),
+        (name("input_layernorm")             , att_layernorm),
+        (name("self_attn.q_proj")            , q.clone()),
+        (name("self_attn.k_proj")            , k.clone()),
+        (name("self_attn.v_proj")            , v.clone()),
+        (name("self_attn.o_proj")            , o),
+        (name("post_attention_layernorm")    , mlp_layernorm),
+        (name("mlp.gate_proj")               , gate.clone()),
+        (name("mlp.up_proj")                 , up.clone()),
+        (name("mlp.down_proj")               , down),
+        ("model.norm.weight".into()          , lm_layernorm),
+        ("lm_head.weight".into()             , lm_head),
+    ];
+    write_safetensors(dir.join("model.safetensors"), &tensors).unwrap();
+    let loaded = Storage::load_safetensors(&dir).unwrap();
+
+    // q、k 每个头内的行为 rope 交错重排，v 保持原样
+    let rows = |t: &Tensor<Weight>, heads: udim| {
+        let data = reslice::<u8, f16>(t.physical());
+        (0..heads * dh)
+            .map(|r| {
+                let (h, i) = (r / dh, r % dh);
+                h * dh + i % 2 * (dh / 2) + i / 2
+            })
+            .flat_map(|r| data[(r * d) as usize..][..d as usize].to_vec())
+            .collect::<Vec<_>>()
+    };
+    let mut expected = rows(&q, nh);
+    expected.extend(rows(&k, nkvh));
+    expected.extend_from_slice(reslice::<u8, f16>(v.physical()));
+
+    let qkv = loaded.layers[0].att_qkv.clone().transpose(&[1, 0]);
+    assert_eq!(qkv.shape(), [d + dkv + dkv, d]);
+    assert_eq!(reslice::<u8, f16>(qkv.physical()), expected);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,142 @@
+use std::f32::consts::PI;
+use tensor::udim;
+
+/// 旋转位置编码的缩放方式，用于扩展模型的上下文长度。
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RopeScaling {
+    /// 位置线性插值，所有频率除以 `factor`。
+    Linear { factor: f32 },
+    /// YaRN，按波长分段插值，编码整体乘以 `attention_factor`。
+    Yarn {
+        factor: f32,
+        original_max_seq_len: udim,
+        beta_fast: f32,
+        beta_slow: f32,
+        attention_factor: f32,
+    },
+    /// Llama 3 的分段插值，低频插值、高频保持，中间平滑过渡。
+    Llama3 {
+        factor: f32,
+        low_freq_factor: f32,
+        high_freq_factor: f32,
+        original_max_seq_len: udim,
+    },
+}
+
+/// 维度为 `dh` 的头在 `theta` 下未缩放的各个频率，长度为 `dh / 2`。
+fn rope_freqs(theta: f32, dh: usize) -> Vec<f32> {
+    (0..dh / 2)
+        .map(|k| theta.powf(-((2 * k) as f32) / dh as f32))
+        .collect()
+}
+
+impl RopeScaling {
+    /// 维度为 `dh` 的头在 `theta` 下缩放后的各个频率，长度为 `dh / 2`。
+    pub fn freqs(&self, theta: f32, dh: usize) -> Vec<f32> {
+        match *self {
+            Self::Linear { factor } => rope_freqs(theta, dh)
+                .into_iter()
+                .map(|f| f / factor)
+                .collect(),
+            Self::Yarn {
+                factor,
+                original_max_seq_len,
+                beta_fast,
+                beta_slow,
+                ..
+            } => {
+                let dh_ = dh as f32;
+                // 在原始长度内旋转 `n` 圈的维度
+                let dim = |n: f32| {
+                    dh_ * (original_max_seq_len as f32 / (n * 2. * PI)).ln() / (2. * theta.ln())
+                };
+                let low = dim(beta_fast).floor().max(0.);
+                let mut high = dim(beta_slow).ceil().min(dh_ - 1.);
+                if low == high {
+                    high += 1e-3;
+                }
+                rope_freqs(theta, dh)
+                    .into_iter()
+                    .enumerate()
+                    .map(|(k, f)| {
+                        // 高频部分保持不变，低频部分插值
+                        let ramp = ((k as f32 - low) / (high - low)).clamp(0., 1.);
+                        f / factor * ramp + f * (1. - ramp)
+                    })
+                    .collect()
+            }
+            Self::Llama3 {
+                factor,
+                low_freq_factor,
+                high_freq_factor,
+                original_max_seq_len,
+            } => {
+                let original = original_max_seq_len as f32;
+                let low_freq_wavelen = original / low_freq_factor;
+                let high_freq_wavelen = original / high_freq_factor;
+                rope_freqs(theta, dh)
+                    .into_iter()
+                    .map(|f| {
+                        let wavelen = 2. * PI / f;
+                        if wavelen < high_freq_wavelen {
+                            f
+                        } else if wavelen > low_freq_wavelen {
+                            f / factor
+                        } else {
+                            let smooth = (original / wavelen - low_freq_factor)
+                                / (high_freq_factor - low_freq_factor);
+                            (1. - smooth) * f / factor + smooth * f
+                        }
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// 编码的 sin 和 cos 整体的缩放系数。
+    #[inline]
+    pub fn mscale(&self) -> f32 {
+        match *self {
+            Self::Yarn {
+                attention_factor, ..
+            } => attention_factor,
+            _ => 1.,
+        }
+    }
+}
+
+#[test]
+fn test_freqs() {
+    let (theta, dh) = (1e4, 64);
+    let freqs = rope_freqs(theta, dh);
+    assert_eq!(freqs.len(), dh / 2);
+    assert_eq!(freqs[0], 1.);
+
+    let linear = RopeScaling::Linear { factor: 4. }.freqs(theta, dh);
+    for (a, b) in freqs.iter().zip(&linear) {
+        assert!((a / 4. - b).abs() <= 1e-6 * a);
+    }
+
+    // 高频保持不变，低频除以 `factor`
+    let llama3 = RopeScaling::Llama3 {
+        factor: 8.,
+        low_freq_factor: 1.,
+        high_freq_factor: 4.,
+        original_max_seq_len: 64,
+    }
+    .freqs(theta, dh);
+    assert_eq!(llama3[0], freqs[0]);
+    assert!((llama3[dh / 2 - 1] - freqs[dh / 2 - 1] / 8.).abs() <= 1e-9);
+
+    let yarn = RopeScaling::Yarn {
+        factor: 8.,
+        original_max_seq_len: 64,
+        beta_fast: 32.,
+        beta_slow: 1.,
+        attention_factor: 1.2,
+    };
+    let yarn_freqs = yarn.freqs(theta, dh);
+    assert_eq!(yarn_freqs[0], freqs[0]);
+    assert!((yarn_freqs[dh / 2 - 1] - freqs[dh / 2 - 1] / 8.).abs() <= 1e-9);
+    assert_eq!(yarn.mscale(), 1.2);
+}
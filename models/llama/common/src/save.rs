@@ -1,24 +1,46 @@
 ﻿use crate::{
-    json::{data_layout_name, ConfigJson},
+    json::{data_layout_name, ConfigJson, QuantizationJson, INT4_QUANT_METHOD},
     Storage, Weight,
 };
-use common::safe_tensors::{Dtype, SafeTensorsHeader, SafeTensorsHeaderMetadata, TensorInfo};
-use digit_layout::DigitLayout;
-use std::{
-    collections::HashMap,
-    fs,
-    io::{self, BufWriter, Write},
-    path::Path,
-};
+use common::safe_tensors::write_safetensors;
+use std::{fs, io, path::Path};
 use tensor::Tensor;
 
+/// 4 位分组量化的矩阵保存的张量，打包方式与 GPTQ 相似但不兼容。
+///
+/// 逻辑形状为 `[k, n]` 的矩阵每 `group` 行共享一组缩放和零点，
+/// `qweight`、`qzeros` 以 i32 存储，形状分别为 `[k / 8, n]` 和 `[k / group, n / 8]`，
+/// `scales` 以 f16 存储，形状为 `[k / group, n]`。
+pub struct Int4Tensors {
+    pub qweight: Tensor<Weight>,
+    pub qzeros: Tensor<Weight>,
+    pub scales: Tensor<Weight>,
+}
+
 impl Storage {
+    #[inline]
     pub fn save(&self, dir: impl AsRef<Path>) -> io::Result<()> {
-        let dir = dir.as_ref();
+        self.save_with(dir.as_ref(), None)
+    }
+
+    /// 保存 4 位分组量化的模型，每 `group_size` 个输入通道共享一组缩放和零点。
+    ///
+    /// `layers` 依次为每层 qkv、o、gate_up、down 投影矩阵量化的张量，代替原始的权重保存。
+    pub fn save_int4(
+        &self,
+        dir: impl AsRef<Path>,
+        group_size: usize,
+        layers: &[[Int4Tensors; 4]],
+    ) -> io::Result<()> {
+        assert_eq!(layers.len(), self.layers.len());
+        self.save_with(dir.as_ref(), Some((group_size, layers)))
+    }
+
+    fn save_with(&self, dir: &Path, int4: Option<(usize, &[[Int4Tensors; 4]])>) -> io::Result<()> {
         fs::create_dir_all(dir)?;
         let config = serde_json::to_string_pretty(&ConfigJson {
             bos_token_id: self.config.bos_token,
-            eos_token_id: self.config.eos_token,
+            eos_token_id: Some(self.config.eos_token),
             hidden_size: self.config.d as _,
             intermediate_size: self.config.di as _,
             max_position_embeddings: self.config.max_seq_len as _,
@@ -28,103 +50,84 @@ impl Storage {
             vocab_size: self.config.voc as _,
             rms_norm_eps: self.config.epsilon,
             rope_theta: self.config.theta,
+            attn_logit_softcapping: self.config.attn_logit_softcap,
+            final_logit_softcapping: self.config.final_logit_softcap,
+            use_sliding_window: None,
+            sliding_window: self.config.sliding_window.map(|w| w.size as _),
+            sliding_window_pattern: self
+                .config
+                .sliding_window
+                .and_then(|w| w.pattern)
+                .map(|p| p as _),
+            max_window_layers: self
+                .config
+                .sliding_window
+                .map(|w| w.full_layers as _)
+                .filter(|&n| n > 0),
+            rope_scaling: self.config.rope_scaling.map(Into::into),
+            quantization_config: int4.map(|(group_size, _)| QuantizationJson {
+                quant_method: INT4_QUANT_METHOD.into(),
+                bits: 4,
+                group_size,
+            }),
+            model_type: None,
             torch_dtype: data_layout_name(self.config.dt).to_string(),
         })?;
         fs::write(dir.join("config.json"), config)?;
 
-        let mut offset = 0usize;
-        let mut header = SafeTensorsHeader {
-            tensors: HashMap::new(),
-            metadata: SafeTensorsHeaderMetadata {
-                format: "rs".into(),
-            },
-        };
-
-        let mut t = |tensor: &Tensor<Weight>| TensorInfo {
-            dtype: convert(tensor.data_layout()),
-            shape: tensor.shape().iter().map(|&d| d as _).collect(),
-            data_offsets: {
-                let start = offset;
-                offset += tensor.bytes_size();
-                (start, offset)
-            },
-        };
-
-        header
-            .tensors
-            .insert("model.embed_tokens.weight".into(), t(&self.embed_tokens));
+        let mut tensors = vec![(
+            "model.embed_tokens.weight".to_string(),
+            self.embed_tokens.clone(),
+        )];
         for (i, l) in self.layers.iter().enumerate() {
+            let prefix = |name: &str| format!("model.layers.{i}.{name}");
+            let int4 = |k: usize| int4.map(|(_, layers)| &layers[i][k]);
             #[rustfmt::skip]
             let iter = [
-                ("input_layernorm"         , &l.att_layernorm),
-                ("self_attn.qkv_proj"      , &l.att_qkv    .clone().transpose(&[1, 0])),
-                ("self_attn.o_proj"        , &l.att_o      .clone().transpose(&[1, 0])),
-                ("post_attention_layernorm", &l.mlp_layernorm),
-                ("mlp.gate_up_proj"        , &l.mlp_gate_up.clone().transpose(&[1, 0])),
-                ("mlp.down_proj"           , &l.mlp_down   .clone().transpose(&[1, 0])),
+                layer_tensors(prefix("input_layernorm"         ), &l.att_layernorm, None   , false),
+                layer_tensors(prefix("self_attn.qkv_proj"      ), &l.att_qkv      , int4(0), true ),
+                layer_tensors(prefix("self_attn.o_proj"        ), &l.att_o        , int4(1), true ),
+                layer_tensors(prefix("post_attention_layernorm"), &l.mlp_layernorm, None   , false),
+                layer_tensors(prefix("mlp.gate_up_proj"        ), &l.mlp_gate_up  , int4(2), true ),
+                layer_tensors(prefix("mlp.down_proj"           ), &l.mlp_down     , int4(3), true ),
             ];
-            header.tensors.extend(
-                iter.map(|(name, tensor)| (format!("model.layers.{i}.{name}.weight"), t(tensor))),
-            );
+            tensors.extend(iter.into_iter().flatten());
+            for (name, tensor) in [
+                ("self_attn.q_norm", &l.att_q_norm),
+                ("self_attn.k_norm", &l.att_k_norm),
+            ] {
+                if let Some(tensor) = tensor {
+                    tensors.push((format!("model.layers.{i}.{name}.weight"), tensor.clone()));
+                }
+            }
         }
-        header.tensors.extend([
-            ("model.norm.weight".into(), t(&self.lm_layernorm)),
-            (
+        tensors.push(("model.norm.weight".into(), self.lm_layernorm.clone()));
+        // 与词嵌入绑定的 lm_head 不单独保存
+        let tied = self.lm_head.physical().as_ptr() == self.embed_tokens.physical().as_ptr();
+        if !tied {
+            tensors.push((
                 "lm_head.weight".into(),
-                t(&self.lm_head.clone().transpose(&[1, 0])),
-            ),
-        ]);
-
-        let header = {
-            let str = serde_json::to_string(&header)?;
-            let len = str.len();
-            const ALIGN: usize = std::mem::size_of::<usize>();
-            let aligned = (len + ALIGN - 1) & !(ALIGN - 1);
-
-            let mut buffer = Vec::with_capacity(aligned);
-            let mut write = BufWriter::new(&mut buffer);
-            write.write_all(&(aligned as u64).to_le_bytes())?;
-            write.write_all(str.as_bytes())?;
-            for _ in len..aligned {
-                write.write_all(&[32])?;
-            }
-            drop(write);
-            buffer
-        };
-
-        let mut file = fs::File::create(dir.join("model.safetensors"))?;
-        file.write_all(&header)?;
-        file.write_all(self.embed_tokens.physical())?;
-        for l in self.layers.iter() {
-            file.write_all(l.att_layernorm.physical())?;
-            file.write_all(l.att_qkv.physical())?;
-            file.write_all(l.att_o.physical())?;
-            file.write_all(l.mlp_layernorm.physical())?;
-            file.write_all(l.mlp_gate_up.physical())?;
-            file.write_all(l.mlp_down.physical())?;
+                self.lm_head.clone().transpose(&[1, 0]),
+            ));
         }
-        file.write_all(self.lm_layernorm.physical())?;
-        file.write_all(self.lm_head.physical())?;
-        Ok(())
+        write_safetensors(dir.join("model.safetensors"), &tensors)
     }
 }
 
-fn convert(dtype: DigitLayout) -> Dtype {
-    use digit_layout::types::*;
-    match dtype {
-        BOOL => Dtype::BOOL,
-        U8 => Dtype::U8,
-        I8 => Dtype::I8,
-        I16 => Dtype::I16,
-        U16 => Dtype::U16,
-        F16 => Dtype::F16,
-        BF16 => Dtype::BF16,
-        I32 => Dtype::I32,
-        U32 => Dtype::U32,
-        F32 => Dtype::F32,
-        F64 => Dtype::F64,
-        I64 => Dtype::I64,
-        U64 => Dtype::U64,
-        _ => todo!(),
+/// 名为 `prefix` 的权重保存的张量，量化的矩阵保存打包的整数、零点和缩放，其他矩阵按需转置为 `[n, k]`。
+fn layer_tensors(
+    prefix: String,
+    w: &Tensor<Weight>,
+    int4: Option<&Int4Tensors>,
+    transpose: bool,
+) -> Vec<(String, Tensor<Weight>)> {
+    match int4 {
+        Some(q) => vec![
+            (format!("{prefix}.qweight"), q.qweight.clone()),
+            (format!("{prefix}.qzeros"), q.qzeros.clone()),
+            (format!("{prefix}.scales"), q.scales.clone()),
+        ],
+        None if transpose => vec![(format!("{prefix}.weight"), w.clone().transpose(&[1, 0]))],
+        None => vec![(format!("{prefix}.weight"), w.clone())],
     }
 }
@@ -0,0 +1,88 @@
+use crate::{Cache, Transformer};
+use causal_lm::{CacheOverflow, CausalLM, DecodingMeta, QueryContext, SampleArgs, SampleMeta};
+use common::{upos, utok, FileLoadError};
+use common_nv::{cuda::Device, Tensor};
+use std::{path::Path, time::Instant};
+
+/// 预填充与解码分离部署的模型。
+///
+/// 提示词在吞吐量优先的一组设备上预填充，缓存迁移到延迟优先的另一组设备上解码。
+/// 两组设备的数量必须相同。
+pub struct Disaggregated {
+    prefill: Transformer,
+    decode: Transformer,
+}
+
+impl Disaggregated {
+    /// 加载模型，分别部署到预填充设备和解码设备上。
+    pub fn load(
+        model_dir: impl AsRef<Path>,
+        prefill: &[Device],
+        decode: &[Device],
+    ) -> Result<Self, FileLoadError> {
+        let time = Instant::now();
+        let host = llama::Storage::load_safetensors(model_dir)?;
+        host.check_rope_scaling()?;
+        info!("load host: {:?}", time.elapsed());
+        Ok(Self::new(&host, prefill, decode))
+    }
+
+    /// 将模型分别部署到预填充设备和解码设备上。
+    pub fn new(host: &llama::Storage, prefill: &[Device], decode: &[Device]) -> Self {
+        assert_eq!(
+            prefill.len(),
+            decode.len(),
+            "prefill and decode groups must have the same size"
+        );
+        Self {
+            prefill: Transformer::new(host, prefill),
+            decode: Transformer::new(host, decode),
+        }
+    }
+
+    /// 预填充使用的模型。
+    #[inline]
+    pub fn prefill_model(&self) -> &Transformer {
+        &self.prefill
+    }
+
+    /// 解码使用的模型，[`prefill`](Self::prefill) 返回的缓存在这个模型上继续推理。
+    #[inline]
+    pub fn decode_model(&self) -> &Transformer {
+        &self.decode
+    }
+
+    /// 在预填充设备上计算 `tokens` 并按 `args` 采样第一个 token，再把缓存迁移到解码设备上。
+    ///
+    /// `tokens` 超出缓存容量时返回错误。
+    pub fn prefill(
+        &self,
+        tokens: &[utok],
+        args: SampleArgs,
+    ) -> Result<(Tensor<Cache>, utok), CacheOverflow> {
+        let model = &self.prefill;
+        let mut cache = model.new_cache();
+        let queries = [QueryContext {
+            cache: Some(&mut cache),
+            range: 0..tokens.len() as upos,
+        }];
+        let x = model.forward(queries, model.token_embed(tokens.iter().copied()))?;
+        let logits = model.decode(
+            [DecodingMeta {
+                num_query: tokens.len(),
+                num_decode: 1,
+            }],
+            x,
+        );
+        let next = model.sample(
+            [SampleMeta {
+                num_decode: 1,
+                args,
+                history: &[],
+                suppressed: &[],
+            }],
+            logits,
+        );
+        Ok((self.decode.migrate_cache(model, cache), next[0]))
+    }
+}
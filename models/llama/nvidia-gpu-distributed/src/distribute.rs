@@ -59,6 +59,10 @@ impl<'a> Distributer<'a> {
 
         let mut blob = Blob::new(total_size);
         let layer = &self.model.layers[layer];
+        assert!(
+            layer.att_q_norm.is_none() && layer.att_k_norm.is_none(),
+            "QK-Norm is not supported by the distributed model yet"
+        );
         // layernorm
         layer
             .att_layernorm
@@ -1,34 +1,43 @@
 #![cfg(detected_nccl)]
 
+mod disaggregate;
 mod distribute;
 mod parameters;
 
 #[macro_use]
 extern crate log;
 
-use causal_lm::{CausalLM, DecodingMeta, Model, QueryContext, SampleMeta};
-use common::{upos, utok, FileLoadError};
+use causal_lm::{
+    CacheOverflow, CausalLM, DecodingMeta, InvalidSampleArgs, Model, ModelInfo, QueryContext,
+    SampleArgs, SampleMeta,
+};
+use common::{f16, upos, utok, Blob, FileLoadError};
 use common_nv::{
     cuda::{
-        AsRaw, Context, ContextResource, ContextSpore, DevByte, DevMem, DevMemSpore, Device,
-        HostMemSpore, Stream, StreamSpore,
+        memcpy_d2h, AsRaw, Context, ContextResource, ContextSpore, DevByte, DevMem, DevMemSpore,
+        Device, HostMemSpore, Stream, StreamSpore,
     },
     nccl::{CommunicatorGroup, ReduceType},
     slice, split, udim, KernelsA, KernelsB, LocalSplitable, NvidiaKernels, Tensor,
 };
+use digit_layout::{
+    types::{F16, F32},
+    DigitLayout,
+};
 use itertools::izip;
-use llama::InferenceConfig;
+use llama::{attention_start, in_window, window_masked, InferenceConfig};
 use parameters::{Layer, ParameterMatrix};
 use std::{
-    iter::{repeat, zip},
+    iter::{once, repeat, zip},
     mem::{take, ManuallyDrop},
     path::Path,
-    slice::{from_raw_parts, from_raw_parts_mut},
-    sync::Arc,
+    slice::from_raw_parts,
+    sync::{Arc, Mutex},
     time::Instant,
 };
 
 pub use common_nv::cuda;
+pub use disaggregate::Disaggregated;
 
 pub struct Transformer {
     config: InferenceConfig,
@@ -36,12 +45,36 @@ pub struct Transformer {
     comms: CommunicatorGroup,
     streams: Vec<StreamSpore>,
     kernels: NvidiaKernels,
-    sample_workspace: ManuallyDrop<DevMemSpore>,
+    /// 词嵌入、输出层和采样所在的设备序号。
+    head: usize,
+    /// 采样的工作空间，位于 `head` 设备上，同一时间只能被一次采样使用。
+    ///
+    /// 按一行词表的大小分配，一批中的各行在同一个流上依次采样，复用这块工作空间。
+    sample_workspace: Mutex<ManuallyDrop<DevMemSpore>>,
 
     embed_tokens: Tensor<ManuallyDrop<HostMemSpore>>,
     matrix: ParameterMatrix,
     lm_layernorm: Tensor<ManuallyDrop<DevMemSpore>>,
     lm_head: Tensor<ManuallyDrop<DevMemSpore>>,
+    f32_logits: Option<F32Logits>,
+}
+
+/// 复制缓存的方式。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum DuplicateMode {
+    /// 深复制，副本与原缓存相互独立，用于分叉后继续生成。
+    #[default]
+    Deep,
+    /// 浅复制，副本与原缓存共享存储，例如为多个分支打分。
+    ///
+    /// 复制不占用额外的显存，共享的缓存第一次写入时再复制，不会覆盖其他缓存。
+    Shallow,
+}
+
+/// 以 f32 计算 logits 所需的资源，位于 `head` 设备上。
+struct F32Logits {
+    /// 转换为 f32 的输出层权重。
+    lm_head: Tensor<ManuallyDrop<DevMemSpore>>,
 }
 
 impl Model for Transformer {
@@ -52,11 +85,58 @@ impl Model for Transformer {
     fn load(model_dir: impl AsRef<Path>, meta: Self::Meta) -> Result<Self, Self::Error> {
         let time = Instant::now();
         let host = llama::Storage::load_safetensors(model_dir)?;
+        host.check_rope_scaling()?;
+        check_reduce_dtype(&host)?;
         info!("load host: {:?}", time.elapsed());
+        Ok(Self::new(&host, &meta))
+    }
+}
 
-        let kernels = NvidiaKernels::new(&meta, host.config.d as _, host.config.voc as _);
+impl Transformer {
+    /// 将设备按 `tp` 个一组划分，每组以张量并行方式部署一个模型副本。
+    ///
+    /// 副本之间相互独立，由上层调度将请求分配到各个副本。
+    pub fn load_replicas(
+        model_dir: impl AsRef<Path>,
+        devices: &[Device],
+        tp: usize,
+    ) -> Result<Vec<Self>, FileLoadError> {
+        assert!(
+            tp > 0 && devices.len() % tp == 0,
+            "{} devices cannot be divided into groups of {tp}",
+            devices.len(),
+        );
+        let time = Instant::now();
+        let host = llama::Storage::load_safetensors(model_dir)?;
+        host.check_rope_scaling()?;
+        check_reduce_dtype(&host)?;
+        info!("load host: {:?}", time.elapsed());
+        Ok(devices
+            .chunks(tp)
+            .map(|group| Self::new(&host, group))
+            .collect())
+    }
+
+    /// 在 `devices` 上以张量并行方式部署模型。
+    #[inline]
+    pub fn new(host: &llama::Storage, devices: &[Device]) -> Self {
+        Self::with_head(host, devices, 0)
+    }
 
-        let contexts = meta
+    /// 在 `devices` 上以张量并行方式部署模型，词嵌入、输出层和采样放在第 `head` 个设备上。
+    ///
+    /// 可以把这部分工作从负载较重的设备上移开，或让多个模型实例使用不同的设备。
+    ///
+    /// 还不支持旋转位置编码缩放，模型配置了缩放时 panic，通过 [`load`](Model::load) 加载时返回错误。
+    pub fn with_head(host: &llama::Storage, devices: &[Device], head: usize) -> Self {
+        assert!(head < devices.len());
+        assert!(
+            host.check_rope_scaling().is_ok(),
+            "rope scaling is not supported on nvidia gpu"
+        );
+        let kernels = NvidiaKernels::new(devices, host.config.d as _, host.config.voc as _);
+
+        let contexts = devices
             .iter()
             .map(|dev| {
                 dev.set_mempool_threshold(u64::MAX);
@@ -64,46 +144,136 @@ impl Model for Transformer {
             })
             .collect::<Vec<_>>();
         let comms = CommunicatorGroup::new(
-            &meta
+            &devices
                 .iter()
                 .map(|dev| unsafe { dev.as_raw() })
                 .collect::<Vec<_>>(),
         );
-        let matrix = ParameterMatrix::load(&host, &contexts);
+        let matrix = ParameterMatrix::load(host, &contexts);
         let streams = contexts
             .iter()
             .map(|context| context.apply(|ctx| ctx.stream().sporulate()))
             .collect::<Vec<_>>();
-        let (embed_tokens, lm_layernorm, lm_head, sample_workspace) = contexts[0].apply(|ctx| {
+        let (embed_tokens, lm_layernorm, lm_head, sample_workspace) = contexts[head].apply(|ctx| {
             let sample_workspace = kernels
-                .sample_workspace(streams[0].sprout_ref(ctx))
+                .sample_workspace(streams[head].sprout_ref(ctx))
                 .sporulate();
 
             (
-                host.embed_tokens.map_physical(|u| {
+                host.embed_tokens.as_ref().map_physical(|u| {
                     let mut host = ctx.malloc_host::<u8>(u.len());
-                    host.clone_from_slice(&u);
+                    host.clone_from_slice(u);
                     ManuallyDrop::new(host.sporulate())
                 }),
                 host.lm_layernorm
-                    .map_physical(|u| ManuallyDrop::new(ctx.from_host(&u).sporulate())),
+                    .as_ref()
+                    .map_physical(|u| ManuallyDrop::new(ctx.from_host(&**u).sporulate())),
                 host.lm_head
-                    .map_physical(|u| ManuallyDrop::new(ctx.from_host(&u).sporulate())),
+                    .as_ref()
+                    .map_physical(|u| ManuallyDrop::new(ctx.from_host(&**u).sporulate())),
                 ManuallyDrop::new(sample_workspace),
             )
         });
-        Ok(Self {
+        Self {
             comms,
             streams,
             kernels,
-            sample_workspace,
+            head,
+            sample_workspace: Mutex::new(sample_workspace),
 
             embed_tokens,
             matrix,
             lm_layernorm,
             lm_head,
+            f32_logits: None,
 
-            config: host.config,
+            config: host.config.clone(),
+        }
+    }
+
+    /// 以 `mode` 方式复制缓存的前 `pos` 个位置，[`duplicate_cache`](CausalLM::duplicate_cache) 总是深复制。
+    pub fn duplicate_cache_with(
+        &self,
+        cache: &Tensor<Cache>,
+        pos: upos,
+        mode: DuplicateMode,
+    ) -> Tensor<Cache> {
+        if mode == DuplicateMode::Shallow {
+            return cache.as_ref().map_physical(Cache::share);
+        }
+        let contexts = Arc::new(self.comms.contexts().collect::<Vec<_>>());
+        InferenceConfig::duplicate_cache(
+            cache,
+            pos,
+            |len| Cache::new(contexts.clone(), malloc_all(&contexts, len)),
+            |mut dst, src| {
+                for (i, context) in contexts.iter().enumerate() {
+                    context.apply(|ctx| {
+                        self.kernels.reform(
+                            &mut dst
+                                .as_mut()
+                                .map_physical(|u| &mut **u.mem_mut()[i].sprout_mut(ctx)),
+                            &src.as_ref().map_physical(|u| &**u.mem[i].sprout_ref(ctx)),
+                            &ctx.stream(),
+                        );
+                    });
+                }
+            },
+        )
+    }
+
+    /// 设置是否以 f32 计算输出的 logits 并以 f32 精度采样，可以提高 top-p/top-k 采样的数值稳定性。
+    pub fn set_f32_logits(&mut self, enabled: bool) {
+        let context = self.comms.contexts().nth(self.head).unwrap();
+        if let Some(mut old) = self.f32_logits.take() {
+            context
+                .apply(|ctx| unsafe { ManuallyDrop::take(old.lm_head.physical_mut()).sprout(ctx) });
+        }
+        if !enabled {
+            return;
+        }
+
+        let lm_head = context.apply(|ctx| {
+            let stream = self.streams[self.head].sprout_ref(ctx);
+            let dev = &**self.lm_head.physical().sprout_ref(ctx);
+            let mut host = vec![f16::ZERO; dev.len() / F16.nbytes()];
+            stream.synchronize();
+            memcpy_d2h(&mut host, dev);
+            // 逐元素转换整个存储，以元素为单位的布局保持不变
+            let host = host.into_iter().map(f16::to_f32).collect::<Vec<_>>();
+            let mem = ManuallyDrop::new(stream.from_host(&host).sporulate());
+            unsafe {
+                Tensor::from_raw_parts(F32, self.lm_head.shape(), self.lm_head.pattern(), mem)
+            }
+        });
+        self.f32_logits = Some(F32Logits { lm_head });
+    }
+
+    /// 将 `from` 的设备上的缓存迁移到这个模型的设备上，缓存的位置和内容保持不变，可用于在副本之间平衡负载。
+    ///
+    /// 两个模型必须以相同的张量并行规模部署同一个模型。各卡的存储在设备之间直接拷贝，不经过主机。
+    pub fn migrate_cache(&self, from: &Self, cache: Tensor<Cache>) -> Tensor<Cache> {
+        let contexts = Arc::new(self.comms.contexts().collect::<Vec<_>>());
+        assert_eq!(cache.physical().contexts.len(), contexts.len());
+        assert_eq!(from.streams.len(), contexts.len());
+        cache.map_physical(|src| {
+            let mem = izip!(&*src.contexts, src.mem.iter(), &*contexts, &from.streams)
+                .map(|(src_context, mem, dst_context, stream)| {
+                    let dst = dst_context.apply(|ctx| ctx.malloc::<u8>(mem.len()).sporulate());
+                    src_context.apply(|ctx| {
+                        // 统一寻址下目标设备的存储可以作为拷贝的目标，在源设备的流上完成拷贝
+                        let dst = unsafe {
+                            std::slice::from_raw_parts_mut(dst.as_raw() as *mut DevByte, dst.len())
+                        };
+                        let stream = stream.sprout_ref(ctx);
+                        stream.memcpy_d2d(dst, &**mem.sprout_ref(ctx));
+                        stream.synchronize();
+                    });
+                    dst
+                })
+                .collect();
+            // 原来的缓存随 `src` 释放
+            Cache::new(contexts, mem)
         })
     }
 }
@@ -111,6 +281,11 @@ impl Model for Transformer {
 impl CausalLM for Transformer {
     type Storage = Cache;
 
+    #[inline]
+    fn architecture(&self) -> ModelInfo {
+        self.config.architecture()
+    }
+
     #[inline]
     fn max_seq_len(&self) -> upos {
         self.config.max_seq_len
@@ -132,41 +307,51 @@ impl CausalLM for Transformer {
             ..self.config.clone()
         };
 
-        distributed.new_cache(|len| Cache {
-            mem: contexts
-                .iter()
-                .map(|context| context.apply(|ctx| ctx.malloc::<u8>(len).sporulate()))
-                .collect(),
-            contexts,
-        })
+        distributed.new_cache(|len| Cache::new(contexts.clone(), malloc_all(&contexts, len)))
     }
 
+    #[inline]
+    fn cache_bytes(&self, len: upos) -> usize {
+        // 缓存按 kv 头分布在各卡上，总量与单卡相同
+        self.config.cache_bytes(len)
+    }
+
+    #[inline]
     fn duplicate_cache(&self, cache: &Tensor<Self::Storage>, pos: upos) -> Tensor<Self::Storage> {
-        let contexts = Arc::new(self.comms.contexts().collect::<Vec<_>>());
-        InferenceConfig::duplicate_cache(
-            cache,
-            pos,
-            |len| Cache {
-                mem: contexts
-                    .iter()
-                    .map(|context| context.apply(|ctx| ctx.malloc::<u8>(len).sporulate()))
-                    .collect(),
-                contexts: contexts.clone(),
-            },
-            |mut dst, src| {
-                for (i, context) in contexts.iter().enumerate() {
-                    context.apply(|ctx| {
-                        self.kernels.reform(
-                            &mut dst
-                                .as_mut()
-                                .map_physical(|u| &mut **u.mem[i].sprout_mut(ctx)),
-                            &src.as_ref().map_physical(|u| &**u.mem[i].sprout_ref(ctx)),
-                            &ctx.stream(),
-                        );
-                    });
-                }
-            },
-        )
+        self.duplicate_cache_with(cache, pos, DuplicateMode::Deep)
+    }
+
+    fn cache_to_host(
+        &self,
+        cache: &Tensor<Self::Storage>,
+        layer: usize,
+        pos: upos,
+    ) -> Tensor<Vec<f16>> {
+        let Cache { contexts, mem } = cache.physical();
+        let parts = zip(contexts.iter(), mem.iter())
+            .enumerate()
+            .map(|(i, (context, mem))| {
+                context.apply(|ctx| {
+                    let dev = &**mem.sprout_ref(ctx);
+                    let mut host = Blob::new(dev.len());
+                    self.streams[i].sprout_ref(ctx).synchronize();
+                    memcpy_d2h(&mut host, dev);
+                    causal_lm::cache_layer(&cache.as_ref().map_physical(|_| host), layer, pos)
+                })
+            })
+            .collect::<Vec<_>>();
+        // 缓存按 kv 头分布在各卡上，在头的维度上拼接
+        let &[2, nkvh, _, dh] = parts[0].shape() else {
+            unreachable!()
+        };
+        let len = parts[0].physical().len() / 2;
+        let mut ans = Vec::with_capacity(len * 2 * parts.len());
+        for kv in 0..2 {
+            for part in &parts {
+                ans.extend_from_slice(&part.physical()[kv * len..][..len]);
+            }
+        }
+        Tensor::new(F16, &[2, nkvh * parts.len() as udim, pos, dh], ans)
     }
 
     fn token_embed(&self, queries: impl IntoIterator<Item = utok>) -> Tensor<Self::Storage> {
@@ -177,35 +362,47 @@ impl CausalLM for Transformer {
         let dt = self.config.dt;
         let d = self.config.d;
 
+        let head = self.head;
         let mut x = Tensor::alloc(dt, &[nt, d], |len| malloc_all(&contexts, len));
-        contexts[0].apply(|ctx| {
-            let mut x = x.as_mut().map_physical(|u| &mut **u[0].sprout_mut(ctx));
+        contexts[head].apply(|ctx| {
+            let mut x = x.as_mut().map_physical(|u| &mut **u[head].sprout_mut(ctx));
             self.kernels.gather(
                 &mut x,
                 &self.embed_tokens.as_ref().map_physical(|u| &***u),
                 tokens,
-                self.streams[0].sprout_ref(ctx),
+                self.streams[head].sprout_ref(ctx),
             );
         });
         for (i, comm) in self.comms.call().iter().enumerate() {
             contexts[i].apply(|ctx| {
                 let stream = self.streams[i].sprout_ref(ctx);
                 let dst = x.physical_mut()[i].sprout_mut(ctx);
-                comm.broadcast(dst, None, 0, stream);
+                comm.broadcast(dst, None, head as _, stream);
             });
         }
-        x.map_physical(|mem| Cache { contexts, mem })
+        x.map_physical(|mem| Cache::new(contexts, mem))
     }
 
     fn forward<'a>(
         &self,
         queries: impl IntoIterator<Item = QueryContext<'a, Self::Storage>>,
         mut token_embedded: Tensor<Self::Storage>,
-    ) -> Tensor<Self::Storage>
+    ) -> Result<Tensor<Self::Storage>, CacheOverflow>
     where
         Self: 'a,
     {
-        let queries = queries.into_iter().collect::<Vec<_>>();
+        let mut queries = queries.into_iter().collect::<Vec<_>>();
+        QueryContext::check_all(&queries)?;
+        // 写入共享的缓存之前先复制已有的部分，避免覆盖共享同一存储的其他缓存
+        for query in &mut queries {
+            let start = query.range.start;
+            if let Some(cache) = query.cache.as_deref_mut() {
+                if cache.physical().is_shared() {
+                    let copy = self.duplicate_cache_with(cache, start, DuplicateMode::Deep);
+                    *cache = copy;
+                }
+            }
+        }
         let mut nt = 0;
         let mut max_seq_len = 0;
         let mut max_att_len = 0;
@@ -348,7 +545,7 @@ impl CausalLM for Transformer {
                 .map(|t| t.join().unwrap())
                 .collect::<Vec<_>>();
         });
-        token_embedded
+        Ok(token_embedded)
     }
 
     fn decode(
@@ -359,21 +556,21 @@ impl CausalLM for Transformer {
         let dt = self.config.dt;
         let d = self.config.d;
 
-        let contexts = Arc::new(vec![self.comms.contexts().next().unwrap()]);
+        let head = self.head;
+        let contexts = Arc::new(vec![self.comms.contexts().nth(head).unwrap()]);
         let ans = contexts[0].apply(|ctx| {
-            let stream = self.streams[0].sprout_ref(ctx);
+            let stream = self.streams[head].sprout_ref(ctx);
 
             let mut x = hidden_state
                 .as_mut()
-                .map_physical(|u| &mut **u.mem[0].sprout_mut(ctx));
+                .map_physical(|u| &mut **u.mem_mut()[head].sprout_mut(ctx));
             let range = DecodingMeta::select(&mut x, decoding, |dst, src| {
                 stream.memcpy_d2d(dst, src);
             });
 
             if range.is_empty() {
-                return Tensor::alloc(dt, &[0, d as _], |_| Cache {
-                    contexts: contexts.clone(),
-                    mem: vec![stream.malloc::<u8>(0).sporulate()],
+                return Tensor::alloc(dt, &[0, d as _], |_| {
+                    Cache::new(contexts.clone(), vec![stream.malloc::<u8>(0).sporulate()])
                 });
             }
 
@@ -384,7 +581,8 @@ impl CausalLM for Transformer {
             let lm_head = self.lm_head.as_ref().map_physical(|u| &**u.sprout_ref(ctx));
 
             let mut x = x.slice(&[slice![range.start => range.end], slice![=>]]);
-            let mut logits = Tensor::alloc(dt, &[x.shape()[0], lm_head.shape()[1]], |len| {
+            let logits_dt = if self.f32_logits.is_some() { F32 } else { dt };
+            let mut logits = Tensor::alloc(logits_dt, &[x.shape()[0], lm_head.shape()[1]], |len| {
                 stream.malloc::<u8>(len)
             });
 
@@ -394,16 +592,29 @@ impl CausalLM for Transformer {
                 .map_physical(|u| unsafe { from_raw_parts(u.as_ptr(), u.len()) });
             self.kernels
                 .rms_norm(&mut x, &x_, &model_norm, self.config.epsilon, stream);
-            self.kernels
-                .mat_mul(&mut logits, 0., &x, &lm_head, 1., stream);
+            if let Some(f32_logits) = &self.f32_logits {
+                // 输出层以 f32 计算，采样也在 f32 上进行
+                let lm_head = f32_logits
+                    .lm_head
+                    .as_ref()
+                    .map_physical(|u| &**u.sprout_ref(ctx));
+                let mut x_ = Tensor::alloc(F32, x.shape(), |len| stream.malloc::<u8>(len));
+                self.kernels.upcast(&mut x_, &x, stream);
+                self.kernels
+                    .mat_mul(&mut logits, 0., &x_, &lm_head, 1., stream);
+                x_.take_physical().drop_on(stream);
+            } else {
+                self.kernels
+                    .mat_mul(&mut logits, 0., &x, &lm_head, 1., stream);
+            }
+            if let Some(cap) = self.config.final_logit_softcap {
+                self.kernels.softcap(&mut logits, cap, stream);
+            }
 
-            logits.map_physical(|u| Cache {
-                contexts: contexts.clone(),
-                mem: vec![u.sporulate()],
-            })
+            logits.map_physical(|u| Cache::new(contexts.clone(), vec![u.sporulate()]))
         });
 
-        take(&mut ManuallyDrop::new(hidden_state.take_physical()).mem)
+        take(hidden_state.take_physical().mem_mut())
             .into_iter()
             .zip(self.comms.contexts())
             .enumerate()
@@ -414,25 +625,51 @@ impl CausalLM for Transformer {
         ans
     }
 
-    fn sample(
+    #[inline]
+    fn check_sample_args(&self, args: &SampleArgs) -> Result<(), InvalidSampleArgs> {
+        // 采样算子只支持默认的过滤顺序，不支持 Gumbel-max 采样
+        args.validate_for_operator()
+    }
+
+    fn sample<'a>(
         &self,
-        args: impl IntoIterator<Item = SampleMeta>,
-        logits: Tensor<Self::Storage>,
+        args: impl IntoIterator<Item = SampleMeta<'a>>,
+        mut logits: Tensor<Self::Storage>,
     ) -> Vec<utok> {
-        let workspace_ptr = unsafe { self.sample_workspace.as_raw() };
-        let workspace_len = self.sample_workspace.len();
-        let Cache { contexts, mem } = logits.physical();
+        let mut workspace = self.sample_workspace.lock().unwrap();
+        let voc = self.config.voc as usize;
+        let eos = self.config.eos_token;
+        let dt = logits.data_layout();
+        let metas = args.into_iter().collect::<Vec<_>>();
+        let rows = || {
+            metas
+                .iter()
+                .flat_map(|meta| repeat(meta).take(meta.num_decode))
+        };
+        let args = rows().map(|meta| meta.args).collect::<Vec<_>>();
+        let Cache { contexts, mem } = logits.physical_mut();
+        let mem = Arc::get_mut(mem).unwrap();
         contexts[0].apply(|ctx| {
-            let workspace =
-                unsafe { from_raw_parts_mut(workspace_ptr as *mut DevByte, workspace_len) };
-            self.kernels.sample(
-                self.config.voc as _,
-                args.into_iter()
-                    .flat_map(|meta| repeat(meta.args).take(meta.num_decode)),
-                mem[0].sprout_ref(ctx),
-                workspace,
-                self.streams[0].sprout_ref(ctx),
-            )
+            let stream = self.streams[self.head].sprout_ref(ctx);
+            let logits = &mut **mem[0].sprout_mut(ctx);
+            // 在按行访问 logits 之前检查越界，避免读写到其他的显存
+            let capacity = logits_rows(voc, dt, logits.len());
+            assert!(
+                args.len() <= capacity,
+                "sampling {} rows from logits of {capacity} rows",
+                args.len(),
+            );
+            let penalties = rows().map(|meta| (meta.args.repetition_penalty, meta.history));
+            self.kernels
+                .repetition_penalty(voc, dt, penalties, logits, stream);
+            let biases = rows().map(|meta| meta.biases(eos));
+            self.kernels.logit_bias(voc, dt, biases, logits, stream);
+            // 偏置和惩罚之后按 min-p 过滤，采样算子再按 top-k 限制数量
+            let min_p = args.iter().map(|args| (args.temperature, args.min_p));
+            self.kernels.min_p(voc, dt, min_p, logits, stream);
+            let workspace = &mut **workspace.sprout_mut(ctx);
+            self.kernels
+                .sample(voc, dt, args, logits, workspace, stream)
         })
     }
 }
@@ -490,6 +727,7 @@ impl Transformer {
         let v = v.transpose(&[1, 0, 2]).split(1, seq_len);
         let o = o.transpose(&[1, 0, 2]).split(1, seq_len);
 
+        let window = self.config.sliding_window.and_then(|w| w.window(layer));
         for (query, q, k, v, mut o) in izip!(queries, q, k, v, o) {
             let pos = query.pos();
             let seq_len = query.seq_len();
@@ -498,8 +736,11 @@ impl Transformer {
                 continue;
             };
 
-            let slice_cat = &[slice![=>], slice![pos =>=> seq_len], slice![=>]];
-            let slice_att = &[slice![=>], slice![      => att_len], slice![=>]];
+            let start = attention_start(window, att_len, seq_len);
+            let masked = window_masked(window, att_len, seq_len);
+            let att_len = att_len - start;
+            let slice_cat = &[slice![=>], slice![pos   =>=> seq_len], slice![=>]];
+            let slice_att = &[slice![=>], slice![start =>=> att_len], slice![=>]];
             let shape_q0 = &[nkvh / n * head_group, seq_len, dh];
             let shape_q1 = &[nkvh / n, head_group * seq_len, dh];
             let shape_att0 = &[nkvh / n, head_group * seq_len, att_len];
@@ -512,12 +753,32 @@ impl Transformer {
             kernels.reform(&mut k_cat, &k, stream);
             kernels.reform(&mut v_cat, &v, stream);
 
+            // 窗口截断了部分查询位置能看到的缓存时，逐个位置按窗口计算掩码
+            if masked {
+                kernels.attention_f32(
+                    &mut o,
+                    &q_att,
+                    &k_cache.slice(slice_att),
+                    &v_cache.slice(slice_att),
+                    head_div,
+                    self.config.attn_logit_softcap,
+                    |i, j| {
+                        let (i, j) = (pos + i, start + j);
+                        j <= i && in_window(window, i, j)
+                    },
+                    stream,
+                );
+                continue;
+            }
             let q_att = q_att.reshape(shape_q1);
             let k_att = k_cache.slice(slice_att).transpose(&[0, 2, 1]);
             let v_att = v_cache.slice(slice_att);
 
             let mut att = Tensor::new(dt, shape_att0, &mut att_buf[..]);
             kernels.mat_mul(&mut att, 0., &q_att, &k_att, head_div, stream);
+            if let Some(cap) = self.config.attn_logit_softcap {
+                kernels.softcap(&mut att, cap, stream);
+            }
             let mut att = att.reshape(shape_att1);
             kernels.softmax(&mut att, stream);
             let mut x2 = q_att;
@@ -582,10 +843,14 @@ impl Drop for Transformer {
     fn drop(&mut self) {
         let contexts = self.comms.contexts().collect::<Vec<_>>();
         unsafe {
-            contexts[0].apply(|ctx| {
+            contexts[self.head].apply(|ctx| {
                 ManuallyDrop::take(self.embed_tokens.physical_mut()).sprout(ctx);
                 ManuallyDrop::take(self.lm_layernorm.physical_mut()).sprout(ctx);
                 ManuallyDrop::take(self.lm_head.physical_mut()).sprout(ctx);
+                ManuallyDrop::take(self.sample_workspace.get_mut().unwrap()).sprout(ctx);
+                if let Some(f32_logits) = &mut self.f32_logits {
+                    ManuallyDrop::take(f32_logits.lm_head.physical_mut()).sprout(ctx);
+                }
             });
             self.matrix.kill(&contexts);
             for (context, stream) in zip(contexts, std::mem::take(&mut self.streams)) {
@@ -595,13 +860,74 @@ impl Drop for Transformer {
     }
 }
 
+/// 检查参与 all-reduce 的数据类型。
+///
+/// 规约的激活按配置的数据类型传给 NCCL，激活由词嵌入产生、由各层的输出投影写入，
+/// 这些权重的数据类型与配置不同时 NCCL 会按错误的类型解释数据。
+fn check_reduce_dtype(host: &llama::Storage) -> Result<(), FileLoadError> {
+    let dt = host.config.dt;
+    let layers = host.layers.iter().enumerate().flat_map(|(i, layer)| {
+        [
+            (format!("layer {i} att_o"), &layer.att_o),
+            (format!("layer {i} mlp_down"), &layer.mlp_down),
+        ]
+    });
+    for (name, t) in once(("embed_tokens".into(), &host.embed_tokens)).chain(layers) {
+        if t.data_layout() != dt {
+            return Err(FileLoadError::InvalidTensor(format!(
+                "{name} is {:?}, but all-reduce uses {dt:?}",
+                t.data_layout()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// `len` 字节、每行 `voc` 个 `dt` 的 logits 容纳的完整行数。
+#[inline]
+fn logits_rows(voc: usize, dt: DigitLayout, len: usize) -> usize {
+    len / (voc * dt.nbytes())
+}
+
 pub struct Cache {
     pub contexts: Arc<Vec<Context>>,
-    pub mem: Vec<DevMemSpore>,
+    /// 各卡上的存储，浅复制的缓存之间共享。
+    pub mem: Arc<Vec<DevMemSpore>>,
 }
 
 impl Cache {
+    #[inline]
+    fn new(contexts: Arc<Vec<Context>>, mem: Vec<DevMemSpore>) -> Self {
+        Self {
+            contexts,
+            mem: Arc::new(mem),
+        }
+    }
+
+    /// 浅复制，与原缓存共享存储。
+    #[inline]
+    fn share(&self) -> Self {
+        Self {
+            contexts: self.contexts.clone(),
+            mem: self.mem.clone(),
+        }
+    }
+
+    /// 是否与其他缓存共享存储。
+    #[inline]
+    pub fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.mem) > 1
+    }
+
+    /// 可写地访问各卡上的存储，共享的存储是只读的。
+    #[inline]
+    fn mem_mut(&mut self) -> &mut Vec<DevMemSpore> {
+        Arc::get_mut(&mut self.mem).expect("shared cache is read-only")
+    }
+
+    /// 取出各卡上存储的地址用于写入，共享的存储不能写入。
     unsafe fn split(&mut self) -> Vec<(cuda::bindings::CUdeviceptr, usize)> {
+        assert!(!self.is_shared(), "shared cache is read-only");
         self.mem
             .iter()
             .map(|mem| (mem.as_raw(), mem.len()))
@@ -612,8 +938,11 @@ impl Cache {
 impl Drop for Cache {
     #[inline]
     fn drop(&mut self) {
-        let mem = std::mem::take(&mut self.mem);
-        for (context, mem) in zip(&*self.contexts, mem) {
+        // 共享的存储由最后一个持有者释放
+        let Some(mem) = Arc::get_mut(&mut self.mem) else {
+            return;
+        };
+        for (context, mem) in zip(&*self.contexts, take(mem)) {
             context.apply(|ctx| drop(mem.sprout(ctx)));
         }
     }
@@ -626,6 +955,77 @@ fn malloc_all(contexts: &[Context], len: usize) -> Vec<DevMemSpore> {
         .collect()
 }
 
+/// 多设备测试共用的提示词。
+#[cfg(test)]
+const TEST_PROMPT: [utok; 22] = [
+    29966, 29989, 1792, 29989, 29958, 13, 29903, 388, 376, 18567, 29908, 304, 592, 21106, 29879,
+    5299, 29989, 465, 22137, 29989, 29958, 13,
+];
+
+/// 从 `pos` 开始输入 `tokens`，贪心解码 `n` 步，返回解码出的 token。
+///
+/// 下一次输入最后一个 token，位置为 `pos + tokens.len() + n - 1`。
+#[cfg(test)]
+fn greedy_decode(
+    model: &Transformer,
+    cache: &mut Tensor<Cache>,
+    tokens: &[utok],
+    mut pos: upos,
+    n: usize,
+) -> Vec<utok> {
+    let mut tokens = tokens.to_vec();
+    let mut output = Vec::with_capacity(n);
+    for _ in 0..n {
+        let len = tokens.len();
+        let x = model.token_embed(tokens.iter().copied());
+        let queries = [QueryContext {
+            cache: Some(&mut *cache),
+            range: pos..pos + len as upos,
+        }];
+        let x = model.forward(queries, x).unwrap();
+        let decoding = [DecodingMeta {
+            num_query: len,
+            num_decode: 1,
+        }];
+        let logits = model.decode(decoding, x);
+        let args = [SampleMeta {
+            num_decode: 1,
+            args: causal_lm::SampleArgs::ARG_MAX,
+            history: &[],
+            suppressed: &[],
+        }];
+        tokens = model.sample(args, logits);
+        output.extend_from_slice(&tokens);
+        pos += len as upos;
+    }
+    output
+}
+
+#[test]
+fn test_check_reduce_dtype() {
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    let mut host = llama::Storage::load_safetensors(model_dir).unwrap();
+    assert!(check_reduce_dtype(&host).is_ok());
+    // 配置的数据类型与权重不同时加载失败，而不是在推理时按错误的类型规约
+    host.config.dt = if host.config.dt == F32 { F16 } else { F32 };
+    assert!(matches!(
+        check_reduce_dtype(&host),
+        Err(FileLoadError::InvalidTensor(_))
+    ));
+}
+
+#[test]
+fn test_logits_rows() {
+    let row = 32 * F16.nbytes();
+    assert_eq!(logits_rows(32, F16, row * 4), 4);
+    // 不完整的行不计入
+    assert_eq!(logits_rows(32, F16, row * 4 + 1), 4);
+    assert_eq!(logits_rows(32, F16, row * 4 - 1), 3);
+    assert_eq!(logits_rows(32, F32, row * 4), 2);
+}
+
 #[test]
 fn test_infer() {
     if let Err(cuda::NoDevice) = cuda::init() {
@@ -634,10 +1034,277 @@ fn test_infer() {
     if cuda::Device::count() >= 2 {
         causal_lm::test_impl::<Transformer>(
             [0, 1].map(cuda::Device::new).into_iter().collect(),
-            &[
-                29966, 29989, 1792, 29989, 29958, 13, 29903, 388, 376, 18567, 29908, 304, 592,
-                21106, 29879, 5299, 29989, 465, 22137, 29989, 29958, 13,
-            ],
+            &TEST_PROMPT,
         );
     }
 }
+
+#[test]
+fn test_replicas() {
+    if let Err(cuda::NoDevice) = cuda::init() {
+        return;
+    }
+    if cuda::Device::count() < 4 {
+        return;
+    }
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    let devices = (0..4).map(cuda::Device::new).collect::<Vec<_>>();
+    let replicas = Transformer::load_replicas(model_dir, &devices, 2).unwrap();
+    assert_eq!(replicas.len(), 2);
+
+    // 两个副本独立推理，贪心解码的结果应当一致
+    let outputs = replicas
+        .iter()
+        .map(|model| greedy_decode(model, &mut model.new_cache(), &TEST_PROMPT, 0, 8))
+        .collect::<Vec<_>>();
+    assert_eq!(outputs[0], outputs[1]);
+}
+
+#[test]
+fn test_head_device() {
+    if let Err(cuda::NoDevice) = cuda::init() {
+        return;
+    }
+    if cuda::Device::count() < 2 {
+        return;
+    }
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    let host = llama::Storage::load_safetensors(model_dir).unwrap();
+    let devices = (0..2).map(cuda::Device::new).collect::<Vec<_>>();
+
+    // 在设备 0 和设备 1 上解码，贪心解码的结果应当一致
+    let outputs = [0, 1].map(|head| {
+        let model = Transformer::with_head(&host, &devices, head);
+        greedy_decode(&model, &mut model.new_cache(), &TEST_PROMPT, 0, 8)
+    });
+    assert_eq!(outputs[0], outputs[1]);
+}
+
+#[test]
+fn test_f32_logits() {
+    if let Err(cuda::NoDevice) = cuda::init() {
+        return;
+    }
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    let host = llama::Storage::load_safetensors(model_dir).unwrap();
+    let mut model = Transformer::new(&host, &[cuda::Device::new(0)]);
+    model.set_f32_logits(true);
+    let prompt = [29966, 29989, 1792, 29989, 29958, 13];
+    let voc = model.config.voc as usize;
+    let args = |temperature| causal_lm::SampleArgs {
+        temperature,
+        top_k: 50,
+        top_p: 0.9,
+        min_p: 0.,
+        logit_bias: None,
+        eos_bias: 0.,
+        repetition_penalty: 1.,
+        sample_order: causal_lm::SampleStage::DEFAULT_ORDER,
+        gumbel: false,
+    };
+
+    for temperature in [0., 0.9] {
+        let mut cache = model.new_cache();
+        let queries = [QueryContext {
+            cache: Some(&mut cache),
+            range: 0..prompt.len() as upos,
+        }];
+        let x = model.forward(queries, model.token_embed(prompt)).unwrap();
+        let logits = model.decode([DecodingMeta::all(prompt.len())], x);
+        assert_eq!(logits.data_layout(), F32);
+
+        let Cache { contexts, mem } = logits.physical();
+        let mut host = vec![0f32; prompt.len() * voc];
+        contexts[0].apply(|ctx| memcpy_d2h(&mut host, &mem[0].sprout_ref(ctx)[..host.len() * 4]));
+
+        let meta = [SampleMeta {
+            num_decode: prompt.len(),
+            args: args(temperature),
+            history: &[],
+            suppressed: &[],
+        }];
+        let sampled = model.sample(meta, logits);
+        for (row, &token) in zip(host.chunks_exact(voc), &sampled) {
+            // 比采样的 token 大的 logit 的数量，即它在这一行中的排名
+            let rank = row.iter().filter(|&&x| x > row[token as usize]).count();
+            if temperature == 0. {
+                // 贪心采样在设备上取最大值，与主机的结果相同
+                assert_eq!(rank, 0);
+            } else {
+                // 随机采样由设备上的采样算子以 f32 精度按 top-k 过滤
+                assert!(rank < 50);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_migrate_cache() {
+    if let Err(cuda::NoDevice) = cuda::init() {
+        return;
+    }
+    if cuda::Device::count() < 2 {
+        return;
+    }
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    let host = llama::Storage::load_safetensors(model_dir).unwrap();
+    let a = Transformer::new(&host, &[cuda::Device::new(0)]);
+    let b = Transformer::new(&host, &[cuda::Device::new(1)]);
+
+    // 在设备 0 上解码 8 步，或者解码 4 步后迁移到设备 1 上继续解码，贪心解码的结果应当一致
+    let expected = greedy_decode(&a, &mut a.new_cache(), &TEST_PROMPT, 0, 8);
+
+    let mut cache = a.new_cache();
+    let mut output = greedy_decode(&a, &mut cache, &TEST_PROMPT, 0, 4);
+    let mut cache = b.migrate_cache(&a, cache);
+    let pos = (TEST_PROMPT.len() + 3) as upos;
+    let rest = greedy_decode(&b, &mut cache, &output[3..], pos, 4);
+    output.extend(rest);
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn test_duplicate_mode() {
+    if let Err(cuda::NoDevice) = cuda::init() {
+        return;
+    }
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    let host = llama::Storage::load_safetensors(model_dir).unwrap();
+    let model = Transformer::new(&host, &[cuda::Device::new(0)]);
+    let prompt = [29966, 29989, 1792, 29989, 29958, 13];
+
+    let mut cache = model.new_cache();
+    let queries = [QueryContext {
+        cache: Some(&mut cache),
+        range: 0..prompt.len() as upos,
+    }];
+    model.forward(queries, model.token_embed(prompt)).unwrap();
+    let pos = prompt.len() as upos;
+    let layer = |model: &Transformer, cache: &Tensor<Cache>| {
+        model.cache_to_host(cache, 0, pos).take_physical()
+    };
+    let origin = layer(&model, &cache);
+
+    // 浅复制与原缓存共享存储
+    let shallow = model.duplicate_cache_with(&cache, pos, DuplicateMode::Shallow);
+    assert!(Arc::ptr_eq(&shallow.physical().mem, &cache.physical().mem));
+    assert!(shallow.physical().is_shared());
+    assert_eq!(layer(&model, &shallow), origin);
+    drop(shallow);
+    assert!(!cache.physical().is_shared());
+
+    // 在浅复制的副本上推理时先复制，不覆盖原缓存
+    let mut shallow = model.duplicate_cache_with(&cache, pos, DuplicateMode::Shallow);
+    let queries = [QueryContext {
+        cache: Some(&mut shallow),
+        range: 0..prompt.len() as upos,
+    }];
+    model.forward(queries, model.token_embed([1; 6])).unwrap();
+    assert!(!cache.physical().is_shared());
+    assert!(!Arc::ptr_eq(&shallow.physical().mem, &cache.physical().mem));
+    assert_ne!(layer(&model, &shallow), origin);
+    assert_eq!(layer(&model, &cache), origin);
+
+    // 深复制独立于原缓存，在副本上继续推理不影响原缓存
+    let mut deep = model.duplicate_cache(&cache, pos);
+    assert!(!Arc::ptr_eq(&deep.physical().mem, &cache.physical().mem));
+    assert_eq!(layer(&model, &deep), origin);
+    let queries = [QueryContext {
+        cache: Some(&mut deep),
+        range: 0..prompt.len() as upos,
+    }];
+    model.forward(queries, model.token_embed([1; 6])).unwrap();
+    assert_ne!(layer(&model, &deep), origin);
+    assert_eq!(layer(&model, &cache), origin);
+}
+
+#[test]
+fn test_large_batch_sample() {
+    if let Err(cuda::NoDevice) = cuda::init() {
+        return;
+    }
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    let host = llama::Storage::load_safetensors(model_dir).unwrap();
+    let model = Transformer::new(&host, &[cuda::Device::new(0)]);
+    let voc = model.config.voc as usize;
+
+    // 每个位置都采样，一批的行数远大于单个请求
+    let prompt = [29966, 29989, 1792, 29989, 29958, 13]
+        .into_iter()
+        .cycle()
+        .take(256)
+        .collect::<Vec<utok>>();
+    let mut cache = model.new_cache();
+    let queries = [QueryContext {
+        cache: Some(&mut cache),
+        range: 0..prompt.len() as upos,
+    }];
+    let x = model
+        .forward(queries, model.token_embed(prompt.iter().copied()))
+        .unwrap();
+    let logits = model.decode([DecodingMeta::all(prompt.len())], x);
+    assert_eq!(logits.shape()[0] as usize, prompt.len());
+
+    // 在主机上逐行取最大值作为参考
+    let Cache { contexts, mem } = logits.physical();
+    let mut host = vec![f16::ZERO; prompt.len() * voc];
+    contexts[0].apply(|ctx| memcpy_d2h(&mut host, &mem[0].sprout_ref(ctx)[..host.len() * 2]));
+    let expected = host
+        .chunks_exact(voc)
+        .map(|row| (0..voc).fold(0, |best, i| if row[i] > row[best] { i } else { best }) as utok)
+        .collect::<Vec<_>>();
+
+    let meta = [SampleMeta {
+        num_decode: prompt.len(),
+        args: causal_lm::SampleArgs::ARG_MAX,
+        history: &[],
+        suppressed: &[],
+    }];
+    assert_eq!(model.sample(meta, logits), expected);
+}
+
+#[test]
+fn test_disaggregated() {
+    if let Err(cuda::NoDevice) = cuda::init() {
+        return;
+    }
+    if cuda::Device::count() < 2 {
+        return;
+    }
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    let host = llama::Storage::load_safetensors(model_dir).unwrap();
+    let reference = Transformer::new(&host, &[cuda::Device::new(0)]);
+    let model = Disaggregated::new(&host, &[cuda::Device::new(0)], &[cuda::Device::new(1)]);
+
+    // 全部在设备 0 上推理作为参考
+    let expected = greedy_decode(&reference, &mut reference.new_cache(), &TEST_PROMPT, 0, 8);
+
+    // 在设备 0 上预填充，在设备 1 上解码，贪心解码的结果应当一致
+    let (mut cache, next) = model
+        .prefill(&TEST_PROMPT, causal_lm::SampleArgs::ARG_MAX)
+        .unwrap();
+    let pos = TEST_PROMPT.len() as upos;
+    let mut output = vec![next];
+    output.extend(greedy_decode(
+        model.decode_model(),
+        &mut cache,
+        &[next],
+        pos,
+        7,
+    ));
+    assert_eq!(output, expected);
+}
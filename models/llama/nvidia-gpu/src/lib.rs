@@ -5,8 +5,11 @@ mod resource;
 #[macro_use]
 extern crate log;
 
-use causal_lm::{CausalLM, DecodingMeta, Model, QueryContext, SampleMeta};
-use common::{upos, utok, Blob, FileLoadError};
+use causal_lm::{
+    CacheOverflow, CausalLM, DecodingMeta, InvalidSampleArgs, Model, ModelInfo, QueryContext,
+    SampleArgs, SampleMeta,
+};
+use common::{f16, upos, utok, Blob, FileLoadError};
 use common_nv::{
     cuda::{memcpy_d2h, AsRaw},
     slice, udim, Gpu, Kernels, KernelsA, KernelsB, NvidiaKernels, Tensor,
@@ -15,7 +18,8 @@ use cuda::{
     ContextResource, ContextSpore, DevByte, DevMem, DevMemSpore, Device, EventSpore, HostMemSpore,
     Stream, StreamSpore,
 };
-use llama::{ComputeConst, InferenceConfig, LayerStorage, SliceOn, Weight};
+use digit_layout::types::F16;
+use llama::{ComputeConst, InferenceConfig, LayerStorage, SliceOn, SlidingWindow, Weight};
 use resource::Resource;
 use std::{
     cell::RefCell,
@@ -54,6 +58,8 @@ struct Internal {
 pub struct ModelLoadMeta {
     pub device: Device,
     pub load_layers: usize,
+    /// 严格数据类型模式，模型不是 f16 时报错而不是自动转换。
+    pub strict_dtype: bool,
 }
 
 impl ModelLoadMeta {
@@ -62,6 +68,7 @@ impl ModelLoadMeta {
         Self {
             device: Device::new(n),
             load_layers: usize::MAX,
+            strict_dtype: false,
         }
     }
 }
@@ -76,10 +83,13 @@ impl Model for Transformer {
         Self::Meta {
             device,
             load_layers,
+            strict_dtype,
         }: Self::Meta,
     ) -> Result<Self, Self::Error> {
         let time = Instant::now();
-        let host = llama::Storage::load_safetensors(model_dir)?;
+        let host =
+            llama::Storage::load_safetensors(model_dir)?.cast_for_compute(F16, strict_dtype)?;
+        host.check_rope_scaling()?;
         info!("load host: {:?}", time.elapsed());
         let load_layers = (load_layers as udim).min(host.config.nlayers);
 
@@ -157,6 +167,11 @@ impl Transformer {
 impl CausalLM for Transformer {
     type Storage = Cache;
 
+    #[inline]
+    fn architecture(&self) -> ModelInfo {
+        self.0.config.architecture()
+    }
+
     #[inline]
     fn max_seq_len(&self) -> upos {
         self.0.config.max_seq_len
@@ -174,6 +189,11 @@ impl CausalLM for Transformer {
         self.0.config.new_cache(|len| self.cache(len))
     }
 
+    #[inline]
+    fn cache_bytes(&self, len: upos) -> usize {
+        self.0.config.cache_bytes(len)
+    }
+
     fn duplicate_cache(&self, cache: &Tensor<Self::Storage>, pos: upos) -> Tensor<Self::Storage> {
         InferenceConfig::duplicate_cache(
             cache,
@@ -192,6 +212,23 @@ impl CausalLM for Transformer {
         )
     }
 
+    fn cache_to_host(
+        &self,
+        cache: &Tensor<Self::Storage>,
+        layer: usize,
+        pos: upos,
+    ) -> Tensor<Vec<f16>> {
+        let host = self.0.resource.apply(|stream| {
+            let ctx = stream.ctx();
+            let dev = &**cache.physical().mem.sprout_ref(ctx);
+            let mut host = Blob::new(dev.len());
+            stream.synchronize();
+            memcpy_d2h(&mut host, dev);
+            host
+        });
+        causal_lm::cache_layer(&cache.as_ref().map_physical(|_| host), layer, pos)
+    }
+
     fn token_embed(&self, queries: impl IntoIterator<Item = utok>) -> Tensor<Self::Storage> {
         let tokens = queries.into_iter().collect::<Vec<_>>();
         let nt = tokens.len() as udim;
@@ -215,7 +252,7 @@ impl CausalLM for Transformer {
         &self,
         queries: impl IntoIterator<Item = QueryContext<'a, Self::Storage>>,
         token_embedded: Tensor<Self::Storage>,
-    ) -> Tensor<Self::Storage>
+    ) -> Result<Tensor<Self::Storage>, CacheOverflow>
     where
         Self: 'a,
     {
@@ -228,6 +265,8 @@ impl CausalLM for Transformer {
                 di: self.0.config.di,
                 epsilon: self.0.config.epsilon,
                 theta: self.0.config.theta,
+                attn_softcap: self.0.config.attn_logit_softcap,
+                sliding_window: self.0.config.sliding_window,
                 kernels: &self.0.kernels,
                 compute,
                 transfer,
@@ -275,39 +314,60 @@ impl CausalLM for Transformer {
             self.0
                 .kernels
                 .rms_norm(&mut x, &x_, &lm_layernorm, self.0.config.epsilon, compute);
-            self.0.kernels.mat_mul(
-                &mut logits
-                    .as_mut()
-                    .map_physical(|u| &mut **u.mem.sprout_mut(ctx)),
-                0.,
-                &x,
-                &lm_head,
-                1.,
-                compute,
-            );
+            let mut logits_ = logits
+                .as_mut()
+                .map_physical(|u| &mut **u.mem.sprout_mut(ctx));
+            self.0
+                .kernels
+                .mat_mul(&mut logits_, 0., &x, &lm_head, 1., compute);
+            if let Some(cap) = self.0.config.final_logit_softcap {
+                self.0.kernels.softcap(&mut logits_, cap, compute);
+            }
 
             logits
         })
     }
 
-    fn sample(
+    #[inline]
+    fn check_sample_args(&self, args: &SampleArgs) -> Result<(), InvalidSampleArgs> {
+        // 采样算子只支持默认的过滤顺序，不支持 Gumbel-max 采样
+        args.validate_for_operator()
+    }
+
+    fn sample<'a>(
         &self,
-        args: impl IntoIterator<Item = SampleMeta>,
+        args: impl IntoIterator<Item = SampleMeta<'a>>,
         logits: Tensor<Self::Storage>,
     ) -> Vec<utok> {
         let workspace_ptr = unsafe { self.0.sample_workspace.as_raw() };
         let workspace_len = self.0.sample_workspace.len();
+        let voc = self.0.config.voc as usize;
+        let eos = self.0.config.eos_token;
+        let dt = logits.data_layout();
+        let metas = args.into_iter().collect::<Vec<_>>();
+        let rows = || {
+            metas
+                .iter()
+                .flat_map(|meta| repeat(meta).take(meta.num_decode))
+        };
+        let args = rows().map(|meta| meta.args).collect::<Vec<_>>();
         self.0.resource.apply(|compute| {
             let workspace =
                 unsafe { from_raw_parts_mut(workspace_ptr as *mut DevByte, workspace_len) };
-            self.0.kernels.sample(
-                self.0.config.voc as _,
-                args.into_iter()
-                    .flat_map(|meta| repeat(meta.args).take(meta.num_decode)),
-                logits.take_physical().mem.sprout_ref(compute.ctx()),
-                workspace,
-                compute,
-            )
+            let mut logits = logits.take_physical();
+            let logits = &mut **logits.mem.sprout_mut(compute.ctx());
+            let penalties = rows().map(|meta| (meta.args.repetition_penalty, meta.history));
+            self.0
+                .kernels
+                .repetition_penalty(voc, dt, penalties, logits, compute);
+            let biases = rows().map(|meta| meta.biases(eos));
+            self.0.kernels.logit_bias(voc, dt, biases, logits, compute);
+            // 偏置和惩罚之后按 min-p 过滤，采样算子再按 top-k 限制数量
+            let min_p = args.iter().map(|args| (args.temperature, args.min_p));
+            self.0.kernels.min_p(voc, dt, min_p, logits, compute);
+            self.0
+                .kernels
+                .sample(voc, dt, args, logits, workspace, compute)
         })
     }
 }
@@ -341,6 +401,9 @@ impl Drop for Transformer {
                 layer.mlp_layernorm.take_physical().sprout(ctx);
                 layer.mlp_gate_up.take_physical().sprout(ctx);
                 layer.mlp_down.take_physical().sprout(ctx);
+                for t in [layer.att_q_norm, layer.att_k_norm].into_iter().flatten() {
+                    t.take_physical().sprout(ctx);
+                }
             }
             for (layer, event) in take(&mut *pool.lock().unwrap()) {
                 layer.att_layernorm.take_physical().sprout(ctx);
@@ -349,6 +412,9 @@ impl Drop for Transformer {
                 layer.mlp_layernorm.take_physical().sprout(ctx);
                 layer.mlp_gate_up.take_physical().sprout(ctx);
                 layer.mlp_down.take_physical().sprout(ctx);
+                for t in [layer.att_q_norm, layer.att_k_norm].into_iter().flatten() {
+                    t.take_physical().sprout(ctx);
+                }
                 event.sprout(ctx);
             }
         });
@@ -361,6 +427,8 @@ struct ComputeStream<'a> {
     di: udim,
     epsilon: f32,
     theta: f32,
+    attn_softcap: Option<f32>,
+    sliding_window: Option<SlidingWindow>,
     kernels: &'a NvidiaKernels,
     compute: &'a Stream<'a>,
     transfer: &'a Stream<'a>,
@@ -416,6 +484,9 @@ impl<'a> llama::ComputeStream for ComputeStream<'a> {
             di: self.di,
             epsilon: self.epsilon,
             theta: self.theta,
+            attn_softcap: self.attn_softcap,
+            sliding_window: self.sliding_window,
+            attn_f32: false,
         }
     }
 
@@ -514,10 +585,19 @@ macro_rules! access {
             .as_ref()
             .map_physical(|u| &**u.sprout_ref($self.transfer.ctx()))
     };
+    ($self:expr, ?$name:ident) => {
+        $self.storage.as_ref().unwrap().$name.as_ref().map(|t| {
+            t.as_ref()
+                .map_physical(|u| &**u.sprout_ref($self.transfer.ctx()))
+        })
+    };
 }
 impl<'a> llama::LLamaLayer for LayerLoader<'a> {
     type Byte = DevByte;
-    type Storage<'m> = &'m[DevByte] where Self: 'm;
+    type Storage<'m>
+        = &'m [DevByte]
+    where
+        Self: 'm;
 
     fn att_layernorm(&self) -> Tensor<Self::Storage<'_>> {
         access!(self, att_layernorm)
@@ -537,6 +617,12 @@ impl<'a> llama::LLamaLayer for LayerLoader<'a> {
     fn mlp_down(&self) -> Tensor<Self::Storage<'_>> {
         access!(self, mlp_down)
     }
+    fn att_q_norm(&self) -> Option<Tensor<Self::Storage<'_>>> {
+        access!(self, ?att_q_norm)
+    }
+    fn att_k_norm(&self) -> Option<Tensor<Self::Storage<'_>>> {
+        access!(self, ?att_k_norm)
+    }
 }
 
 impl Drop for LayerLoader<'_> {
@@ -560,6 +646,16 @@ impl Drop for LayerLoader<'_> {
                 mlp_gate_up
                 mlp_down
             }
+            let host = &self.host[load];
+            for (host, dev) in [
+                (&host.att_q_norm, &mut lll.att_q_norm),
+                (&host.att_k_norm, &mut lll.att_k_norm),
+            ] {
+                if let (Some(host), Some(dev)) = (host, dev) {
+                    let mut dev = dev.physical_mut().sprout_mut(self.transfer.ctx());
+                    self.transfer.memcpy_h2d(&mut dev, host.physical());
+                }
+            }
         }
         self.pool
             .borrow_mut()
@@ -577,6 +673,26 @@ fn test_infer() {
         ModelLoadMeta {
             device,
             load_layers: 20,
+            strict_dtype: false,
+        },
+        &[
+            29966, 29989, 1792, 29989, 29958, 13, 29903, 388, 376, 18567, 29908, 304, 592, 21106,
+            29879, 5299, 29989, 465, 22137, 29989, 29958, 13,
+        ],
+    );
+}
+
+#[test]
+fn test_conformance() {
+    if let Err(cuda::NoDevice) = cuda::init() {
+        return;
+    }
+    let device = cuda::Device::new(0);
+    causal_lm::conformance::run::<Transformer>(
+        ModelLoadMeta {
+            device,
+            load_layers: 20,
+            strict_dtype: false,
         },
         &[
             29966, 29989, 1792, 29989, 29958, 13, 29903, 388, 376, 18567, 29908, 304, 592, 21106,
@@ -584,3 +700,23 @@ fn test_infer() {
         ],
     );
 }
+
+#[test]
+fn test_prefill() {
+    if let Err(cuda::NoDevice) = cuda::init() {
+        return;
+    }
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    let model = Transformer::load(model_dir, ModelLoadMeta::load_all_to(0)).unwrap();
+    let d = model.architecture().d as udim;
+
+    let mut cache = model.new_cache();
+    let mut pos = 0;
+    for tokens in [&[29966, 29989, 1792, 29989][..], &[29958, 13]] {
+        let hidden_state = model.prefill(tokens, &mut cache, &mut pos).unwrap();
+        assert_eq!(hidden_state.shape(), [tokens.len() as udim, d]);
+    }
+    assert_eq!(pos, 6);
+}
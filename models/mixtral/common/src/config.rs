@@ -23,6 +23,9 @@ pub struct ConfigJson {
     pub torch_dtype: String,
     pub num_local_experts: usize,
     pub num_experts_per_tok: usize,
+    /// 路由 softmax 的温度，1.0 即原始的路由分布。
+    #[serde(default = "default_router_temperature")]
+    pub router_temperature: f32,
 }
 
 impl ConfigJson {
@@ -51,3 +54,8 @@ const fn default_rms_norm_eps() -> f32 {
 const fn default_rope_theta() -> f32 {
     1e4
 }
+
+#[inline(always)]
+const fn default_router_temperature() -> f32 {
+    1.
+}
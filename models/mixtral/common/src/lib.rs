@@ -2,4 +2,4 @@ mod config;
 mod params;
 
 pub use config::ConfigJson;
-pub use params::MixtralParams;
+pub use params::{ExpertWeight, MixtralParams};
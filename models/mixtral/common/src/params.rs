@@ -1,19 +1,103 @@
 use super::ConfigJson;
 use common::{
+    can_cast, cast_tensor,
     safe_tensors::{Dtype, SafeTensor, SafeTensors},
-    Blob,
+    Blob, FileLoadError,
 };
 use digit_layout::DigitLayout;
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, VecDeque},
+    ops::Deref,
+    sync::{Arc, Mutex},
+};
 use tensor::{udim, Shape, Tensor};
 
 pub struct MixtralParams {
     safe_tensors: SafeTensors,
     transformed_tensors: HashMap<String, Tensor<Blob>>,
+    /// 预先转置并连续存储的线性层权重。
+    transposed_tensors: HashMap<String, Tensor<Blob>>,
+    /// 按需加载的专家，为 `None` 时所有专家常驻内存。
+    experts: Option<Mutex<ExpertCache>>,
+}
+
+/// 专家权重，数据类型与计算类型相同时直接借用，否则为临时转换的副本。
+///
+/// 按需加载的专家与缓存共享。
+pub enum ExpertWeight<'a> {
+    Borrowed(&'a [u8]),
+    Owned(Blob),
+    Shared(Arc<Blob>),
+}
+
+impl Deref for ExpertWeight<'_> {
+    type Target = [u8];
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Self::Borrowed(slice) => slice,
+            Self::Owned(blob) => blob,
+            Self::Shared(blob) => blob,
+        }
+    }
+}
+
+/// 按需加载的专家权重，超出容量时淘汰最久未使用的专家。
+struct ExpertCache {
+    capacity: usize,
+    /// 以层号、专家序号和计算类型为键，最近使用的在末尾。
+    entries: VecDeque<(ExpertKey, CachedExpert)>,
+}
+
+type ExpertKey = (udim, udim, DigitLayout);
+
+/// 缓存的专家，以 `[输出, 输入]` 形状连续存储并转换为计算类型。
+#[derive(Clone)]
+struct CachedExpert {
+    /// 拼接的 gate 和 up 权重，形状为 `[di + di, d]`。
+    gate_up: Tensor<Arc<Blob>>,
+    /// down 权重，形状为 `[d, di]`。
+    down: Tensor<Arc<Blob>>,
+}
+
+impl ExpertCache {
+    /// 取出 `layer` 层第 `expert` 个转换为 `dt` 的专家，不在缓存中时用 `load` 加载。
+    fn get(
+        &mut self,
+        layer: udim,
+        expert: udim,
+        dt: DigitLayout,
+        load: impl FnOnce() -> [Tensor<Blob>; 2],
+    ) -> CachedExpert {
+        let key = (layer, expert, dt);
+        if let Some(i) = self.entries.iter().position(|(k, _)| *k == key) {
+            let entry = self.entries.remove(i).unwrap();
+            let ans = entry.1.clone();
+            self.entries.push_back(entry);
+            return ans;
+        }
+        let [gate_up, down] = load().map(|t| t.map_physical(Arc::new));
+        let ans = CachedExpert { gate_up, down };
+        if self.capacity > 0 {
+            if self.entries.len() == self.capacity {
+                self.entries.pop_front();
+            }
+            self.entries.push_back((key, ans.clone()));
+        }
+        ans
+    }
 }
 
 impl MixtralParams {
-    pub fn new(config: &ConfigJson, safe_tensors: SafeTensors) -> Self {
+    /// `expert_cache` 不为 `None` 时专家权重保留在文件映射中，参与计算时才加载，
+    /// 最多缓存 `expert_cache` 个专家。
+    ///
+    /// 专家权重不能转换为模型的数据类型时返回错误，而不是在参与计算时失败。
+    pub fn new(
+        config: &ConfigJson,
+        safe_tensors: SafeTensors,
+        expert_cache: Option<usize>,
+    ) -> Result<Self, FileLoadError> {
         let mut transformed_tensors: HashMap<String, Tensor<Blob>> = HashMap::new();
         let tensor_names = safe_tensors
             .iter()
@@ -40,15 +124,161 @@ impl MixtralParams {
                     name.replace("q_proj", "qkv_proj"),
                     concat0(&[q, k, v]).reshape(&[d + dkv + dkv, d]),
                 );
-            } else if name.contains("w1") {
+            } else if name.contains("w1") && expert_cache.is_none() {
                 let w1 = to_tensor(safe_tensors.get(&name).unwrap());
                 let w3 = to_tensor(safe_tensors.get(&name.replace("w1", "w3")).unwrap());
                 transformed_tensors.insert(name.replace("w1", "gate_up_proj"), concat0(&[w1, w3]));
             }
         }
-        Self {
+        let ans = Self {
             safe_tensors,
             transformed_tensors,
+            transposed_tensors: HashMap::new(),
+            experts: expert_cache.map(|capacity| {
+                Mutex::new(ExpertCache {
+                    capacity,
+                    entries: VecDeque::new(),
+                })
+            }),
+        };
+        let dt = config.data_layout();
+        for layer in 0..config.num_hidden_layers as udim {
+            for expert in 0..config.num_local_experts as udim {
+                for from in [
+                    ans.expert_dtype(layer, expert),
+                    ans.untransposed(&expert_name(layer, expert, "w2"))
+                        .data_layout(),
+                ] {
+                    if !can_cast(from, dt) {
+                        return Err(FileLoadError::UnsupportedDtype(from));
+                    }
+                }
+            }
+        }
+        Ok(ans)
+    }
+
+    /// 按需加载时当前缓存的专家数量，所有专家常驻内存时为 `None`。
+    pub fn cached_experts(&self) -> Option<usize> {
+        self.experts
+            .as_ref()
+            .map(|cache| cache.lock().unwrap().entries.len())
+    }
+
+    /// 将所有线性层权重转置后连续存储，计算时不再按步长访问转置的权重。
+    ///
+    /// 转置的副本额外占用与线性层权重相同大小的内存，按需加载的专家不转置。
+    pub fn pretranspose(&mut self, nlayers: udim, ne: udim) {
+        let ne = if self.experts.is_some() { 0 } else { ne };
+        let mut names = vec!["lm_head.weight".to_string()];
+        for layer in 0..nlayers {
+            names.push(layer_name(layer, "self_attn.qkv_proj"));
+            names.push(layer_name(layer, "self_attn.o_proj"));
+            names.push(layer_name(layer, "block_sparse_moe.gate"));
+            for expert in 0..ne {
+                names.push(expert_name(layer, expert, "gate_up_proj"));
+                names.push(expert_name(layer, expert, "w2"));
+            }
+        }
+        for name in names {
+            let t = self.untransposed(&name).transpose(&[1, 0]);
+            let mut ans = Tensor::alloc(t.data_layout(), t.shape(), Blob::new);
+            t.reform_to(&mut ans);
+            self.transposed_tensors.insert(name, ans);
+        }
+    }
+
+    /// 将数据类型与 `dt` 不同的专家权重一次性转换为 `dt`，计算时不再逐次转换。
+    ///
+    /// 不调用时，混合精度的专家在每次参与计算时临时转换。
+    /// 按需加载的专家在加载时转换，不受影响。不支持的转换返回错误。
+    pub fn cast_experts(
+        &mut self,
+        nlayers: udim,
+        ne: udim,
+        dt: DigitLayout,
+    ) -> Result<(), FileLoadError> {
+        if self.experts.is_some() {
+            return Ok(());
+        }
+        for layer in 0..nlayers {
+            for expert in 0..ne {
+                for name in ["gate_up_proj", "w2"] {
+                    let name = expert_name(layer, expert, name);
+                    let t = self.linear(&name);
+                    if t.data_layout() != dt {
+                        let ans = cast_tensor(&t, dt)?;
+                        self.transposed_tensors.insert(name, ans);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 专家权重的数据类型，混合精度的模型中各个专家可能不同。
+    pub fn expert_dtype(&self, layer: udim, expert: udim) -> DigitLayout {
+        let name = expert_name(layer, expert, "gate_up_proj");
+        if self.transposed_tensors.contains_key(&name)
+            || self.transformed_tensors.contains_key(&name)
+        {
+            self.linear(&name).data_layout()
+        } else {
+            self.linear(&expert_name(layer, expert, "w1")).data_layout()
+        }
+    }
+
+    /// 从文件映射加载专家并转换为 `dt`，依次为拼接的 gate 和 up 权重 `[di + di, d]` 和 down 权重 `[d, di]`。
+    fn load_expert(&self, layer: udim, expert: udim, dt: DigitLayout) -> [Tensor<Blob>; 2] {
+        let w1 = self.untransposed(&expert_name(layer, expert, "w1"));
+        let w3 = self.untransposed(&expert_name(layer, expert, "w3"));
+        let gate_up = concat0(&[w1, w3]);
+        let gate_up = if gate_up.data_layout() == dt {
+            gate_up
+        } else {
+            cast(&gate_up, dt)
+        };
+        let down = cast(&self.untransposed(&expert_name(layer, expert, "w2")), dt);
+        [gate_up, down]
+    }
+
+    /// 从缓存中取出按需加载的专家，专家常驻内存时返回 `None`。
+    fn cached_expert(&self, layer: udim, expert: udim, dt: DigitLayout) -> Option<CachedExpert> {
+        let cache = self.experts.as_ref()?;
+        if self
+            .transposed_tensors
+            .contains_key(&expert_name(layer, expert, "gate_up_proj"))
+        {
+            return None;
+        }
+        let ans = cache
+            .lock()
+            .unwrap()
+            .get(layer, expert, dt, || self.load_expert(layer, expert, dt));
+        Some(ans)
+    }
+
+    /// 取出以 `[输入, 输出]` 形状参与矩阵乘的线性层权重。
+    fn linear(&self, name: &str) -> Tensor<&[u8]> {
+        match self.transposed_tensors.get(name) {
+            Some(t) => t.as_ref().map_physical(|u| &**u),
+            None => self.untransposed(name).transpose(&[1, 0]),
+        }
+    }
+
+    /// 取出以 `[输出, 输入]` 形状存储的线性层权重。
+    fn untransposed(&self, name: &str) -> Tensor<&[u8]> {
+        if name == "lm_head.weight" && !self.safe_tensors.contains(name) {
+            // 词嵌入与输出层绑定的模型不单独存储 lm_head
+            self.embed_tokens()
+        } else if let Some(t) = self.safe_tensors.get(name) {
+            to_tensor(t)
+        } else {
+            self.transformed_tensors
+                .get(name)
+                .unwrap_or_else(|| panic!("Tensor {name} not found"))
+                .as_ref()
+                .map_physical(|u| &**u)
         }
     }
 }
@@ -62,21 +292,14 @@ impl MixtralParams {
         convert(&self.safe_tensors, layer_name(layer, "input_layernorm"))
     }
 
+    /// 形状为 `[d, d + dkv + dkv]`。
     pub fn w_qkv(&self, layer: udim) -> Tensor<&[u8]> {
-        let name = layer_name(layer, "self_attn.qkv_proj");
-        if let Some(t) = self.safe_tensors.get(&name) {
-            to_tensor(t)
-        } else {
-            self.transformed_tensors
-                .get(&name)
-                .unwrap()
-                .as_ref()
-                .map_physical(|u| &**u)
-        }
+        self.linear(&layer_name(layer, "self_attn.qkv_proj"))
     }
 
+    /// 形状为 `[d, d]`。
     pub fn w_o(&self, layer: udim) -> Tensor<&[u8]> {
-        convert(&self.safe_tensors, layer_name(layer, "self_attn.o_proj"))
+        self.linear(&layer_name(layer, "self_attn.o_proj"))
     }
 
     pub fn post_attention_layernorm(&self, layer: udim) -> Tensor<&[u8]> {
@@ -86,42 +309,44 @@ impl MixtralParams {
         )
     }
 
+    /// 形状为 `[d, ne]`。
     pub fn moe_gate(&self, layer: udim) -> Tensor<&[u8]> {
-        convert(
-            &self.safe_tensors,
-            layer_name(layer, "block_sparse_moe.gate"),
-        )
+        self.linear(&layer_name(layer, "block_sparse_moe.gate"))
     }
 
-    pub fn mlp_gate_up(&self, layer: udim, expert: udim) -> Tensor<&[u8]> {
-        let name = layer_name(
-            layer,
-            &format!("block_sparse_moe.experts.{}.gate_up_proj", expert),
-        );
-        if let Some(t) = self.safe_tensors.get(&name) {
-            to_tensor(t)
-        } else {
-            self.transformed_tensors
-                .get(&name)
-                .unwrap()
-                .as_ref()
-                .map_physical(|u| &**u)
+    /// 形状为 `[d, di + di]`，数据类型与 `dt` 不同时转换为 `dt`。
+    ///
+    /// 按需加载时从缓存中取出，不在缓存中的专家从文件映射加载。
+    pub fn mlp_gate_up(&self, layer: udim, expert: udim, dt: DigitLayout) -> Tensor<ExpertWeight> {
+        match self.cached_expert(layer, expert, dt) {
+            Some(expert) => expert
+                .gate_up
+                .transpose(&[1, 0])
+                .map_physical(ExpertWeight::Shared),
+            None => cast_if_needed(self.linear(&expert_name(layer, expert, "gate_up_proj")), dt),
         }
     }
 
-    pub fn mlp_down(&self, layer: udim, expert: udim) -> Tensor<&[u8]> {
-        convert(
-            &self.safe_tensors,
-            layer_name(layer, &format!("block_sparse_moe.experts.{}.w2", expert)),
-        )
+    /// 形状为 `[di, d]`，数据类型与 `dt` 不同时转换为 `dt`。
+    ///
+    /// 与 [`mlp_gate_up`](Self::mlp_gate_up) 相同，按需加载时从缓存中取出。
+    pub fn mlp_down(&self, layer: udim, expert: udim, dt: DigitLayout) -> Tensor<ExpertWeight> {
+        match self.cached_expert(layer, expert, dt) {
+            Some(expert) => expert
+                .down
+                .transpose(&[1, 0])
+                .map_physical(ExpertWeight::Shared),
+            None => cast_if_needed(self.linear(&expert_name(layer, expert, "w2")), dt),
+        }
     }
 
     pub fn model_norm(&self) -> Tensor<&[u8]> {
         convert(&self.safe_tensors, "model.norm.weight")
     }
 
+    /// 形状为 `[d, voc]`。
     pub fn lm_head(&self) -> Tensor<&[u8]> {
-        convert(&self.safe_tensors, "lm_head.weight")
+        self.linear("lm_head.weight")
     }
 }
 
@@ -129,6 +354,10 @@ fn layer_name(layer: udim, name: &str) -> String {
     format!("model.layers.{layer}.{name}.weight")
 }
 
+fn expert_name(layer: udim, expert: udim, name: &str) -> String {
+    layer_name(layer, &format!("block_sparse_moe.experts.{expert}.{name}"))
+}
+
 fn to_tensor(tensor: SafeTensor) -> Tensor<&[u8]> {
     let data_type = type_convert(tensor.dtype);
     let shape = tensor.shape.iter().map(|&x| x as udim).collect::<Vec<_>>();
@@ -164,6 +393,21 @@ pub fn type_convert(dtype: Dtype) -> DigitLayout {
     }
 }
 
+fn cast_if_needed(t: Tensor<&[u8]>, dt: DigitLayout) -> Tensor<ExpertWeight> {
+    if t.data_layout() == dt {
+        t.map_physical(ExpertWeight::Borrowed)
+    } else {
+        cast(&t, dt).map_physical(ExpertWeight::Owned)
+    }
+}
+
+/// 将参与计算的专家权重 `t` 转换为数据类型 `dt` 的连续张量。
+///
+/// 专家的数据类型在 [`MixtralParams::new`] 中已经检查过，转换不会失败。
+fn cast<P: Deref<Target = [u8]>>(t: &Tensor<P>, dt: DigitLayout) -> Tensor<Blob> {
+    cast_tensor(t, dt).unwrap()
+}
+
 fn concat0(tensors: &[Tensor<&[u8]>]) -> Tensor<Blob> {
     assert!(tensors
         .windows(2)
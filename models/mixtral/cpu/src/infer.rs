@@ -1,15 +1,28 @@
 use super::MixtralCPU;
-use causal_lm::{CausalLM, DecodingMeta, QueryContext, SampleMeta};
+use causal_lm::{CacheOverflow, CausalLM, DecodingMeta, ModelInfo, QueryContext, SampleMeta};
 use common::{f16, upos, utok, Blob};
-use common_cpu::{KernelsA, KernelsB, ThisThread};
+use common_cpu::{CpuKernels, KernelsA, KernelsB, ThisThread};
 use digit_layout::{types::U32, DigitLayout};
 use itertools::izip;
-use std::{iter::repeat, slice::from_raw_parts};
+use std::{iter::repeat, ops::Deref, slice::from_raw_parts};
 use tensor::{reslice, reslice_mut, slice, split, udim, LocalSplitable, Tensor};
 
 impl CausalLM for MixtralCPU {
     type Storage = Blob;
 
+    fn architecture(&self) -> ModelInfo {
+        ModelInfo {
+            nlayers: self.nlayers as _,
+            nh: self.nh as _,
+            nkvh: self.nkvh as _,
+            d: self.d as _,
+            di: self.di as _,
+            voc: self.voc as _,
+            max_seq_len: self.max_seq_len as _,
+            moe: Some((self.ne as _, self.k as _)),
+        }
+    }
+
     #[inline]
     fn bos_token(&self) -> utok {
         self.bos_token
@@ -33,6 +46,15 @@ impl CausalLM for MixtralCPU {
         Tensor::alloc(dt, &[nlayers, 2, nkvh, max_seq_len, d / nh], Blob::new)
     }
 
+    fn cache_bytes(&self, len: upos) -> usize {
+        let dh = self.d / self.nh;
+        [self.nlayers, 2, self.nkvh, len, dh]
+            .iter()
+            .map(|&d| d as usize)
+            .product::<usize>()
+            * self.data_type.nbytes()
+    }
+
     fn duplicate_cache(&self, cache: &Tensor<Self::Storage>, pos: upos) -> Tensor<Self::Storage> {
         let &[_nlayers, 2, _nkvh, max_seq_len, _dh] = cache.shape() else {
             panic!()
@@ -55,6 +77,16 @@ impl CausalLM for MixtralCPU {
         ans
     }
 
+    #[inline]
+    fn cache_to_host(
+        &self,
+        cache: &Tensor<Self::Storage>,
+        layer: usize,
+        pos: upos,
+    ) -> Tensor<Vec<f16>> {
+        causal_lm::cache_layer(cache, layer, pos)
+    }
+
     fn token_embed(&self, queries: impl IntoIterator<Item = utok>) -> Tensor<Self::Storage> {
         let dt = self.data_type;
         let d = self.d;
@@ -72,11 +104,12 @@ impl CausalLM for MixtralCPU {
         &self,
         queries: impl IntoIterator<Item = QueryContext<'a, Self::Storage>>,
         token_embedded: Tensor<Self::Storage>,
-    ) -> Tensor<Self::Storage>
+    ) -> Result<Tensor<Self::Storage>, CacheOverflow>
     where
         Self: 'a,
     {
         let mut queries = queries.into_iter().collect::<Vec<_>>();
+        QueryContext::check_all(&queries)?;
         let mut nt = 0;
         let mut max_seq_len = 0;
         let mut max_att_len = 0;
@@ -127,7 +160,7 @@ impl CausalLM for MixtralCPU {
             self.kernels
                 .rms_norm(&mut x1, &x, &input_layernorm, self.epsilon, &ThisThread);
 
-            let w_qkv = self.params.w_qkv(layer).transpose(&[1, 0]);
+            let w_qkv = self.params.w_qkv(layer);
             self.kernels
                 .mat_mul(&mut qkv, 0., &x1, &w_qkv, 1., &ThisThread);
 
@@ -137,8 +170,13 @@ impl CausalLM for MixtralCPU {
             let v = v.reshape(&[nt, nkvh, dh]);
             let o = x1.reshape(&[nt, nh, dh]);
 
-            self.kernels.rope(&mut q, &pos, self.theta, &ThisThread);
-            self.kernels.rope(&mut k, &pos, self.theta, &ThisThread);
+            if self.rope.covers(&pos) {
+                self.rope.apply(&mut q, &pos);
+                self.rope.apply(&mut k, &pos);
+            } else {
+                self.kernels.rope(&mut q, &pos, self.theta, &ThisThread);
+                self.kernels.rope(&mut k, &pos, self.theta, &ThisThread);
+            }
 
             let q = q.transpose(&[1, 0, 2]).split(1, &seq_len);
             let k = k.transpose(&[1, 0, 2]).split(1, &seq_len);
@@ -192,17 +230,21 @@ impl CausalLM for MixtralCPU {
             let (mut x1, gate_up) = state!();
             let gate_up = gate_up.slice(&[slice![=>], slice![=> di + di]]);
 
-            let wo = self.params.w_o(layer).transpose(&[1, 0]);
+            let wo = self.params.w_o(layer);
             self.kernels.mat_mul(&mut x, 1., &x1, &wo, 1., &ThisThread);
 
             let post_layernorm = self.params.post_attention_layernorm(layer);
             self.kernels
                 .rms_norm(&mut x1, &x, &post_layernorm, self.epsilon, &ThisThread);
 
-            let w_moe_gate = self.params.moe_gate(layer).transpose(&[1, 0]);
-            self.kernels
-                .mat_mul(&mut routes, 0., &x1, &w_moe_gate, 1., &ThisThread);
-            self.kernels.softmax(&mut routes, &ThisThread);
+            let w_moe_gate = self.params.moe_gate(layer);
+            route(
+                &self.kernels,
+                &mut routes,
+                &x1,
+                &w_moe_gate,
+                self.router_temperature,
+            );
             topk(&routes, self.k as _, &mut moe_w, &mut moe_i);
             let weights: &[f16] = reslice(moe_w.as_slice());
             let indices: &[u32] = reslice(moe_i.as_slice());
@@ -224,8 +266,9 @@ impl CausalLM for MixtralCPU {
                 for k in 0..self.k {
                     let expert = indices[(tok * self.k + k) as usize];
                     let expert_w = weights[(tok * self.k + k) as usize].to_f32() / sum;
-                    let w_gate_up = self.params.mlp_gate_up(layer, expert).transpose(&[1, 0]);
-                    let w_down = self.params.mlp_down(layer, expert).transpose(&[1, 0]);
+                    // 混合精度的专家按需转换为计算使用的数据类型
+                    let w_gate_up = self.params.mlp_gate_up(layer, expert, dt);
+                    let w_down = self.params.mlp_down(layer, expert, dt);
                     self.kernels.mlp(
                         &mut x0_slice,
                         &x1_slice,
@@ -240,7 +283,7 @@ impl CausalLM for MixtralCPU {
             }
         }
 
-        x
+        Ok(x)
     }
 
     fn decode(
@@ -259,7 +302,7 @@ impl CausalLM for MixtralCPU {
         }
 
         let lm_layernorm = &self.params.model_norm();
-        let lm_head = self.params.lm_head().transpose(&[1, 0]);
+        let lm_head = self.params.lm_head();
         let mut x = x.slice(&[slice![range.start => range.end], slice![=>]]);
         let mut logits = Tensor::alloc(dt, &[x.shape()[0], lm_head.shape()[1]], Blob::new);
 
@@ -275,26 +318,39 @@ impl CausalLM for MixtralCPU {
         logits
     }
 
-    fn sample(
+    fn sample<'a>(
         &self,
-        args: impl IntoIterator<Item = SampleMeta>,
+        args: impl IntoIterator<Item = SampleMeta<'a>>,
         logits: Tensor<Self::Storage>,
     ) -> Vec<utok> {
         let &[_, voc] = logits.shape() else { panic!() };
         let logits: &[f16] = reslice(logits.as_slice());
-        args.into_iter()
-            .flat_map(|meta| repeat(meta.args).take(meta.num_decode))
+        let eos = self.eos_token;
+        let metas = args.into_iter().collect::<Vec<_>>();
+        metas
+            .iter()
+            .flat_map(|meta| repeat(meta).take(meta.num_decode))
             .enumerate()
-            .map(|(i, args)| {
-                self.kernels.sample(
-                    args.temperature,
-                    args.top_p,
-                    args.top_k,
-                    &common_cpu::slice!(logits; voc; [i]),
-                )
+            .map(|(i, meta)| {
+                let args = meta.args;
+                let logits = &common_cpu::slice!(logits; voc; [i]);
+                // 重复惩罚换算为偏置，与其他偏置一起加到 logits 上
+                let penalties =
+                    args.penalty_biases(meta.history, |token| logits[token as usize].to_f32());
+                let biases = meta.biases(eos).chain(penalties);
+                if args.gumbel {
+                    self.kernels.sample_gumbel(args.into(), biases, logits)
+                } else {
+                    self.kernels.sample_ordered(args.into(), biases, logits)
+                }
             })
             .collect()
     }
+
+    fn logits_to_host(&self, logits: &Tensor<Self::Storage>) -> Option<Vec<f32>> {
+        let logits: &[f16] = reslice(logits.as_slice());
+        Some(logits.iter().map(|x| x.to_f32()).collect())
+    }
 }
 
 #[inline]
@@ -302,6 +358,21 @@ fn tensor(dt: DigitLayout, shape: &[udim]) -> Tensor<Blob> {
     Tensor::alloc(dt, shape, Blob::new)
 }
 
+/// 计算每个 token 在各专家上的路由权重，温度越低权重越集中。
+fn route<T, U>(
+    kernels: &CpuKernels,
+    routes: &mut Tensor<Blob>,
+    x: &Tensor<T>,
+    w_gate: &Tensor<U>,
+    temperature: f32,
+) where
+    T: Deref<Target = [u8]>,
+    U: Deref<Target = [u8]>,
+{
+    kernels.mat_mul(routes, 0., x, w_gate, temperature.recip(), &ThisThread);
+    kernels.softmax(routes, &ThisThread);
+}
+
 fn topk(logits: &Tensor<Blob>, k: usize, weight: &mut Tensor<Blob>, indices: &mut Tensor<Blob>) {
     let n = logits.shape()[0];
     let dim = logits.shape()[1];
@@ -323,7 +394,11 @@ fn topk(logits: &Tensor<Blob>, k: usize, weight: &mut Tensor<Blob>, indices: &mu
         impl Eq for WithIndex {}
         impl Ord for WithIndex {
             fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-                self.data.total_cmp(&other.data).reverse()
+                // 值相同时序号小的在前，保证结果确定
+                self.data
+                    .total_cmp(&other.data)
+                    .reverse()
+                    .then(self.idx.cmp(&other.idx))
             }
         }
 
@@ -334,8 +409,12 @@ fn topk(logits: &Tensor<Blob>, k: usize, weight: &mut Tensor<Blob>, indices: &mu
             .enumerate()
             .map(|(idx, &data)| WithIndex { idx, data })
             .collect::<Vec<_>>();
-        vec.sort_unstable();
-        let top = &vec[..k];
+        // 只需要前 k 个，先划分出前 k 个再排序，避免对整行排序
+        if 0 < k && k < vec.len() {
+            vec.select_nth_unstable(k - 1);
+        }
+        let top = &mut vec[..k];
+        top.sort_unstable();
         for top_i in 0..k {
             weight_slice[(token_i as usize) * k + top_i] = top[top_i].data;
             indices_slice[(token_i as usize) * k + top_i] = top[top_i].idx as u32;
@@ -373,3 +452,87 @@ fn test_topk() {
     assert_eq!(weights[3], f16::from_f64(3.));
     assert_eq!(indices[3], 0);
 }
+
+#[test]
+fn test_route_temperature() {
+    use digit_layout::types::F16;
+
+    let kernels = CpuKernels::default();
+    let x = [f16::ONE];
+    let x = Tensor::new(F16, &[1, 1], reslice::<f16, u8>(&x));
+    let gate = [2., 1., 0., -1.].map(f16::from_f32);
+    let w_gate = Tensor::new(F16, &[1, 4], reslice::<f16, u8>(&gate));
+
+    let top_weight = |temperature: f32| {
+        let mut routes = tensor(F16, &[1, 4]);
+        route(&kernels, &mut routes, &x, &w_gate, temperature);
+        let weights: &[f16] = reslice(routes.as_slice());
+        let sum: f32 = weights.iter().map(|w| w.to_f32()).sum();
+        assert!((sum - 1.).abs() < 1e-2);
+        weights[0].to_f32()
+    };
+    let sharp = top_weight(0.5);
+    let plain = top_weight(1.);
+    let smooth = top_weight(2.);
+    assert!(sharp > plain && plain > smooth);
+}
+
+#[test]
+fn test_topk_large() {
+    use digit_layout::types::{F16, U32};
+
+    let (n, k) = (32000, 8);
+    let src = (0..n)
+        .map(|i| f16::from_f32(((i * 7919) % 1009) as f32 / 64.))
+        .collect::<Vec<_>>();
+    let mut blob = Blob::new(n * 2);
+    blob.copy_from_slice(reslice(&src));
+    let logits = Tensor::new(F16, &[1, n as udim], blob);
+    let mut weights = Tensor::alloc(F16, &[1, k as udim], Blob::new);
+    let mut indices = Tensor::alloc(U32, &[1, k as udim], Blob::new);
+
+    topk(&logits, k, &mut weights, &mut indices);
+
+    // 以整行排序的结果作为参照
+    let mut sorted = src.iter().enumerate().collect::<Vec<_>>();
+    sorted.sort_by(|(i, a), (j, b)| a.total_cmp(b).reverse().then(i.cmp(j)));
+
+    let weights: &[f16] = reslice(weights.as_slice());
+    let indices: &[u32] = reslice(indices.as_slice());
+    for (i, &(idx, &data)) in sorted[..k].iter().enumerate() {
+        assert_eq!(indices[i], idx as u32);
+        assert_eq!(weights[i], data);
+    }
+}
+
+/// 比较部分选择与整行排序的耗时，只报告不判断，需要时以 `cargo test -- --ignored --nocapture` 运行。
+#[test]
+#[ignore = "timing"]
+fn test_topk_timing() {
+    use digit_layout::types::{F16, U32};
+    use std::time::Instant;
+
+    let (n, k, rows) = (32000, 8, 64);
+    let src = (0..n * rows)
+        .map(|i| f16::from_f32(((i * 7919) % 1009) as f32 / 64.))
+        .collect::<Vec<_>>();
+    let mut blob = Blob::new(src.len() * 2);
+    blob.copy_from_slice(reslice(&src));
+    let logits = Tensor::new(F16, &[rows as udim, n as udim], blob);
+    let mut weights = Tensor::alloc(F16, &[rows as udim, k as udim], Blob::new);
+    let mut indices = Tensor::alloc(U32, &[rows as udim, k as udim], Blob::new);
+
+    let time = Instant::now();
+    topk(&logits, k, &mut weights, &mut indices);
+    let partial = time.elapsed();
+
+    // 以逐行整行排序作为参照
+    let time = Instant::now();
+    for line in src.chunks(n) {
+        let mut sorted = line.iter().enumerate().collect::<Vec<_>>();
+        sorted.sort_by(|(i, a), (j, b)| a.total_cmp(b).reverse().then(i.cmp(j)));
+        std::hint::black_box(&sorted[..k]);
+    }
+    let full = time.elapsed();
+    println!("{rows} rows × {n}, top {k}: partial {partial:?}, full sort {full:?}");
+}
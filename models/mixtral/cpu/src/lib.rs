@@ -2,7 +2,7 @@ mod infer;
 
 use causal_lm::Model;
 use common::{safe_tensors::SafeTensors, utok, FileLoadError};
-use common_cpu::CpuKernels;
+use common_cpu::{CpuKernels, RopeTable};
 use digit_layout::DigitLayout;
 use mixtral::{ConfigJson, MixtralParams};
 use std::path::Path;
@@ -18,20 +18,34 @@ pub struct MixtralCPU {
     max_seq_len: udim,
     d: udim,
     di: udim,
+    voc: udim,
     ne: udim,
     k: udim,
     epsilon: f32,
     theta: f32,
+    router_temperature: f32,
     params: MixtralParams,
+    /// 预先计算的旋转位置编码表。
+    rope: RopeTable,
 
     kernels: CpuKernels,
 }
 
+/// 模型加载参数。
+#[derive(Clone, Copy, Default, Debug)]
+pub struct ModelLoadMeta {
+    /// 按需加载专家时缓存的专家数量。
+    ///
+    /// 为 `None` 时所有专家常驻内存；否则专家权重保留在文件映射中，被路由到时才加载，
+    /// 超出容量时淘汰最久未使用的专家。
+    pub expert_cache: Option<usize>,
+}
+
 impl Model for MixtralCPU {
     type Error = FileLoadError;
-    type Meta = ();
+    type Meta = ModelLoadMeta;
 
-    fn load(model_dir: impl AsRef<Path>, _: Self::Meta) -> Result<Self, Self::Error> {
+    fn load(model_dir: impl AsRef<Path>, meta: Self::Meta) -> Result<Self, Self::Error> {
         let config = ConfigJson::load(&model_dir)?;
         Ok(Self {
             bos_token: config.bos_token_id,
@@ -43,17 +57,47 @@ impl Model for MixtralCPU {
             max_seq_len: config.max_position_embeddings as _,
             d: config.hidden_size as _,
             di: config.intermediate_size as _,
+            voc: config.vocab_size as _,
             epsilon: config.rms_norm_eps,
             theta: config.rope_theta,
-            params: MixtralParams::new(&config, SafeTensors::load_from_dir(model_dir)?),
+            router_temperature: config.router_temperature,
+            params: MixtralParams::new(
+                &config,
+                SafeTensors::load_from_dir(model_dir)?,
+                meta.expert_cache,
+            )?,
             ne: config.num_local_experts as _,
             k: config.num_experts_per_tok as _,
+            rope: RopeTable::new(
+                config.rope_theta,
+                config.hidden_size / config.num_attention_heads,
+                config.max_position_embeddings,
+            ),
 
             kernels: Default::default(),
         })
     }
 }
 
+impl MixtralCPU {
+    /// 将所有线性层权重转置后连续存储，计算时不再按步长访问转置的权重。
+    ///
+    /// 转置的副本额外占用与线性层权重相同大小的内存。
+    #[inline]
+    pub fn pretranspose(&mut self) {
+        self.params.pretranspose(self.nlayers, self.ne);
+    }
+
+    /// 将数据类型与计算类型不同的专家权重一次性转换，避免每次推理时重复转换。
+    ///
+    /// 转换后的副本常驻内存，适合混合精度的专家较多的模型。
+    #[inline]
+    pub fn cast_experts(&mut self) -> Result<(), FileLoadError> {
+        self.params
+            .cast_experts(self.nlayers, self.ne, self.data_type)
+    }
+}
+
 #[test]
 fn test_build() {
     use std::time::Instant;
@@ -61,8 +105,319 @@ fn test_build() {
     let t0 = Instant::now();
     let _transformer = MixtralCPU::load(
         "/data1/shared/hugging_face/Mixtral-8x7B-Instruct-v0.1_F16/",
-        (),
+        Default::default(),
     );
     let t1 = Instant::now();
     println!("build transformer {:?}", t1 - t0);
 }
+
+#[test]
+fn test_architecture() {
+    use causal_lm::CausalLM;
+
+    let model_dir = "/data1/shared/hugging_face/Mixtral-8x7B-Instruct-v0.1_F16/";
+    let Ok(transformer) = MixtralCPU::load(model_dir, Default::default()) else {
+        return;
+    };
+    let config = ConfigJson::load(model_dir).unwrap();
+    let info = transformer.architecture();
+    assert_eq!(info.nlayers, config.num_hidden_layers);
+    assert_eq!(info.nh, config.num_attention_heads);
+    assert_eq!(info.nkvh, config.num_key_value_heads);
+    assert_eq!(info.d, config.hidden_size);
+    assert_eq!(info.di, config.intermediate_size);
+    assert_eq!(info.voc, config.vocab_size);
+    assert_eq!(info.max_seq_len, config.max_position_embeddings);
+    assert_eq!(
+        info.moe,
+        Some((config.num_local_experts, config.num_experts_per_tok))
+    );
+}
+
+#[test]
+fn test_prefill() {
+    use causal_lm::CausalLM;
+    use common::upos;
+
+    let model_dir = "/data1/shared/hugging_face/Mixtral-8x7B-Instruct-v0.1_F16/";
+    let Ok(transformer) = MixtralCPU::load(model_dir, Default::default()) else {
+        return;
+    };
+    let d = transformer.architecture().d as udim;
+
+    let mut cache = transformer.new_cache();
+    let mut pos: upos = 0;
+    for tokens in [&[1, 733, 16289, 28793][..], &[22557, 28808]] {
+        let hidden_state = transformer.prefill(tokens, &mut cache, &mut pos).unwrap();
+        assert_eq!(hidden_state.shape(), [tokens.len() as udim, d]);
+    }
+    assert_eq!(pos, 6);
+}
+
+#[test]
+fn test_mixed_dtype_experts() {
+    use common::{f16, Blob};
+    use digit_layout::types::{F16, F32};
+    use std::fs;
+    use tensor::{reslice_mut, Tensor};
+
+    let (d, di) = (8usize, 16usize);
+    let value = |i: usize, seed: usize| ((i * 37 + seed) % 17) as f32 / 32. - 0.25;
+    // 专家 0 以 f16 存储，专家 1 以 f32 存储，数值都能被 f16 精确表示
+    let dir = std::env::temp_dir().join("mixtral-cpu-test-mixed-dtype-experts");
+    save_test_experts(&dir, d, di, &[false, true], value);
+
+    let mut transformer = MixtralCPU::load(&dir, Default::default()).unwrap();
+    assert_eq!(transformer.params.expert_dtype(0, 0), F16);
+    assert_eq!(transformer.params.expert_dtype(0, 1), F32);
+
+    // 以 f16 构造同样数值的参考权重，形状为 `[输入, 输出]`
+    let reference = |seed: usize, rows: usize, cols: usize| {
+        let mut t = Tensor::alloc(F16, &[rows as udim, cols as udim], Blob::new);
+        for (i, x) in reslice_mut::<u8, f16>(t.as_mut_slice())
+            .iter_mut()
+            .enumerate()
+        {
+            *x = f16::from_f32(value(i, seed));
+        }
+        t.transpose(&[1, 0])
+    };
+    let gate_up = |seed: usize| {
+        let mut t = Tensor::alloc(F16, &[(di + di) as udim, d as udim], Blob::new);
+        let (w1, w3) = t.as_mut_slice().split_at_mut(di * d * 2);
+        for (i, (a, b)) in reslice_mut::<u8, f16>(w1)
+            .iter_mut()
+            .zip(reslice_mut::<u8, f16>(w3))
+            .enumerate()
+        {
+            *a = f16::from_f32(value(i, seed));
+            *b = f16::from_f32(value(i, seed + 1));
+        }
+        t.transpose(&[1, 0])
+    };
+    let experts = [
+        (gate_up(0), reference(2, d, di)),
+        (gate_up(3), reference(5, d, di)),
+    ];
+
+    let run = |transformer: &MixtralCPU, selected: &[udim]| {
+        let experts = selected.iter().map(|&e| {
+            let w_gate_up = transformer.params.mlp_gate_up(0, e, F16);
+            let w_down = transformer.params.mlp_down(0, e, F16);
+            assert_eq!(w_gate_up.data_layout(), F16);
+            assert_eq!(w_down.data_layout(), F16);
+            (w_gate_up, w_down)
+        });
+        run_test_experts(transformer, d, di, value, experts)
+    };
+    let reference = run_test_experts(
+        &transformer,
+        d,
+        di,
+        value,
+        experts.iter().map(|(w_gate_up, w_down)| {
+            (
+                w_gate_up.as_ref().map_physical(|b| &**b),
+                w_down.as_ref().map_physical(|b| &**b),
+            )
+        }),
+    );
+
+    // 两个专家都按计算类型参与，结果与全部为 f16 的参考一致
+    let both = run(&transformer, &[0, 1]);
+    assert_eq!(both, reference);
+    assert_ne!(both, run(&transformer, &[0]));
+    assert_ne!(both, run(&transformer, &[1]));
+
+    // 一次性转换后不再需要临时转换，结果不变
+    transformer.cast_experts().unwrap();
+    assert_eq!(transformer.params.expert_dtype(0, 1), F16);
+    assert_eq!(both, run(&transformer, &[0, 1]));
+
+    drop(transformer);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// 在 `dir` 中保存只有一层专家的测试模型，`f32_experts` 的每一项表示对应的专家是否以 f32 存储。
+///
+/// 第 `e` 个专家的 w1、w3、w2 依次以 `3e`、`3e + 1`、`3e + 2` 为种子用 `value` 生成。
+#[cfg(test)]
+fn save_test_experts(
+    dir: &Path,
+    d: usize,
+    di: usize,
+    f32_experts: &[bool],
+    value: impl Fn(usize, usize) -> f32,
+) {
+    use common::{cast_tensor, safe_tensors::write_safetensors, Blob};
+    use digit_layout::types::{F16, F32};
+    use std::fs;
+    use tensor::{reslice_mut, Tensor};
+
+    let tensors = f32_experts
+        .iter()
+        .enumerate()
+        .flat_map(|(expert, &is_f32)| {
+            [("w1", di, d), ("w3", di, d), ("w2", d, di)]
+                .map(|(name, rows, cols)| (expert, is_f32, name, rows, cols))
+        })
+        .enumerate()
+        .map(|(seed, (expert, is_f32, name, rows, cols))| {
+            let mut t = Tensor::alloc(F32, &[rows as udim, cols as udim], Blob::new);
+            for (i, x) in reslice_mut::<u8, f32>(t.as_mut_slice())
+                .iter_mut()
+                .enumerate()
+            {
+                *x = value(i, seed);
+            }
+            let t = if is_f32 {
+                t
+            } else {
+                cast_tensor(&t, F16).unwrap()
+            };
+            let name = format!("model.layers.0.block_sparse_moe.experts.{expert}.{name}.weight");
+            (name, t)
+        })
+        .collect::<Vec<_>>();
+
+    let ne = f32_experts.len();
+    fs::create_dir_all(dir).unwrap();
+    write_safetensors(dir.join("model.safetensors"), &tensors).unwrap();
+    fs::write(
+        dir.join("config.json"),
+        format!(
+            r#"{{"bos_token_id":1,"eos_token_id":2,"hidden_size":{d},"intermediate_size":{di},
+                "max_position_embeddings":16,"num_attention_heads":1,"num_hidden_layers":1,
+                "num_key_value_heads":1,"vocab_size":8,"torch_dtype":"float16",
+                "num_local_experts":{ne},"num_experts_per_tok":2}}"#
+        ),
+    )
+    .unwrap();
+}
+
+/// 以 `value` 的种子 7 和 11 生成 `[1, d]` 的 f16 输入，依次经过 `experts` 中每个专家的 mlp，返回输出。
+#[cfg(test)]
+fn run_test_experts<W: std::ops::Deref<Target = [u8]>>(
+    transformer: &MixtralCPU,
+    d: usize,
+    di: usize,
+    value: impl Fn(usize, usize) -> f32,
+    experts: impl IntoIterator<Item = (tensor::Tensor<W>, tensor::Tensor<W>)>,
+) -> Vec<common::f16> {
+    use common::{f16, Blob};
+    use common_cpu::{KernelsA, ThisThread};
+    use digit_layout::types::F16;
+    use tensor::{reslice, reslice_mut, Tensor};
+
+    let mut x = Tensor::alloc(F16, &[1, d as udim], Blob::new);
+    let mut x1 = Tensor::alloc(F16, &[1, d as udim], Blob::new);
+    let mut buf = Tensor::alloc(F16, &[1, (di + di) as udim], Blob::new);
+    for (i, (x, x1)) in reslice_mut::<u8, f16>(x.as_mut_slice())
+        .iter_mut()
+        .zip(reslice_mut::<u8, f16>(x1.as_mut_slice()))
+        .enumerate()
+    {
+        *x = f16::from_f32(value(i, 7));
+        *x1 = f16::from_f32(value(i, 11));
+    }
+    for (w_gate_up, w_down) in experts {
+        transformer.kernels.mlp(
+            &mut x,
+            &x1,
+            &mut buf,
+            &w_gate_up,
+            &w_down,
+            0.5,
+            true,
+            &ThisThread,
+        );
+    }
+    reslice::<u8, f16>(x.as_slice()).to_vec()
+}
+
+#[test]
+fn test_expert_cache() {
+    use digit_layout::types::{F16, F32};
+    use std::fs;
+
+    let (d, di) = (8usize, 16usize);
+    let value = |i: usize, seed: usize| ((i * 29 + seed) % 13) as f32 / 16. - 0.375;
+    let dir = std::env::temp_dir().join("mixtral-cpu-test-expert-cache");
+    save_test_experts(&dir, d, di, &[false, true, false], value);
+
+    let resident = MixtralCPU::load(&dir, Default::default()).unwrap();
+    let offloaded = MixtralCPU::load(
+        &dir,
+        ModelLoadMeta {
+            expert_cache: Some(2),
+        },
+    )
+    .unwrap();
+    assert_eq!(resident.params.cached_experts(), None);
+    assert_eq!(offloaded.params.cached_experts(), Some(0));
+
+    let run = |transformer: &MixtralCPU, e: udim| {
+        let w_gate_up = transformer.params.mlp_gate_up(0, e, F16);
+        let w_down = transformer.params.mlp_down(0, e, F16);
+        assert_eq!(w_gate_up.data_layout(), F16);
+        run_test_experts(transformer, d, di, value, [(w_gate_up, w_down)])
+    };
+
+    // 路由到的专家超出缓存容量时淘汰旧的专家，重新加载后结果与常驻内存的专家一致
+    for e in [0, 1, 2, 0, 0, 2, 1] {
+        assert_eq!(run(&offloaded, e), run(&resident, e));
+        assert!(offloaded.params.cached_experts().unwrap() <= 2);
+    }
+    assert_eq!(offloaded.params.cached_experts(), Some(2));
+    assert_eq!(
+        offloaded.params.expert_dtype(0, 1),
+        resident.params.expert_dtype(0, 1)
+    );
+    // 同一个专家按不同的计算类型分别缓存，gate_up 和 down 一起缓存
+    assert_eq!(offloaded.params.mlp_gate_up(0, 1, F32).data_layout(), F32);
+    assert_eq!(offloaded.params.mlp_down(0, 1, F32).data_layout(), F32);
+    assert_eq!(offloaded.params.mlp_down(0, 1, F16).data_layout(), F16);
+    assert_eq!(offloaded.params.cached_experts(), Some(2));
+
+    drop(resident);
+    drop(offloaded);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_unsupported_expert_dtype() {
+    use common::{safe_tensors::write_safetensors, Blob};
+    use digit_layout::types::{F16, U32};
+    use std::fs;
+    use tensor::Tensor;
+
+    let (d, di) = (8usize, 16usize);
+    let dir = std::env::temp_dir().join("mixtral-cpu-test-unsupported-expert-dtype");
+    save_test_experts(&dir, d, di, &[false, false], |_, _| 0.);
+    // 专家 1 的 down 权重以不能转换为计算类型的整数存储
+    let tensors = (0..2)
+        .flat_map(|expert| {
+            [("w1", di, d), ("w3", di, d), ("w2", d, di)].map(move |(name, rows, cols)| {
+                let dt = if expert == 1 && name == "w2" {
+                    U32
+                } else {
+                    F16
+                };
+                (
+                    format!("model.layers.0.block_sparse_moe.experts.{expert}.{name}.weight"),
+                    Tensor::alloc(dt, &[rows as udim, cols as udim], Blob::new),
+                )
+            })
+        })
+        .collect::<Vec<_>>();
+    write_safetensors(dir.join("model.safetensors"), &tensors).unwrap();
+
+    // 常驻内存和按需加载的专家都在加载时报错，而不是在参与计算时失败
+    for expert_cache in [None, Some(2)] {
+        assert!(matches!(
+            MixtralCPU::load(&dir, ModelLoadMeta { expert_cache }),
+            Err(FileLoadError::UnsupportedDtype(dt)) if dt == U32
+        ));
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,196 @@
+use causal_lm::{CausalLM, Model};
+use log::{info, warn};
+use std::{any::type_name, fmt::Debug, marker::PhantomData, path::Path};
+
+/// 使用加载成功的模型执行的任务，对每一种可能加载的模型类型实例化。
+///
+/// 特性约束继承自 [`Service`](crate::Service)。
+pub trait WithModel {
+    /// 任务的结果。
+    type Output;
+    /// 在加载成功的模型上执行任务。
+    fn run<M>(self, model: M) -> Self::Output
+    where
+        M: CausalLM + Send + Sync + 'static,
+        M::Storage: Send,
+        M::Error: Debug;
+}
+
+/// 一个候选的后端，由模型类型和加载元数据组成，元数据为 `None` 表示后端不可用，例如没有检测到加速卡。
+pub struct Backend<M: Model> {
+    meta: Option<M::Meta>,
+    _phantom: PhantomData<M>,
+}
+
+impl<M: Model> Backend<M> {
+    /// 使用 `meta` 加载的后端。
+    #[inline]
+    pub fn new(meta: Option<M::Meta>) -> Self {
+        Self {
+            meta,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+/// 按顺序尝试加载的后端列表。
+///
+/// 单个 [`Backend`] 是只有一项的列表，元组 `(A, B)` 先尝试 `A` 再尝试 `B`，嵌套元组构成更长的列表。
+pub trait Backends {
+    /// 依次尝试加载模型，在第一个加载成功的模型上执行 `task`，全部失败时交还 `task`。
+    fn load<T: WithModel>(self, model_dir: &Path, task: T) -> Result<T::Output, T>;
+}
+
+impl<M> Backends for Backend<M>
+where
+    M: CausalLM + Send + Sync + 'static,
+    M::Storage: Send,
+    M::Error: Debug,
+{
+    fn load<T: WithModel>(self, model_dir: &Path, task: T) -> Result<T::Output, T> {
+        let name = type_name::<M>();
+        let Some(meta) = self.meta else {
+            info!("backend {name} is not available, skipped");
+            return Err(task);
+        };
+        match M::load(model_dir, meta) {
+            Ok(model) => {
+                info!("model loaded by backend {name}");
+                Ok(task.run(model))
+            }
+            Err(e) => {
+                warn!("backend {name} failed to load model: {e:?}");
+                Err(task)
+            }
+        }
+    }
+}
+
+impl<A: Backends, B: Backends> Backends for (A, B) {
+    #[inline]
+    fn load<T: WithModel>(self, model_dir: &Path, task: T) -> Result<T::Output, T> {
+        let (a, b) = self;
+        a.load(model_dir, task)
+            .or_else(|task| b.load(model_dir, task))
+    }
+}
+
+/// 按 `backends` 的顺序尝试加载 `model_dir` 中的模型，在第一个加载成功的模型上执行 `task`。
+///
+/// 所有后端都不可用或加载失败时交还 `task`。
+#[inline]
+pub fn load_first<T: WithModel>(
+    model_dir: impl AsRef<Path>,
+    backends: impl Backends,
+    task: T,
+) -> Result<T::Output, T> {
+    backends.load(model_dir.as_ref(), task)
+}
+
+#[test]
+fn test_fallback() {
+    use causal_lm::{CacheOverflow, DecodingMeta, ModelInfo, QueryContext, SampleMeta};
+    use common::{f16, upos, utok, Blob};
+    use tensor::Tensor;
+
+    /// 模拟没有检测到设备的加速卡后端，总是加载失败。
+    struct NoDevice;
+
+    impl Model for NoDevice {
+        type Meta = ();
+        type Error = &'static str;
+        fn load(_: impl AsRef<Path>, _: Self::Meta) -> Result<Self, Self::Error> {
+            Err("no device")
+        }
+    }
+
+    impl CausalLM for NoDevice {
+        type Storage = Blob;
+        fn architecture(&self) -> ModelInfo {
+            unreachable!()
+        }
+        fn max_seq_len(&self) -> upos {
+            unreachable!()
+        }
+        fn bos_token(&self) -> utok {
+            unreachable!()
+        }
+        fn eos_token(&self) -> utok {
+            unreachable!()
+        }
+        fn new_cache(&self) -> Tensor<Blob> {
+            unreachable!()
+        }
+        fn cache_bytes(&self, _: upos) -> usize {
+            unreachable!()
+        }
+        fn duplicate_cache(&self, _: &Tensor<Blob>, _: upos) -> Tensor<Blob> {
+            unreachable!()
+        }
+        fn cache_to_host(&self, _: &Tensor<Blob>, _: usize, _: upos) -> Tensor<Vec<f16>> {
+            unreachable!()
+        }
+        fn token_embed(&self, _: impl IntoIterator<Item = utok>) -> Tensor<Blob> {
+            unreachable!()
+        }
+        fn forward<'a>(
+            &self,
+            _: impl IntoIterator<Item = QueryContext<'a, Blob>>,
+            _: Tensor<Blob>,
+        ) -> Result<Tensor<Blob>, CacheOverflow> {
+            unreachable!()
+        }
+        fn decode(
+            &self,
+            _: impl IntoIterator<Item = DecodingMeta>,
+            _: Tensor<Blob>,
+        ) -> Tensor<Blob> {
+            unreachable!()
+        }
+        fn sample<'a>(
+            &self,
+            _: impl IntoIterator<Item = SampleMeta<'a>>,
+            _: Tensor<Blob>,
+        ) -> Vec<utok> {
+            unreachable!()
+        }
+    }
+
+    /// 返回加载成功的模型类型。
+    struct TypeName;
+
+    impl WithModel for TypeName {
+        type Output = &'static str;
+        fn run<M>(self, _: M) -> Self::Output
+        where
+            M: CausalLM + Send + Sync + 'static,
+            M::Storage: Send,
+            M::Error: Debug,
+        {
+            type_name::<M>()
+        }
+    }
+
+    // 没有可用的后端，或者所有后端都加载失败时交还任务
+    let none = Backend::<llama_cpu::Transformer>::new(None);
+    assert!(load_first("", none, TypeName).is_err());
+    let gpu = Backend::<NoDevice>::new(Some(()));
+    assert!(load_first("", gpu, TypeName).is_err());
+
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    // 加速卡后端不可用或加载失败时，回退到 CPU 后端
+    let cpu = || Backend::<llama_cpu::Transformer>::new(Some(Default::default()));
+    let cpu_name = type_name::<llama_cpu::Transformer>();
+    let backends = (Backend::<NoDevice>::new(None), cpu());
+    assert_eq!(
+        load_first(&model_dir, backends, TypeName).ok(),
+        Some(cpu_name)
+    );
+    let backends = (Backend::<NoDevice>::new(Some(())), cpu());
+    assert_eq!(
+        load_first(&model_dir, backends, TypeName).ok(),
+        Some(cpu_name)
+    );
+}
@@ -1,32 +1,51 @@
 #![deny(warnings)]
 
+mod fallback;
+mod service_group;
 mod session;
 mod session_manager;
+mod session_pool;
 mod tokenizer;
 
 use causal_lm::{CausalLM, SampleArgs};
-use chat_template::ChatTemplate;
-use session::{Dispatcher, Generator};
+use chat_template::{BuiltinTemplate, ChatTemplate, RoleMap};
+use common::utok;
+use log::warn;
+use session::{Dispatcher, Generator, PinnedPrefix, SessionCache};
 use std::{
     fmt::{self, Debug},
-    fs::File,
+    fs::{self, File},
+    io,
     path::Path,
-    sync::Arc,
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
 };
 use tokeneer::{Bpe, Lpe, Tokeneer};
-use tokenizer::{BPECommonNormalizer, Normalizer, Tokenize};
+use tokenizer::{SpecialTokens, Tokenize};
 use tokio::task::JoinHandle;
 
-pub use chat_template::Message;
-pub use session::{BusySession, ChatError, Session};
+pub use chat_template::{BuiltinTemplate, Message, RoleMap, UnknownRole};
+pub use fallback::{load_first, Backend, Backends, WithModel};
+pub use service_group::ServiceGroup;
+pub use session::{
+    AssistantMessage, BusySession, ChatError, CollapseNewlines, Completion, Decoded, EosSchedule,
+    FinishReason, GenerationConfig, OutputMode, PostProcessor, PrefillProgress, PromptOverflow,
+    RepetitionLimit, RolePolicy, Session, StripPrefix, Summarizer, TrimLeadingSpace, TrimStart,
+};
 pub use session_manager::{SessionError, SessionManager};
+pub use session_pool::{PooledSession, SessionPool};
+pub use tokenizer::{BPECommonNormalizer, Normalizer, Whitespace};
 
 /// 对话服务。
 pub struct Service<M: CausalLM> {
     // 共享组件，用于模型推理
     component: Arc<ServiceComponent<M>>,
     // 用户自定义组件
-    pub default_sample: SampleArgs,
+    /// 生成的设置，启动会话时整体复制给会话。
+    pub generation: GenerationConfig,
+    pub max_prompt_tokens: Option<usize>,
+    pub prompt_overflow: PromptOverflow,
+    pub system_prompt: Option<String>,
 }
 
 /// 服务中不变的组件，将在所有会话之间共享。
@@ -34,12 +53,24 @@ pub struct Service<M: CausalLM> {
 /// 推理线程的生命周期与这个组件绑定。
 struct ServiceComponent<M: CausalLM> {
     handle: Arc<Dispatcher<M>>,
-    tokenizer: Box<dyn Tokenize + Send + Sync>,
+    tokenizer: SpecialTokens<Box<dyn Tokenize + Send + Sync>>,
     normalizer: Box<dyn Normalizer + Send + Sync>,
     template: ChatTemplate,
+    /// 渲染前把消息的角色名转换为模板使用的角色名。
+    roles: RoleMap,
+    /// 所有会话的缓存，用于释放闲置会话的缓存。
+    sessions: Mutex<Vec<Weak<Mutex<SessionCache<M::Storage>>>>>,
+    /// 所有新会话共享的预填充前缀。
+    pinned: Mutex<Option<PinnedPrefix<M::Storage>>>,
     bos: String,
     #[allow(unused)]
     eos: String,
+    /// 换行符对应的 token，换行符不能编码为单个 token 时为 `None`。
+    newline: Option<utok>,
+    /// 推理片段的开始和结束标记，不能编码为单个 token 时为 `None`。
+    think: Option<(utok, utok)>,
+    /// 解码时等待拼接为完整字符的字节的上限。
+    max_pending_bytes: usize,
 }
 
 impl<M: CausalLM> Drop for ServiceComponent<M> {
@@ -57,23 +88,66 @@ where
     M::Error: Debug,
 {
     /// 加载模型文件和元数据
+    #[inline]
     pub fn load(model_dir: impl AsRef<Path>, meta: M::Meta) -> (Self, JoinHandle<()>) {
+        let model = M::load(&model_dir, meta).unwrap();
+        Self::from_model(model_dir, model)
+    }
+
+    /// 基于已加载的模型启动服务，分词器和对话模板从 `model_dir` 加载。
+    pub fn from_model(model_dir: impl AsRef<Path>, model: M) -> (Self, JoinHandle<()>) {
         // Dispatcher器
-        let handle = Arc::new(Dispatcher::from(M::load(&model_dir, meta).unwrap()));
+        let handle = Arc::new(Dispatcher::from(model));
         let tokenizer = tokenizer(&model_dir);
         let normalizer = normalizer(&model_dir);
         let template = template(model_dir);
+        // 模板中以字符串出现的 bos、eos 编码为单个 token
+        let bos_token = handle.model.bos_token();
+        let eos_token = handle.model.eos_token();
+        let bos = String::from(tokenizer.decode(bos_token));
+        let eos = String::from(tokenizer.decode(eos_token));
+        // 结束符不可靠时生成可能永不停止，默认限制生成长度
+        let max_tokens = default_max_tokens(bos_token, eos_token);
+        if let Some(max) = max_tokens {
+            warn!("eos token {eos_token} is same as bos, generation is limited to {max} tokens by default");
+        }
+        let mut tokenizer = SpecialTokens::new(
+            tokenizer,
+            [(bos.clone(), bos_token), (eos.clone(), eos_token)],
+        );
+        if tokenizer.detect_auto_bos(bos_token) {
+            warn!("tokenizer prepends bos token {bos_token} automatically, it will be removed to avoid double bos");
+        }
+        let single = |text: &str| {
+            Some(tokenizer.encode(&normalizer.encode(text)))
+                .filter(|tokens| tokens.len() == 1)
+                .map(|tokens| tokens[0])
+        };
+        let newline = single("\n");
+        let think = single("<think>").zip(single("</think>"));
         (
             Self {
                 component: Arc::new(ServiceComponent {
                     handle: handle.clone(),
-                    bos: tokenizer.decode(handle.model.bos_token()).into(),
-                    eos: tokenizer.decode(handle.model.eos_token()).into(),
+                    bos,
+                    eos,
+                    newline,
+                    think,
+                    max_pending_bytes: session::MAX_PENDING_BYTES,
                     tokenizer,
                     normalizer,
                     template,
+                    roles: Default::default(),
+                    sessions: Default::default(),
+                    pinned: Default::default(),
                 }),
-                default_sample: Default::default(),
+                generation: GenerationConfig {
+                    max_tokens,
+                    ..Default::default()
+                },
+                max_prompt_tokens: None,
+                prompt_overflow: Default::default(),
+                system_prompt: None,
             },
             // 启动推理任务，在阻塞线程中运行
             tokio::task::spawn_blocking(move || handle.run()),
@@ -86,59 +160,581 @@ impl<M: CausalLM> Service<M> {
     #[inline]
     pub fn launch(&self) -> Session<M> {
         let mut session: Session<M> = self.component.clone().into();
-        session.sample = self.default_sample;
+        session.generation = self.generation.clone();
+        session.max_prompt_tokens = self.max_prompt_tokens;
+        session.prompt_overflow = self.prompt_overflow;
+        session.system_prompt = self.system_prompt.clone();
         session
     }
 
     /// 从对话服务启动一个文本生成器。
+    ///
+    /// 提示词超过 [`max_prompt_tokens`](Self::max_prompt_tokens) 且策略为拒绝时返回错误，
+    /// 最大 token 数量为 0 时返回 [`ChatError::EmptyBudget`]，采样参数在模型上不可用时返回 [`ChatError::SampleArgs`]。
+    #[inline]
+    pub fn generate(
+        &self,
+        prompt: impl fmt::Display,
+        sample: Option<SampleArgs>,
+    ) -> Result<Generator<M>, ChatError> {
+        let generation = GenerationConfig {
+            sample: sample.unwrap_or(self.generation.sample),
+            ..self.generation.clone()
+        };
+        self.component.check_generation(&generation)?;
+        Generator::new(
+            self.component.clone(),
+            prompt,
+            self.component.task_args(generation),
+            self.max_prompt_tokens,
+            self.prompt_overflow,
+        )
+    }
+
+    /// 以非流式的方式生成文本，等待生成结束后返回完整的结果和 token 用量。
+    ///
+    /// 参数与 [`generate`](Self::generate) 相同。
+    pub async fn complete(
+        &self,
+        prompt: impl fmt::Display,
+        sample: Option<SampleArgs>,
+    ) -> Result<Completion, ChatError> {
+        Ok(self.generate(prompt, sample)?.complete().await)
+    }
+
+    /// 替换服务的文本规范化方式，例如调整 [`BPECommonNormalizer`] 对空格的处理。
+    ///
+    /// 只能在启动会话或生成器之前调用。
+    pub fn set_normalizer(&mut self, normalizer: impl Normalizer + Send + Sync + 'static) {
+        Arc::get_mut(&mut self.component)
+            .expect("normalizer cannot be changed after sessions are launched")
+            .normalizer = Box::new(normalizer);
+    }
+
+    /// 设置解码时末尾不完整的字符最多暂存的字节数，默认足够容纳任何不完整的 UTF-8 字符。
+    ///
+    /// 模型输出无法组成有效 UTF-8 的字节时，暂存的字节超出上限即替换为 `U+FFFD` 输出。
+    /// 只能在启动会话或生成器之前调用。
+    pub fn set_max_pending_bytes(&mut self, max: usize) {
+        Arc::get_mut(&mut self.component)
+            .expect("max pending bytes cannot be changed after sessions are launched")
+            .max_pending_bytes = max;
+    }
+
+    /// 设置消息角色名到模板角色名的映射，用于适配使用不同角色名约定的调用者。
+    ///
+    /// 只能在启动会话或生成器之前调用。
+    pub fn set_role_map(&mut self, roles: RoleMap) {
+        Arc::get_mut(&mut self.component)
+            .expect("role map cannot be changed after sessions are launched")
+            .roles = roles;
+    }
+
+    /// 使用内置的对话模板替换从模型目录加载的模板。
+    ///
+    /// 模板的特殊 token 在词表中时作为整体编码，其中的结束标记加入停止 token。
+    /// 只能在启动会话或生成器之前调用。
+    pub fn set_builtin_template(&mut self, template: BuiltinTemplate) {
+        let component = Arc::get_mut(&mut self.component)
+            .expect("template cannot be changed after sessions are launched");
+        component.template = ChatTemplate::builtin(template);
+        for &text in template.special_tokens() {
+            let Some(token) = component.tokenizer.find(text) else {
+                warn!("special token {text} of {template:?} template is not in vocab");
+                continue;
+            };
+            component.tokenizer.insert(text.into(), token);
+            let stop_token_ids = &mut self.generation.stop_token_ids;
+            if template.stop_tokens().contains(&text) && !stop_token_ids.contains(&token) {
+                stop_token_ids.push(token);
+            }
+        }
+    }
+
+    /// 预先填充所有会话共享的系统提示词，返回固定前缀的 token 数量。
+    ///
+    /// 系统提示词按对话模板渲染后在调用线程上计算一次，之后新会话的对话以它开头时复制计算好的缓存，
+    /// 不再重复预填充。再次调用时替换之前的前缀，前缀超过模型的上下文长度时返回错误。
+    #[inline]
+    pub fn pin_prefix(&self, text: &str) -> Result<usize, ChatError> {
+        self.component.pin_prefix(text)
+    }
+
+    /// 词表大小。
+    #[inline]
+    pub fn vocab_size(&self) -> usize {
+        self.component.tokenizer.vocab_size()
+    }
+
+    /// `token` 对应的文本，空格等经过规范化的字符被还原，`token` 超出词表时返回 `None`。
+    pub fn token_to_str(&self, token: utok) -> Option<String> {
+        let ServiceComponent {
+            tokenizer,
+            normalizer,
+            ..
+        } = &*self.component;
+        (token < tokenizer.vocab_size() as utok)
+            .then(|| normalizer.decode(tokenizer.decode(token)).into_owned())
+    }
+
+    /// `text` 恰好编码为单个 token 时返回这个 token。
+    pub fn str_to_token(&self, text: &str) -> Option<utok> {
+        let ServiceComponent {
+            tokenizer,
+            normalizer,
+            ..
+        } = &*self.component;
+        match *tokenizer.encode(&normalizer.encode(text)) {
+            [token] => Some(token),
+            _ => None,
+        }
+    }
+
+    /// 整个词表的文本，第 `i` 项是 token `i` 对应的文本。
+    ///
+    /// 词表较大时构造整个列表的开销较大，只需要少数 token 时使用 [`token_to_str`](Self::token_to_str)。
+    pub fn vocab(&self) -> Vec<String> {
+        (0..self.vocab_size() as utok)
+            .filter_map(|token| self.token_to_str(token))
+            .collect()
+    }
+
+    /// 估计 `available_bytes` 字节的存储空间可以同时容纳多少个上下文长度为 `context_len` 的会话。
+    ///
+    /// 只计算 kv 缓存，不包括模型参数和计算时的临时空间。
+    pub fn estimated_max_sessions(&self, available_bytes: usize, context_len: usize) -> usize {
+        let per_session = self.component.handle.model.cache_bytes(context_len as _);
+        available_bytes.checked_div(per_session).unwrap_or(0)
+    }
+
+    /// 优雅地停止服务：不再接受新的推理任务，等待进行中的任务生成完毕后停止推理线程。
+    ///
+    /// 最多等待 `timeout`，返回进行中的任务是否全部完成。停止后启动的推理会立即结束。
     #[inline]
-    pub fn generate(&self, prompt: impl fmt::Display, sample: Option<SampleArgs>) -> Generator<M> {
-        let sample = sample.unwrap_or(self.default_sample);
-        Generator::new(self.component.clone(), prompt, sample)
+    pub fn shutdown_graceful(&self, timeout: Duration) -> bool {
+        self.component.handle.stop_graceful(timeout)
     }
+
+    /// 设置推理线程的空闲回调，没有任务时每隔 `timeout` 调用一次 `f`，例如释放闲置的显存。
+    #[inline]
+    pub fn set_idle_callback(&self, timeout: Duration, f: impl Fn() + Send + Sync + 'static) {
+        self.component.handle.set_idle(Some((timeout, Arc::new(f))));
+    }
+
+    /// 清除推理线程的空闲回调。
+    #[inline]
+    pub fn clear_idle_callback(&self) {
+        self.component.handle.set_idle(None);
+    }
+
+    /// 开始记录推理线程每一步，以及其中词嵌入、前向传播、解码和采样的耗时。
+    #[inline]
+    pub fn start_trace(&self) {
+        self.component.handle.start_trace();
+    }
+
+    /// 停止记录，将记录以 Chrome tracing（`chrome://tracing`）兼容的 JSON 写入 `path`。
+    #[inline]
+    pub fn write_trace(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, self.component.handle.take_trace())
+    }
+
+    /// 释放闲置超过 `idle` 的会话的 kv 缓存，返回释放的缓存数量。
+    ///
+    /// 会话本身保留，再次使用时从对话记录重新计算缓存。正在推理的会话不受影响。
+    #[inline]
+    pub fn evict_idle(&self, idle: Duration) -> usize {
+        self.component.evict_idle(idle)
+    }
+}
+
+/// 结束符不可靠时默认的最大生成长度。
+const DEFAULT_MAX_TOKENS: usize = 512;
+
+/// 结束符与起始符相同时模型无法正常结束生成，返回默认的生成长度限制。
+#[inline]
+fn default_max_tokens(bos: utok, eos: utok) -> Option<usize> {
+    (eos == bos).then_some(DEFAULT_MAX_TOKENS)
 }
 
 #[test]
-fn test() {
-    use colored::{Color, Colorize};
-    use std::{io::Write, iter::zip};
-    use tokio::{runtime::Builder, task::JoinSet};
+fn test_default_max_tokens() {
+    assert_eq!(default_max_tokens(1, 2), None);
+    assert_eq!(default_max_tokens(1, 1), Some(DEFAULT_MAX_TOKENS));
+}
 
+/// 测试用的服务，加载测试模型，在当前线程的运行时中执行 `f`，没有测试模型时跳过。
+#[cfg(test)]
+fn test_service(
+    meta: llama_cpu::ModelLoadMeta,
+    f: impl FnOnce(&tokio::runtime::Runtime, Service<llama_cpu::Transformer>),
+) {
     let Some(model_dir) = common::test_model::find() else {
         return;
     };
-    println!("model_dir: {}", model_dir.display());
-
-    let runtime = Builder::new_current_thread().build().unwrap();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
     let _rt = runtime.enter();
 
-    let (service, _handle) = Service::<llama_cpu::Transformer>::load(model_dir, ());
+    let (service, _handle) = Service::load(model_dir, meta);
+    f(&runtime, service);
+    runtime.shutdown_background();
+}
 
-    let mut set = JoinSet::new();
-    let tasks = vec![
-        ("Say \"Hi\" to me.", Color::Yellow),
-        ("Hi", Color::Red),
-        ("Where is the capital of France?", Color::Green),
-    ];
+/// 测试用的对话，接收忙会话的全部输出直到生成结束，返回回答的文本。
+#[cfg(test)]
+fn test_chat<M: CausalLM>(runtime: &tokio::runtime::Runtime, busy: &mut BusySession<M>) -> String {
+    runtime.block_on(async {
+        let mut text = String::new();
+        while let Some(s) = busy.decode().await {
+            text.push_str(&s);
+        }
+        text
+    })
+}
+
+#[test]
+fn test() {
+    use colored::{Color, Colorize};
+    use std::{io::Write, iter::zip};
+    use tokio::task::JoinSet;
+
+    test_service(Default::default(), |runtime, service| {
+        let mut set = JoinSet::new();
+        let tasks = vec![
+            ("Say \"Hi\" to me.", Color::Yellow),
+            ("Hi", Color::Red),
+            ("Where is the capital of France?", Color::Green),
+        ];
+
+        let sessions = tasks.iter().map(|_| service.launch()).collect::<Vec<_>>();
 
-    let sessions = tasks.iter().map(|_| service.launch()).collect::<Vec<_>>();
+        for ((prompt, color), mut session) in zip(tasks, sessions) {
+            set.spawn(async move {
+                session
+                    .extend(&[Message {
+                        role: "user",
+                        content: prompt,
+                    }])
+                    .unwrap();
+                let mut busy = session.chat();
+                while let Some(s) = busy.decode().await {
+                    print!("{}", s.color(color));
+                    std::io::stdout().flush().unwrap();
+                }
+            });
+        }
 
-    for ((prompt, color), mut session) in zip(tasks, sessions) {
-        set.spawn(async move {
-            session.extend(&[Message {
+        runtime.block_on(async { while set.join_next().await.is_some() {} });
+    });
+}
+
+#[test]
+fn test_complete() {
+    test_service(Default::default(), |runtime, mut service| {
+        service.generation.max_tokens = Some(8);
+        let prompt = "Once upon a time,";
+        let completion = runtime
+            .block_on(service.complete(prompt, Some(SampleArgs::ARG_MAX)))
+            .unwrap();
+
+        // 用量与实际编码的提示词和贪心生成的 token 一致
+        let component = &service.component;
+        let text = component
+            .normalizer
+            .encode(&format!("{}{prompt}", component.bos));
+        assert_eq!(
+            completion.prompt_tokens,
+            component.tokenizer.encode(&text).len()
+        );
+
+        let mut generator = service.generate(prompt, Some(SampleArgs::ARG_MAX)).unwrap();
+        let generated = runtime.block_on(async {
+            let mut generated = Vec::new();
+            while let Some(Decoded::Tokens(ids)) = generator.decode_as(OutputMode::Tokens).await {
+                generated.extend(ids);
+            }
+            generated
+        });
+        assert_eq!(completion.completion_tokens, generated.len());
+        assert!(completion.completion_tokens <= 8);
+        assert_eq!(completion.finish_reason, generator.finish_reason());
+        assert!(!completion.text.is_empty());
+        drop(generator);
+
+        // 没有生成预算的请求被拒绝，不处理提示词
+        service.generation.max_tokens = Some(0);
+        assert!(matches!(
+            service.generate(prompt, None),
+            Err(ChatError::EmptyBudget)
+        ));
+    });
+}
+
+#[test]
+fn test_shutdown_graceful() {
+    test_service(Default::default(), |runtime, service| {
+        let message = [Message {
+            role: "user",
+            content: "Hi",
+        }];
+
+        let mut before = service.launch();
+        before.extend(&message).unwrap();
+        let mut busy = before.chat();
+        // 停止前提交的任务生成完毕
+        assert!(service.shutdown_graceful(Duration::from_secs(600)));
+        assert!(!test_chat(runtime, &mut busy).is_empty());
+        drop(busy);
+
+        // 停止后提交的任务被拒绝
+        let mut after = service.launch();
+        after.extend(&message).unwrap();
+        let mut busy = after.chat();
+        assert!(runtime.block_on(busy.decode()).is_none());
+    });
+}
+
+#[test]
+fn test_bos_single_token() {
+    test_service(Default::default(), |_, service| {
+        let component = &service.component;
+        let bos = component.handle.model.bos_token();
+        let eos = component.handle.model.eos_token();
+
+        let messages = [
+            Message {
                 role: "user",
-                content: prompt,
-            }]);
-            let mut busy = session.chat();
-            while let Some(s) = busy.decode().await {
-                print!("{}", s.color(color));
-                std::io::stdout().flush().unwrap();
+                content: "Hi",
+            },
+            Message {
+                role: "assistant",
+                content: "Hello",
+            },
+        ];
+        let text = component
+            .template
+            .render(&messages, &component.bos, &component.eos, false)
+            .unwrap();
+        let text = if text.starts_with(&component.bos) {
+            text
+        } else {
+            format!("{}{text}", component.bos)
+        };
+        let text = component.normalizer.encode(&text);
+        let tokens = component.tokenizer.encode(&text);
+        // bos 只出现一次，且是单个 token
+        assert_eq!(tokens[0], bos);
+        assert_eq!(tokens.iter().filter(|&&t| t == bos).count(), 1);
+        // 模板中的 eos 字符串同样编码为单个 token
+        let eos_str = text.matches(&*component.eos).count();
+        assert_eq!(tokens.iter().filter(|&&t| t == eos).count(), eos_str);
+    });
+}
+
+#[test]
+fn test_newline_penalty() {
+    test_service(Default::default(), |_, service| {
+        let component = &service.component;
+        let Some(newline) = component.newline else {
+            return;
+        };
+        assert_eq!(component.tokenizer.decode(newline), "\n");
+        let generation = |newline_penalty| GenerationConfig {
+            newline_penalty,
+            ..Default::default()
+        };
+        let sample = SampleArgs::default();
+        assert_eq!(
+            component.task_args(generation(0.)).generation.sample,
+            sample
+        );
+        assert_eq!(
+            component
+                .task_args(generation(2.))
+                .generation
+                .sample
+                .logit_bias,
+            Some((newline, -2.))
+        );
+    });
+}
+
+#[test]
+fn test_trace() {
+    test_service(Default::default(), |runtime, mut service| {
+        service.generation.max_tokens = Some(8);
+        service.start_trace();
+        let mut generator = service.generate("Once upon a time,", None).unwrap();
+        let generated = runtime.block_on(async {
+            let mut generated = 0;
+            while let Some((_, ids)) = generator.decode_with_ids().await {
+                generated += ids.len();
             }
+            generated
         });
-    }
+        // 每个接收的 token 都随文本返回，包括最后没有产生文本的 token
+        assert_eq!(generated, generator.completion_tokens());
+        let path = std::env::temp_dir().join(format!("infini-trace-{}.json", std::process::id()));
+        service.write_trace(&path).unwrap();
+        let json = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
 
-    runtime.block_on(async { while set.join_next().await.is_some() {} });
-    runtime.shutdown_background();
+        // 每一步采样一个 token，结束生成的 token 不输出
+        let json = serde_json::from_str::<serde_json::Value>(&json).unwrap();
+        let events = json["traceEvents"].as_array().unwrap();
+        let steps = events
+            .iter()
+            .filter(|e| e["name"] == "step" && e["args"]["decode"].as_u64() > Some(0))
+            .count();
+        assert_eq!(steps, generated + 1);
+        for name in ["embed", "forward", "decode", "sample"] {
+            assert!(events.iter().any(|e| e["name"] == name), "{name}");
+        }
+    });
+}
+
+#[test]
+fn test_vocab() {
+    test_service(Default::default(), |_, service| {
+        let voc = service.vocab_size();
+        assert_eq!(service.token_to_str(voc as _), None);
+
+        let ServiceComponent {
+            handle,
+            tokenizer,
+            normalizer,
+            ..
+        } = &*service.component;
+        let tokens = tokenizer.encode(&normalizer.encode("Hello world, how are you?"));
+        let model = &handle.model;
+        for token in [model.bos_token(), model.eos_token()]
+            .into_iter()
+            .chain(tokens)
+        {
+            let text = service.token_to_str(token).unwrap();
+            assert_eq!(service.str_to_token(&text), Some(token), "{text:?}");
+        }
+        assert_eq!(service.vocab().len(), voc);
+    });
+}
+
+#[test]
+fn test_evict_idle() {
+    test_service(Default::default(), |runtime, service| {
+        let chat = |session: &mut Session<_>, content| {
+            session
+                .extend(&[Message {
+                    role: "user",
+                    content,
+                }])
+                .unwrap();
+            test_chat(runtime, &mut session.chat())
+        };
+
+        let mut session = service.launch();
+        chat(&mut session, "Hi");
+        assert!(session.cache_bytes() > 0);
+
+        // 刚使用过的会话不会被释放
+        assert_eq!(service.evict_idle(Duration::from_secs(3600)), 0);
+        assert!(session.cache_bytes() > 0);
+        // 释放后会话保留对话记录，可以继续使用
+        assert_eq!(service.evict_idle(Duration::ZERO), 1);
+        assert_eq!(session.cache_bytes(), 0);
+        assert_eq!(session.dialog_pos(), 2);
+        assert!(!chat(&mut session, "Tell me a joke.").is_empty());
+        assert!(session.cache_bytes() > 0);
+    });
+}
+
+#[test]
+fn test_compact_cache() {
+    use llama_cpu::{CacheGrowth, ModelLoadMeta};
+
+    let meta = ModelLoadMeta {
+        cache_growth: CacheGrowth::Geometric {
+            initial: 4,
+            factor: 2.,
+        },
+        ..Default::default()
+    };
+    test_service(meta, |runtime, service| {
+        let chat = |session: &mut Session<_>, content| {
+            session
+                .extend(&[Message {
+                    role: "user",
+                    content,
+                }])
+                .unwrap();
+            test_chat(runtime, &mut session.chat())
+        };
+
+        let mut session = service.launch();
+        chat(&mut session, "Hi");
+        let first = session.cache_bytes();
+        chat(&mut session, "Tell me a joke.");
+        chat(&mut session, "Tell me another one.");
+        let grown = session.cache_bytes();
+        assert!(grown > first);
+
+        // 对话本身不会压缩缓存，回滚之后显式压缩才释放多余的容量
+        for pos in [4, 2] {
+            session.revert(pos).unwrap();
+            assert_eq!(session.cache_bytes(), grown);
+        }
+        session.compact_cache();
+        assert!(session.cache_bytes() < grown);
+        // 压缩后的缓存可以继续使用，并与重新开始的会话得到相同的回答
+        let answer = chat(&mut session, "Tell me a joke.");
+        let mut fresh = service.launch();
+        chat(&mut fresh, "Hi");
+        assert_eq!(answer, chat(&mut fresh, "Tell me a joke."));
+    });
+}
+
+#[test]
+fn test_pin_prefix() {
+    const SYSTEM: &str = "You are a helpful assistant.";
+    test_service(Default::default(), |runtime, mut service| {
+        service.system_prompt = Some(SYSTEM.into());
+        service.generation.prefill_chunk = Some(usize::MAX);
+        // 返回命中缓存的 token 数量和实际预填充的 token 数量
+        let chat = |session: &mut Session<_>| {
+            session
+                .extend(&[Message {
+                    role: "user",
+                    content: "Hi",
+                }])
+                .unwrap();
+            let mut busy = session.chat();
+            let mut prefilled = 0;
+            runtime.block_on(async {
+                while let Some(progress) = busy.progress().await {
+                    prefilled = progress.total;
+                }
+            });
+            test_chat(runtime, &mut busy);
+            (busy.cached_tokens(), prefilled)
+        };
+
+        let (cached, full) = chat(&mut service.launch());
+        assert_eq!(cached, 0);
+        let pinned = service.pin_prefix(SYSTEM).unwrap();
+        assert!(pinned > 0);
+        // 超过上下文长度的前缀被拒绝，之前的前缀仍然有效
+        let max = service.component.handle.model.max_seq_len() as usize;
+        let long = SYSTEM.repeat(max / 4);
+        assert!(matches!(
+            service.pin_prefix(&long),
+            Err(ChatError::PromptTooLong { max: m, .. }) if m == max
+        ));
+        // 固定前缀之后启动的会话复用前缀的缓存，只预填充其余部分
+        let (cached, prefilled) = chat(&mut service.launch());
+        assert!(cached > 0 && cached <= pinned);
+        assert_eq!(cached + prefilled, full);
+    });
 }
 
 fn template(model_dir: impl AsRef<Path>) -> ChatTemplate {
@@ -177,7 +773,7 @@ fn template(model_dir: impl AsRef<Path>) -> ChatTemplate {
 
 fn normalizer(model_dir: impl AsRef<Path>) -> Box<dyn Normalizer + Send + Sync> {
     if model_dir.as_ref().join("tokenizer.model").is_file() {
-        return Box::new(BPECommonNormalizer {});
+        return Box::new(BPECommonNormalizer::default());
     }
     if model_dir.as_ref().join("vocabs.txt").is_file() {
         return Box::new(());
@@ -0,0 +1,89 @@
+use crate::{session::Generator, ChatError, Service, Session};
+use causal_lm::{CausalLM, SampleArgs};
+use std::{
+    fmt::{self, Debug},
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering::Relaxed},
+};
+use tokio::task::JoinHandle;
+
+/// 服务组，由多个模型副本的服务构成。
+///
+/// 新的会话和生成任务按轮询的方式分配到各个副本。
+pub struct ServiceGroup<M: CausalLM> {
+    services: Vec<Service<M>>,
+    next: AtomicUsize,
+}
+
+impl<M> ServiceGroup<M>
+where
+    M: CausalLM + Send + Sync + 'static,
+    M::Storage: Send,
+    M::Error: Debug,
+{
+    /// 为每个模型副本启动一个服务。
+    pub fn from_models(
+        model_dir: impl AsRef<Path>,
+        models: impl IntoIterator<Item = M>,
+    ) -> (Self, Vec<JoinHandle<()>>) {
+        let (services, handles): (Vec<_>, Vec<_>) = models
+            .into_iter()
+            .map(|model| Service::from_model(&model_dir, model))
+            .unzip();
+        (Self::from(services), handles)
+    }
+}
+
+impl<M: CausalLM> From<Vec<Service<M>>> for ServiceGroup<M> {
+    #[inline]
+    fn from(services: Vec<Service<M>>) -> Self {
+        assert!(!services.is_empty());
+        Self {
+            services,
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<M: CausalLM> ServiceGroup<M> {
+    /// 组中的服务，可用于逐个修改参数。
+    #[inline]
+    pub fn services_mut(&mut self) -> &mut [Service<M>] {
+        &mut self.services
+    }
+
+    /// 副本数量。
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.services.len()
+    }
+
+    /// 服务组是否为空，总是 `false`。
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.services.is_empty()
+    }
+
+    /// 选择下一个副本。
+    #[inline]
+    fn route(&self) -> &Service<M> {
+        let i = self.next.fetch_add(1, Relaxed) % self.services.len();
+        &self.services[i]
+    }
+
+    /// 在下一个副本上启动一个会话。
+    #[inline]
+    pub fn launch(&self) -> Session<M> {
+        self.route().launch()
+    }
+
+    /// 在下一个副本上启动一个文本生成器。
+    #[inline]
+    pub fn generate(
+        &self,
+        prompt: impl fmt::Display,
+        sample: Option<SampleArgs>,
+    ) -> Result<Generator<M>, ChatError> {
+        self.route().generate(prompt, sample)
+    }
+}
@@ -1,49 +1,183 @@
-﻿use std::sync::{Condvar, Mutex};
+﻿use std::{
+    sync::{Condvar, Mutex},
+    time::Duration,
+};
 
 pub struct Batcher<T> {
-    queue: Mutex<(Vec<T>, bool)>,
+    queue: Mutex<Queue<T>>,
     // 用来同步线程
     condvar: Condvar,
 }
 
+struct Queue<T> {
+    tasks: Vec<T>,
+    /// 已取出但还没有归还的任务数量。
+    running: usize,
+    /// 是否接受新任务。
+    accepting: bool,
+    alive: bool,
+}
+
+impl<T> Queue<T> {
+    /// 队列停止接受新任务，并且所有任务都已完成。
+    #[inline]
+    fn drained(&self) -> bool {
+        !self.accepting && self.tasks.is_empty() && self.running == 0
+    }
+
+    /// 取任务时是否需要等待。
+    #[inline]
+    fn blocked(&self) -> bool {
+        self.tasks.is_empty() && self.alive && !self.drained()
+    }
+
+    #[inline]
+    fn take(&mut self) -> Vec<T> {
+        let tasks = std::mem::take(&mut self.tasks);
+        self.running += tasks.len();
+        tasks
+    }
+}
+
 impl<T> Batcher<T> {
     #[inline]
     pub fn new() -> Self {
         Self {
-            queue: Mutex::new((Vec::new(), true)),
+            queue: Mutex::new(Queue {
+                tasks: Vec::new(),
+                running: 0,
+                accepting: true,
+                alive: true,
+            }),
             condvar: Default::default(),
         }
     }
 
+    /// 提交新任务，队列关闭后提交的任务被丢弃。
     #[inline]
     pub fn enq(&self, val: T) {
-        let mut lock = self.queue.lock().unwrap();
-        let (queue, alive) = &mut *lock;
-        if *alive {
-            queue.push(val);
+        let mut queue = self.queue.lock().unwrap();
+        if queue.alive && queue.accepting {
+            queue.tasks.push(val);
         }
         self.condvar.notify_one();
     }
 
+    /// 归还取出的 `taken` 个任务，其中 `tasks` 还需要继续执行。
+    ///
+    /// 即使队列已经关闭，未完成的任务也会重新入队。
+    pub fn put_back(&self, taken: usize, tasks: impl IntoIterator<Item = T>) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.running -= taken;
+        if queue.alive {
+            queue.tasks.extend(tasks);
+        }
+        self.condvar.notify_all();
+    }
+
     #[inline]
     pub fn deq(&self) -> Vec<T> {
         // 转移所有权，并且清空队列
         // 阻塞直到队列不为空
-        std::mem::take(
-            &mut self
+        self.condvar
+            .wait_while(self.queue.lock().unwrap(), |q| q.blocked())
+            .unwrap()
+            .take()
+    }
+
+    /// 与 [`deq`](Self::deq) 相同，但队列每空闲 `timeout` 就调用一次 `idle`。
+    pub fn deq_with_idle(&self, timeout: Duration, mut idle: impl FnMut()) -> Vec<T> {
+        let mut lock = self.queue.lock().unwrap();
+        loop {
+            let (mut guard, result) = self
                 .condvar
-                .wait_while(self.queue.lock().unwrap(), |(q, a)| q.is_empty() && *a)
-                .unwrap()
-                .0,
-        )
+                .wait_timeout_while(lock, timeout, |q| q.blocked())
+                .unwrap();
+            if !result.timed_out() {
+                break guard.take();
+            }
+            // 回调期间不持有锁，以免阻塞任务提交
+            drop(guard);
+            idle();
+            lock = self.queue.lock().unwrap();
+        }
+    }
+
+    /// 停止接受新任务，已提交的任务继续执行直到完成。
+    #[inline]
+    pub fn close(&self) {
+        self.queue.lock().unwrap().accepting = false;
+        self.condvar.notify_all();
+    }
+
+    /// 等待关闭的队列中所有任务完成，超时返回 `false`。
+    pub fn wait_drained(&self, timeout: Duration) -> bool {
+        let (_guard, result) = self
+            .condvar
+            .wait_timeout_while(self.queue.lock().unwrap(), timeout, |q| {
+                q.alive && !q.drained()
+            })
+            .unwrap();
+        !result.timed_out()
     }
 
     #[inline]
     pub fn shutdown(&self) {
-        let mut lock = self.queue.lock().unwrap();
-        let (queue, alive) = &mut *lock;
-        *alive = false;
-        queue.clear();
+        let mut queue = self.queue.lock().unwrap();
+        queue.alive = false;
+        queue.tasks.clear();
         self.condvar.notify_all();
     }
 }
+
+#[test]
+fn test_idle() {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering::SeqCst},
+            Arc,
+        },
+        thread,
+    };
+
+    let batcher = Arc::new(Batcher::new());
+    let count = Arc::new(AtomicUsize::new(0));
+    let handle = {
+        let batcher = batcher.clone();
+        let count = count.clone();
+        thread::spawn(move || {
+            batcher.deq_with_idle(Duration::from_millis(10), || {
+                count.fetch_add(1, SeqCst);
+            })
+        })
+    };
+
+    thread::sleep(Duration::from_millis(100));
+    assert!(count.load(SeqCst) >= 2);
+
+    batcher.enq(1);
+    assert_eq!(handle.join().unwrap(), [1]);
+    // 有任务到来后不再调用
+    let fired = count.load(SeqCst);
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(count.load(SeqCst), fired);
+}
+
+#[test]
+fn test_close() {
+    let batcher = Batcher::new();
+    batcher.enq(1);
+    assert_eq!(batcher.deq(), [1]);
+
+    batcher.close();
+    // 关闭后不再接受新任务
+    batcher.enq(2);
+    assert!(!batcher.wait_drained(Duration::ZERO));
+    // 进行中的任务可以继续执行
+    batcher.put_back(1, [1]);
+    assert_eq!(batcher.deq(), [1]);
+    batcher.put_back(1, []);
+    // 所有任务完成后取出空队列，推理线程退出
+    assert!(batcher.wait_drained(Duration::ZERO));
+    assert!(batcher.deq().is_empty());
+}
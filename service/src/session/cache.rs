@@ -1,8 +1,8 @@
-﻿use causal_lm::{CausalLM, QueryContext};
-use common::{upos, utok};
+﻿use causal_lm::{CacheOverflow, CausalLM, QueryContext};
+use common::{f16, upos, utok};
 use log::{debug, info};
 use rangemap::{range_set, RangeSet};
-use std::{cmp::min, ops::Range};
+use std::{cmp::min, mem::size_of, ops::Range};
 use tensor::Tensor;
 
 pub(super) struct Cache<Storage> {
@@ -16,6 +16,10 @@ pub(super) struct Cache<Storage> {
     to_be_cached: RangeSet<usize>,
     /// 计算缓存。
     cache: Tensor<Storage>,
+    /// token 序列每个位置的前缀累积哈希，`hashes[i]` 是 `tokens[..=i]` 的哈希。
+    hashes: Vec<u64>,
+    /// 计算缓存中与 token 序列一致的长度，回退位置后可以再前进到这里。
+    computed: usize,
 }
 
 pub struct CacheQuery<'a> {
@@ -92,7 +96,7 @@ impl<Storage> Cache<Storage> {
     #[inline]
     pub fn new(t: &impl CausalLM<Storage = Storage>, tokens: Vec<utok>) -> Self {
         let tokens_len = tokens.len();
-        Self {
+        let mut ans = Self {
             tokens,
             pos: 0,
             cached: RangeSet::new(),
@@ -102,6 +106,29 @@ impl<Storage> Cache<Storage> {
                 RangeSet::new()
             },
             cache: t.new_cache(),
+            hashes: Vec::new(),
+            computed: 0,
+        };
+        ans.sync_hashes();
+        ans
+    }
+
+    /// 复制前 `len` 个已缓存的 token 作为新缓存，要求这部分从对话开头连续计算。
+    pub fn fork_prefix(&self, t: &impl CausalLM<Storage = Storage>, len: usize) -> Self {
+        debug!("call fork_prefix {len}");
+        assert!(len > 0 && self.pos == 0);
+        assert!(self
+            .cached
+            .first()
+            .is_some_and(|r| r.start == 0 && r.end >= len));
+        Self {
+            tokens: self.tokens[..len].to_vec(),
+            pos: 0,
+            cached: range_set![0..len],
+            to_be_cached: RangeSet::new(),
+            cache: t.duplicate_cache(&self.cache, len as _),
+            hashes: self.hashes[..len].to_vec(),
+            computed: len,
         }
     }
 
@@ -115,6 +142,8 @@ impl<Storage> Cache<Storage> {
             cached: self.cached.clone(),
             to_be_cached: self.to_be_cached.clone(),
             cache: t.duplicate_cache(&self.cache, self.cached_len() as _),
+            hashes: self.hashes.clone(),
+            computed: self.cached_len(),
         }
     }
     /// 回滚缓存到 `pos`，并返回剩余的有效缓存长度。
@@ -132,6 +161,8 @@ impl<Storage> Cache<Storage> {
         }
         // 3. tokens.len() 不大于 pos；
         self.tokens.truncate(len);
+        self.sync_hashes();
+        self.computed = self.cached_len();
         // 返回当前的缓存长度
         Some(self.cached_len())
     }
@@ -142,29 +173,63 @@ impl<Storage> Cache<Storage> {
         let before_len = self.tokens.len();
         self.tokens.extend_from_slice(tokens);
         self.to_be_cached.insert(before_len..self.tokens.len());
+        self.sync_hashes();
     }
     /// 所有 token 中还没有加入缓存的部分就是这次的查询。
     #[inline]
     pub fn query(&self) -> CacheQuery {
         CacheQuery::new(&self.tokens, &self.to_be_cached)
     }
-    /// 生成对应的查询上下文。
+    /// 生成对应的查询上下文，查询长度为 `len`。
     #[inline]
-    pub fn as_ctx(&mut self) -> QueryContext<Storage> {
+    pub fn as_ctx(&mut self, len: usize) -> QueryContext<Storage> {
         debug!("call as_ctx");
         debug!(
             "cache reset\ncached is {:?}\nto_be_cached is {:?}",
             self.cached, self.to_be_cached
         );
+        assert!(len <= self.to_be_cached_len());
         QueryContext {
-            range: self.cached_len() as upos..(self.cached_len() + self.to_be_cached_len()) as upos,
+            range: self.cached_len() as upos..(self.cached_len() + len) as upos,
             cache: Some(&mut (self.cache)),
         }
     }
+    /// 将前 `len` 个待缓存的 token 标记为已缓存，用于分块预填充。
+    pub fn commit(&mut self, len: usize) {
+        debug!("call commit");
+        let mut rest = len;
+        for range in self.to_be_cached.iter().cloned().collect::<Vec<_>>() {
+            if rest == 0 {
+                break;
+            }
+            let done = range.start..range.start + min(range.len(), rest);
+            rest -= done.len();
+            self.to_be_cached.remove(done.clone());
+            self.cached.insert(done);
+        }
+        self.computed = self.computed.max(self.cached_len());
+    }
+
+    /// 在调用线程上直接计算所有待缓存的 token，不经过推理线程，超出缓存容量时返回错误。
+    pub fn prefill(&mut self, t: &impl CausalLM<Storage = Storage>) -> Result<(), CacheOverflow> {
+        debug!("call prefill");
+        let len = self.to_be_cached_len();
+        if len > 0 {
+            let tokens = self.query().into_iter().copied().collect::<Vec<_>>();
+            let mut pos = self.cached_len() as upos;
+            t.prefill(&tokens, &mut self.cache, &mut pos)?;
+            self.commit(len);
+        }
+        Ok(())
+    }
 
     /// 将新采样的值加入缓存。默认to_be_cached不为空
     #[inline]
     pub fn push(&mut self, token: utok) {
+        self.push_step(&[token]);
+    }
+    /// 将一步采样得到的多个值加入缓存，它们在下一轮推理中一起计算。
+    pub fn push_step(&mut self, tokens: &[utok]) {
         debug!("call push");
         assert!(self.is_continue());
 
@@ -172,12 +237,48 @@ impl<Storage> Cache<Storage> {
         self.to_be_cached
             .iter()
             .for_each(|range| self.cached.insert(range.clone()));
+        self.computed = self.computed.max(self.cached_len());
         //清空to_be_cached 并插入新的需要缓存的token
         self.to_be_cached.clear();
-        self.to_be_cached
-            .insert(self.tokens.len()..self.tokens.len() + 1);
+        if !tokens.is_empty() {
+            self.to_be_cached
+                .insert(self.tokens.len()..self.tokens.len() + tokens.len());
+        }
         //插入token
-        self.tokens.push(token);
+        self.tokens.extend_from_slice(tokens);
+        self.sync_hashes();
+    }
+    /// 计算缓存的位置，即已缓存的 token 数量，下一次推理从这里开始写入计算缓存。
+    #[inline]
+    pub fn position(&self) -> usize {
+        self.cached_len()
+    }
+    /// 将计算缓存的位置设置到 `pos`，不重新分配缓存。
+    ///
+    /// 回退时之后的 token 变为待缓存，在下一次推理中重新计算；
+    /// 前进时只能回到曾经计算过且 token 没有改变的位置，超出时返回 `None`。
+    pub fn set_position(&mut self, pos: usize) -> Option<()> {
+        debug!("call set_position {pos}");
+        let current = self.cached_len();
+        if pos > self.computed {
+            return None;
+        }
+        if pos < current {
+            // 从已缓存部分的末尾移出多余的 token
+            let mut rest = current - pos;
+            for range in self.cached.iter().rev().cloned().collect::<Vec<_>>() {
+                if rest == 0 {
+                    break;
+                }
+                let undo = range.end - min(range.len(), rest)..range.end;
+                rest -= undo.len();
+                self.cached.remove(undo.clone());
+                self.to_be_cached.insert(undo);
+            }
+        } else {
+            self.commit(pos - current);
+        }
+        Some(())
     }
     /// 已采样的最后一个词在对话中的位置。
     #[inline]
@@ -198,6 +299,7 @@ impl<Storage> Cache<Storage> {
         if self.cached_len() + self.to_be_cached_len() >= max {
             self.cached.clear();
             self.to_be_cached = range_set![(self.tokens.len() - min..self.tokens.len())];
+            self.computed = 0;
         }
     }
     /// 重置缓存窗口，保留起始的一部分，并将起始点设置为尾部一部分之前
@@ -232,6 +334,7 @@ impl<Storage> Cache<Storage> {
                 self.to_be_cached = range_set![self.tokens.len() - end_size..self.tokens.len()];
             }
 
+            self.computed = self.cached_len();
             info!(
                 "cache reset\ncached is {:?}\nto_be_cached is {:?}",
                 self.cached, self.to_be_cached
@@ -242,7 +345,10 @@ impl<Storage> Cache<Storage> {
     pub fn reset_with(&mut self, tokens: Vec<utok>, pos: usize) {
         self.tokens = tokens;
         self.pos = pos;
+        self.hashes.clear();
+        self.sync_hashes();
         self.cached.clear();
+        self.computed = 0;
         let tokens_len = self.tokens.len();
         self.to_be_cached = if tokens_len > 0 {
             range_set![0..tokens_len]
@@ -257,6 +363,9 @@ impl<Storage> Cache<Storage> {
             self.tokens.copy_within(to_remove.., 0);
             self.pos += to_remove;
             self.tokens.truncate(self.tokens.len() - to_remove);
+            // 序列的开头变了，重新计算哈希
+            self.hashes.clear();
+            self.sync_hashes();
 
             // 整体减小cached和to_be_cached
             self.cached
@@ -275,6 +384,34 @@ impl<Storage> Cache<Storage> {
                 .clone_into(&mut self.to_be_cached);
         }
     }
+    /// 压缩缓存结构，丢弃缓存窗口之前的 token，释放 token 序列和计算缓存在有效长度之后的容量。
+    ///
+    /// 计算缓存中的 kv 总是从 0 开始紧密排列，因此不需要搬移。
+    pub fn compact(&mut self, t: &impl CausalLM<Storage = Storage>) {
+        debug!("call compact");
+        if !self.cached.is_empty() {
+            self.cleanup_before_start();
+        }
+        self.tokens.shrink_to_fit();
+        self.hashes.shrink_to_fit();
+        t.shrink_cache(&mut self.cache, self.computed as _);
+    }
+
+    /// 缓存占用的字节数，包括 token 序列和计算缓存。
+    pub fn cache_bytes(&self) -> usize {
+        self.tokens.capacity() * size_of::<utok>() + self.cache.bytes_size()
+    }
+
+    /// 将第 `layer` 层已缓存部分的 K-V 拷贝到主存（`2 x nkvh x cached_len x dh`）。
+    #[inline]
+    pub fn kv_to_host(
+        &self,
+        t: &impl CausalLM<Storage = Storage>,
+        layer: usize,
+    ) -> Tensor<Vec<f16>> {
+        t.cache_to_host(&self.cache, layer, self.cached_len() as _)
+    }
+
     /// 获取cached中最后一个区间的长度，如果cached为空则会panic
     pub fn get_last_cached_range_len(&self) -> usize {
         self.cached.last().unwrap().len()
@@ -291,7 +428,7 @@ impl<Storage> Cache<Storage> {
 
     /// 获取cached 总长度
     #[inline]
-    fn cached_len(&self) -> usize {
+    pub fn cached_len(&self) -> usize {
         self.cached.iter().map(|range| range.len()).sum()
     }
 
@@ -300,6 +437,30 @@ impl<Storage> Cache<Storage> {
     fn to_be_cached_len(&self) -> usize {
         self.to_be_cached.iter().map(|range| range.len()).sum()
     }
+
+    /// token 序列每个位置的前缀累积哈希。
+    ///
+    /// 两个缓存在位置 `i` 的哈希相同，说明它们的前 `i + 1` 个 token 相同，可以共享这部分 kv 缓存。
+    #[inline]
+    pub fn prefix_hashes(&self) -> &[u64] {
+        &self.hashes
+    }
+
+    /// 使哈希与 token 序列保持一致，只计算新增的部分。
+    fn sync_hashes(&mut self) {
+        // FNV-1a
+        const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+        const PRIME: u64 = 0x0100_0000_01b3;
+
+        self.hashes.truncate(self.tokens.len());
+        let mut hash = self.hashes.last().copied().unwrap_or(OFFSET);
+        for &token in &self.tokens[self.hashes.len()..] {
+            for byte in token.to_le_bytes() {
+                hash = (hash ^ byte as u64).wrapping_mul(PRIME);
+            }
+            self.hashes.push(hash);
+        }
+    }
 }
 
 #[test]
@@ -312,3 +473,85 @@ fn test_cache_query() {
     .into_iter()
     .for_each(|a| println!("{:?}", a));
 }
+
+#[test]
+fn test_prefix_hashes() {
+    use digit_layout::types::F16;
+    use std::iter::zip;
+
+    let cache = |tokens: &[utok]| {
+        let mut cache = Cache {
+            tokens: vec![],
+            pos: 0,
+            cached: RangeSet::new(),
+            to_be_cached: RangeSet::new(),
+            cache: Tensor::new(F16, &[2, 2, 4, 1024, 64], ()),
+            hashes: Vec::new(),
+            computed: 0,
+        };
+        cache.extend(tokens);
+        cache
+    };
+
+    let prompt = [1, 529, 29989, 1792, 29989, 29958, 13, 18567];
+    let a = cache(&prompt);
+    let b = cache(&prompt);
+    assert_eq!(a.prefix_hashes().len(), prompt.len());
+    assert_eq!(a.prefix_hashes(), b.prefix_hashes());
+
+    // 第 5 个 token 不同，哈希从这个位置开始分叉
+    let mut other = prompt;
+    other[5] = 42;
+    let c = cache(&other);
+    assert_eq!(a.prefix_hashes()[..5], c.prefix_hashes()[..5]);
+    assert!(zip(&a.prefix_hashes()[5..], &c.prefix_hashes()[5..]).all(|(a, c)| a != c));
+
+    // 回滚之后再扩展，与一次性扩展的结果相同
+    let mut d = cache(&other);
+    d.cached.insert(0..other.len());
+    d.revert(5).unwrap();
+    d.extend(&prompt[5..]);
+    assert_eq!(d.prefix_hashes(), a.prefix_hashes());
+}
+
+#[test]
+fn test_set_position() {
+    use digit_layout::types::F16;
+
+    let mut cache = Cache {
+        tokens: vec![],
+        pos: 0,
+        cached: RangeSet::new(),
+        to_be_cached: RangeSet::new(),
+        cache: Tensor::new(F16, &[2, 2, 4, 1024, 64], ()),
+        hashes: Vec::new(),
+        computed: 0,
+    };
+    cache.extend(&(0..100).collect::<Vec<utok>>());
+    assert_eq!(cache.position(), 0);
+    // 还没有计算过的位置不能设置
+    assert_eq!(cache.set_position(1), None);
+
+    cache.commit(100);
+    assert_eq!(cache.position(), 100);
+    assert_eq!(cache.set_position(101), None);
+
+    // 回退之后，之后的 token 在下一次推理中重新计算
+    cache.set_position(40).unwrap();
+    assert_eq!(cache.position(), 40);
+    assert_eq!(cache.query().len(), 60);
+    assert_eq!(cache.as_ctx(60).range, 40..100);
+    // 可以前进到曾经计算过的位置
+    cache.set_position(70).unwrap();
+    assert_eq!(cache.query().into_iter().next(), Some(&70));
+    cache.commit(30);
+    assert_eq!(cache.position(), 100);
+
+    // 回滚改变了 token 序列，不能再前进
+    cache.set_position(40).unwrap();
+    assert_eq!(cache.revert(30), Some(30));
+    assert_eq!(cache.set_position(31), None);
+    cache.push(30);
+    assert_eq!(cache.position(), 30);
+    assert_eq!(cache.end(), 31);
+}
@@ -2,7 +2,18 @@
 use std::sync::Arc;
 
 #[derive(Clone, Default, Debug)]
-pub(crate) struct Dialog(Vec<Arc<(Vec<utok>, usize)>>);
+pub(crate) struct Dialog(Vec<Arc<Sentence>>);
+
+#[derive(Debug)]
+struct Sentence {
+    tokens: Vec<utok>,
+    /// 对话到这个句子结束时的 token 数量。
+    end: usize,
+    /// 句子最后一条消息的角色，直接填充的文本没有角色。
+    role: Option<String>,
+    /// 渲染出这个句子的消息，生成的回答和摘要没有记录。
+    messages: Vec<(String, String)>,
+}
 
 impl Dialog {
     #[inline]
@@ -12,7 +23,7 @@ impl Dialog {
 
     #[inline]
     pub fn num_tokens(&self) -> usize {
-        self.0.last().map_or(0, |s| s.1)
+        self.0.last().map_or(0, |s| s.end)
     }
 
     #[inline]
@@ -25,19 +36,68 @@ impl Dialog {
         self.0
             .last()
             .filter(|_| self.0.len() % 2 != 0)
-            .map(|s| &*s.0)
+            .map(|s| &*s.tokens)
+    }
+
+    #[inline]
+    pub fn sentence(&self, i: usize) -> &[utok] {
+        &self.0[i].tokens
+    }
+
+    /// 用 `summary` 替换前 `len` 个句子，`summary` 与之后的第一个句子合为一句。
+    ///
+    /// 合成的句子保留原来的角色，不再记录消息。
+    pub fn summarize_front(&mut self, len: usize, summary: Vec<utok>) {
+        let rest = self.0.split_off(len);
+        self.0.clear();
+        let mut rest = rest.iter();
+        let mut first = summary;
+        let head = rest.next();
+        first.extend(head.into_iter().flat_map(|s| &s.tokens));
+        self.push(first, head.and_then(|s| s.role.as_deref()), vec![]);
+        for s in rest {
+            self.0.push(Arc::new(Sentence {
+                tokens: s.tokens.clone(),
+                end: self.num_tokens() + s.tokens.len(),
+                role: s.role.clone(),
+                messages: s.messages.clone(),
+            }));
+        }
+    }
+
+    #[inline]
+    pub fn last_sentence(&self) -> Option<&[utok]> {
+        self.0.last().map(|s| &*s.tokens)
+    }
+
+    /// 最后一个句子的角色。
+    #[inline]
+    pub fn last_role(&self) -> Option<&str> {
+        self.0.last().and_then(|s| s.role.as_deref())
+    }
+
+    /// 渲染出最后一个句子的消息，没有记录时为空。
+    #[inline]
+    pub fn last_messages(&self) -> &[(String, String)] {
+        self.0.last().map_or(&[], |s| &s.messages)
     }
 
+    /// 加入一个句子，`messages` 是渲染出这个句子的角色和内容，用于之后与新消息合并。
     #[inline]
-    pub fn push(&mut self, tokens: Vec<utok>) {
-        let len = self.num_tokens() + tokens.len();
-        self.0.push(Arc::new((tokens, len)))
+    pub fn push(&mut self, tokens: Vec<utok>, role: Option<&str>, messages: Vec<(String, String)>) {
+        let end = self.num_tokens() + tokens.len();
+        self.0.push(Arc::new(Sentence {
+            tokens,
+            end,
+            role: role.map(str::to_string),
+            messages,
+        }))
     }
 
     #[inline]
     pub fn window(&self, len: usize) -> (Vec<utok>, usize) {
         let start = self.num_tokens().saturating_sub(len);
-        let mut iter = self.0.iter().map(|s| &*s.0);
+        let mut iter = self.0.iter().map(|s| &*s.tokens);
         let mut pos = 0;
         for tokens in iter.by_ref() {
             if let Some(len) = start.checked_sub(pos) {
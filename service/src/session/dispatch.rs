@@ -1,19 +1,44 @@
-﻿use super::{batcher::Batcher, cache::Cache, task::Task};
-use crate::ServiceComponent;
-use causal_lm::{CausalLM, DecodingMeta, SampleArgs, SampleMeta};
+﻿use super::{
+    batcher::Batcher,
+    cache::Cache,
+    task::{
+        next_request_id, Decoded, FinishReason, Output, OutputMode, PrefillProgress, Task,
+        TaskArgs, TokenStats,
+    },
+    trace::Trace,
+};
+use crate::{tokenizer::StreamDecoder, ServiceComponent};
+use causal_lm::{CausalLM, DecodingMeta};
 use common::utok;
 use std::{
+    char::REPLACEMENT_CHARACTER,
     iter::zip,
-    mem::{replace, size_of},
+    mem::{size_of, take},
     str,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
 
 pub(super) struct TaskHandle<M: CausalLM> {
-    receiver: Option<UnboundedReceiver<utok>>,
+    /// 请求 id，与请求日志中的 id 对应。
+    id: u64,
+    receiver: Option<UnboundedReceiver<Output>>,
     cache: Arc<Mutex<Option<Cache<M::Storage>>>>,
+    decoder: StreamDecoder,
     buffer: Utf8Buffer,
+    /// 等待预填充进度时提前收到的 token 及其统计量。
+    pending: Option<(utok, Option<TokenStats>)>,
+    /// 生成结束的原因。
+    finish: Option<FinishReason>,
+    /// 提示词中命中缓存的 token 数量。
+    cached_tokens: usize,
+    /// 已接收的 token 的累积对数概率。
+    logprob: Option<f32>,
+    /// 已接收的 token 数量。
+    received: usize,
+    /// 已接收的 token 的采样分布的熵。
+    entropy: Vec<f32>,
 }
 
 impl<M: CausalLM> TaskHandle<M> {
@@ -24,55 +49,190 @@ impl<M: CausalLM> TaskHandle<M> {
         // 取走 cache
         self.cache.lock().unwrap().take().unwrap()
     }
+    #[inline]
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+    /// 生成的文本是一句新的话，解码时去掉开头 token 的前缀。
+    #[inline]
+    pub fn start_sentence(&mut self) {
+        self.decoder = StreamDecoder::sentence();
+    }
+    /// 生成结束的原因，生成未结束或被中断时为 `None`。
+    #[inline]
+    pub fn finish_reason(&self) -> Option<FinishReason> {
+        self.finish
+    }
+    /// 提示词中命中缓存、不需要重新计算的 token 数量。
+    #[inline]
+    pub fn cached_tokens(&self) -> usize {
+        self.cached_tokens
+    }
+    /// 已接收的 token 的累积对数概率，模型不支持计算对数概率时为 `None`。
+    #[inline]
+    pub fn cumulative_logprob(&self) -> Option<f32> {
+        self.logprob
+    }
+    /// 已接收的 token 数量。
+    #[inline]
+    pub fn received_tokens(&self) -> usize {
+        self.received
+    }
+    /// 已接收的每个 token 的采样分布的熵，未开启或模型不支持时为空。
+    #[inline]
+    pub fn entropies(&self) -> &[f32] {
+        &self.entropy
+    }
+    /// 由会话结束生成，推理任务在下一步发现接收端关闭后停止。
+    #[inline]
+    pub fn stop(&mut self, reason: FinishReason) {
+        let _ = self.receiver.take();
+        self.finish = Some(reason);
+    }
 }
 
 impl<M: CausalLM> ServiceComponent<M> {
-    pub(super) fn infer(&self, sample: SampleArgs, mut cache: Cache<M::Storage>) -> TaskHandle<M> {
+    pub(super) fn infer(&self, args: TaskArgs, mut cache: Cache<M::Storage>) -> TaskHandle<M> {
         let max = self.handle.model.max_seq_len() as usize;
         cache.reset_within_start_and_end_range(max / 4, max / 4, max / 4 * 3);
+        let prompt_len = cache.query().len();
+        // 与缓存中已计算的前缀相同的部分不需要重新计算
+        let cached_tokens = cache.cached_len();
+        // 窗口保证注意力长度不超过缓存容量，模型中的越界检查不会触发
+        debug_assert!(cached_tokens + prompt_len <= max);
         // 生成推理任务与会话的交互管道
-        let cache = Arc::new(Mutex::new(Some(cache)));
         let (sender, receiver) = unbounded_channel();
-        self.handle
-            .batcher
-            .enq(Task::new(cache.clone(), sample, sender));
+        let id = next_request_id();
+        let shared = Arc::new(Mutex::new(None));
+        let mut task = Task::new(id, shared.clone(), args, prompt_len, sender);
+        task.scan_prompt(cache.query());
+        *shared.lock().unwrap() = Some(cache);
+        self.handle.batcher.enq(task);
         TaskHandle {
+            id,
             receiver: Some(receiver),
-            cache,
-            buffer: Default::default(),
+            cache: shared,
+            decoder: Default::default(),
+            buffer: Utf8Buffer::new(self.max_pending_bytes),
+            pending: None,
+            finish: None,
+            cached_tokens,
+            logprob: None,
+            received: 0,
+            entropy: Vec::new(),
+        }
+    }
+
+    /// 接收下一个预填充进度，预填充结束后返回 `None`。
+    pub(super) async fn progress(&self, x: &mut TaskHandle<M>) -> Option<PrefillProgress> {
+        if x.pending.is_some() {
+            return None;
+        }
+        match x.receiver.as_mut()?.recv().await? {
+            Output::Progress(progress) => Some(progress),
+            Output::Token(token, stats) => {
+                x.pending = Some((token, stats));
+                None
+            }
+            Output::Finish(reason) => {
+                x.finish = Some(reason);
+                None
+            }
         }
     }
 
-    pub(super) async fn decode(&self, x: &mut TaskHandle<M>) -> Option<String> {
+    /// 接收模型解码产生的文本，以及产生这段文本的 token。
+    pub(super) async fn decode(&self, x: &mut TaskHandle<M>) -> Option<(String, Vec<utok>)> {
+        let mut ids = Vec::new();
         loop {
-            let s = x.receiver.as_mut().unwrap().recv().await.map(|token| {
-                // detokenize and denormalize the token
-                let ServiceComponent {
-                    normalizer,
-                    tokenizer,
-                    ..
-                } = self;
-                normalizer.decode(tokenizer.decode(token))
-            })?;
-            let s = x.buffer.push(s.as_bytes());
+            let (token, bytes) = match self.decode_bytes(x).await {
+                Some(Some(decoded)) => decoded,
+                Some(None) => {
+                    // 输出结束，清空缓冲区中残留的字节，没有产生文本的 token 同样返回
+                    let s = x.buffer.flush();
+                    return Some((s, ids)).filter(|(s, ids)| !s.is_empty() || !ids.is_empty());
+                }
+                None => return None,
+            };
+            let s = x.buffer.push(bytes);
+            ids.push(token);
             if !s.is_empty() {
-                return Some(s);
+                return Some((s, ids));
             }
         }
     }
+
+    /// 接收下一个 token 及其解码得到的原始字节，不拼接 UTF-8 字符。
+    ///
+    /// 输出结束时返回 `Some(None)`，已经停止接收时返回 `None`。
+    pub(super) async fn decode_bytes(
+        &self,
+        x: &mut TaskHandle<M>,
+    ) -> Option<Option<(utok, Vec<u8>)>> {
+        let (token, stats) = loop {
+            match x.pending.take() {
+                Some(token) => break token,
+                None => match x.receiver.as_mut()?.recv().await {
+                    Some(Output::Token(token, stats)) => break (token, stats),
+                    Some(Output::Progress(_)) => {}
+                    Some(Output::Finish(reason)) => x.finish = Some(reason),
+                    None => return Some(None),
+                },
+            }
+        };
+        if let Some(TokenStats { logprob, entropy }) = stats {
+            if let Some(logprob) = logprob {
+                *x.logprob.get_or_insert(0.) += logprob;
+            }
+            x.entropy.extend(entropy);
+        }
+        x.received += 1;
+        // detokenize and denormalize the token
+        let ServiceComponent {
+            normalizer,
+            tokenizer,
+            ..
+        } = self;
+        let s = x.decoder.decode(tokenizer, &**normalizer, token);
+        Some(Some((token, s.as_bytes().to_vec())))
+    }
+
+    /// 按 `mode` 接收未经后处理的原始输出，文本模式与 [`decode`](Self::decode) 相同，其他模式每次输出一个 token 的结果。
+    pub(super) async fn decode_as(
+        &self,
+        x: &mut TaskHandle<M>,
+        mode: OutputMode,
+    ) -> Option<Decoded> {
+        if mode == OutputMode::Text {
+            return self.decode(x).await.map(|(s, _)| Decoded::Text(s));
+        }
+        let (token, bytes) = self.decode_bytes(x).await??;
+        Some(match mode {
+            OutputMode::Bytes => Decoded::Bytes(bytes),
+            _ => Decoded::Tokens(vec![token]),
+        })
+    }
 }
 
 pub(crate) struct Dispatcher<M: CausalLM> {
     pub model: M,
     pub(super) batcher: Batcher<Task<M::Storage>>,
+    idle: Mutex<Option<IdleHandler>>,
+    /// 推理线程的耗时记录，未开始记录时为 `None`。
+    trace: Mutex<Option<Trace>>,
 }
 
+/// 推理线程空闲时的回调。
+type IdleHandler = (Duration, Arc<dyn Fn() + Send + Sync>);
+
 impl<M: CausalLM> From<M> for Dispatcher<M> {
     #[inline]
     fn from(model: M) -> Self {
         Self {
             model,
             batcher: Batcher::new(),
+            idle: Mutex::new(None),
+            trace: Mutex::new(None),
         }
     }
 }
@@ -83,6 +243,58 @@ impl<M: CausalLM> Dispatcher<M> {
     pub fn stop(&self) {
         self.batcher.shutdown();
     }
+
+    /// 停止接受新任务，等待已提交的任务完成后通知推理线程退出。
+    ///
+    /// 超过 `timeout` 仍未完成的任务被丢弃，返回是否全部完成。
+    pub fn stop_graceful(&self, timeout: Duration) -> bool {
+        self.batcher.close();
+        let drained = self.batcher.wait_drained(timeout);
+        self.batcher.shutdown();
+        drained
+    }
+
+    /// 设置空闲回调，推理线程每空闲 `timeout` 调用一次 `f`。
+    ///
+    /// 新的设置从推理线程下一次等待任务时生效。
+    pub fn set_idle(&self, idle: Option<IdleHandler>) {
+        *self.idle.lock().unwrap() = idle;
+    }
+
+    /// 开始记录推理线程的耗时，丢弃之前的记录。
+    pub fn start_trace(&self) {
+        *self.trace.lock().unwrap() = Some(Trace::new());
+    }
+
+    /// 停止记录，返回 Chrome tracing 格式的记录，未开始记录时返回空的记录。
+    pub fn take_trace(&self) -> String {
+        self.trace
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap_or_else(Trace::new)
+            .to_json()
+    }
+
+    /// 正在记录时，记录从 `start` 到现在的一段耗时。
+    fn record(
+        &self,
+        name: &'static str,
+        start: Instant,
+        args: impl IntoIterator<Item = (&'static str, usize)>,
+    ) {
+        if let Some(trace) = self.trace.lock().unwrap().as_mut() {
+            trace.record(name, start, args);
+        }
+    }
+
+    fn deq(&self) -> Vec<Task<M::Storage>> {
+        let idle = self.idle.lock().unwrap().clone();
+        match idle {
+            Some((timeout, f)) => self.batcher.deq_with_idle(timeout, || f()),
+            None => self.batcher.deq(),
+        }
+    }
 }
 
 impl<M> Dispatcher<M>
@@ -91,88 +303,401 @@ where
     M::Storage: Send,
 {
     pub fn run(self: Arc<Self>) {
-        while let Some(tasks) = Some(self.batcher.deq()).filter(|t| !t.is_empty()) {
+        while let Some(mut tasks) = Some(self.deq()).filter(|t| !t.is_empty()) {
             // 锁定所有请求的缓存
             let mut caches = tasks.iter().map(Task::lock_cache).collect::<Vec<_>>();
             // 统计每个任务的查询长度
-            let num_query = caches
-                .iter()
-                .map(|c| c.as_ref().map_or(0, |c| c.query().len()))
+            let num_query = zip(&tasks, &caches)
+                .map(|(t, c)| c.as_ref().map_or(0, |c| t.query_len(c.query().len())))
                 .collect::<Vec<_>>();
             if num_query.iter().all(|&n| n == 0) {
+                self.batcher.put_back(tasks.len(), []);
                 continue;
             }
+            let step_start = Instant::now();
+            let num_tokens = num_query.iter().sum::<usize>();
             // 词嵌入
-            let queries = caches
-                .iter()
-                .filter_map(|c| c.as_ref().map(Cache::query).filter(|q| !q.is_empty()))
+            let start = Instant::now();
+            let queries = zip(&caches, &num_query)
+                .filter(|(_, &n)| n > 0)
+                .filter_map(|(c, &n)| c.as_ref().map(|c| c.query().into_iter().take(n)))
                 .flatten()
                 .copied();
             let token_embedded = self.model.token_embed(queries);
+            self.record("embed", start, [("tokens", num_tokens)]);
             // 推理
-            let queries = caches
-                .iter_mut()
-                .filter_map(|c| c.as_mut().map(Cache::as_ctx).filter(|q| q.seq_len() > 0));
-            let hidden_state = self.model.forward(queries, token_embedded);
+            let start = Instant::now();
+            let queries = zip(&mut caches, &num_query)
+                .filter(|(_, &n)| n > 0)
+                .filter_map(|(c, &n)| c.as_mut().map(|c| c.as_ctx(n)));
+            // 上下文窗口保证注意力长度不超过缓存容量
+            let hidden_state = self
+                .model
+                .forward(queries, token_embedded)
+                .expect("context window exceeds the cache");
             drop(caches);
-            // 采样
-            let num_decode = tasks
-                .iter()
-                .map(|t| if t.is_alive() { 1 } else { 0 })
+            self.record("forward", start, [("tokens", num_tokens)]);
+            // 记录预填充进度，提示词未处理完的任务不解码
+            let num_decode = zip(&mut tasks, &num_query)
+                .map(|(t, &n)| if t.prefill(n) && t.is_alive() { 1 } else { 0 })
                 .collect::<Vec<_>>();
+            let total_decode = num_decode.iter().sum::<usize>();
+            let start = Instant::now();
             let decoding =
                 zip(num_query, &num_decode).map(|(num_query, &num_decode)| DecodingMeta {
                     num_query,
                     num_decode,
                 });
             let logits = self.model.decode(decoding, hidden_state);
+            self.record("decode", start, [("decode", total_decode)]);
             // 采样
-            let args = zip(&tasks, &num_decode).map(|(t, &num_decode)| SampleMeta {
-                num_decode,
-                args: *t.sample(),
-            });
+            let start = Instant::now();
+            let args = zip(&tasks, &num_decode).map(|(t, &num_decode)| t.sample_meta(num_decode));
+            // 每个解码位置只采样一个 token 时，计算采样的 token 的对数概率和分布的熵，
+            // 只在有任务需要时把 logits 拷贝到主存
+            let voc = logits.shape()[1] as usize;
+            let wants_stats = zip(&tasks, &num_decode)
+                .any(|(t, &n)| n > 0 && (t.wants_logprob() || t.wants_entropy()));
+            let host_logits = if self.model.tokens_per_step() == 1 && wants_stats {
+                self.model.logits_to_host(&logits)
+            } else {
+                None
+            };
             let tokens = self.model.sample(args, logits);
+            self.record("sample", start, [("decode", total_decode)]);
+            self.record(
+                "step",
+                step_start,
+                [
+                    ("tasks", tasks.len()),
+                    ("tokens", num_tokens),
+                    ("decode", total_decode),
+                ],
+            );
             // 为每次推理启动一个任务执行发射
             let self_ = self.clone();
             tokio::task::spawn_blocking(move || {
                 let eos = self_.model.eos_token();
+                let step = self_.model.tokens_per_step();
                 let max = self_.model.max_seq_len() as usize;
                 let end_size = max / 4;
                 let start_size = max / 4;
-                zip(tasks, num_decode)
-                    .filter(|(_, n)| *n > 0)
-                    .map(|(t, _)| t)
-                    .zip(tokens)
-                    .filter(|(_, token)| *token != eos)
-                    .for_each(|(mut task, token)| {
-                        if task.push(token, start_size, end_size, max) {
-                            self_.batcher.enq(task);
+                let taken = tasks.len();
+                let mut tokens = tokens.into_iter();
+                let mut rows = host_logits.as_deref().map(|l| l.chunks_exact(voc));
+                let mut unfinished = Vec::with_capacity(taken);
+                for (mut task, num_decode) in zip(tasks, num_decode) {
+                    if num_decode > 0 {
+                        let row = rows.as_mut().and_then(Iterator::next);
+                        // 一步采样的多个 token 中，结束生成的 token 及其之后的部分被丢弃
+                        let mut accepted = Vec::with_capacity(step);
+                        let mut finish = None;
+                        let mut forced = false;
+                        for mut token in tokens.by_ref().take(step) {
+                            if finish.is_none() && !forced {
+                                // 强制结束推理后，同一步中之后的 token 不再有效
+                                forced = task.limit_think(&mut token);
+                                finish = task.check_finish(token, eos);
+                                if finish.is_none() {
+                                    let stats = row.map(|row| TokenStats {
+                                        logprob: task.wants_logprob().then(|| logprob(row, token)),
+                                        entropy: task.wants_entropy().then(|| entropy(row)),
+                                    });
+                                    accepted.push((token, stats));
+                                }
+                            }
+                        }
+                        match finish {
+                            Some(reason) => {
+                                if !accepted.is_empty() {
+                                    task.push_step(&accepted, start_size, end_size, max);
+                                }
+                                task.finish(reason);
+                            }
+                            None => {
+                                if task.push_step(&accepted, start_size, end_size, max) {
+                                    unfinished.push(task);
+                                }
+                            }
                         }
-                    });
+                    } else if task.is_alive() {
+                        // 提示词未处理完，继续预填充
+                        unfinished.push(task);
+                    }
+                }
+                self_.batcher.put_back(taken, unfinished);
             });
         }
     }
 }
 
-#[derive(Clone, Default, Debug)]
-struct Utf8Buffer(Vec<u8>);
+/// `token` 在 `logits` 给出的分布中的对数概率。
+pub(super) fn logprob(logits: &[f32], token: utok) -> f32 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum = logits.iter().map(|&x| (x - max).exp()).sum::<f32>();
+    logits[token as usize] - max - sum.ln()
+}
+
+/// `logits` 给出的分布的熵，以自然对数计算，取值范围为 `[0, ln(n)]`。
+pub(super) fn entropy(logits: &[f32]) -> f32 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exp = logits.iter().map(|&x| (x - max).exp()).collect::<Vec<_>>();
+    let sum = exp.iter().sum::<f32>();
+    // H = ln(sum) - Σ p (x - max)，概率为 0 的项不参与计算
+    let mean = zip(&exp, logits)
+        .filter(|(&e, _)| e > 0.)
+        .map(|(&e, &x)| e / sum * (x - max))
+        .sum::<f32>();
+    (sum.ln() - mean).max(0.)
+}
+
+/// 等待拼接为完整字符的字节的默认上限，足够容纳任何不完整的 UTF-8 字符。
+pub(crate) const MAX_PENDING_BYTES: usize = size_of::<char>() - 1;
+
+/// 拼接字节 token 的缓冲区。
+#[derive(Clone, Debug)]
+struct Utf8Buffer {
+    bytes: Vec<u8>,
+    /// 末尾不完整的字符最多暂存的字节数，超出时替换为 `U+FFFD` 输出。
+    limit: usize,
+}
+
+impl Default for Utf8Buffer {
+    #[inline]
+    fn default() -> Self {
+        Self::new(MAX_PENDING_BYTES)
+    }
+}
 
 impl Utf8Buffer {
+    #[inline]
+    fn new(limit: usize) -> Self {
+        Self {
+            bytes: Vec::new(),
+            limit,
+        }
+    }
+
+    /// 加入字节，返回可以输出的文本。
+    ///
+    /// 无效的字节立即替换为 `U+FFFD`，只有末尾可能拼接为完整字符的字节被暂存。
     fn push(&mut self, bytes: impl AsRef<[u8]>) -> String {
-        self.0.extend_from_slice(bytes.as_ref());
-        let mut len = match str::from_utf8(&self.0) {
-            Ok(_) => self.0.len(),
-            Err(e) => e.valid_up_to(),
-        };
-        while len + size_of::<char>() <= self.0.len() {
-            len += 1;
-            match str::from_utf8(&self.0[len..]) {
-                Ok(s) => len += s.as_bytes().len(),
-                Err(e) => len += e.valid_up_to(),
+        self.bytes.extend_from_slice(bytes.as_ref());
+        let mut ans = String::new();
+        let mut rest = &self.bytes[..];
+        loop {
+            match str::from_utf8(rest) {
+                Ok(s) => {
+                    ans.push_str(s);
+                    rest = &[];
+                    break;
+                }
+                Err(e) => {
+                    let (valid, tail) = rest.split_at(e.valid_up_to());
+                    ans.push_str(unsafe { str::from_utf8_unchecked(valid) });
+                    match e.error_len() {
+                        Some(len) => {
+                            ans.push(REPLACEMENT_CHARACTER);
+                            rest = &tail[len..];
+                        }
+                        None if tail.len() <= self.limit => {
+                            rest = tail;
+                            break;
+                        }
+                        None => {
+                            ans.push(REPLACEMENT_CHARACTER);
+                            rest = &[];
+                            break;
+                        }
+                    }
+                }
             }
         }
-        let s = self.0.split_off(len);
-        let s = replace(&mut self.0, s);
-        unsafe { String::from_utf8_unchecked(s) }
+        let consumed = self.bytes.len() - rest.len();
+        self.bytes.drain(..consumed);
+        ans
+    }
+
+    /// 取出缓冲区中的全部字节，无效的字节替换为 `U+FFFD`。
+    fn flush(&mut self) -> String {
+        let s = take(&mut self.bytes);
+        String::from_utf8_lossy(&s).into_owned()
+    }
+}
+
+#[test]
+fn test_utf8_buffer_flush() {
+    let bytes = "好".as_bytes();
+    let mut buffer = Utf8Buffer::default();
+    assert_eq!(buffer.push(b"a"), "a");
+    assert_eq!(buffer.push(&bytes[..2]), "");
+    assert_eq!(buffer.flush(), "\u{FFFD}");
+    assert_eq!(buffer.flush(), "");
+
+    assert_eq!(buffer.push(&bytes[..1]), "");
+    assert_eq!(buffer.push(&bytes[1..]), "好");
+    assert_eq!(buffer.flush(), "");
+}
+
+#[test]
+fn test_utf8_buffer_limit() {
+    // 源源不断的无效后续字节逐个替换为 U+FFFD，不会积累在缓冲区中
+    let mut buffer = Utf8Buffer::default();
+    for _ in 0..1024 {
+        assert_eq!(buffer.push([0x80, 0xbf]), "\u{FFFD}\u{FFFD}");
+        assert!(buffer.bytes.is_empty());
+    }
+    // 不断开始却从不完整的字符
+    assert_eq!(buffer.push([0xf0]), "");
+    for _ in 0..1024 {
+        assert_eq!(buffer.push([0x9f, 0x98]), "");
+        assert!(buffer.bytes.len() <= MAX_PENDING_BYTES);
+        assert_eq!(buffer.push([0xf0]), "\u{FFFD}");
+    }
+    assert_eq!(buffer.flush(), "\u{FFFD}");
+
+    // 暂存的字节超过上限时替换为 U+FFFD 输出，之后的字节不受影响
+    let bytes = "😀".as_bytes();
+    let mut buffer = Utf8Buffer::new(2);
+    assert_eq!(buffer.push(&bytes[..2]), "");
+    assert_eq!(buffer.push(&bytes[2..3]), "\u{FFFD}");
+    assert!(buffer.bytes.is_empty());
+    assert_eq!(buffer.push(&bytes[3..]), "\u{FFFD}");
+    assert_eq!(buffer.push("好"), "好");
+    assert_eq!(buffer.flush(), "");
+}
+
+#[test]
+fn test_tokens_per_step() {
+    use causal_lm::{CacheOverflow, ModelInfo, QueryContext, SampleMeta};
+    use common::{upos, Blob};
+    use digit_layout::types::U32;
+    use tensor::{reslice, reslice_mut, Tensor};
+    use tokio::{runtime::Builder, sync::mpsc::unbounded_channel};
+
+    /// 每步生成两个 token 的模型，总是预测输入的最后一个 token 之后的两个整数。
+    #[derive(Default)]
+    struct TwoTokens {
+        seq_len: Mutex<Vec<upos>>,
+    }
+
+    impl CausalLM for TwoTokens {
+        type Storage = Blob;
+
+        fn architecture(&self) -> ModelInfo {
+            unimplemented!()
+        }
+        fn max_seq_len(&self) -> upos {
+            1024
+        }
+        fn bos_token(&self) -> utok {
+            1
+        }
+        fn eos_token(&self) -> utok {
+            20
+        }
+        fn new_cache(&self) -> Tensor<Blob> {
+            Tensor::alloc(U32, &[1], Blob::new)
+        }
+        fn cache_bytes(&self, _: upos) -> usize {
+            0
+        }
+        fn duplicate_cache(&self, _: &Tensor<Blob>, _: upos) -> Tensor<Blob> {
+            self.new_cache()
+        }
+        fn cache_to_host(&self, _: &Tensor<Blob>, _: usize, _: upos) -> Tensor<Vec<common::f16>> {
+            unimplemented!()
+        }
+        fn token_embed(&self, queries: impl IntoIterator<Item = utok>) -> Tensor<Blob> {
+            let tokens = queries.into_iter().collect::<Vec<_>>();
+            let mut x = Tensor::alloc(U32, &[tokens.len() as _, 1], Blob::new);
+            reslice_mut::<u8, utok>(x.physical_mut()).copy_from_slice(&tokens);
+            x
+        }
+        fn forward<'a>(
+            &self,
+            queries: impl IntoIterator<Item = QueryContext<'a, Blob>>,
+            token_embedded: Tensor<Blob>,
+        ) -> Result<Tensor<Blob>, CacheOverflow> {
+            let mut seq_len = self.seq_len.lock().unwrap();
+            seq_len.extend(queries.into_iter().map(|q| q.seq_len()));
+            Ok(token_embedded)
+        }
+        fn decode(
+            &self,
+            decoding: impl IntoIterator<Item = DecodingMeta>,
+            mut hidden_state: Tensor<Blob>,
+        ) -> Tensor<Blob> {
+            let range = DecodingMeta::select(&mut hidden_state, decoding, |dst, src| {
+                dst.copy_from_slice(src)
+            });
+            let tokens = reslice::<u8, utok>(hidden_state.physical());
+            self.token_embed(tokens[range].iter().copied())
+        }
+        fn sample<'a>(
+            &self,
+            _: impl IntoIterator<Item = SampleMeta<'a>>,
+            logits: Tensor<Blob>,
+        ) -> Vec<utok> {
+            let tokens = reslice::<u8, utok>(logits.physical());
+            tokens.iter().flat_map(|&t| [t + 1, t + 2]).collect()
+        }
+        fn tokens_per_step(&self) -> usize {
+            2
+        }
+    }
+
+    let runtime = Builder::new_current_thread().build().unwrap();
+    let dispatcher = Arc::new(Dispatcher::from(TwoTokens::default()));
+    let prompt = vec![1, 7, 8, 9, 10];
+    let cache = Arc::new(Mutex::new(Some(Cache::new(&dispatcher.model, prompt))));
+    let (sender, mut receiver) = unbounded_channel();
+    let task = Task::new(0, cache.clone(), Default::default(), 5, sender);
+    dispatcher.batcher.enq(task);
+    let run = runtime.spawn_blocking({
+        let dispatcher = dispatcher.clone();
+        move || dispatcher.run()
+    });
+
+    let mut generated = vec![];
+    let reason = loop {
+        match receiver.blocking_recv().unwrap() {
+            Output::Progress(_) => {}
+            Output::Token(token, _) => generated.push(token),
+            Output::Finish(reason) => break reason,
+        }
+    };
+    dispatcher.stop();
+    runtime.block_on(run).unwrap();
+
+    // 结束符之前的 token 全部输出，同一步中结束符之后的部分被丢弃
+    assert_eq!(reason, FinishReason::Stop);
+    assert_eq!(generated, (11..20).collect::<Vec<_>>());
+    // 每一步的两个 token 在下一轮推理中一起计算
+    assert_eq!(*dispatcher.model.seq_len.lock().unwrap(), [5, 2, 2, 2, 2]);
+    let cache = cache.lock().unwrap();
+    let cache = cache.as_ref().unwrap();
+    assert_eq!(cache.end(), 5 + generated.len());
+    assert_eq!(cache.slice_tail(5), generated);
+}
+
+#[test]
+fn test_entropy() {
+    // 独热分布的熵接近 0
+    let mut one_hot = vec![0f32; 1000];
+    one_hot[7] = 50.;
+    assert!(entropy(&one_hot) < 1e-4, "{}", entropy(&one_hot));
+    let mut masked = vec![f32::NEG_INFINITY; 16];
+    masked[3] = 1.;
+    assert_eq!(entropy(&masked), 0.);
+    // 均匀分布的熵为 ln(n)
+    for n in [2, 16, 1000] {
+        let uniform = vec![0.3f32; n];
+        let expected = (n as f32).ln();
+        assert!((entropy(&uniform) - expected).abs() < 1e-4 * expected);
     }
+    // 其他分布的熵在两者之间
+    let logits = [2f32, 1., 0.5, -1.];
+    let h = entropy(&logits);
+    assert!(0. < h && h < 4f32.ln());
 }
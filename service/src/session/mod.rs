@@ -2,56 +2,328 @@
 mod cache;
 mod dialog;
 mod dispatch;
+mod post;
+mod stop;
 mod task;
+mod think;
+mod trace;
 
-use crate::ServiceComponent;
+use crate::{tokenizer::Tokenize, ServiceComponent};
 use cache::Cache;
-use causal_lm::{CausalLM, SampleArgs};
-use chat_template::Message;
+use causal_lm::{CausalLM, InvalidSampleArgs};
+use chat_template::{Message, UnknownRole};
+use common::{f16, utok};
 use dialog::Dialog;
 use dispatch::TaskHandle;
 use log::info;
+use post::PostChain;
 use std::{
     cmp::Ordering::{Equal, Greater, Less},
     error, fmt,
-    sync::Arc,
+    iter::zip,
+    mem::take,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
     vec,
 };
+use stop::StopSequences;
+use tensor::Tensor;
+use think::ThinkFilter;
+use tokio::sync::mpsc::{error::SendError, UnboundedSender};
 
-pub(crate) use dispatch::Dispatcher;
+pub(crate) use dispatch::{Dispatcher, MAX_PENDING_BYTES};
+pub use post::{CollapseNewlines, PostProcessor, StripPrefix, TrimLeadingSpace, TrimStart};
+pub use task::{
+    Decoded, EosSchedule, FinishReason, GenerationConfig, OutputMode, PrefillProgress,
+    RepetitionLimit,
+};
+pub(crate) use task::{TaskArgs, ThinkBudget};
+
+/// 压缩对话的回调，输入最早的若干个句子解码得到的文本（不含开头的系统消息），返回替换它们的摘要。
+///
+/// 句子的文本包含对话模板加入的标记。
+pub type Summarizer = Arc<dyn Fn(&[String]) -> String + Send + Sync>;
 
 /// 会话。
 pub struct Session<M: CausalLM> {
     component: Arc<ServiceComponent<M>>,
-    pub sample: SampleArgs,
+    /// 生成的设置，启动时从服务复制。
+    ///
+    /// 最大 token 数量为 0 时 [`extend`](Self::extend) 和 [`continue_last`](Self::continue_last) 返回 [`ChatError::EmptyBudget`]，
+    /// 采样参数在模型上不可用时返回 [`ChatError::SampleArgs`]；
+    /// 累积对数概率和熵的结果由 [`BusySession::cumulative_logprob`] 和 [`BusySession::entropies`] 获取。
+    pub generation: GenerationConfig,
+    /// 渲染对话模板时传入的布尔变量，如 `enable_thinking`。
+    pub template_vars: Vec<(String, bool)>,
+    /// 是否从输出中移除 `<think>...</think>` 片段。
+    pub strip_think: bool,
+    /// 是否移除输出开头的一个空格，SentencePiece 模型常在回答的第一个 token 前加入空格。
+    pub trim_leading_space: bool,
+    /// 停止序列结束生成时，是否移除输出末尾在停止序列之前的空白。
+    pub trim_stop_whitespace: bool,
+    /// 连续相同角色消息的处理策略。
+    pub role_policy: RolePolicy,
+    /// 一次输入的提示词的最大 token 数量，不限制生成的长度。
+    pub max_prompt_tokens: Option<usize>,
+    /// 提示词超过 [`max_prompt_tokens`](Self::max_prompt_tokens) 时的处理策略。
+    pub prompt_overflow: PromptOverflow,
+    /// 默认的系统提示词，新对话的第一条消息不是系统消息时自动加在最前面。
+    pub system_prompt: Option<String>,
+    /// 是否跳过与对话开头的系统消息内容相同的系统消息，避免分叉的会话重复加入系统提示词，默认关闭。
+    pub skip_duplicate_system: bool,
+    /// [`extend`](Self::extend) 之后对话接近上下文长度时压缩最早的几轮对话，未设置时只保留末尾的窗口。
+    pub summarizer: Option<Summarizer>,
 
+    /// 停止序列，每次启动推理时生效。
+    stops: Vec<String>,
     dialog: Dialog,
-    cache: Option<Cache<M::Storage>>,
+    /// 对话开头的系统消息的内容。
+    system: Option<String>,
+    cache: SharedCache<M::Storage>,
 }
 
-/// 对话错误类型。
+/// 会话的缓存，由会话和服务共享，服务可以释放闲置会话的缓存。
+pub(crate) struct SessionCache<Storage> {
+    cache: Option<Cache<Storage>>,
+    /// 会话最后一次使用缓存的时刻。
+    last_use: Instant,
+}
+
+pub(crate) type SharedCache<Storage> = Arc<Mutex<SessionCache<Storage>>>;
+
+/// 预先填充的共享前缀，新会话复制其中的缓存作为起点。
+pub(crate) struct PinnedPrefix<Storage>(Arc<Mutex<Cache<Storage>>>);
+
+impl<M: CausalLM> ServiceComponent<M> {
+    /// 登记一个会话的缓存，以便服务释放闲置会话的缓存。
+    fn register(&self, cache: Option<Cache<M::Storage>>) -> SharedCache<M::Storage> {
+        let cache = Arc::new(Mutex::new(SessionCache {
+            cache,
+            last_use: Instant::now(),
+        }));
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|s| s.strong_count() > 0);
+        sessions.push(Arc::downgrade(&cache));
+        cache
+    }
+
+    /// 释放闲置超过 `idle` 的会话的缓存，返回释放的缓存数量。
+    pub(crate) fn evict_idle(&self, idle: Duration) -> usize {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|s| s.strong_count() > 0);
+        sessions
+            .iter()
+            .filter_map(|s| s.upgrade())
+            .filter(|s| {
+                let mut s = s.lock().unwrap();
+                s.last_use.elapsed() >= idle && s.cache.take().is_some()
+            })
+            .count()
+    }
+
+    /// 按对话模板渲染系统提示词 `text`，在调用线程上预填充后作为所有新会话共享的前缀。
+    ///
+    /// 前缀超过模型的上下文长度时返回 [`ChatError::PromptTooLong`]，之前固定的前缀不变。
+    pub(crate) fn pin_prefix(&self, text: &str) -> Result<usize, ChatError> {
+        let message = Message {
+            role: "system",
+            content: text,
+        };
+        let tokens = self.render(&[message], false, &[])?;
+        let len = tokens.len();
+        // 前缀直接在调用线程上计算，不经过推理任务的上下文窗口
+        let mut cache = Cache::new(&self.handle.model, tokens);
+        cache
+            .prefill(&self.handle.model)
+            .map_err(|e| ChatError::PromptTooLong {
+                len,
+                max: e.max_seq_len as _,
+            })?;
+        info!("Prefix pinned: {len} tokens");
+        *self.pinned.lock().unwrap() = Some(PinnedPrefix(Arc::new(Mutex::new(cache))));
+        Ok(len)
+    }
+
+    /// 空白的缓存与共享前缀有相同的开头时，复制共享前缀中相同的部分，返回 `tokens` 中复用的长度。
+    ///
+    /// `tokens` 的最后一个 token 总是留给推理计算。
+    fn start_from_pinned(&self, cache: &mut Cache<M::Storage>, tokens: &[utok]) -> usize {
+        if cache.end() > 0 {
+            return 0;
+        }
+        let pinned = self.pinned.lock().unwrap().as_ref().map(|p| p.0.clone());
+        let Some(pinned) = pinned else {
+            return 0;
+        };
+        let pinned = pinned.lock().unwrap();
+        let len = zip(pinned.slice_tail(0), tokens)
+            .take(tokens.len().saturating_sub(1))
+            .take_while(|(a, b)| a == b)
+            .count();
+        if len > 0 {
+            *cache = pinned.fork_prefix(&self.handle.model, len);
+        }
+        len
+    }
+
+    /// 从对话重建缓存，对话超出上下文长度时只保留末尾的窗口。
+    fn rebuild_cache(&self, dialog: &Dialog) -> Cache<M::Storage> {
+        let mut cache = Cache::new(&self.handle.model, vec![]);
+        if dialog.num_tokens() > 0 {
+            let len = self.handle.model.max_seq_len() as usize;
+            let (tokens, pos) = dialog.window(len);
+            cache.reset_with(tokens, pos);
+        }
+        cache
+    }
+
+    /// 按对话模板渲染消息并编码。
+    ///
+    /// 只有模板加入的文本按特殊 token 编码，消息内容中的特殊 token 字符串按普通文本编码。
+    fn render(
+        &self,
+        messages: &[Message],
+        add_generation_prompt: bool,
+        vars: &[(&str, bool)],
+    ) -> Result<Vec<utok>, ChatError> {
+        let contents = messages
+            .iter()
+            .map(|m| self.tokenizer.escape(m.content))
+            .collect::<Vec<_>>();
+        let messages = zip(messages, &contents)
+            .map(|(m, content)| Message {
+                role: m.role,
+                content,
+            })
+            .collect::<Vec<_>>();
+        let text = self
+            .template
+            .render_with(&messages, &self.bos, &self.eos, add_generation_prompt, vars)
+            .map_err(|_| ChatError::Template)?;
+        Ok(self.tokenizer.encode(&self.normalizer.encode(&text)))
+    }
+
+    /// 将生成的设置转换为推理任务的参数。
+    ///
+    /// 换行惩罚转换为换行符的 logit 偏置，惩罚为 0 或换行符不是单个 token 时不改变采样参数；
+    /// 推理预算与推理片段的标记组合，标记不是单个 token 时不限制推理。
+    pub(crate) fn task_args(&self, mut generation: GenerationConfig) -> TaskArgs {
+        if let Some(newline) = self.newline.filter(|_| generation.newline_penalty != 0.) {
+            generation.sample.logit_bias = Some((newline, -generation.newline_penalty));
+        }
+        let think_budget =
+            self.think
+                .zip(generation.reasoning_budget)
+                .map(|((open, close), budget)| ThinkBudget {
+                    open,
+                    close,
+                    budget,
+                });
+        TaskArgs {
+            generation,
+            think_budget,
+        }
+    }
+
+    /// 检查生成的设置，最大 token 数量为 0 或采样参数在模型上不可用时返回错误。
+    pub(crate) fn check_generation(&self, generation: &GenerationConfig) -> Result<(), ChatError> {
+        if generation.max_tokens == Some(0) {
+            return Err(ChatError::EmptyBudget);
+        }
+        self.handle
+            .model
+            .check_sample_args(&generation.sample)
+            .map_err(ChatError::SampleArgs)
+    }
+}
+
+/// 连续相同角色消息的处理策略。
 ///
-/// 目前唯一可能的对话错误是增量对话中句子位置异常。
+/// 部分对话模板不接受连续两条相同角色的消息。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default, Debug)]
+pub enum RolePolicy {
+    /// 逐条渲染，不做处理。
+    #[default]
+    Allow,
+    /// 合并连续相同角色的消息，内容以换行分隔。
+    Merge,
+    /// 拒绝包含连续相同角色消息的输入。
+    Reject,
+}
+
+/// 提示词超过最大长度时的处理策略。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default, Debug)]
+pub enum PromptOverflow {
+    /// 拒绝过长的提示词，返回 [`ChatError::PromptTooLong`]。
+    #[default]
+    Reject,
+    /// 丢弃最早的完整对话轮次，保留 bos 和系统提示词，丢弃后仍然超长时返回 [`ChatError::PromptTooLong`]。
+    ///
+    /// 没有对话轮次的文本生成保留 bos，丢弃其后最早的 token。
+    Truncate,
+}
+
+/// 对话错误类型。
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
-pub struct ChatError;
+pub enum ChatError {
+    /// 增量对话中句子位置或缓存位置异常。
+    InvalidPosition,
+    /// 输入的消息不符合 [`RolePolicy`]。
+    RoleConflict,
+    /// 没有可以续写的回答。
+    NoAnswer,
+    /// 提示词的 token 数量 `len` 超过了最大长度 `max`。
+    PromptTooLong { len: usize, max: usize },
+    /// 第 `index` 条消息的角色不在服务的 [`RoleMap`](crate::RoleMap) 中。
+    UnknownRole { index: usize },
+    /// 对话模板无法渲染输入的消息。
+    Template,
+    /// 采样参数不合法，或者模型的采样不支持。
+    SampleArgs(InvalidSampleArgs),
+    /// 最大 token 数量为 0，不能生成任何 token。
+    EmptyBudget,
+}
 
 impl error::Error for ChatError {}
 impl fmt::Display for ChatError {
-    #[inline]
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "chat error")
+        match self {
+            Self::InvalidPosition => write!(f, "invalid dialog or cache position"),
+            Self::RoleConflict => write!(f, "consecutive messages with the same role"),
+            Self::NoAnswer => write!(f, "no answer to continue"),
+            Self::PromptTooLong { len, max } => {
+                write!(f, "prompt has {len} tokens, exceeding the limit of {max}")
+            }
+            Self::UnknownRole { index } => write!(f, "message {index} has an unknown role"),
+            Self::Template => write!(f, "chat template failed to render the messages"),
+            Self::SampleArgs(e) => write!(f, "invalid sample arguments: {e}"),
+            Self::EmptyBudget => write!(f, "max tokens is 0, nothing to generate"),
+        }
     }
 }
 
 impl<M: CausalLM> From<Arc<ServiceComponent<M>>> for Session<M> {
     #[inline]
     fn from(component: Arc<ServiceComponent<M>>) -> Self {
+        let cache = component.register(None);
         Self {
             component,
-            sample: Default::default(),
+            generation: Default::default(),
+            template_vars: Default::default(),
+            strip_think: false,
+            trim_leading_space: false,
+            trim_stop_whitespace: false,
+            role_policy: Default::default(),
+            max_prompt_tokens: None,
+            prompt_overflow: Default::default(),
+            system_prompt: None,
+            skip_duplicate_system: false,
+            summarizer: None,
 
+            stops: Default::default(),
             dialog: Default::default(),
-            cache: Default::default(),
+            system: None,
+            cache,
         }
     }
 }
@@ -62,26 +334,191 @@ impl<M: CausalLM> Session<M> {
         self.dialog.num_sentences()
     }
 
+    /// 对话中最后一个句子的角色，生成的回答为 `assistant`，直接填充的文本没有角色。
+    #[inline]
+    pub fn last_role(&self) -> Option<&str> {
+        self.dialog.last_role()
+    }
+
     /// 复制当前会话。
     pub fn fork(&self) -> Self {
         Self {
             component: self.component.clone(),
-            sample: self.sample,
+            generation: self.generation.clone(),
+            template_vars: self.template_vars.clone(),
+            strip_think: self.strip_think,
+            trim_leading_space: self.trim_leading_space,
+            trim_stop_whitespace: self.trim_stop_whitespace,
+            role_policy: self.role_policy,
+            max_prompt_tokens: self.max_prompt_tokens,
+            prompt_overflow: self.prompt_overflow,
+            system_prompt: self.system_prompt.clone(),
+            skip_duplicate_system: self.skip_duplicate_system,
+            summarizer: self.summarizer.clone(),
+            stops: self.stops.clone(),
             dialog: self.dialog.clone(),
-            cache: self
-                .cache
-                .as_ref()
-                .map(|cache| cache.duplicate(&self.component.handle.model)),
+            system: self.system.clone(),
+            cache: self.component.register(
+                self.cache
+                    .lock()
+                    .unwrap()
+                    .cache
+                    .as_ref()
+                    .map(|cache| cache.duplicate(&self.component.handle.model)),
+            ),
         }
     }
 
+    /// 设置停止序列，输出中出现任意一个序列时结束生成，从下一次启动推理开始生效。
+    ///
+    /// 可以在两轮对话之间随时修改，传入空列表取消停止序列。
+    #[inline]
+    pub fn set_stop(&mut self, stops: Vec<String>) {
+        self.stops = stops;
+    }
+
+    /// 当前的停止序列。
+    #[inline]
+    pub fn stops(&self) -> &[String] {
+        &self.stops
+    }
+
+    /// 把 `other` 的所有设置复制到这个会话，包括停止序列，对话和缓存不变。
+    pub(crate) fn clone_settings_from(&mut self, other: &Self) {
+        // 解构全部字段，新增的设置不会被遗漏
+        let Self {
+            component: _,
+            generation,
+            template_vars,
+            strip_think,
+            trim_leading_space,
+            trim_stop_whitespace,
+            role_policy,
+            max_prompt_tokens,
+            prompt_overflow,
+            system_prompt,
+            skip_duplicate_system,
+            summarizer,
+            stops,
+            dialog: _,
+            system: _,
+            cache: _,
+        } = other;
+        self.generation.clone_from(generation);
+        self.template_vars.clone_from(template_vars);
+        self.strip_think = *strip_think;
+        self.trim_leading_space = *trim_leading_space;
+        self.trim_stop_whitespace = *trim_stop_whitespace;
+        self.role_policy = *role_policy;
+        self.max_prompt_tokens = *max_prompt_tokens;
+        self.prompt_overflow = *prompt_overflow;
+        self.system_prompt.clone_from(system_prompt);
+        self.skip_duplicate_system = *skip_duplicate_system;
+        self.summarizer.clone_from(summarizer);
+        self.stops.clone_from(stops);
+    }
+
+    /// 清空对话，保留已分配的缓存以便复用。
+    pub fn reset(&mut self) {
+        self.dialog = Default::default();
+        self.system = None;
+        if let Some(cache) = self.cache.lock().unwrap().cache.as_mut() {
+            cache.reset_with(vec![], 0);
+        }
+    }
+
+    /// 将第 `layer` 层当前的 K-V 缓存拷贝到主存（`2 x num_kv_head x len x head_dim`）。
+    ///
+    /// 对于加速卡上的模型需要把整个缓存拷贝回主机，开销很大，只应在调试和分析时调用。
+    /// 会话还没有缓存或缓存已被释放时返回 `None`。
+    pub fn kv_cache(&self, layer: usize) -> Option<Tensor<Vec<f16>>> {
+        self.cache
+            .lock()
+            .unwrap()
+            .cache
+            .as_ref()
+            .map(|cache| cache.kv_to_host(&self.component.handle.model, layer))
+    }
+
+    /// 缓存中 token 序列每个位置的前缀累积哈希，可用于发现会话之间相同的前缀。
+    ///
+    /// 会话还没有缓存或缓存已被释放时返回空序列。
+    #[inline]
+    pub fn prefix_hashes(&self) -> Vec<u64> {
+        self.cache
+            .lock()
+            .unwrap()
+            .cache
+            .as_ref()
+            .map_or_else(Vec::new, |cache| cache.prefix_hashes().to_vec())
+    }
+
+    /// 会话缓存占用的字节数，缓存被释放后为 0。
+    #[inline]
+    pub fn cache_bytes(&self) -> usize {
+        self.cache
+            .lock()
+            .unwrap()
+            .cache
+            .as_ref()
+            .map_or(0, |cache| cache.cache_bytes())
+    }
+
+    /// 压缩会话缓存，释放回滚之后不再使用的容量。
+    ///
+    /// 压缩之后继续对话可能需要重新扩大缓存，适合在会话空闲时调用。
+    pub fn compact_cache(&self) {
+        if let Some(cache) = self.cache.lock().unwrap().cache.as_mut() {
+            cache.compact(&self.component.handle.model);
+        }
+    }
+
+    /// 计算缓存的位置，即已经计算过的 token 数量，缓存被释放后为 0。
+    #[inline]
+    pub fn cache_position(&self) -> usize {
+        self.cache
+            .lock()
+            .unwrap()
+            .cache
+            .as_ref()
+            .map_or(0, |cache| cache.position())
+    }
+
+    /// 将计算缓存的位置设置到 `pos`，之后的 token 在下一次推理中重新计算，不重新分配缓存。
+    ///
+    /// 可以回退，也可以前进到曾经计算过的位置，超出已计算的范围时返回错误。
+    pub fn set_cache_position(&mut self, pos: usize) -> Result<(), ChatError> {
+        self.lock_cache()
+            .cache
+            .as_mut()
+            .unwrap()
+            .set_position(pos)
+            .ok_or(ChatError::InvalidPosition)
+    }
+
+    /// 锁定会话的缓存并记录使用时刻，缓存已被释放时从对话重建。
+    fn lock_cache(&self) -> MutexGuard<SessionCache<M::Storage>> {
+        let mut cache = self.cache.lock().unwrap();
+        cache.last_use = Instant::now();
+        if cache.cache.is_none() {
+            cache.cache = Some(self.component.rebuild_cache(&self.dialog));
+        }
+        cache
+    }
+
     /// 回滚对话到第 `dialog_pos` 个句子。
     pub fn revert(&mut self, dialog_pos: usize) -> Result<(), ChatError> {
         match dialog_pos.cmp(&self.dialog.num_sentences()) {
             Less => {
-                let cache = self.cache.as_mut().unwrap();
-
                 self.dialog.revert(dialog_pos);
+                if dialog_pos == 0 {
+                    self.system = None;
+                }
+                // 缓存已被释放时不需要回滚，下次使用时从对话重建
+                let mut cache = self.cache.lock().unwrap();
+                let Some(cache) = cache.cache.as_mut() else {
+                    return Ok(());
+                };
                 let last_prompt = self.dialog.last_prompt().map_or(0, |p| p.len());
                 if cache.revert(self.dialog.num_tokens()).is_none()
                     || cache.get_last_cached_range_len() < last_prompt
@@ -93,79 +530,707 @@ impl<M: CausalLM> Session<M> {
                 Ok(())
             }
             Equal => Ok(()),
-            Greater => Err(ChatError),
+            Greater => Err(ChatError::InvalidPosition),
         }
     }
 
     /// 用 dialog 填充会话。
-    pub fn extend(&mut self, messages: &[Message]) {
-        let cache = self
-            .cache
-            .get_or_insert_with(|| Cache::new(&self.component.handle.model, vec![]));
+    ///
+    /// 消息的角色名先按服务的 [`RoleMap`](crate::RoleMap) 转换，未知的角色返回 [`ChatError::UnknownRole`]；
+    /// 已有对话时，与对话开头的系统消息相同的系统消息按照 [`skip_duplicate_system`](Self::skip_duplicate_system) 跳过；
+    /// 连续相同角色的消息按照 [`role_policy`](Self::role_policy) 处理，第一条消息与对话中最后一个句子比较，
+    /// 渲染后的提示词超过 [`max_prompt_tokens`](Self::max_prompt_tokens) 时按照
+    /// [`prompt_overflow`](Self::prompt_overflow) 处理，被拒绝时会话不变。
+    pub fn extend(&mut self, messages: &[Message]) -> Result<(), ChatError> {
+        self.component.check_generation(&self.generation)?;
+        let mut messages = self
+            .component
+            .roles
+            .apply(messages)
+            .map_err(|UnknownRole(role)| ChatError::UnknownRole {
+                index: messages.iter().position(|m| m.role == role).unwrap(),
+            })?;
+        if let Some(system) = self
+            .system
+            .as_deref()
+            .filter(|_| self.skip_duplicate_system && self.dialog.num_sentences() > 0)
+        {
+            skip_system(&mut messages, system);
+        }
+        let conflict = messages
+            .first()
+            .is_some_and(|m| Some(m.role) == self.dialog.last_role());
+        // 合并时重新渲染对话中最后一个句子，没有记录消息的句子无法合并
+        let reopened = if conflict && self.role_policy == RolePolicy::Merge {
+            let last = self.dialog.last_messages().to_vec();
+            if last.is_empty() {
+                return Err(ChatError::RoleConflict);
+            }
+            last
+        } else {
+            vec![]
+        };
+        let base = self.dialog.num_sentences() - usize::from(!reopened.is_empty());
+        let messages = reopened
+            .iter()
+            .map(|(role, content)| Message { role, content })
+            .chain(messages)
+            .collect::<Vec<_>>();
+        let messages = &messages[..];
+        let merged = match self.role_policy {
+            RolePolicy::Allow => None,
+            RolePolicy::Merge => Some(merge_roles(messages)),
+            RolePolicy::Reject if conflict || has_consecutive_roles(messages) => {
+                return Err(ChatError::RoleConflict)
+            }
+            RolePolicy::Reject => None,
+        };
+        let merged = merged.as_ref().map(|merged| {
+            merged
+                .iter()
+                .map(|(role, content)| Message {
+                    role: *role,
+                    content: content.as_str(),
+                })
+                .collect::<Vec<_>>()
+        });
+        let messages = merged.as_deref().unwrap_or(messages);
 
+        let vars = self
+            .template_vars
+            .iter()
+            .map(|(k, v)| (&**k, *v))
+            .collect::<Vec<_>>();
+        // 新对话的第一条消息不是系统消息时，注入默认的系统提示词，与第一条消息组成一个句子
+        let mut system = self
+            .system_prompt
+            .as_deref()
+            .filter(|_| base == 0 && messages.first().is_some_and(|m| m.role != "system"));
+        // 记录新对话开头的系统消息，用于识别之后重复加入的系统消息
+        let head_system = if base == 0 {
+            system
+                .or_else(|| {
+                    messages
+                        .first()
+                        .filter(|m| m.role == "system")
+                        .map(|m| m.content)
+                })
+                .map(str::to_string)
+        } else {
+            None
+        };
+        let mut sentences = Vec::with_capacity(messages.len());
         for msg in messages {
-            let s = self
-                .component
-                .template
-                .render(
-                    std::slice::from_ref(msg),
-                    &self.component.bos,
-                    &self.component.eos,
-                    true,
-                )
-                .unwrap();
-            let s = self.component.normalizer.encode(&s);
-            let s = self.component.tokenizer.encode(&s);
+            let with_system;
+            let msgs = match system.take() {
+                Some(content) => {
+                    with_system = [
+                        Message {
+                            role: "system",
+                            content,
+                        },
+                        Message {
+                            role: msg.role,
+                            content: msg.content,
+                        },
+                    ];
+                    &with_system[..]
+                }
+                None => std::slice::from_ref(msg),
+            };
+            sentences.push(self.component.render(msgs, true, &vars)?);
+        }
+        // 新对话的第一个句子包含 bos 和系统提示词，总是保留
+        let keep = usize::from(base == 0);
+        limit_prompt(
+            &mut sentences,
+            keep,
+            self.max_prompt_tokens,
+            self.prompt_overflow,
+        )?;
+        // 记录每个句子的消息，被截断的句子一并丢弃
+        let mut sources = messages
+            .iter()
+            .map(|m| (m.role.to_string(), m.content.to_string()))
+            .collect::<Vec<_>>();
+        sources.drain(keep..keep + messages.len() - sentences.len());
 
-            cache.extend(&s);
-            self.dialog.push(s);
+        // 被合并的句子从对话中取出，压缩失败时放回
+        let reopened = (base < self.dialog.num_sentences()).then(|| {
+            let tokens = self.dialog.last_sentence().unwrap().to_vec();
+            let role = self.dialog.last_role().map(str::to_string);
+            (tokens, role, self.dialog.last_messages().to_vec())
+        });
+        self.revert(base)?;
+        let pos = base;
+        let end = {
+            let mut cache = self.lock_cache();
+            let cache = cache.cache.as_mut().unwrap();
+            // 新对话的开头与共享前缀相同时不再重复计算这部分
+            let mut skip = sentences
+                .first()
+                .map_or(0, |s| self.component.start_from_pinned(cache, s));
+            for s in &sentences {
+                cache.extend(&s[skip..]);
+                skip = 0;
+            }
+            cache.end()
+        };
+        for (s, (role, content)) in zip(sentences, sources) {
+            self.dialog
+                .push(s, Some(&role), vec![(role.clone(), content)]);
+        }
+        assert_eq!(end, self.dialog.num_tokens());
+        if head_system.is_some() {
+            self.system = head_system;
+        }
+        // 对话以提示词结尾时即将生成回答，在此之前压缩过长的对话，失败时撤销这次填充
+        if self.dialog.num_sentences() % 2 == 1 {
+            if let Err(e) = self.compress() {
+                self.revert(pos)?;
+                if let Some((tokens, role, messages)) = reopened {
+                    self.lock_cache().cache.as_mut().unwrap().extend(&tokens);
+                    self.dialog.push(tokens, role.as_deref(), messages);
+                }
+                return Err(e);
+            }
         }
+        Ok(())
+    }
 
-        assert_eq!(cache.end(), self.dialog.num_tokens());
+    /// 直接用文本填充会话，不经过对话模板，由调用者控制完整的格式。
+    ///
+    /// 文本作为一个句子加入对话，可以与 [`extend`](Self::extend) 混合使用。
+    pub fn extend_raw(&mut self, text: &str) {
+        let s = self.component.normalizer.encode(text);
+        let s = self.component.tokenizer.encode(&s);
+        let end = {
+            let mut cache = self.lock_cache();
+            let cache = cache.cache.as_mut().unwrap();
+            let skip = self.component.start_from_pinned(cache, &s);
+            cache.extend(&s[skip..]);
+            cache.end()
+        };
+        self.dialog.push(s, None, vec![]);
+        assert_eq!(end, self.dialog.num_tokens());
     }
 
     /// 启动推理任务，返回忙会话。
+    #[inline]
     pub fn chat(&mut self) -> BusySession<M> {
-        let cache = self.cache.take().unwrap();
-        let handle = self.component.infer(self.sample, cache);
+        self.start(true, None)
+    }
+
+    /// 以 `prefix` 作为回答的开头启动推理任务，返回忙会话。
+    ///
+    /// 前缀跟在生成提示之后填入缓存，模型从前缀之后开始采样，前缀会出现在输出中。
+    pub fn chat_with_prefix(&mut self, prefix: &str) -> BusySession<M> {
+        let tokens = self.component.tokenizer.escape(prefix);
+        let tokens = self.component.normalizer.encode(&tokens);
+        let tokens = self.component.tokenizer.encode(&tokens);
+        self.lock_cache().cache.as_mut().unwrap().extend(&tokens);
+        self.start(
+            false,
+            Some((prefix.to_string(), tokens)).filter(|(s, _)| !s.is_empty()),
+        )
+    }
+
+    /// 继续生成上一句回答，生成的文本接在上一句之后，不开始新的一轮对话。
+    ///
+    /// 上一句回答末尾的结束符被移除，生成结束后上一句与续写的部分仍是同一个句子。
+    /// 对话的最后一句不是以结束符结尾的回答时返回错误，会话不变。
+    pub fn continue_last(&mut self) -> Result<BusySession<M>, ChatError> {
+        self.component.check_generation(&self.generation)?;
+        let n = self.dialog.num_sentences();
+        let eos = self.component.handle.model.eos_token();
+        let is_answer = n % 2 == 0
+            && self
+                .dialog
+                .last_sentence()
+                .is_some_and(|s| s.last() == Some(&eos));
+        if !is_answer {
+            return Err(ChatError::NoAnswer);
+        }
+        let end = self.dialog.num_tokens() - 1;
+        {
+            let (tail, _) = self.dialog.window(2);
+            let mut cache = self.lock_cache();
+            let cache = cache.cache.as_mut().unwrap();
+            // 结束符之前的最后一个 token 重新输入模型，从它的输出开始续写；
+            // 这部分没有计算过时从对话重新填充
+            if cache.revert(end - 1).is_some() {
+                cache.extend(&tail[..1]);
+            } else {
+                let len = self.component.handle.model.max_seq_len() as usize;
+                let (mut tokens, pos) = self.dialog.window(len + 1);
+                tokens.pop();
+                cache.reset_with(tokens, pos);
+            }
+        }
+        // 上一句回答从对话中移除，生成结束时与续写的部分合成一个句子
+        self.dialog.revert(n - 1);
+        Ok(self.start(false, None))
+    }
+
+    fn start(&mut self, new_sentence: bool, prefix: Option<(String, Vec<utok>)>) -> BusySession<M> {
+        let cache = self.lock_cache().cache.take().unwrap();
+        // 强制前缀已经填入缓存，和生成的 token 一样从这里开始计数
+        let base = cache.end() - prefix.as_ref().map_or(0, |(_, tokens)| tokens.len());
+        let args = self.component.task_args(self.generation.clone());
+        let mut handle = self.component.infer(args, cache);
+        // 有强制前缀或续写时生成的文本接在已有的部分之后
+        if new_sentence {
+            handle.start_sentence();
+        }
+        let mut post = PostChain::default();
+        if self.strip_think {
+            post.push_processor(ThinkFilter::default());
+        }
+        if self.trim_leading_space {
+            post.push_processor(TrimLeadingSpace::default());
+        }
         BusySession {
             session: self,
             handle,
+            post,
+            stop: StopSequences::new(self.stops.clone(), self.trim_stop_whitespace),
+            prefix,
+            text: String::new(),
+            sent: 0,
+            base,
+            chunks: Vec::new(),
+            truncate: None,
+        }
+    }
+
+    /// 对话超过上下文长度的 3/4 时，用 [`summarizer`](Self::summarizer) 将最早的几轮对话替换为摘要，并重建缓存。
+    ///
+    /// 对话开头的系统消息不参与摘要，与作为用户消息的摘要一起渲染，再与之后的第一个提示词合为一句。
+    /// 压缩后的对话不超过上下文长度的一半，至少保留最后一个提示词；模板无法渲染时对话不变。
+    fn compress(&mut self) -> Result<(), ChatError> {
+        let Some(summarizer) = self.summarizer.clone() else {
+            return Ok(());
+        };
+        let max = self.component.handle.model.max_seq_len() as usize;
+        let total = self.dialog.num_tokens();
+        if total <= max / 4 * 3 {
+            return Ok(());
         }
+        // 只压缩完整的对话轮次，之后的第一个句子是提示词
+        let n = self.dialog.num_sentences();
+        let mut len = 0;
+        let mut removed = 0;
+        while len + 2 < n && total - removed > max / 2 {
+            removed += self.dialog.sentence(len).len() + self.dialog.sentence(len + 1).len();
+            len += 2;
+        }
+        if len == 0 {
+            return Ok(());
+        }
+
+        let ServiceComponent {
+            tokenizer,
+            normalizer,
+            ..
+        } = &*self.component;
+        let texts = (0..len)
+            .map(|i| {
+                let text = self
+                    .dialog
+                    .sentence(i)
+                    .iter()
+                    .map(|&t| tokenizer.decode(t))
+                    .collect::<String>();
+                let text = normalizer.decode(&text);
+                // 开头的系统消息在压缩后原样保留，不交给摘要
+                match self.system.as_deref().filter(|_| i == 0) {
+                    Some(system) => text.replacen(system, "", 1),
+                    None => text.into_owned(),
+                }
+            })
+            .collect::<Vec<_>>();
+        let summary = summarizer(&texts);
+        let vars = self
+            .template_vars
+            .iter()
+            .map(|(k, v)| (&**k, *v))
+            .collect::<Vec<_>>();
+        // 有的模板单独渲染系统消息时输出为空，摘要作为用户消息与系统消息一起渲染
+        let system = self.system.as_deref().map(|content| Message {
+            role: "system",
+            content,
+        });
+        let user = Message {
+            role: "user",
+            content: &summary,
+        };
+        let messages = system.into_iter().chain([user]).collect::<Vec<_>>();
+        let summary = self.component.render(&messages, false, &vars)?;
+        info!("Dialog compressed: {len} sentences summarized");
+        self.dialog.summarize_front(len, summary);
+
+        let cache = self.component.rebuild_cache(&self.dialog);
+        let mut slot = self.cache.lock().unwrap();
+        slot.cache = Some(cache);
+        slot.last_use = Instant::now();
+        Ok(())
     }
 
-    fn restore_cache(&mut self, mut cache: Cache<M::Storage>) {
+    /// 收回忙会话的缓存，`truncate` 之后的 token 被丢弃，不加入对话。
+    fn restore_cache(&mut self, mut cache: Cache<M::Storage>, truncate: Option<usize>) {
         let end = self.dialog.num_tokens();
-        if cache.end() > end {
+        if let Some(pos) = truncate.filter(|&pos| pos < cache.end()) {
+            let kept = cache.slice_tail(end)[..pos - end].to_vec();
+            // 截断位置没有计算过时从对话重建
+            if cache.revert(pos).is_none() {
+                cache = self.component.rebuild_cache(&self.dialog);
+                cache.extend(&kept);
+            }
+        }
+        if cache.end() > end || truncate.is_some() {
             // 无论忙会话为何丢弃，只要生成了新句子，就补充一个结束符
             cache.push(self.component.handle.model.eos_token());
             // 只要忙会话收集到任何 token，就生成一个新的句子
-            self.dialog.push(cache.slice_tail(end).to_vec());
+            self.dialog
+                .push(cache.slice_tail(end).to_vec(), Some("assistant"), vec![]);
         }
         cache.cleanup_before_start();
         info!("Cache restored at {} tokens", cache.end());
-        self.cache = Some(cache);
+        let mut slot = self.cache.lock().unwrap();
+        slot.cache = Some(cache);
+        slot.last_use = Instant::now();
+    }
+}
+
+/// 用后处理器处理一段解码结果，`text` 为 `None` 表示输出结束。
+///
+/// 返回 `None` 表示这段文本被全部暂存或过滤，需要继续解码。
+fn post_process(post: &mut PostChain, text: Option<(String, Vec<utok>)>) -> Option<Option<String>> {
+    if post.is_empty() {
+        // 只有 token 没有文本的输出不产生文本
+        return match text {
+            Some((s, _)) if s.is_empty() => None,
+            text => Some(text.map(|(s, _)| s)),
+        };
+    }
+    match text {
+        Some((s, _)) => Some(post.push(&s)).filter(|s| !s.is_empty()).map(Some),
+        None => Some(Some(post.finish()).filter(|s| !s.is_empty())),
+    }
+}
+
+/// 按 `overflow` 处理总 token 数量超过 `max` 的 `sentences`，开头的 `keep` 个句子总是保留。
+///
+/// 截断时从第 `keep` 个句子开始成对丢弃最早的句子，保持提问与回答交替，至少保留最后一个句子；
+/// 拒绝或截断后仍然超长时返回 [`ChatError::PromptTooLong`]，句子不变。
+fn limit_prompt(
+    sentences: &mut Vec<Vec<utok>>,
+    keep: usize,
+    max: Option<usize>,
+    overflow: PromptOverflow,
+) -> Result<(), ChatError> {
+    let len = sentences.iter().map(Vec::len).sum::<usize>();
+    let Some(max) = max.filter(|&max| len > max) else {
+        return Ok(());
+    };
+    if overflow == PromptOverflow::Reject {
+        return Err(ChatError::PromptTooLong { len, max });
+    }
+    let mut end = keep;
+    let mut rest = len;
+    while rest > max && end + 2 < sentences.len() {
+        rest -= sentences[end].len() + sentences[end + 1].len();
+        end += 2;
+    }
+    if rest > max {
+        return Err(ChatError::PromptTooLong { len, max });
+    }
+    sentences.drain(keep..end);
+    Ok(())
+}
+
+/// 按 `overflow` 处理数量超过 `max` 的 `tokens`，开头的 `keep` 个 token 总是保留。
+///
+/// 截断时丢弃第 `keep` 个 token 之后最早的部分，`keep` 不小于 `max` 时返回 [`ChatError::PromptTooLong`]。
+fn limit_tokens(
+    tokens: &mut Vec<utok>,
+    keep: usize,
+    max: Option<usize>,
+    overflow: PromptOverflow,
+) -> Result<(), ChatError> {
+    let len = tokens.len();
+    let Some(max) = max.filter(|&max| len > max) else {
+        return Ok(());
+    };
+    if overflow == PromptOverflow::Reject || keep >= max {
+        return Err(ChatError::PromptTooLong { len, max });
     }
+    tokens.drain(keep..len - (max - keep));
+    Ok(())
+}
+
+/// 移除内容为 `system` 的系统消息。
+fn skip_system(messages: &mut Vec<Message>, system: &str) {
+    messages.retain(|m| m.role != "system" || m.content != system);
+}
+
+/// 判断消息中是否有连续相同角色的消息。
+fn has_consecutive_roles(messages: &[Message]) -> bool {
+    messages.windows(2).any(|w| w[0].role == w[1].role)
+}
+
+/// 合并连续相同角色的消息。
+fn merge_roles<'a>(messages: &[Message<'a>]) -> Vec<(&'a str, String)> {
+    let mut ans = Vec::<(&str, String)>::with_capacity(messages.len());
+    for msg in messages {
+        match ans.last_mut() {
+            Some((role, content)) if *role == msg.role => {
+                content.push('\n');
+                content.push_str(msg.content);
+            }
+            _ => ans.push((msg.role, msg.content.to_string())),
+        }
+    }
+    ans
 }
 
 /// 忙会话，表示会话正在处理推理任务，并可接收推理结果。
 pub struct BusySession<'a, M: CausalLM> {
     session: &'a mut Session<M>,
     handle: TaskHandle<M>,
+    /// 作用于输出文本的后处理器。
+    post: PostChain,
+    /// 停止序列，在后处理器之前作用于解码的文本。
+    stop: StopSequences,
+    /// 强制的回答前缀及其 token，在生成的文本之前输出。
+    prefix: Option<(String, Vec<utok>)>,
+    /// 经过停止序列和后处理器的全部文本，不论通过哪个接口接收。
+    text: String,
+    /// `text` 中已经由 [`decode`](Self::decode) 返回的字节数。
+    sent: usize,
+    /// 第一个生成的 token（包括强制前缀）在对话中的位置。
+    base: usize,
+    /// 经过停止序列的每段文本结束时，累计的字节数和 token 数量。
+    chunks: Vec<(usize, usize)>,
+    /// 匹配到停止序列时，回答在对话中的结束位置。
+    truncate: Option<usize>,
+}
+
+/// 会话生成的一条完整的回答。
+#[derive(Clone, Default, PartialEq, Eq, Hash, Debug)]
+pub struct AssistantMessage {
+    /// 经过所有后处理器的回答文本。
+    pub content: String,
+}
+
+impl AssistantMessage {
+    /// 回答的角色。
+    pub const ROLE: &'static str = "assistant";
+
+    /// 作为 [`Message`] 借用，可以加入其他会话或渲染模板。
+    #[inline]
+    pub fn as_message(&self) -> Message {
+        Message {
+            role: Self::ROLE,
+            content: &self.content,
+        }
+    }
 }
 
 impl<M: CausalLM> BusySession<'_, M> {
-    /// 接收模型解码产生的文本。
+    /// 接收预填充进度，提示词处理完毕后返回 `None`。
+    ///
+    /// 仅在设置了 [`prefill_chunk`](GenerationConfig::prefill_chunk) 时产生进度。
     #[inline]
+    pub async fn progress(&mut self) -> Option<PrefillProgress> {
+        self.session.component.progress(&mut self.handle).await
+    }
+
+    /// 在输出文本的处理链末尾添加一个后处理器。
+    ///
+    /// 设置了 [`strip_think`](Session::strip_think) 时，移除思考过程总是第一个处理器。
+    #[inline]
+    pub fn post_process(mut self, p: impl PostProcessor + 'static) -> Self {
+        self.post.push_processor(p);
+        self
+    }
+
+    /// 输出中出现 `stops` 中的任意一个序列时结束生成，输出截断在停止序列之前。
+    ///
+    /// 只作用于这一次推理，与会话的停止序列（[`set_stop`](Session::set_stop)）同时生效，
+    /// 可以在解码中途加入，已经输出的文本不受影响。
+    /// 生成结束的原因为 [`FinishReason::StopSequence`]，停止序列及其之后的 token 不加入对话，
+    /// 设置了 [`trim_stop_whitespace`](Session::trim_stop_whitespace) 时同时移除停止序列之前的空白。
+    pub fn stop_at(mut self, stops: impl IntoIterator<Item = String>) -> Self {
+        self.stop.extend(stops);
+        self
+    }
+
+    /// 接收模型解码产生的文本，文本经过所有后处理器。
+    ///
+    /// 设置了 [`strip_think`](Session::strip_think) 时不返回思考过程。
     pub async fn decode(&mut self) -> Option<String> {
-        self.session.component.decode(&mut self.handle).await
+        loop {
+            let end = self.next_until_stop().await.is_none();
+            if self.text.len() > self.sent {
+                let s = self.text[self.sent..].to_string();
+                self.sent = self.text.len();
+                return Some(s);
+            }
+            if end {
+                return None;
+            }
+        }
+    }
+
+    /// 接收剩余的输出，返回完整的回答。
+    ///
+    /// 回答包含之前用 [`decode`](Self::decode)、[`decode_with_ids`](Self::decode_with_ids)
+    /// 或文本模式的 [`decode_as`](Self::decode_as) 接收过的文本，与依次用 [`decode`](Self::decode) 接收的所有文本拼接的结果相同。
+    pub async fn into_message(mut self) -> AssistantMessage {
+        while self.next_until_stop().await.is_some() {}
+        AssistantMessage {
+            content: take(&mut self.text),
+        }
+    }
+
+    /// 按 `mode` 接收模型解码产生的输出。
+    ///
+    /// [`OutputMode::Text`] 与 [`decode`](Self::decode) 相同；
+    /// 其他模式每次返回一个 token 的原始结果，不经过停止序列和后处理器，供客户端自行解码。
+    pub async fn decode_as(&mut self, mode: OutputMode) -> Option<Decoded> {
+        if mode == OutputMode::Text {
+            return self.decode().await.map(Decoded::Text);
+        }
+        match self.prefix.take() {
+            Some((_, ids)) if mode == OutputMode::Tokens => Some(Decoded::Tokens(ids)),
+            Some((s, _)) => Some(Decoded::Bytes(s.into_bytes())),
+            None => {
+                let component = &self.session.component;
+                component.decode_as(&mut self.handle, mode).await
+            }
+        }
+    }
+
+    /// 将经过后处理的文本依次发送到 `sender`，直到生成结束，返回生成结束的原因。
+    ///
+    /// 接收端关闭时停止生成并返回发送失败的文本。接收端可以包装为 `Stream` 供异步流水线使用。
+    pub async fn send_to(
+        mut self,
+        sender: &UnboundedSender<String>,
+    ) -> Result<Option<FinishReason>, SendError<String>> {
+        while let Some(s) = self.decode().await {
+            sender.send(s)?;
+        }
+        Ok(self.finish_reason())
+    }
+
+    /// 生成结束的原因，在 [`decode`](Self::decode) 返回 `None` 之后可用。
+    #[inline]
+    pub fn finish_reason(&self) -> Option<FinishReason> {
+        self.handle.finish_reason()
+    }
+
+    /// 推理任务的请求 id，与请求日志中的 `id` 对应。
+    #[inline]
+    pub fn request_id(&self) -> u64 {
+        self.handle.id()
+    }
+
+    /// 提示词中命中缓存、不需要重新计算的 token 数量。
+    #[inline]
+    pub fn cached_tokens(&self) -> usize {
+        self.handle.cached_tokens()
+    }
+
+    /// 已接收的生成 token 的累积对数概率，按采样参数调整之前的模型分布计算，可用于给候选回答打分。
+    ///
+    /// 在每次解码之后更新，生成结束后是整个回答的对数概率；强制的回答前缀不计入，未开启 [`token_logprob`](GenerationConfig::token_logprob) 或模型不支持时为 `None`。
+    #[inline]
+    pub fn cumulative_logprob(&self) -> Option<f32> {
+        self.handle.cumulative_logprob()
+    }
+
+    /// 已接收的每个生成 token 的采样分布的熵，按模型输出的分布计算，不受采样参数影响。
+    ///
+    /// 设置了 [`token_entropy`](GenerationConfig::token_entropy) 时在每次解码之后更新，强制的回答前缀不计入，模型不支持时为空。
+    #[inline]
+    pub fn entropies(&self) -> &[f32] {
+        self.handle.entropies()
+    }
+
+    /// 接收模型解码产生的文本，以及产生这段文本的 token。
+    ///
+    /// 返回的文本截断在停止序列之前，不经过后处理器，不会移除思考过程。
+    #[inline]
+    pub async fn decode_with_ids(&mut self) -> Option<(String, Vec<utok>)> {
+        self.next_until_stop().await
+    }
+
+    /// 接收下一段文本，出现停止序列时截断文本并结束生成。
+    ///
+    /// 所有接收文本的接口都经过这里，文本同时经过后处理器累积到回答中，输出结束时结束后处理器。
+    async fn next_until_stop(&mut self) -> Option<(String, Vec<utok>)> {
+        let text = self.truncate_at_stop().await;
+        match &text {
+            Some((s, _)) => {
+                let s = self.post.push(s);
+                self.text.push_str(&s)
+            }
+            None => {
+                let s = self.post.finish();
+                self.text.push_str(&s)
+            }
+        }
+        text
+    }
+
+    /// 接收下一段原始文本，出现停止序列时截断文本并结束生成。
+    ///
+    /// 被停止序列暂存的文本以空字符串返回，保证所有 token 都被返回。
+    async fn truncate_at_stop(&mut self) -> Option<(String, Vec<utok>)> {
+        loop {
+            if self.stop.is_stopped() {
+                return None;
+            }
+            let Some((s, ids)) = self.next().await else {
+                return Some((self.stop.finish(), vec![])).filter(|(s, _)| !s.is_empty());
+            };
+            let (bytes, tokens) = self.chunks.last().copied().unwrap_or_default();
+            self.chunks.push((bytes + s.len(), tokens + ids.len()));
+            let s = self.stop.push(&s);
+            if self.stop.is_stopped() {
+                self.handle.stop(FinishReason::StopSequence);
+                // 停止序列及其之后的 token 不留在对话和缓存中，截断在停止序列开始的那个 token 之前
+                let emitted = self.stop.emitted();
+                let kept = self
+                    .chunks
+                    .iter()
+                    .take_while(|(bytes, _)| *bytes <= emitted)
+                    .last()
+                    .map_or(0, |&(_, tokens)| tokens);
+                self.truncate = Some(self.base + kept);
+            }
+            if !s.is_empty() || !ids.is_empty() {
+                return Some((s, ids));
+            }
+        }
+    }
+
+    /// 先输出强制的前缀，再输出模型解码产生的文本。
+    async fn next(&mut self) -> Option<(String, Vec<utok>)> {
+        match self.prefix.take() {
+            Some(prefix) => Some(prefix),
+            None => self.session.component.decode(&mut self.handle).await,
+        }
     }
 }
 
 impl<M: CausalLM> Drop for BusySession<'_, M> {
     #[inline]
     fn drop(&mut self) {
-        self.session.restore_cache(self.handle.take());
+        self.session
+            .restore_cache(self.handle.take(), self.truncate);
     }
 }
 
@@ -173,26 +1238,146 @@ impl<M: CausalLM> Drop for BusySession<'_, M> {
 pub struct Generator<M: CausalLM> {
     component: Arc<ServiceComponent<M>>,
     handle: TaskHandle<M>,
+    /// 作用于输出文本的后处理器。
+    post: PostChain,
+    /// 提示词的 token 数量。
+    prompt_tokens: usize,
+}
+
+/// 一次非流式生成的完整结果，包含 token 用量。
+#[derive(Clone, PartialEq, Debug)]
+pub struct Completion {
+    /// 经过所有后处理器的完整文本。
+    pub text: String,
+    /// 生成结束的原因。
+    pub finish_reason: Option<FinishReason>,
+    /// 提示词的 token 数量，包含 bos，不包含被截断的部分。
+    pub prompt_tokens: usize,
+    /// 生成的 token 数量。
+    pub completion_tokens: usize,
+    /// 整个回答的对数概率，未开启或模型不支持时为 `None`。
+    pub logprob: Option<f32>,
 }
 
 impl<M: CausalLM> Generator<M> {
+    /// 编码提示词并启动推理，提示词超过 `max_prompt_tokens` 时按照 `overflow` 处理。
     pub(crate) fn new(
         component: Arc<ServiceComponent<M>>,
         prompt: impl fmt::Display,
-        sample: SampleArgs,
-    ) -> Self {
-        let prompt = format!("{}{}", component.bos, prompt);
+        args: TaskArgs,
+        max_prompt_tokens: Option<usize>,
+        overflow: PromptOverflow,
+    ) -> Result<Self, ChatError> {
+        let prompt = prompt.to_string();
+        let prompt = format!("{}{}", component.bos, component.tokenizer.escape(&prompt));
         let prompt = component.normalizer.encode(&prompt);
-        let tokens = component.tokenizer.encode(&prompt);
-        let handle = component.infer(sample, Cache::new(&component.handle.model, tokens));
-        Self { handle, component }
+        let mut tokens = component.tokenizer.encode(&prompt);
+        let keep = usize::from(tokens.first() == Some(&component.handle.model.bos_token()));
+        limit_tokens(&mut tokens, keep, max_prompt_tokens, overflow)?;
+        let prompt_tokens = tokens.len();
+        let cache = Cache::new(&component.handle.model, tokens);
+        let handle = component.infer(args, cache);
+        Ok(Self {
+            handle,
+            component,
+            post: Default::default(),
+            prompt_tokens,
+        })
+    }
+
+    /// 接收全部输出，汇总为一个 [`Completion`]。
+    pub async fn complete(mut self) -> Completion {
+        let mut text = String::new();
+        while let Some(s) = self.decode().await {
+            text.push_str(&s);
+        }
+        Completion {
+            text,
+            finish_reason: self.finish_reason(),
+            prompt_tokens: self.prompt_tokens,
+            completion_tokens: self.completion_tokens(),
+            logprob: self.cumulative_logprob(),
+        }
+    }
+
+    /// 在输出文本的处理链末尾添加一个后处理器。
+    #[inline]
+    pub fn post_process(mut self, p: impl PostProcessor + 'static) -> Self {
+        self.post.push_processor(p);
+        self
     }
 
-    /// 接收模型解码产生的文本。
+    /// 接收预填充进度，提示词处理完毕后返回 `None`。
     #[inline]
+    pub async fn progress(&mut self) -> Option<PrefillProgress> {
+        self.component.progress(&mut self.handle).await
+    }
+
+    /// 接收模型解码产生的文本，文本经过所有后处理器。
     pub async fn decode(&mut self) -> Option<String> {
+        loop {
+            let text = self.component.decode(&mut self.handle).await;
+            if let Some(s) = post_process(&mut self.post, text) {
+                return s;
+            }
+        }
+    }
+
+    /// 按 `mode` 接收模型解码产生的输出。
+    ///
+    /// [`OutputMode::Text`] 与 [`decode`](Self::decode) 相同，其他模式每次返回一个 token 的原始结果，不经过后处理器。
+    pub async fn decode_as(&mut self, mode: OutputMode) -> Option<Decoded> {
+        match mode {
+            OutputMode::Text => self.decode().await.map(Decoded::Text),
+            _ => self.component.decode_as(&mut self.handle, mode).await,
+        }
+    }
+
+    /// 接收模型解码产生的文本，以及产生这段文本的 token。
+    ///
+    /// 返回的文本不经过后处理器。
+    #[inline]
+    pub async fn decode_with_ids(&mut self) -> Option<(String, Vec<utok>)> {
         self.component.decode(&mut self.handle).await
     }
+
+    /// 生成结束的原因，在 [`decode`](Self::decode) 返回 `None` 之后可用。
+    #[inline]
+    pub fn finish_reason(&self) -> Option<FinishReason> {
+        self.handle.finish_reason()
+    }
+
+    /// 推理任务的请求 id，与请求日志中的 `id` 对应。
+    #[inline]
+    pub fn request_id(&self) -> u64 {
+        self.handle.id()
+    }
+
+    /// 提示词中命中缓存、不需要重新计算的 token 数量。
+    #[inline]
+    pub fn cached_tokens(&self) -> usize {
+        self.handle.cached_tokens()
+    }
+
+    /// 提示词的 token 数量。
+    #[inline]
+    pub fn prompt_tokens(&self) -> usize {
+        self.prompt_tokens
+    }
+
+    /// 已接收的生成 token 数量。
+    #[inline]
+    pub fn completion_tokens(&self) -> usize {
+        self.handle.received_tokens()
+    }
+
+    /// 已接收的生成 token 的累积对数概率，按采样参数调整之前的模型分布计算，可用于给候选回答打分。
+    ///
+    /// 在每次解码之后更新，生成结束后是整个回答的对数概率；强制的回答前缀不计入，未开启 [`token_logprob`](GenerationConfig::token_logprob) 或模型不支持时为 `None`。
+    #[inline]
+    pub fn cumulative_logprob(&self) -> Option<f32> {
+        self.handle.cumulative_logprob()
+    }
 }
 
 impl<M: CausalLM> Drop for Generator<M> {
@@ -201,3 +1386,844 @@ impl<M: CausalLM> Drop for Generator<M> {
         let _ = self.handle.take();
     }
 }
+
+#[test]
+fn test_kv_cache() {
+    use tensor::udim;
+
+    crate::test_service(Default::default(), |runtime, service| {
+        let mut session = service.launch();
+        assert!(session.kv_cache(0).is_none());
+
+        session
+            .extend(&[Message {
+                role: "user",
+                content: "Hi",
+            }])
+            .unwrap();
+        crate::test_chat(runtime, &mut session.chat());
+
+        let info = session.component.handle.model.architecture();
+        let len = session.lock_cache().cache.as_ref().unwrap().cached_len();
+        let kv = session.kv_cache(info.nlayers - 1).unwrap();
+        let dh = info.d / info.nh;
+        assert_eq!(kv.shape(), [2, info.nkvh as udim, len as udim, dh as udim]);
+    });
+}
+
+#[test]
+fn test_chat_with_prefix() {
+    crate::test_service(Default::default(), |runtime, service| {
+        let mut session = service.launch();
+        session
+            .extend(&[Message {
+                role: "user",
+                content: "Tell me a joke.",
+            }])
+            .unwrap();
+        let end = session.dialog.num_tokens();
+
+        const PREFIX: &str = "Sure, here is";
+        let text = crate::test_chat(runtime, &mut session.chat_with_prefix(PREFIX));
+        assert!(text.starts_with(PREFIX));
+
+        // 前缀 token 位于回答句子的开头，之后是采样得到的 token
+        let prefix = session.component.normalizer.encode(PREFIX);
+        let prefix = session.component.tokenizer.encode(&prefix);
+        let answer = session
+            .lock_cache()
+            .cache
+            .as_ref()
+            .unwrap()
+            .slice_tail(end)
+            .to_vec();
+        assert!(answer.starts_with(&prefix));
+        assert!(answer.len() > prefix.len());
+    });
+}
+
+#[test]
+fn test_continue_last() {
+    crate::test_service(Default::default(), |runtime, service| {
+        let mut session = service.launch();
+        // 还没有回答时不能续写
+        assert!(session.continue_last().is_err());
+
+        session
+            .extend(&[Message {
+                role: "user",
+                content: "Tell me a story.",
+            }])
+            .unwrap();
+        assert!(session.continue_last().is_err());
+        let end = session.dialog.num_tokens();
+
+        // 限制长度使回答被截断
+        session.generation.max_tokens = Some(4);
+        fn collect(
+            runtime: &tokio::runtime::Runtime,
+            mut busy: BusySession<'_, llama_cpu::Transformer>,
+        ) -> (String, Option<FinishReason>) {
+            let text = crate::test_chat(runtime, &mut busy);
+            // 至少生成了一个 token
+            assert!(busy.handle.received_tokens() > 0);
+            (text, busy.finish_reason())
+        }
+        let (first, reason) = collect(runtime, session.chat());
+        assert_eq!(reason, Some(FinishReason::Length));
+        assert_eq!(session.dialog_pos(), 2);
+        let answer = session.dialog.last_sentence().unwrap().to_vec();
+
+        let (second, reason) = collect(runtime, session.continue_last().unwrap());
+        assert_eq!(reason, Some(FinishReason::Length));
+        assert!(!second.is_empty(), "{first:?}");
+        // 续写的部分接在上一句回答之后，句子数量不变
+        assert_eq!(session.dialog_pos(), 2);
+        let eos = session.component.handle.model.eos_token();
+        let continued = session.dialog.last_sentence().unwrap();
+        assert_eq!(continued[..answer.len() - 1], answer[..answer.len() - 1]);
+        assert_eq!(continued.len(), answer.len() + 4);
+        assert_eq!(continued.last(), Some(&eos));
+        let tail = session
+            .lock_cache()
+            .cache
+            .as_ref()
+            .unwrap()
+            .slice_tail(end)
+            .to_vec();
+        assert_eq!(tail, continued);
+    });
+}
+
+#[test]
+fn test_merge_roles() {
+    let messages = [
+        Message {
+            role: "system",
+            content: "Be brief.",
+        },
+        Message {
+            role: "user",
+            content: "Hi.",
+        },
+        Message {
+            role: "user",
+            content: "Who are you?",
+        },
+    ];
+    assert!(has_consecutive_roles(&messages));
+    assert!(!has_consecutive_roles(&messages[..2]));
+    assert_eq!(
+        merge_roles(&messages),
+        [
+            ("system", "Be brief.".to_string()),
+            ("user", "Hi.\nWho are you?".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn test_role_policy() {
+    crate::test_service(Default::default(), |_, service| {
+        let messages = [
+            Message {
+                role: "user",
+                content: "Hi.",
+            },
+            Message {
+                role: "user",
+                content: "Who are you?",
+            },
+        ];
+
+        let mut session = service.launch();
+        session.role_policy = RolePolicy::Reject;
+        assert_eq!(session.extend(&messages), Err(ChatError::RoleConflict));
+        assert_eq!(session.dialog_pos(), 0);
+
+        session.role_policy = RolePolicy::Merge;
+        session.extend(&messages).unwrap();
+        assert_eq!(session.dialog_pos(), 1);
+        assert_eq!(session.last_role(), Some("user"));
+
+        // 与对话中最后一个句子比较角色
+        session.role_policy = RolePolicy::Reject;
+        assert_eq!(session.extend(&messages[1..]), Err(ChatError::RoleConflict));
+        assert_eq!(session.dialog_pos(), 1);
+
+        // 分两次合并与一次合并的结果相同
+        session.role_policy = RolePolicy::Merge;
+        session.extend(&messages[1..]).unwrap();
+        assert_eq!(session.dialog_pos(), 1);
+        let mut once = service.launch();
+        once.role_policy = RolePolicy::Merge;
+        let three = messages
+            .iter()
+            .chain(&messages[1..])
+            .map(|m| Message {
+                role: m.role,
+                content: m.content,
+            })
+            .collect::<Vec<_>>();
+        once.extend(&three).unwrap();
+        assert_eq!(session.dialog.sentence(0), once.dialog.sentence(0));
+
+        let mut session = service.launch();
+        session.extend(&messages).unwrap();
+        assert_eq!(session.dialog_pos(), 2);
+    });
+}
+
+#[test]
+fn test_escape_content() {
+    crate::test_service(Default::default(), |_, service| {
+        let eos = service.component.handle.model.eos_token();
+        let count = |content: &str| {
+            let mut session = service.launch();
+            session
+                .extend(&[Message {
+                    role: "user",
+                    content,
+                }])
+                .unwrap();
+            let tokens = session.dialog.sentence(0).to_vec();
+            tokens.iter().filter(|&&t| t == eos).count()
+        };
+        // 消息内容中的结束符字符串不编码为结束符
+        let content = format!("Hi{}", service.component.eos);
+        assert_eq!(count(&content), count("Hi"));
+    });
+}
+
+#[test]
+fn test_system_prompt() {
+    crate::test_service(Default::default(), |_, mut service| {
+        let user = || Message {
+            role: "user",
+            content: "Hi",
+        };
+        let tokens = |session: &Session<_>| {
+            let cache = session.lock_cache();
+            cache.cache.as_ref().unwrap().slice_tail(0).to_vec()
+        };
+
+        let mut plain = service.launch();
+        plain.extend(&[user()]).unwrap();
+
+        service.system_prompt = Some("You are a helpful assistant.".into());
+        let mut session = service.launch();
+        // 启动的会话在用户输入之前就带有系统提示词
+        assert_eq!(
+            session.system_prompt.as_deref(),
+            Some("You are a helpful assistant.")
+        );
+        session.extend(&[user()]).unwrap();
+        assert_eq!(session.dialog_pos(), 1);
+        let injected = tokens(&session);
+        assert!(injected.len() > tokens(&plain).len());
+
+        // 复制和回滚都保留系统提示词
+        let mut fork = session.fork();
+        fork.revert(0).unwrap();
+        fork.extend(&[user()]).unwrap();
+        assert_eq!(tokens(&fork), injected);
+
+        // 调用者提供了系统消息时不注入
+        let custom = [
+            Message {
+                role: "system",
+                content: "Be brief.",
+            },
+            user(),
+        ];
+        let mut a = service.launch();
+        a.extend(&custom).unwrap();
+        let mut b = plain.fork();
+        b.revert(0).unwrap();
+        b.extend(&custom).unwrap();
+        assert_eq!(tokens(&a), tokens(&b));
+    });
+}
+
+#[test]
+fn test_decode_with_ids() {
+    crate::test_service(Default::default(), |runtime, service| {
+        let mut session = service.launch();
+        session
+            .extend(&[Message {
+                role: "user",
+                content: "Tell me a joke.",
+            }])
+            .unwrap();
+
+        let component = session.component.clone();
+        let chunks = runtime.block_on(async {
+            let mut busy = session.chat();
+            let mut chunks = vec![];
+            while let Some(chunk) = busy.decode_with_ids().await {
+                chunks.push(chunk);
+            }
+            chunks
+        });
+        assert!(!chunks.is_empty());
+
+        // 把所有 token 重新解码，与返回的文本一致
+        let text = chunks.iter().map(|(s, _)| &**s).collect::<String>();
+        let ids = chunks
+            .into_iter()
+            .flat_map(|(_, ids)| ids)
+            .collect::<Vec<_>>();
+        let mut decoder = crate::tokenizer::StreamDecoder::sentence();
+        let bytes = ids
+            .iter()
+            .flat_map(|&t| {
+                decoder
+                    .decode(&component.tokenizer, &*component.normalizer, t)
+                    .into_owned()
+                    .into_bytes()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(String::from_utf8_lossy(&bytes), text);
+    });
+}
+
+#[test]
+fn test_send_to() {
+    use causal_lm::SampleArgs;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    crate::test_service(Default::default(), |runtime, service| {
+        let mut session = service.launch();
+        session.generation.sample = SampleArgs::ARG_MAX;
+        session.generation.max_tokens = Some(16);
+        session
+            .extend(&[Message {
+                role: "user",
+                content: "Tell me a joke.",
+            }])
+            .unwrap();
+        let mut fork = session.fork();
+        let mut closed = session.fork();
+
+        // 贪心采样下，发送到管道的文本与逐段解码的结果相同
+        let (polled, (sent, reason)) = runtime.block_on(async {
+            let mut busy = session.chat();
+            let mut polled = vec![];
+            while let Some(s) = busy.decode().await {
+                polled.push(s);
+            }
+            let (sender, mut receiver) = unbounded_channel();
+            let reason = fork.chat().send_to(&sender).await.unwrap();
+            drop(sender);
+            let mut sent = vec![];
+            while let Some(s) = receiver.recv().await {
+                sent.push(s);
+            }
+            (polled, (sent, reason))
+        });
+        assert!(!polled.is_empty());
+        assert_eq!(sent, polled);
+        assert!(reason.is_some());
+
+        // 接收端关闭时停止生成
+        let (sender, receiver) = unbounded_channel();
+        drop(receiver);
+        assert!(runtime.block_on(closed.chat().send_to(&sender)).is_err());
+    });
+}
+
+#[test]
+fn test_set_stop() {
+    use causal_lm::SampleArgs;
+
+    crate::test_service(Default::default(), |runtime, service| {
+        let mut session = service.launch();
+        session.generation.sample = SampleArgs::ARG_MAX;
+        session.generation.max_tokens = Some(16);
+        session
+            .extend(&[Message {
+                role: "user",
+                content: "Tell me a joke.",
+            }])
+            .unwrap();
+        let chat = |session: &mut Session<_>| {
+            let mut busy = session.chat();
+            let text = crate::test_chat(runtime, &mut busy);
+            (text, busy.finish_reason())
+        };
+
+        let (answer, reason) = chat(&mut session);
+        assert_ne!(reason, Some(FinishReason::StopSequence));
+        // 在两轮之间设置停止序列，重新生成的回答截断在停止序列之前
+        let chars = answer.chars().collect::<Vec<_>>();
+        assert!(chars.len() >= 4);
+        let stop = chars[chars.len() / 2..][..2].iter().collect::<String>();
+        session.set_stop(vec![stop.clone()]);
+        session.revert(1).unwrap();
+        let (text, reason) = chat(&mut session);
+        assert_eq!(text, answer[..answer.find(&stop).unwrap()]);
+        assert_eq!(reason, Some(FinishReason::StopSequence));
+        // 停止序列不留在对话中，缓存与对话一致
+        let ServiceComponent {
+            tokenizer,
+            normalizer,
+            ..
+        } = &*session.component;
+        let last = session
+            .dialog
+            .last_sentence()
+            .unwrap()
+            .iter()
+            .map(|&t| tokenizer.decode(t))
+            .collect::<String>();
+        assert!(!normalizer.decode(&last).contains(&stop));
+        let end = session.dialog.num_tokens();
+        assert_eq!(session.lock_cache().cache.as_ref().unwrap().end(), end);
+    });
+}
+
+#[test]
+fn test_extend_raw() {
+    crate::test_service(Default::default(), |_, service| {
+        let mut session = service.launch();
+
+        const TEXT: &str = "<|user|>\nHi</s>\n<|assistant|>\n";
+        session.extend_raw(TEXT);
+        assert_eq!(session.dialog_pos(), 1);
+
+        // 缓存中只有文本本身的 token，没有模板包装
+        let normalized = session.component.normalizer.encode(TEXT);
+        let expected = session.component.tokenizer.encode(&normalized);
+        let cache = session.lock_cache();
+        assert_eq!(cache.cache.as_ref().unwrap().slice_tail(0), expected);
+    });
+}
+
+#[test]
+fn test_set_cache_position() {
+    use causal_lm::SampleArgs;
+
+    crate::test_service(Default::default(), |runtime, service| {
+        let mut session = service.launch();
+        session.generation.sample = SampleArgs::ARG_MAX;
+        session
+            .extend(&[Message {
+                role: "user",
+                content: "Tell me a joke.",
+            }])
+            .unwrap();
+        let answer = crate::test_chat(runtime, &mut session.chat());
+        let end = session.cache_position();
+        assert!(end > 0);
+        assert!(session.set_cache_position(end + 1).is_err());
+
+        // 回退到提示词中间，之后的部分重新计算，贪心采样的回答不变
+        session.revert(1).unwrap();
+        let prompt_len = session.dialog.num_tokens();
+        session.set_cache_position(prompt_len / 2).unwrap();
+        assert_eq!(session.cache_position(), prompt_len / 2);
+        let query_len = session.lock_cache().cache.as_ref().unwrap().query().len();
+        assert_eq!(query_len, prompt_len - prompt_len / 2);
+        assert_eq!(crate::test_chat(runtime, &mut session.chat()), answer);
+    });
+}
+
+#[test]
+fn test_cached_tokens() {
+    crate::test_service(Default::default(), |runtime, service| {
+        let mut session = service.launch();
+        let chat = |session: &mut Session<_>, content| {
+            session
+                .extend(&[Message {
+                    role: "user",
+                    content,
+                }])
+                .unwrap();
+            let mut busy = session.chat();
+            crate::test_chat(runtime, &mut busy);
+            busy.cached_tokens()
+        };
+        assert_eq!(chat(&mut session, "Hi"), 0);
+        let shared = session.dialog.num_tokens();
+        // 之前的对话作为共享的前缀命中缓存，只有回答末尾补充的结束符需要计算
+        assert_eq!(chat(&mut session, "Tell me a joke."), shared - 1);
+    });
+}
+
+#[test]
+fn test_summarizer() {
+    crate::test_service(Default::default(), |runtime, service| {
+        const SYSTEM: &str = "You are a weather assistant.";
+        let mut session = service.launch();
+        session.system_prompt = Some(SYSTEM.into());
+        let summarized = Arc::new(Mutex::new(Vec::new()));
+        session.summarizer = Some(Arc::new({
+            let summarized = summarized.clone();
+            move |texts: &[String]| {
+                // 开头的系统消息不交给摘要
+                assert!(texts.iter().all(|t| !t.contains(SYSTEM)));
+                summarized.lock().unwrap().push(texts.len());
+                "The user and the assistant talked about the weather.".into()
+            }
+        }));
+
+        // 填充对话直到超过上下文长度的 3/4
+        let max = session.component.handle.model.max_seq_len() as usize;
+        let question = "What is the weather like today? ".repeat(8);
+        let answer = "It is sunny and warm, a good day for a walk. ".repeat(8);
+        while session.dialog.num_tokens() <= max / 4 * 3 {
+            session
+                .extend(&[
+                    Message {
+                        role: "user",
+                        content: &question,
+                    },
+                    Message {
+                        role: "assistant",
+                        content: &answer,
+                    },
+                ])
+                .unwrap();
+        }
+        let n = session.dialog_pos() + 1;
+        let before = session.dialog.num_tokens();
+        // 以提示词结尾的填充触发压缩
+        session
+            .extend(&[Message {
+                role: "user",
+                content: "Should I bring an umbrella?",
+            }])
+            .unwrap();
+
+        session.generation.max_tokens = Some(8);
+        let text = crate::test_chat(runtime, &mut session.chat());
+        assert!(!text.is_empty());
+
+        // 最早的几轮对话被替换为摘要，之后的提示词和新的回答保留
+        let summarized = summarized.lock().unwrap().clone();
+        let &[len] = &*summarized else {
+            panic!("summarizer called {} times", summarized.len())
+        };
+        assert!(len >= 2 && len % 2 == 0);
+        assert_eq!(session.dialog_pos(), n - len + 1);
+        // 系统消息仍在对话开头
+        assert_eq!(session.system.as_deref(), Some(SYSTEM));
+        let ServiceComponent {
+            tokenizer,
+            normalizer,
+            ..
+        } = &*session.component;
+        let head = session
+            .dialog
+            .sentence(0)
+            .iter()
+            .map(|&t| tokenizer.decode(t))
+            .collect::<String>();
+        assert!(normalizer.decode(&head).contains(SYSTEM));
+        let end = session.dialog.num_tokens();
+        assert!(end - session.dialog.last_sentence().unwrap().len() < before);
+        let cache = session.lock_cache();
+        assert_eq!(cache.cache.as_ref().unwrap().end(), end);
+    });
+}
+
+#[test]
+fn test_output_mode() {
+    use causal_lm::SampleArgs;
+
+    crate::test_service(Default::default(), |runtime, mut service| {
+        service.generation.max_tokens = Some(16);
+        let generate = |mode| {
+            runtime.block_on(async {
+                let mut generator = service
+                    .generate("Once upon a time,", Some(SampleArgs::ARG_MAX))
+                    .unwrap();
+                let mut outputs = Vec::new();
+                while let Some(output) = generator.decode_as(mode).await {
+                    outputs.push(output);
+                }
+                outputs
+            })
+        };
+
+        let text = generate(OutputMode::Text)
+            .into_iter()
+            .map(|output| match output {
+                Decoded::Text(s) => s,
+                _ => panic!("expected text"),
+            })
+            .collect::<String>();
+        assert!(!text.is_empty());
+        // 每种形式的输出都能还原为相同的文本
+        let tokens = generate(OutputMode::Tokens)
+            .into_iter()
+            .flat_map(|output| match output {
+                Decoded::Tokens(ids) => ids,
+                _ => panic!("expected tokens"),
+            })
+            .map(|t| service.token_to_str(t).unwrap())
+            .collect::<String>();
+        assert_eq!(tokens, text);
+        let bytes = generate(OutputMode::Bytes)
+            .into_iter()
+            .flat_map(|output| match output {
+                Decoded::Bytes(bytes) => bytes,
+                _ => panic!("expected bytes"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(String::from_utf8(bytes).unwrap(), text);
+    });
+}
+
+#[test]
+fn test_cumulative_logprob() {
+    use causal_lm::{DecodingMeta, QueryContext, SampleArgs};
+
+    crate::test_service(Default::default(), |runtime, mut service| {
+        service.generation.max_tokens = Some(8);
+        let prompt = "Once upon a time,";
+        // 默认不计算对数概率
+        let generator = service.generate(prompt, Some(SampleArgs::ARG_MAX)).unwrap();
+        let completion = runtime.block_on(generator.complete());
+        assert!(completion.completion_tokens > 0 && completion.logprob.is_none());
+
+        service.generation.token_logprob = true;
+        let mut generator = service.generate(prompt, Some(SampleArgs::ARG_MAX)).unwrap();
+        let (generated, cumulative) = runtime.block_on(async {
+            let mut generated = Vec::new();
+            let mut cumulative = Vec::new();
+            while let Some(Decoded::Tokens(ids)) = generator.decode_as(OutputMode::Tokens).await {
+                generated.extend(ids);
+                cumulative.push(generator.cumulative_logprob().unwrap());
+            }
+            (generated, cumulative)
+        });
+        drop(generator);
+        assert!(!generated.is_empty());
+
+        // 一次性计算提示词和生成的 token 的 logits，逐个求生成的 token 的对数概率
+        let component = &service.component;
+        let model = &component.handle.model;
+        let text = format!("{}{prompt}", component.bos);
+        let mut tokens = component
+            .tokenizer
+            .encode(&component.normalizer.encode(&text));
+        let prompt_len = tokens.len();
+        tokens.extend(&generated);
+        let len = tokens.len() - 1;
+        let mut cache = model.new_cache();
+        let queries = [QueryContext {
+            cache: Some(&mut cache),
+            range: 0..len as _,
+        }];
+        let hidden_state = model
+            .forward(queries, model.token_embed(tokens[..len].iter().copied()))
+            .unwrap();
+        let logits = model.decode([DecodingMeta::all(len)], hidden_state);
+        let logits = model.logits_to_host(&logits).unwrap();
+        let voc = logits.len() / len;
+
+        let mut sum = 0.;
+        for (i, (&token, cumulative)) in zip(&generated, cumulative).enumerate() {
+            let row = &logits[(prompt_len - 1 + i) * voc..][..voc];
+            let logprob = dispatch::logprob(row, token);
+            assert!(logprob <= 0.);
+            sum += logprob;
+            // 累积对数概率等于已生成的 token 的对数概率之和
+            assert!(
+                (cumulative - sum).abs() < 0.1 * (i + 1) as f32,
+                "{cumulative} != {sum}"
+            );
+        }
+    });
+}
+
+#[test]
+fn test_max_prompt_tokens() {
+    use PromptOverflow::{Reject, Truncate};
+
+    let origin = vec![vec![1, 2], vec![3, 4, 5], vec![6], vec![7, 8], vec![9]];
+    let mut sentences = origin.clone();
+    // 超长的提示词被拒绝，句子不变
+    assert_eq!(
+        limit_prompt(&mut sentences, 1, Some(4), Reject),
+        Err(ChatError::PromptTooLong { len: 9, max: 4 })
+    );
+    assert_eq!(sentences, origin);
+    // 未超长或不限制时句子不变
+    limit_prompt(&mut sentences, 1, Some(9), Reject).unwrap();
+    limit_prompt(&mut sentences, 1, None, Truncate).unwrap();
+    assert_eq!(sentences, origin);
+    // 截断时保留开头的句子，成对丢弃之后最早的句子
+    limit_prompt(&mut sentences, 1, Some(5), Truncate).unwrap();
+    assert_eq!(sentences, [vec![1, 2], vec![7, 8], vec![9]]);
+    // 没有可以丢弃的完整轮次时仍然拒绝，句子不变
+    assert_eq!(
+        limit_prompt(&mut sentences, 1, Some(4), Truncate),
+        Err(ChatError::PromptTooLong { len: 5, max: 4 })
+    );
+    assert_eq!(sentences, [vec![1, 2], vec![7, 8], vec![9]]);
+    let mut sentences = origin.clone();
+    limit_prompt(&mut sentences, 0, Some(4), Truncate).unwrap();
+    assert_eq!(sentences, [vec![6], vec![7, 8], vec![9]]);
+
+    // 文本生成截断时保留开头的 bos
+    let mut tokens = vec![1, 2, 3, 4, 5];
+    assert!(limit_tokens(&mut tokens, 1, Some(3), Reject).is_err());
+    limit_tokens(&mut tokens, 1, Some(3), Truncate).unwrap();
+    assert_eq!(tokens, [1, 4, 5]);
+    assert!(limit_tokens(&mut tokens, 1, Some(1), Truncate).is_err());
+    assert_eq!(tokens, [1, 4, 5]);
+}
+
+#[test]
+fn test_role_map() {
+    use crate::RoleMap;
+
+    crate::test_service(Default::default(), |_, mut service| {
+        let message = |role| Message {
+            role,
+            content: "Hi.",
+        };
+
+        // 默认没有别名的角色原样交给模板
+        let mut session = service.launch();
+        assert!(!matches!(
+            session.extend(&[message("tool")]),
+            Err(ChatError::UnknownRole { .. })
+        ));
+        drop(session);
+
+        // 严格的映射拒绝未知的角色，报告第一条角色未知的消息，会话不变
+        service.set_role_map(RoleMap::strict());
+        let mut session = service.launch();
+        assert_eq!(
+            session.extend(&[message("user"), message("human")]),
+            Err(ChatError::UnknownRole { index: 1 })
+        );
+        assert_eq!(session.dialog_pos(), 0);
+        drop(session);
+
+        // human 映射为 user 后，与直接使用 user 相同
+        service.set_role_map(RoleMap::strict().alias("human", "user"));
+        let mut session = service.launch();
+        session.extend(&[message("human")]).unwrap();
+        assert_eq!(session.dialog_pos(), 1);
+    });
+}
+
+#[test]
+fn test_skip_duplicate_system() {
+    let system = "You are a helpful assistant.";
+    let question = "Who are you?";
+    let skip = |messages: &[(&'static str, &'static str)]| {
+        let mut messages = messages
+            .iter()
+            .map(|&(role, content)| Message { role, content })
+            .collect::<Vec<_>>();
+        skip_system(&mut messages, system);
+        messages
+            .into_iter()
+            .map(|m| (m.role, m.content))
+            .collect::<Vec<_>>()
+    };
+
+    // 再次加入相同的系统消息时，只保留新的提示词
+    assert_eq!(
+        skip(&[("system", system), ("user", question)]),
+        [("user", question)]
+    );
+    // 内容不同的系统消息和内容相同的其他角色的消息照常保留
+    let messages = [
+        ("system", "Answer briefly."),
+        ("user", system),
+        ("user", question),
+    ];
+    assert_eq!(skip(&messages), messages);
+}
+
+#[test]
+fn test_into_message() {
+    crate::test_service(Default::default(), |runtime, service| {
+        let mut session = service.launch();
+        session.generation.max_tokens = Some(8);
+        session
+            .extend(&[Message {
+                role: "user",
+                content: "Tell me a story.",
+            }])
+            .unwrap();
+
+        // 已经接收的文本也包含在回答中
+        let (chunks, message) = runtime.block_on(async {
+            let mut busy = session.chat();
+            let mut chunks = vec![];
+            for _ in 0..2 {
+                chunks.extend(busy.decode().await);
+            }
+            let message = busy.into_message().await;
+            (chunks, message)
+        });
+        assert!(message.content.starts_with(&chunks.concat()));
+        assert_eq!(message.as_message().role, "assistant");
+
+        // 回答等于依次接收的所有文本
+        let mut fork = session.fork();
+        fork.revert(1).unwrap();
+        let mut busy = fork.chat();
+        let text = crate::test_chat(runtime, &mut busy);
+        let message = runtime.block_on(busy.into_message());
+        assert_eq!(message.content, text);
+
+        // 用其他接口接收的文本同样包含在回答中
+        fork.revert(1).unwrap();
+        let (chunks, message) = runtime.block_on(async {
+            let mut busy = fork.chat();
+            let mut chunks = vec![];
+            chunks.extend(busy.decode_with_ids().await.map(|(s, _)| s));
+            chunks.extend(busy.decode_as(OutputMode::Text).await.map(|d| match d {
+                Decoded::Text(s) => s,
+                _ => unreachable!(),
+            }));
+            (chunks, busy.into_message().await)
+        });
+        assert!(!chunks.is_empty());
+        assert!(message.content.starts_with(&chunks.concat()));
+    });
+}
+
+#[test]
+fn test_token_entropy() {
+    crate::test_service(Default::default(), |runtime, service| {
+        let mut session = service.launch();
+        session.generation.max_tokens = Some(8);
+        session
+            .extend(&[Message {
+                role: "user",
+                content: "Tell me a story.",
+            }])
+            .unwrap();
+        let mut fork = session.fork();
+
+        // 默认不计算熵
+        let entropies = |session: &mut Session<llama_cpu::Transformer>| {
+            runtime.block_on(async {
+                let mut busy = session.chat();
+                let mut received = 0;
+                while let Some((_, ids)) = busy.decode_with_ids().await {
+                    received += ids.len();
+                }
+                (received, busy.entropies().to_vec())
+            })
+        };
+        let (_, none) = entropies(&mut session);
+        assert!(none.is_empty());
+
+        // 每个接收的 token 对应一个熵
+        fork.generation.token_entropy = true;
+        let (received, entropies) = entropies(&mut fork);
+        assert_eq!(entropies.len(), received);
+        let voc = fork.component.handle.model.architecture().voc as f32;
+        assert!(entropies.iter().all(|&h| (0. ..=voc.ln()).contains(&h)));
+    });
+}
@@ -0,0 +1,200 @@
+/// 流式输出的后处理器，依次作用于解码产生的每一段文本。
+///
+/// 处理器可以暂存暂时无法确定的尾部，在之后的输入或输出结束时再返回。
+pub trait PostProcessor: Send {
+    /// 输入一段文本，返回处理后可以输出的部分。
+    fn push(&mut self, s: &str) -> String;
+
+    /// 输出结束，返回暂存的文本。
+    fn finish(&mut self) -> String {
+        String::new()
+    }
+}
+
+/// 移除输出开头的空白。
+#[derive(Clone, Default, Debug)]
+pub struct TrimStart {
+    started: bool,
+}
+
+impl PostProcessor for TrimStart {
+    fn push(&mut self, s: &str) -> String {
+        if self.started {
+            return s.into();
+        }
+        let s = s.trim_start();
+        self.started = !s.is_empty();
+        s.into()
+    }
+}
+
+/// 移除输出开头的一个空格，例如 SentencePiece 模型在第一个 token 前加入的空格。
+///
+/// 只移除第一段非空输出开头的 ASCII 空格，其他空白和之后的空格保持不变。
+#[derive(Clone, Default, Debug)]
+pub struct TrimLeadingSpace {
+    started: bool,
+}
+
+impl PostProcessor for TrimLeadingSpace {
+    fn push(&mut self, s: &str) -> String {
+        if self.started || s.is_empty() {
+            return s.into();
+        }
+        self.started = true;
+        s.strip_prefix(' ').unwrap_or(s).into()
+    }
+}
+
+/// 将连续超过 `max` 个的换行合并为 `max` 个。
+#[derive(Clone, Debug)]
+pub struct CollapseNewlines {
+    max: usize,
+    run: usize,
+}
+
+impl CollapseNewlines {
+    /// 最多保留 `max` 个连续的换行。
+    #[inline]
+    pub fn new(max: usize) -> Self {
+        Self { max, run: 0 }
+    }
+}
+
+impl PostProcessor for CollapseNewlines {
+    fn push(&mut self, s: &str) -> String {
+        s.chars()
+            .filter(|&c| {
+                if c == '\n' {
+                    self.run += 1;
+                    self.run <= self.max
+                } else {
+                    self.run = 0;
+                    true
+                }
+            })
+            .collect()
+    }
+}
+
+/// 移除输出开头的固定前缀，例如模型自行生成的 `Assistant:`。
+#[derive(Clone, Debug)]
+pub struct StripPrefix {
+    prefix: String,
+    pending: Option<String>,
+}
+
+impl StripPrefix {
+    /// 移除输出开头的 `prefix`。
+    #[inline]
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            pending: Some(String::new()),
+        }
+    }
+}
+
+impl PostProcessor for StripPrefix {
+    fn push(&mut self, s: &str) -> String {
+        let Some(pending) = self.pending.as_mut() else {
+            return s.into();
+        };
+        pending.push_str(s);
+        if let Some(rest) = pending.strip_prefix(&*self.prefix) {
+            let rest = rest.to_string();
+            self.pending = None;
+            rest
+        } else if self.prefix.starts_with(&**pending) {
+            // 还不能确定是否以前缀开头
+            String::new()
+        } else {
+            self.pending.take().unwrap()
+        }
+    }
+
+    fn finish(&mut self) -> String {
+        self.pending.take().unwrap_or_default()
+    }
+}
+
+/// 依次串联的后处理器。
+#[derive(Default)]
+pub(super) struct PostChain(Vec<Box<dyn PostProcessor>>);
+
+impl PostChain {
+    /// 在链的末尾添加一个处理器。
+    #[inline]
+    pub fn push_processor(&mut self, p: impl PostProcessor + 'static) {
+        self.0.push(Box::new(p));
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// 文本依次经过每个处理器。
+    pub fn push(&mut self, s: &str) -> String {
+        self.0.iter_mut().fold(s.into(), |s, p| p.push(&s))
+    }
+
+    /// 依次结束每个处理器，前面的处理器暂存的文本交给后面的处理器。
+    pub fn finish(&mut self) -> String {
+        self.0.iter_mut().fold(String::new(), |s, p| {
+            let mut s = p.push(&s);
+            s.push_str(&p.finish());
+            s
+        })
+    }
+}
+
+#[test]
+fn test_post_chain() {
+    let mut chain = PostChain::default();
+    chain.push_processor(TrimStart::default());
+    chain.push_processor(CollapseNewlines::new(2));
+    let output = ["\n", "  \n Hello", ",\n\n", "\n\nworld", "!\n"]
+        .into_iter()
+        .map(|s| chain.push(s))
+        .collect::<String>()
+        + &chain.finish();
+    assert_eq!(output, "Hello,\n\nworld!\n");
+
+    let mut chain = PostChain::default();
+    chain.push_processor(StripPrefix::new("Assistant:"));
+    chain.push_processor(TrimStart::default());
+    let output = ["Assi", "stant: ", " Hi", " there"]
+        .into_iter()
+        .map(|s| chain.push(s))
+        .collect::<String>()
+        + &chain.finish();
+    assert_eq!(output, "Hi there");
+
+    // 不以前缀开头的输出保持不变
+    let mut strip = StripPrefix::new("Assistant:");
+    assert_eq!(strip.push("Ass"), "");
+    assert_eq!(strip.push("ume"), "Assume");
+    let mut strip = StripPrefix::new("Assistant:");
+    assert_eq!(strip.push("Assist"), "");
+    assert_eq!(strip.finish(), "Assist");
+}
+
+#[test]
+fn test_trim_leading_space() {
+    let output = |trim: bool, pieces: &[&str]| {
+        let mut chain = PostChain::default();
+        if trim {
+            chain.push_processor(TrimLeadingSpace::default());
+        }
+        pieces.iter().map(|s| chain.push(s)).collect::<String>() + &chain.finish()
+    };
+    assert_eq!(output(true, &[" Hello", " world"]), "Hello world");
+    assert_eq!(output(false, &[" Hello", " world"]), " Hello world");
+    // 只移除一个空格，空白的片段不算作开头
+    assert_eq!(output(true, &["", " ", " Hi"]), " Hi");
+    assert_eq!(output(true, &["  Hi"]), " Hi");
+    // 其他空白字符不受影响
+    assert_eq!(output(true, &["\u{3000}你好"]), "\u{3000}你好");
+    assert_eq!(output(true, &["\nHi"]), "\nHi");
+}
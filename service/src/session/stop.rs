@@ -0,0 +1,150 @@
+use std::mem::{replace, take};
+
+/// 停止序列的匹配器，输出中出现任意一个停止序列时截断输出。
+///
+/// 可能是停止序列开头的尾部暂存到确定不是停止序列为止；
+/// 设置了移除末尾空白时，尾部的空白也暂存，停止时被丢弃。
+#[derive(Clone, Default, Debug)]
+pub(super) struct StopSequences {
+    stops: Vec<String>,
+    trim_whitespace: bool,
+    pending: String,
+    stopped: bool,
+    /// 已经输出的文本的字节数。
+    emitted: usize,
+}
+
+impl StopSequences {
+    /// 匹配 `stops` 中的任意一个非空序列，`trim_whitespace` 为真时停止序列之前的空白也被移除。
+    pub fn new(stops: impl IntoIterator<Item = String>, trim_whitespace: bool) -> Self {
+        Self {
+            stops: stops.into_iter().filter(|s| !s.is_empty()).collect(),
+            trim_whitespace,
+            pending: String::new(),
+            stopped: false,
+            emitted: 0,
+        }
+    }
+
+    /// 加入更多停止序列，已经暂存和输出的文本不变，从下一次输入开始匹配。
+    pub fn extend(&mut self, stops: impl IntoIterator<Item = String>) {
+        self.stops
+            .extend(stops.into_iter().filter(|s| !s.is_empty()));
+    }
+
+    /// 是否已经匹配到停止序列。
+    #[inline]
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// 已经输出的文本的字节数，输出总是输入的前缀，停止后即停止序列在输入中的位置。
+    #[inline]
+    pub fn emitted(&self) -> usize {
+        self.emitted
+    }
+
+    /// 输入一段文本，返回可以输出的部分。
+    ///
+    /// 匹配到停止序列时返回停止序列之前的部分，之后的输入全部丢弃。
+    pub fn push(&mut self, s: &str) -> String {
+        let ans = self.push_inner(s);
+        self.emitted += ans.len();
+        ans
+    }
+
+    fn push_inner(&mut self, s: &str) -> String {
+        if self.stopped {
+            return String::new();
+        }
+        self.pending.push_str(s);
+        if self.stops.is_empty() {
+            return take(&mut self.pending);
+        }
+        // 取最早出现的停止序列
+        let found = self
+            .stops
+            .iter()
+            .filter_map(|stop| self.pending.find(&**stop))
+            .min();
+        if let Some(pos) = found {
+            self.stopped = true;
+            let mut ans = take(&mut self.pending);
+            ans.truncate(pos);
+            if self.trim_whitespace {
+                ans.truncate(ans.trim_end().len());
+            }
+            return ans;
+        }
+        // 暂存可能是停止序列开头的尾部
+        let mut keep = self
+            .pending
+            .char_indices()
+            .map(|(i, _)| i)
+            .find(|&i| {
+                let tail = &self.pending[i..];
+                self.stops.iter().any(|stop| stop.starts_with(tail))
+            })
+            .unwrap_or(self.pending.len());
+        if self.trim_whitespace {
+            keep = self.pending[..keep].trim_end().len();
+        }
+        let tail = self.pending.split_off(keep);
+        replace(&mut self.pending, tail)
+    }
+
+    /// 输出结束，返回暂存的文本。
+    #[inline]
+    pub fn finish(&mut self) -> String {
+        let ans = take(&mut self.pending);
+        self.emitted += ans.len();
+        ans
+    }
+}
+
+#[test]
+fn test_stop_sequences() {
+    let output = |trim: bool, pieces: &[&str]| {
+        let mut stop = StopSequences::new(["\n\n".to_string(), "END".to_string()], trim);
+        let mut ans = pieces.iter().map(|s| stop.push(s)).collect::<String>();
+        if !stop.is_stopped() {
+            ans.push_str(&stop.finish());
+        }
+        (ans, stop.is_stopped())
+    };
+    // 停止序列之后的部分被丢弃，跨越多段文本的停止序列也能匹配
+    assert_eq!(
+        output(false, &["Hello", "\n", "\nworld"]),
+        ("Hello".into(), true)
+    );
+    assert_eq!(output(false, &["Hi E", "N", "D!"]), ("Hi ".into(), true));
+    // 停止后输出的字节数就是停止序列在输入中的位置
+    let mut stop = StopSequences::new(["END".to_string()], true);
+    assert_eq!(stop.push("ab "), "ab");
+    assert_eq!(stop.push(" cEN"), " c");
+    assert_eq!(stop.push("D!"), "");
+    assert_eq!(stop.emitted(), "ab  c".len());
+    // 停止时移除末尾的空白，包括之前已经暂存的部分
+    assert_eq!(
+        output(true, &["Hello ", " \t", "\n\n"]),
+        ("Hello".into(), true)
+    );
+    assert_eq!(output(true, &["Hi E", "ND"]), ("Hi".into(), true));
+    // 没有停止时空白和暂存的部分原样输出
+    assert_eq!(output(true, &["Hello ", "EN"]), ("Hello EN".into(), false));
+    assert_eq!(output(false, &["a\n", "b"]), ("a\nb".into(), false));
+
+    // 中途加入的停止序列只匹配之后的输入，已经输出的字节数继续累计
+    let mut stop = StopSequences::new(["END".to_string()], false);
+    assert_eq!(stop.push("ab"), "ab");
+    assert_eq!(stop.push("c\n"), "c\n");
+    stop.extend(["\n\n".to_string()]);
+    assert_eq!(stop.push("x\n"), "x");
+    assert_eq!(stop.push("\nd"), "");
+    assert!(stop.is_stopped());
+    assert_eq!(stop.emitted(), "abc\nx".len());
+
+    let mut stop = StopSequences::new([String::new()], true);
+    assert_eq!(stop.push("text  "), "text  ");
+    assert!(!stop.is_stopped());
+}
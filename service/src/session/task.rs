@@ -1,12 +1,210 @@
 ﻿use super::cache::Cache;
-use causal_lm::SampleArgs;
+use causal_lm::{SampleArgs, SampleMeta};
 use common::utok;
-use std::sync::{Arc, Mutex, MutexGuard};
+use log::info;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Instant,
+};
 use tokio::sync::mpsc::UnboundedSender;
 
+/// 预填充进度。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct PrefillProgress {
+    /// 已处理的提示词 token 数量。
+    pub processed: usize,
+    /// 提示词 token 总数。
+    pub total: usize,
+}
+
+/// 生成结束的原因。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum FinishReason {
+    /// 采样到结束符或停止 token。
+    Stop,
+    /// 检测到生成陷入重复循环。
+    Repetition,
+    /// 生成的 token 数量达到上限。
+    Length,
+    /// 输出中出现了停止序列。
+    StopSequence,
+}
+
+/// 解码输出的形式。
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+pub enum OutputMode {
+    /// 拼接为完整 UTF-8 字符的文本。
+    #[default]
+    Text,
+    /// 生成的 token 序号。
+    Tokens,
+    /// 每个 token 解码得到的原始字节，多字节字符可能被拆分到多个 token 中。
+    Bytes,
+}
+
+/// 一段解码输出，形式由 [`OutputMode`] 决定。
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Decoded {
+    Text(String),
+    Tokens(Vec<utok>),
+    Bytes(Vec<u8>),
+}
+
+/// 重复检测的限制。
+///
+/// 生成序列的末尾，同一个长度不超过 `max_ngram` 的片段连续出现超过 `max_repeat` 次时结束生成。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct RepetitionLimit {
+    /// 检测的最大片段长度。
+    pub max_ngram: usize,
+    /// 允许片段连续出现的次数。
+    pub max_repeat: usize,
+}
+
+impl RepetitionLimit {
+    /// 判断 `tokens` 的末尾是否出现了超出限制的重复。
+    pub fn is_exceeded(&self, tokens: &[utok]) -> bool {
+        (1..=self.max_ngram).any(|n| {
+            let len = n * (self.max_repeat + 1);
+            len <= tokens.len() && {
+                let tail = &tokens[tokens.len() - len..];
+                tail.chunks_exact(n).all(|gram| gram == &tail[..n])
+            }
+        })
+    }
+}
+
+/// 按生成长度调整结束符 logit 的偏置，实现不截断的软性长度控制。
+///
+/// 达到目标长度前结束符的偏置从 `-suppress` 线性增加到 0，之后在 `ramp` 步内线性增加到 `boost`。
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct EosSchedule {
+    /// 目标生成长度。
+    pub target: usize,
+    /// 生成开始时对结束符的抑制强度。
+    pub suppress: f32,
+    /// 超过目标长度后对结束符的最大鼓励强度。
+    pub boost: f32,
+    /// 超过目标长度后偏置增加到 `boost` 的步数。
+    pub ramp: usize,
+}
+
+impl EosSchedule {
+    /// 采样第 `step` 个生成的 token 时结束符的偏置。
+    pub fn bias(&self, step: usize) -> f32 {
+        if step < self.target {
+            -self.suppress * (self.target - step) as f32 / self.target as f32
+        } else {
+            let progress = (step - self.target) as f32 / self.ramp.max(1) as f32;
+            self.boost * progress.min(1.)
+        }
+    }
+}
+
+/// 推理片段的 token 预算。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub(crate) struct ThinkBudget {
+    /// 推理片段的开始标记。
+    pub open: utok,
+    /// 推理片段的结束标记。
+    pub close: utok,
+    /// 推理片段中允许生成的 token 数量。
+    pub budget: usize,
+}
+
+/// 采样得到的 token 在模型输出的分布中的统计量。
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(super) struct TokenStats {
+    /// token 的对数概率，未开启时为 `None`。
+    pub logprob: Option<f32>,
+    /// 整个分布的熵，未开启时为 `None`。
+    pub entropy: Option<f32>,
+}
+
+/// 推理任务向会话发送的消息。
+pub(super) enum Output {
+    /// 分块预填充的进度。
+    Progress(PrefillProgress),
+    /// 采样得到的 token 及其统计量，模型不支持把 logits 拷贝到主存时为 `None`。
+    Token(utok, Option<TokenStats>),
+    /// 生成结束。
+    Finish(FinishReason),
+}
+
+/// 生成的设置，服务复制给启动的会话，会话复制给每次推理任务，整体克隆。
+#[derive(Clone, Default, Debug)]
+pub struct GenerationConfig {
+    /// 采样参数，只作用于生成的 token。
+    pub sample: SampleArgs,
+    /// 预填充分块大小，设置后每处理一块提示词报告一次进度。
+    pub prefill_chunk: Option<usize>,
+    /// 采样到这些 token 时结束生成。
+    pub stop_token_ids: Vec<utok>,
+    /// 重复检测的限制，生成陷入循环时结束生成。
+    pub repetition_limit: Option<RepetitionLimit>,
+    /// 每轮生成的最大 token 数量。
+    pub max_tokens: Option<usize>,
+    /// 按生成长度调整结束符概率的计划，用于软性控制生成长度。
+    pub eos_schedule: Option<EosSchedule>,
+    /// 生成至少这么多 token 之前不允许采样到结束符，避免产生空回答。
+    pub min_tokens: usize,
+    /// 换行符的惩罚，为正时减少换行，为负时鼓励换行。
+    pub newline_penalty: f32,
+    /// 每个 `<think>` 推理片段中最多生成的 token 数量，超出时强制结束推理。
+    pub reasoning_budget: Option<usize>,
+    /// 是否计算生成的 token 的累积对数概率，需要把 logits 拷贝到主存，默认关闭。
+    pub token_logprob: bool,
+    /// 是否计算每个生成的 token 的采样分布的熵，需要把 logits 拷贝到主存，默认关闭。
+    pub token_entropy: bool,
+}
+
+/// 推理任务的生成参数。
+///
+/// `generation` 中的换行惩罚已经并入采样参数，推理预算已经转换为 `think_budget`。
+#[derive(Clone, Default, Debug)]
+pub(crate) struct TaskArgs {
+    pub generation: GenerationConfig,
+    pub think_budget: Option<ThinkBudget>,
+}
+
+impl From<GenerationConfig> for TaskArgs {
+    #[inline]
+    fn from(generation: GenerationConfig) -> Self {
+        Self {
+            generation,
+            think_budget: None,
+        }
+    }
+}
+
+/// 请求日志的 target，便于单独过滤。
+pub(super) const REQUEST_LOG: &str = "service::request";
+
+/// 分配一个进程内唯一的请求 id。
+pub(super) fn next_request_id() -> u64 {
+    static ID: AtomicU64 = AtomicU64::new(0);
+    ID.fetch_add(1, Relaxed)
+}
+
 pub(super) struct Task<Storage> {
-    sample: SampleArgs,
-    sender: UnboundedSender<utok>,
+    id: u64,
+    args: TaskArgs,
+    sender: UnboundedSender<Output>,
+    progress: PrefillProgress,
+    /// 已生成的 token，用于重复检测。
+    generated: Vec<utok>,
+    /// 已生成的不重复的 token，有序，用于重复惩罚。
+    penalized: Vec<utok>,
+    /// 已生成的 token 数量。
+    num_generated: usize,
+    /// 已检查过的采样 token 数量，用于限制生成长度。
+    num_sampled: usize,
+    /// 推理片段中已生成的 token 数量，不在推理片段中时为 `None`。
+    thinking: Option<usize>,
+    start: Instant,
 
     cache: Arc<Mutex<Option<Cache<Storage>>>>,
 }
@@ -14,20 +212,153 @@ pub(super) struct Task<Storage> {
 impl<Storage> Task<Storage> {
     #[inline]
     pub fn new(
+        id: u64,
         cache: Arc<Mutex<Option<Cache<Storage>>>>,
-        sample: SampleArgs,
-        sender: UnboundedSender<utok>,
+        args: TaskArgs,
+        prompt_len: usize,
+        sender: UnboundedSender<Output>,
     ) -> Self {
+        info!(target: REQUEST_LOG, "id={id} event=start prompt_tokens={prompt_len}");
         Self {
-            sample,
+            id,
+            args,
             sender,
+            progress: PrefillProgress {
+                processed: 0,
+                total: prompt_len,
+            },
+            generated: Vec::new(),
+            penalized: Vec::new(),
+            num_generated: 0,
+            num_sampled: 0,
+            thinking: None,
+            start: Instant::now(),
             cache,
         }
     }
 
+    /// 本次采样的参数，按已生成的长度设置结束符的偏置。
+    ///
+    /// 生成的 token 少于 `min_tokens` 时结束符的偏置为负无穷，不会被采样到。
+    #[inline]
+    pub fn sample(&self) -> SampleArgs {
+        if self.num_sampled < self.args.generation.min_tokens {
+            return SampleArgs {
+                eos_bias: f32::NEG_INFINITY,
+                ..self.args.generation.sample
+            };
+        }
+        match self.args.generation.eos_schedule {
+            Some(schedule) => SampleArgs {
+                eos_bias: schedule.bias(self.num_sampled),
+                ..self.args.generation.sample
+            },
+            None => self.args.generation.sample,
+        }
+    }
+    /// 本次解码 `num_decode` 个位置的采样要求。
+    ///
+    /// 生成的 token 少于 `min_tokens` 时禁止采样所有的停止 token，结束符由 [`sample`](Self::sample) 的偏置禁止。
+    pub fn sample_meta(&self, num_decode: usize) -> SampleMeta {
+        let suppressed = if self.num_sampled < self.args.generation.min_tokens {
+            &self.args.generation.stop_token_ids[..]
+        } else {
+            &[]
+        };
+        SampleMeta {
+            num_decode,
+            args: self.sample(),
+            history: self.penalized_tokens(),
+            suppressed,
+        }
+    }
+    /// 重复惩罚作用的 token，即本次已经生成的不重复的 token，不需要重复惩罚时为空。
+    ///
+    /// 与 transformers 不同，提示词中的 token 不受惩罚。
+    #[inline]
+    pub fn penalized_tokens(&self) -> &[utok] {
+        &self.penalized
+    }
+    /// 是否计算采样得到的 token 的对数概率。
+    #[inline]
+    pub fn wants_logprob(&self) -> bool {
+        self.args.generation.token_logprob
+    }
+    /// 是否计算每个采样分布的熵。
+    #[inline]
+    pub fn wants_entropy(&self) -> bool {
+        self.args.generation.token_entropy
+    }
+    /// 判断 `token` 是否是结束生成的 token。
     #[inline]
-    pub fn sample(&self) -> &SampleArgs {
-        &self.sample
+    pub fn is_stop(&self, token: utok) -> bool {
+        self.args.generation.stop_token_ids.contains(&token)
+    }
+    /// 检查采样得到的 `token` 是否结束生成，返回结束的原因。
+    pub fn check_finish(&mut self, token: utok, eos: utok) -> Option<FinishReason> {
+        if token == eos || self.is_stop(token) {
+            return Some(FinishReason::Stop);
+        }
+        if self
+            .args
+            .generation
+            .max_tokens
+            .is_some_and(|max| self.num_sampled >= max)
+        {
+            return Some(FinishReason::Length);
+        }
+        self.num_sampled += 1;
+        if self.args.generation.sample.has_repetition_penalty() {
+            if let Err(i) = self.penalized.binary_search(&token) {
+                self.penalized.insert(i, token);
+            }
+        }
+        let limit = self.args.generation.repetition_limit?;
+        self.generated.push(token);
+        limit
+            .is_exceeded(&self.generated)
+            .then_some(FinishReason::Repetition)
+    }
+    /// 提示词以未闭合的推理开始标记结尾时，生成从推理片段中开始。
+    pub fn scan_prompt<'a>(&mut self, prompt: impl IntoIterator<Item = &'a utok>) {
+        let Some(ThinkBudget { open, close, .. }) = self.args.think_budget else {
+            return;
+        };
+        for &token in prompt {
+            if token == open {
+                self.thinking = Some(0);
+            } else if token == close {
+                self.thinking = None;
+            }
+        }
+    }
+    /// 推理片段中生成的 token 达到预算时，将采样得到的 `token` 替换为推理结束标记，返回是否替换。
+    pub fn limit_think(&mut self, token: &mut utok) -> bool {
+        let Some(ThinkBudget {
+            open,
+            close,
+            budget,
+        }) = self.args.think_budget
+        else {
+            return false;
+        };
+        match self.thinking {
+            Some(_) if *token == close => self.thinking = None,
+            Some(n) if n >= budget => {
+                *token = close;
+                self.thinking = None;
+                return true;
+            }
+            Some(n) => self.thinking = Some(n + 1),
+            None if *token == open => self.thinking = Some(0),
+            None => {}
+        }
+        false
+    }
+    /// 通知会话生成结束。
+    #[inline]
+    pub fn finish(self, reason: FinishReason) {
+        let _ = self.sender.send(Output::Finish(reason));
     }
     #[inline]
     pub fn is_alive(&self) -> bool {
@@ -38,15 +369,409 @@ impl<Storage> Task<Storage> {
         self.cache.lock().unwrap()
     }
 
+    /// 根据预填充分块大小限制本轮的查询长度。
     #[inline]
-    pub fn push(&mut self, token: utok, start_size: usize, end_size: usize, max: usize) -> bool {
-        if self.sender.send(token).is_ok() {
+    pub fn query_len(&self, remain: usize) -> usize {
+        match self.args.generation.prefill_chunk {
+            Some(chunk) => remain.min(chunk.max(1)),
+            None => remain,
+        }
+    }
+
+    /// 记录本轮处理的查询长度，返回提示词是否已全部处理。
+    ///
+    /// 未处理完的部分保留在缓存中，等待下一轮推理。
+    pub fn prefill(&mut self, len: usize) -> bool {
+        let PrefillProgress { processed, total } = &mut self.progress;
+        if *processed >= *total || len == 0 {
+            return *processed >= *total;
+        }
+        *processed = (*processed + len).min(*total);
+        if self.args.generation.prefill_chunk.is_some() {
+            let _ = self.sender.send(Output::Progress(self.progress));
+        }
+        if self.progress.processed < self.progress.total {
             if let Some(cache) = self.cache.lock().unwrap().as_mut() {
-                cache.push(token);
-                cache.reset_within_start_and_end_range(start_size, end_size, max);
-                return true;
+                cache.commit(len);
             }
+            false
+        } else {
+            true
+        }
+    }
+
+    /// 发送一步采样得到的 `tokens` 及其统计量并加入缓存，返回任务是否可以继续。
+    pub fn push_step(
+        &mut self,
+        tokens: &[(utok, Option<TokenStats>)],
+        start_size: usize,
+        end_size: usize,
+        max: usize,
+    ) -> bool {
+        for &(token, stats) in tokens {
+            if self.sender.send(Output::Token(token, stats)).is_err() {
+                return false;
+            }
+            self.num_generated += 1;
+        }
+        if let Some(cache) = self.cache.lock().unwrap().as_mut() {
+            let tokens = tokens.iter().map(|&(t, _)| t).collect::<Vec<_>>();
+            cache.push_step(&tokens);
+            cache.reset_within_start_and_end_range(start_size, end_size, max);
+            return true;
         }
         false
     }
 }
+
+impl<Storage> Drop for Task<Storage> {
+    fn drop(&mut self) {
+        info!(
+            target: REQUEST_LOG,
+            "id={} event=end prompt_tokens={} generated_tokens={} elapsed_ms={}",
+            self.id,
+            self.progress.total,
+            self.num_generated,
+            self.start.elapsed().as_millis(),
+        );
+    }
+}
+
+/// 测试用的任务，没有缓存，返回任务和接收输出的通道。
+#[cfg(test)]
+pub(super) fn test_task(
+    args: TaskArgs,
+    prompt_len: usize,
+) -> (Task<()>, tokio::sync::mpsc::UnboundedReceiver<Output>) {
+    let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+    let cache = Arc::new(Mutex::new(None));
+    let id = next_request_id();
+    (Task::new(id, cache, args, prompt_len, sender), receiver)
+}
+
+#[test]
+fn test_prefill_progress() {
+    let args = GenerationConfig {
+        prefill_chunk: Some(4),
+        ..Default::default()
+    };
+    let (mut task, mut receiver) = test_task(args.into(), 10);
+
+    let mut remain = 10;
+    while remain > 0 {
+        let len = task.query_len(remain);
+        remain -= len;
+        assert_eq!(task.prefill(len), remain == 0);
+    }
+    // 预填充完成后的解码不再报告进度
+    assert!(task.prefill(1));
+
+    let mut processed = vec![];
+    while let Ok(output) = receiver.try_recv() {
+        let Output::Progress(progress) = output else {
+            panic!("unexpected token")
+        };
+        assert_eq!(progress.total, 10);
+        processed.push(progress.processed);
+    }
+    assert_eq!(processed, [4, 8, 10]);
+}
+
+#[test]
+fn test_stop_token_ids() {
+    let args = GenerationConfig {
+        stop_token_ids: vec![1234, 5678],
+        ..Default::default()
+    };
+    // 采样到任意一个停止 token 时以 Stop 结束，之前的 token 正常生成
+    for (sampled, expected) in [
+        (&[17, 29, 1233, 1234, 42, 5678][..], &[17, 29, 1233][..]),
+        (&[17, 5678, 1234], &[17]),
+    ] {
+        let (mut task, _receiver) = test_task(args.clone().into(), 0);
+        let mut generated = vec![];
+        let reason = sampled.iter().find_map(|&token| {
+            let reason = task.check_finish(token, 2);
+            if reason.is_none() {
+                generated.push(token);
+            }
+            reason
+        });
+        assert_eq!(reason, Some(FinishReason::Stop));
+        assert_eq!(generated, expected);
+        assert_eq!(task.num_sampled, expected.len());
+    }
+}
+
+#[test]
+fn test_prompt_not_sampled() {
+    for prefill_chunk in [None, Some(1), Some(3)] {
+        let args = GenerationConfig {
+            sample: SampleArgs {
+                temperature: 1.,
+                top_k: 10,
+                top_p: 0.9,
+                min_p: 0.,
+                logit_bias: None,
+                eos_bias: 0.,
+                repetition_penalty: 1.,
+                sample_order: causal_lm::SampleStage::DEFAULT_ORDER,
+                gumbel: false,
+            },
+            prefill_chunk,
+            ..Default::default()
+        };
+        let (mut task, _receiver) = test_task(args.into(), 7);
+
+        // 只有提示词全部处理完的那一轮才需要采样
+        let mut remain = 7;
+        let mut num_decode = vec![];
+        while remain > 0 {
+            let len = task.query_len(remain);
+            remain -= len;
+            num_decode.push(task.prefill(len) as usize);
+        }
+        assert_eq!(num_decode.iter().sum::<usize>(), 1);
+        assert_eq!(num_decode.last(), Some(&1));
+    }
+}
+
+#[test]
+fn test_repetition_limit() {
+    let limit = RepetitionLimit {
+        max_ngram: 2,
+        max_repeat: 3,
+    };
+    assert!(!limit.is_exceeded(&[5, 5, 5]));
+    assert!(limit.is_exceeded(&[1, 5, 5, 5, 5]));
+    assert!(!limit.is_exceeded(&[8, 9, 8, 9, 8, 9]));
+    assert!(limit.is_exceeded(&[1, 8, 9, 8, 9, 8, 9, 8, 9]));
+    assert!(!limit.is_exceeded(&[1, 2, 3, 1, 2, 3, 1, 2, 3, 1, 2, 3]));
+
+    let args = GenerationConfig {
+        repetition_limit: Some(limit),
+        ..Default::default()
+    };
+    let (mut task, mut receiver) = test_task(args.into(), 0);
+
+    // 模型陷入 `8 9` 的循环
+    let sampled = [17, 29, 8, 9, 8, 9, 8, 9, 8, 9, 8, 9];
+    let mut sampled = sampled.into_iter();
+    let reason = sampled
+        .by_ref()
+        .find_map(|token| task.check_finish(token, 2));
+    assert_eq!(reason, Some(FinishReason::Repetition));
+    assert_eq!(sampled.len(), 2);
+
+    task.finish(FinishReason::Repetition);
+    assert!(matches!(
+        receiver.try_recv(),
+        Ok(Output::Finish(FinishReason::Repetition))
+    ));
+}
+
+#[test]
+fn test_max_tokens() {
+    // 结束符与起始符相同的模型不会采样到结束符
+    let args = GenerationConfig {
+        max_tokens: Some(4),
+        ..Default::default()
+    };
+    let (mut task, _receiver) = test_task(args.into(), 0);
+
+    let generated = (10..)
+        .map_while(|token| task.check_finish(token, 1).is_none().then_some(token))
+        .collect::<Vec<_>>();
+    assert_eq!(generated, [10, 11, 12, 13]);
+    assert_eq!(task.check_finish(15, 1), Some(FinishReason::Length));
+    // 结束符仍然优先
+    assert_eq!(task.check_finish(1, 1), Some(FinishReason::Stop));
+}
+
+#[test]
+fn test_eos_schedule() {
+    let schedule = EosSchedule {
+        target: 4,
+        suppress: 8.,
+        boost: 6.,
+        ramp: 2,
+    };
+    let biases = (0..8).map(|step| schedule.bias(step)).collect::<Vec<_>>();
+    assert_eq!(biases, [-8., -6., -4., -2., 0., 3., 6., 6.]);
+
+    let args = GenerationConfig {
+        eos_schedule: Some(schedule),
+        ..Default::default()
+    };
+    let (mut task, _receiver) = test_task(args.into(), 0);
+
+    // 固定的分布上，结束符的概率随生成长度增加，超过目标长度后超过不加偏置时的概率
+    const EOS: utok = 2;
+    let logits = [1.5f32, 0.5, 0., 1., -0.5];
+    let p_eos = |args: SampleArgs| {
+        let mut logits = logits;
+        for (token, bias) in args.biases(EOS) {
+            logits[token as usize] += bias;
+        }
+        let sum = logits.iter().map(|x| x.exp()).sum::<f32>();
+        logits[EOS as usize].exp() / sum
+    };
+    let base = p_eos(SampleArgs::default());
+    let mut probs = vec![];
+    for token in 10..18 {
+        probs.push(p_eos(task.sample()));
+        assert_eq!(task.check_finish(token, EOS), None);
+    }
+    assert!(probs.windows(2).all(|w| w[0] <= w[1]));
+    assert!(probs[..4].iter().all(|&p| p < base));
+    assert_eq!(probs[4], base);
+    assert!(probs[5..].iter().all(|&p| p > base));
+}
+
+#[test]
+fn test_min_tokens() {
+    // 不是结束符的停止 token 同样在前 5 个 token 中被禁止
+    const STOP: utok = 4;
+    let args = GenerationConfig {
+        sample: SampleArgs::ARG_MAX,
+        stop_token_ids: vec![STOP],
+        min_tokens: 5,
+        ..Default::default()
+    };
+    let (mut task, _receiver) = test_task(args.into(), 0);
+
+    // 停止 token 和结束符的概率总是最大，前 5 个 token 中不出现
+    const EOS: utok = 2;
+    let logits = [1.5f32, 0.5, 3., 1., 3.5];
+    let mut generated = vec![];
+    loop {
+        let mut logits = logits;
+        for (token, bias) in task.sample_meta(1).biases(EOS) {
+            logits[token as usize] += bias;
+        }
+        let token = (0..logits.len())
+            .fold(0, |best, i| if logits[i] > logits[best] { i } else { best })
+            as utok;
+        if task.check_finish(token, EOS).is_some() {
+            break;
+        }
+        generated.push(token);
+    }
+    assert_eq!(generated, [0; 5]);
+    assert_eq!(task.num_sampled, 5);
+}
+
+#[test]
+fn test_reasoning_budget() {
+    const OPEN: utok = 100;
+    const CLOSE: utok = 101;
+    let args = TaskArgs {
+        think_budget: Some(ThinkBudget {
+            open: OPEN,
+            close: CLOSE,
+            budget: 3,
+        }),
+        ..Default::default()
+    };
+    let generate = |task: &mut Task<()>, sampled: &[utok]| {
+        sampled
+            .iter()
+            .map(|&token| {
+                let mut token = token;
+                task.limit_think(&mut token);
+                token
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let (mut task, _receiver) = test_task(args.clone(), 0);
+    // 超出预算的推理被强制结束，之后的生成不受影响
+    let sampled = [5, OPEN, 7, 8, 9, 10, 11, 12, CLOSE, 13];
+    assert_eq!(
+        generate(&mut task, &sampled),
+        [5, OPEN, 7, 8, 9, CLOSE, 11, 12, CLOSE, 13]
+    );
+    // 预算内自行结束的推理不受影响，新的推理片段重新计数
+    let sampled = [OPEN, 7, 8, CLOSE, 9, OPEN, 1, 2, 3, 4];
+    assert_eq!(
+        generate(&mut task, &sampled),
+        [OPEN, 7, 8, CLOSE, 9, OPEN, 1, 2, 3, CLOSE]
+    );
+
+    // 提示词以推理开始标记结尾
+    let (mut task, _receiver) = test_task(args, 0);
+    task.scan_prompt(&[1, OPEN, 2, CLOSE, 3, OPEN]);
+    assert_eq!(
+        generate(&mut task, &[7, 8, 9, 10, 11]),
+        [7, 8, 9, CLOSE, 11]
+    );
+}
+
+#[test]
+fn test_request_log() {
+    use log::{Log, Metadata, Record};
+
+    static RECORDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    struct Capture;
+    impl Log for Capture {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.target() == REQUEST_LOG
+        }
+        fn log(&self, record: &Record) {
+            if self.enabled(record.metadata()) {
+                RECORDS.lock().unwrap().push(record.args().to_string());
+            }
+        }
+        fn flush(&self) {}
+    }
+    let _ = log::set_logger(&Capture);
+    log::set_max_level(log::LevelFilter::Info);
+
+    let (mut task, _receiver) = test_task(Default::default(), 5);
+    let id = task.id;
+    task.prefill(5);
+    for token in [17, 29, 42] {
+        task.push_step(&[(token, None)], 0, 0, 16);
+    }
+    drop(task);
+
+    let tag = format!("id={id} ");
+    let records = RECORDS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|r| r.starts_with(&tag))
+        .cloned()
+        .collect::<Vec<_>>();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0], format!("id={id} event=start prompt_tokens=5"));
+    assert!(records[1].starts_with(&format!(
+        "id={id} event=end prompt_tokens=5 generated_tokens=3 "
+    )));
+}
+
+#[test]
+fn test_penalized_tokens() {
+    let (mut task, _receiver) = test_task(Default::default(), 0);
+    for token in [17, 29, 17] {
+        assert_eq!(task.check_finish(token, 2), None);
+    }
+    // 没有设置重复惩罚时不需要记录生成的 token
+    assert!(task.penalized_tokens().is_empty());
+
+    let args = GenerationConfig {
+        sample: SampleArgs {
+            repetition_penalty: 1.3,
+            ..SampleArgs::ARG_MAX
+        },
+        ..Default::default()
+    };
+    let (mut task, _receiver) = test_task(args.into(), 0);
+    for token in [17, 29, 17] {
+        assert_eq!(task.check_finish(token, 2), None);
+    }
+    assert_eq!(task.check_finish(2, 2), Some(FinishReason::Stop));
+    // 重复的 token 只记录一次
+    assert_eq!(task.penalized_tokens(), [17, 29]);
+    assert_eq!(task.sample().repetition_penalty, 1.3);
+}
@@ -0,0 +1,75 @@
+use super::PostProcessor;
+
+const OPEN: &str = "<think>";
+const CLOSE: &str = "</think>";
+
+/// 从流式输出中移除 `<think>...</think>` 片段。
+///
+/// 标签可能被拆分在多个片段中，无法确定的尾部会暂存到下一次输入。
+#[derive(Default, Debug)]
+pub(super) struct ThinkFilter {
+    thinking: bool,
+    pending: String,
+}
+
+impl PostProcessor for ThinkFilter {
+    fn push(&mut self, s: &str) -> String {
+        let mut buf = std::mem::take(&mut self.pending);
+        buf.push_str(s);
+
+        let mut ans = String::new();
+        let mut rest = &*buf;
+        loop {
+            let tag = if self.thinking { CLOSE } else { OPEN };
+            if let Some(i) = rest.find(tag) {
+                if !self.thinking {
+                    ans.push_str(&rest[..i]);
+                }
+                rest = &rest[i + tag.len()..];
+                self.thinking = !self.thinking;
+            } else {
+                let keep = (1..tag.len())
+                    .rev()
+                    .find(|&n| rest.ends_with(&tag[..n]))
+                    .unwrap_or(0);
+                let (visible, pending) = rest.split_at(rest.len() - keep);
+                if !self.thinking {
+                    ans.push_str(visible);
+                }
+                self.pending = pending.into();
+                break ans;
+            }
+        }
+    }
+
+    fn finish(&mut self) -> String {
+        let pending = std::mem::take(&mut self.pending);
+        if self.thinking {
+            String::new()
+        } else {
+            pending
+        }
+    }
+}
+
+#[test]
+fn test_think_filter() {
+    let mut filter = ThinkFilter::default();
+    let output = [
+        "<th",
+        "ink>\nlet me",
+        " think</",
+        "think>\n\nHello",
+        "<",
+        "b>!",
+    ]
+    .into_iter()
+    .map(|s| filter.push(s))
+    .collect::<String>()
+        + &filter.finish();
+    assert_eq!(output, "\n\nHello<b>!");
+
+    let mut filter = ThinkFilter::default();
+    assert_eq!(filter.push("a <think"), "a ");
+    assert_eq!(filter.finish(), "<think");
+}
@@ -0,0 +1,91 @@
+use std::{
+    fmt::Write,
+    time::{Duration, Instant},
+};
+
+/// 推理线程的耗时记录，可以导出为 Chrome tracing（`chrome://tracing`）格式。
+pub(crate) struct Trace {
+    start: Instant,
+    spans: Vec<Span>,
+}
+
+/// 一段耗时。
+struct Span {
+    name: &'static str,
+    start: Duration,
+    dur: Duration,
+    args: Vec<(&'static str, usize)>,
+}
+
+impl Trace {
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            spans: Vec::new(),
+        }
+    }
+
+    /// 记录从 `start` 到现在的一段耗时，`args` 作为附加信息显示在 span 上。
+    pub fn record(
+        &mut self,
+        name: &'static str,
+        start: Instant,
+        args: impl IntoIterator<Item = (&'static str, usize)>,
+    ) {
+        self.spans.push(Span {
+            name,
+            start: start.saturating_duration_since(self.start),
+            dur: start.elapsed(),
+            args: args.into_iter().collect(),
+        })
+    }
+
+    /// 生成 Chrome tracing 格式的 JSON，时间单位为微秒。
+    pub fn to_json(&self) -> String {
+        let us = |d: Duration| d.as_nanos() as f64 / 1e3;
+        let mut json = String::from(r#"{"traceEvents":["#);
+        for (i, span) in self.spans.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            write!(
+                json,
+                r#"{{"name":"{}","cat":"infer","ph":"X","ts":{:.3},"dur":{:.3},"pid":0,"tid":0,"args":{{"#,
+                span.name,
+                us(span.start),
+                us(span.dur),
+            )
+            .unwrap();
+            for (j, (key, value)) in span.args.iter().enumerate() {
+                if j > 0 {
+                    json.push(',');
+                }
+                write!(json, r#""{key}":{value}"#).unwrap();
+            }
+            json.push_str("}}");
+        }
+        json.push_str(r#"],"displayTimeUnit":"ms"}"#);
+        json
+    }
+}
+
+#[test]
+fn test_trace() {
+    let mut trace = Trace::new();
+    assert_eq!(
+        trace.to_json(),
+        r#"{"traceEvents":[],"displayTimeUnit":"ms"}"#
+    );
+
+    let start = Instant::now();
+    trace.record("forward", start, [("tokens", 5)]);
+    trace.record("step", start, [("tokens", 5), ("decode", 1)]);
+    let json = serde_json::from_str::<serde_json::Value>(&trace.to_json()).unwrap();
+    let events = json["traceEvents"].as_array().unwrap();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0]["name"], "forward");
+    assert_eq!(events[0]["ph"], "X");
+    assert_eq!(events[1]["args"]["decode"], 1);
+    assert!(events[1]["dur"].as_f64().unwrap() >= events[0]["dur"].as_f64().unwrap());
+}
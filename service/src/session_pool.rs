@@ -0,0 +1,128 @@
+use crate::{Service, Session, SessionError};
+use causal_lm::CausalLM;
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+};
+
+/// 会话池，管理固定数量的预启动会话。
+///
+/// 会话借出后由 [`PooledSession`] 持有，归还时自动重置。
+pub struct SessionPool<M: CausalLM> {
+    idle: Mutex<Vec<Session<M>>>,
+    /// 以服务的默认设置启动的会话，归还的会话恢复为它的设置，借用者的修改不会影响下一个借用者。
+    template: Session<M>,
+}
+
+/// 从会话池借出的会话，释放时归还会话池。
+pub struct PooledSession<'a, M: CausalLM> {
+    pool: &'a SessionPool<M>,
+    session: Option<Session<M>>,
+}
+
+impl<M: CausalLM> SessionPool<M> {
+    /// 从服务启动 `size` 个会话构成会话池。
+    pub fn new(service: &Service<M>, size: usize) -> Self {
+        Self {
+            idle: Mutex::new((0..size).map(|_| service.launch()).collect()),
+            template: service.launch(),
+        }
+    }
+
+    /// 空闲会话的数量。
+    #[inline]
+    pub fn idle(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    /// 借出一个空闲会话，所有会话都被占用时返回 [`SessionError::Busy`]。
+    pub fn take(&self) -> Result<PooledSession<M>, SessionError> {
+        self.idle
+            .lock()
+            .unwrap()
+            .pop()
+            .map(|session| PooledSession {
+                pool: self,
+                session: Some(session),
+            })
+            .ok_or(SessionError::Busy)
+    }
+
+    fn restore(&self, mut session: Session<M>) {
+        session.reset();
+        session.clone_settings_from(&self.template);
+        self.idle.lock().unwrap().push(session);
+    }
+}
+
+impl<M: CausalLM> Deref for PooledSession<'_, M> {
+    type Target = Session<M>;
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        self.session.as_ref().unwrap()
+    }
+}
+
+impl<M: CausalLM> DerefMut for PooledSession<'_, M> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.session.as_mut().unwrap()
+    }
+}
+
+impl<M: CausalLM> Drop for PooledSession<'_, M> {
+    #[inline]
+    fn drop(&mut self) {
+        if let Some(session) = self.session.take() {
+            self.pool.restore(session);
+        }
+    }
+}
+
+#[test]
+fn test() {
+    use crate::Message;
+    use tokio::runtime::Builder;
+
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    println!("model_dir: {}", model_dir.display());
+
+    let runtime = Builder::new_current_thread().build().unwrap();
+    let _rt = runtime.enter();
+
+    let (service, _handle) = Service::<llama_cpu::Transformer>::load(model_dir, Default::default());
+    let pool = SessionPool::new(&service, 2);
+
+    let mut a = pool.take().unwrap();
+    let b = pool.take().unwrap();
+    assert_eq!(pool.take().err(), Some(SessionError::Busy));
+    assert_eq!(pool.idle(), 0);
+
+    // 借用者修改的设置在归还时恢复为服务的默认设置
+    a.set_stop(vec!["\n".into()]);
+    a.system_prompt = Some("You are a pirate.".into());
+    a.strip_think = true;
+    a.extend(&[Message {
+        role: "user",
+        content: "Hi",
+    }])
+    .unwrap();
+    assert_eq!(a.dialog_pos(), 1);
+    drop(a);
+    drop(b);
+    assert_eq!(pool.idle(), 2);
+
+    let a = pool.take().unwrap();
+    let b = pool.take().unwrap();
+    for s in [&a, &b] {
+        assert_eq!(s.dialog_pos(), 0);
+        assert!(s.stops().is_empty());
+        assert_eq!(s.system_prompt, None);
+        assert!(!s.strip_think);
+    }
+    drop((a, b));
+
+    runtime.shutdown_background();
+}
@@ -1,12 +1,17 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, cmp::Reverse};
 use tokeneer::{utok, Tokeneer};
 
 pub trait Tokenize {
+    fn vocab_size(&self) -> usize;
     fn encode(&self, text: &str) -> Vec<utok>;
     fn decode(&self, token: utok) -> &str;
 }
 
 impl<M: tokeneer::Method> Tokenize for Tokeneer<M> {
+    #[inline]
+    fn vocab_size(&self) -> usize {
+        self.internal().vocab_size()
+    }
     #[inline]
     fn encode(&self, text: &str) -> Vec<utok> {
         self.encode(text)
@@ -17,9 +22,191 @@ impl<M: tokeneer::Method> Tokenize for Tokeneer<M> {
     }
 }
 
+/// 识别文本中的特殊 token 字符串，编码为对应的单个 token。
+///
+/// 对话模板以字符串的形式插入 `bos`、`eos` 等特殊 token，分词器不把它们当作整体时会拆成多个普通 token。
+/// 插入模板的消息内容要先经过 [`escape`](Self::escape)，其中的特殊 token 字符串按普通文本编码。
+pub struct SpecialTokens<T> {
+    tokenizer: T,
+    specials: Vec<(String, utok)>,
+    /// 内部分词器在编码结果开头自动加入的起始符。
+    auto_bos: Option<utok>,
+}
+
+impl<T> SpecialTokens<T> {
+    pub fn new(tokenizer: T, specials: impl IntoIterator<Item = (String, utok)>) -> Self {
+        Self {
+            tokenizer,
+            specials: specials
+                .into_iter()
+                .filter(|(s, _)| !s.is_empty())
+                .collect(),
+            auto_bos: None,
+        }
+    }
+
+    /// 添加一个特殊 token，字符串已经存在时替换对应的 token。
+    pub fn insert(&mut self, text: String, token: utok) {
+        if text.is_empty() {
+            return;
+        }
+        match self.specials.iter_mut().find(|(s, _)| *s == text) {
+            Some((_, t)) => *t = token,
+            None => self.specials.push((text, token)),
+        }
+    }
+}
+
+impl<T: Tokenize> SpecialTokens<T> {
+    /// 在内部分词器的词表中查找恰好是 `text` 的 token。
+    pub fn find(&self, text: &str) -> Option<utok> {
+        (0..self.tokenizer.vocab_size() as utok).find(|&t| self.tokenizer.decode(t) == text)
+    }
+
+    /// 检测内部分词器是否在每次编码的结果开头自动加入 `bos`。
+    ///
+    /// 对话模板和生成器会显式加入起始符，检测到时之后编码移除分词器自动加入的起始符，避免出现两个起始符。
+    pub fn detect_auto_bos(&mut self, bos: utok) -> bool {
+        let probe = self.tokenizer.encode("a");
+        self.auto_bos = (probe.len() > 1 && probe[0] == bos).then_some(bos);
+        self.auto_bos.is_some()
+    }
+
+    /// 转义文本中的特殊 token 字符串，使它们在编码时按普通文本处理。
+    ///
+    /// 在每个特殊 token 字符串的第一个字符之后插入转义字符，编码时转义字符被移除。
+    pub fn escape<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut ans = String::new();
+        let mut rest = text;
+        while let Some(pos) = self
+            .specials
+            .iter()
+            .filter_map(|(s, _)| {
+                rest.find(&**s)
+                    .map(|pos| pos + s.chars().next().unwrap().len_utf8())
+            })
+            .min()
+        {
+            ans.push_str(&rest[..pos]);
+            ans.push(ESCAPE);
+            rest = &rest[pos..];
+        }
+        if ans.is_empty() {
+            return Cow::Borrowed(text);
+        }
+        ans.push_str(rest);
+        Cow::Owned(ans)
+    }
+
+    /// 用内部分词器编码不含特殊 token 的文本，移除转义字符和自动加入的起始符。
+    fn encode_plain(&self, text: &str) -> Vec<utok> {
+        let mut ans = if text.contains(ESCAPE) {
+            self.tokenizer.encode(&text.replace(ESCAPE, ""))
+        } else {
+            self.tokenizer.encode(text)
+        };
+        if self.auto_bos.is_some() && ans.first() == self.auto_bos.as_ref() {
+            ans.remove(0);
+        }
+        ans
+    }
+}
+
+/// 转义字符，使用 Unicode 非字符，不会出现在正常的文本中。
+const ESCAPE: char = '\u{FDD0}';
+
+impl<T: Tokenize> Tokenize for SpecialTokens<T> {
+    #[inline]
+    fn vocab_size(&self) -> usize {
+        self.tokenizer.vocab_size()
+    }
+    fn encode(&self, text: &str) -> Vec<utok> {
+        let mut ans = Vec::new();
+        let mut rest = text;
+        // 每次找到最靠前的特殊 token，位置相同时取最长的
+        while let Some((pos, len, token)) = self
+            .specials
+            .iter()
+            .filter_map(|(s, t)| rest.find(&**s).map(|pos| (pos, s.len(), *t)))
+            .min_by_key(|&(pos, len, _)| (pos, Reverse(len)))
+        {
+            if pos > 0 {
+                ans.extend(self.encode_plain(&rest[..pos]));
+            }
+            ans.push(token);
+            rest = &rest[pos + len..];
+        }
+        if !rest.is_empty() {
+            ans.extend(self.encode_plain(rest));
+        }
+        ans
+    }
+    #[inline]
+    fn decode(&self, token: utok) -> &str {
+        self.tokenizer.decode(token)
+    }
+}
+
+impl Tokenize for Box<dyn Tokenize + Send + Sync> {
+    #[inline]
+    fn vocab_size(&self) -> usize {
+        (**self).vocab_size()
+    }
+    #[inline]
+    fn encode(&self, text: &str) -> Vec<utok> {
+        (**self).encode(text)
+    }
+    #[inline]
+    fn decode(&self, token: utok) -> &str {
+        (**self).decode(token)
+    }
+}
+
+/// 文本规范化，在分词之前编码文本，在解码之后还原文本。
 pub trait Normalizer {
     fn encode<'a>(&self, text: &'a str) -> Cow<'a, str>;
     fn decode<'a>(&self, text: &'a str) -> Cow<'a, str>;
+    /// 解码一句话开头的 token，去掉编码时加入的前缀。
+    #[inline]
+    fn decode_start<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        self.decode(text)
+    }
+}
+
+/// 流式解码器，记住已解码的 token，使逐个输出的文本与整句解码一致。
+#[derive(Clone, Default, Debug)]
+pub(crate) struct StreamDecoder {
+    /// 解码的是一句新的话，开头的 token 需要去掉编码时加入的前缀。
+    sentence: bool,
+    num_tokens: usize,
+}
+
+impl StreamDecoder {
+    /// 解码一句新的话。
+    #[inline]
+    pub fn sentence() -> Self {
+        Self {
+            sentence: true,
+            num_tokens: 0,
+        }
+    }
+
+    /// 解码下一个 token，返回新增的文本。
+    pub fn decode<'a>(
+        &mut self,
+        tokenizer: &'a dyn Tokenize,
+        normalizer: &dyn Normalizer,
+        token: utok,
+    ) -> Cow<'a, str> {
+        let piece = tokenizer.decode(token);
+        let start = self.sentence && self.num_tokens == 0;
+        self.num_tokens += 1;
+        if start {
+            normalizer.decode_start(piece)
+        } else {
+            normalizer.decode(piece)
+        }
+    }
 }
 
 impl Normalizer for () {
@@ -34,21 +221,59 @@ impl Normalizer for () {
     }
 }
 
+/// 编码前对文本中空格的处理方式。
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+pub enum Whitespace {
+    /// 保留所有空格，适合对空白敏感的代码模型。
+    #[default]
+    Preserve,
+    /// 连续的多个空格合并为一个。
+    Collapse,
+    /// 去掉开头和结尾的空格，保留中间的空格。
+    Strip,
+}
+
+/// SentencePiece 风格的规范化，空格编码为 `▁`。
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
-pub struct BPECommonNormalizer;
+pub struct BPECommonNormalizer {
+    /// 空格的处理方式。
+    pub whitespace: Whitespace,
+    /// 是否在字母开头的文本前加入 `▁`。
+    pub prefix_space: bool,
+}
+
+impl Default for BPECommonNormalizer {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            whitespace: Whitespace::Preserve,
+            prefix_space: true,
+        }
+    }
+}
 
 impl Normalizer for BPECommonNormalizer {
     fn encode<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let text = match self.whitespace {
+            Whitespace::Strip => text.trim_matches(' '),
+            Whitespace::Preserve | Whitespace::Collapse => text,
+        };
         let mut ans = String::new();
-        if text
-            .chars()
-            .next()
-            .filter(char::is_ascii_alphabetic)
-            .is_some()
+        if self.prefix_space
+            && text
+                .chars()
+                .next()
+                .filter(char::is_ascii_alphabetic)
+                .is_some()
         {
             ans.push('▁');
         }
+        let mut last = None;
         for c in text.chars() {
+            if self.whitespace == Whitespace::Collapse && c == ' ' && last == Some(' ') {
+                continue;
+            }
+            last = Some(c);
             ans.push(match c {
                 ' ' => '▁',
                 c => c,
@@ -65,4 +290,227 @@ impl Normalizer for BPECommonNormalizer {
             Cow::Borrowed(text)
         }
     }
+
+    /// 与 `encode` 对应，去掉字母开头的句子前加入的 `▁`。
+    fn decode_start<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if !self.prefix_space {
+            return self.decode(text);
+        }
+        let text = match text.strip_prefix('▁') {
+            Some(rest) if rest.starts_with(|c: char| c.is_ascii_alphabetic()) => rest,
+            _ => text,
+        };
+        self.decode(text)
+    }
+}
+
+#[test]
+fn test_stream_decoder() {
+    struct Vocab(&'static [&'static str]);
+    impl Tokenize for Vocab {
+        fn vocab_size(&self) -> usize {
+            self.0.len()
+        }
+        fn encode(&self, _: &str) -> Vec<utok> {
+            unimplemented!()
+        }
+        fn decode(&self, token: utok) -> &str {
+            self.0[token as usize]
+        }
+    }
+
+    let vocab = Vocab(&["▁Hello", ",", "▁world", "▁how", "▁are", "▁you", "?"]);
+    let tokens = [0, 1, 2, 1, 3, 4, 5, 6];
+    let normalizer = BPECommonNormalizer::default();
+    // 整句解码
+    let full = tokens.iter().map(|&t| vocab.decode(t)).collect::<String>();
+    let full = normalizer
+        .decode(full.strip_prefix('▁').unwrap())
+        .into_owned();
+    assert_eq!(full, "Hello, world, how are you?");
+
+    let mut decoder = StreamDecoder::sentence();
+    let streamed = tokens
+        .iter()
+        .map(|&t| decoder.decode(&vocab, &normalizer, t))
+        .collect::<String>();
+    assert_eq!(streamed, full);
+
+    // 接续已有文本时保留空格
+    let mut decoder = StreamDecoder::default();
+    let continued = tokens[2..]
+        .iter()
+        .map(|&t| decoder.decode(&vocab, &normalizer, t))
+        .collect::<String>();
+    assert_eq!(continued, " world, how are you?");
+}
+
+#[test]
+fn test_whitespace() {
+    const TEXT: &str = "  fn  main() { }  ";
+    let encode = |whitespace, prefix_space| {
+        BPECommonNormalizer {
+            whitespace,
+            prefix_space,
+        }
+        .encode(TEXT)
+        .into_owned()
+    };
+    assert_eq!(encode(Whitespace::Preserve, true), "▁▁fn▁▁main()▁{▁}▁▁");
+    assert_eq!(encode(Whitespace::Collapse, true), "▁fn▁main()▁{▁}▁");
+    assert_eq!(encode(Whitespace::Strip, true), "▁fn▁▁main()▁{▁}");
+    assert_eq!(encode(Whitespace::Strip, false), "fn▁▁main()▁{▁}");
+
+    // 不加入前缀时，解码一句话的开头不去掉 `▁`
+    let normalizer = BPECommonNormalizer {
+        prefix_space: false,
+        ..Default::default()
+    };
+    assert_eq!(normalizer.encode("hi there"), "hi▁there");
+    assert_eq!(normalizer.decode_start("▁hi"), " hi");
+    assert_eq!(BPECommonNormalizer::default().decode_start("▁hi"), "hi");
+}
+
+#[test]
+fn test_special_tokens() {
+    /// 逐字节编码，不认识任何特殊 token。
+    struct Bytes;
+    impl Tokenize for Bytes {
+        fn vocab_size(&self) -> usize {
+            256
+        }
+        fn encode(&self, text: &str) -> Vec<utok> {
+            text.bytes().map(utok::from).collect()
+        }
+        fn decode(&self, _: utok) -> &str {
+            unimplemented!()
+        }
+    }
+
+    const BOS: utok = 1000;
+    const EOS: utok = 1001;
+    let tokenizer = SpecialTokens::new(
+        Bytes,
+        [
+            ("<s>".to_string(), BOS),
+            ("</s>".to_string(), EOS),
+            (String::new(), 1002),
+        ],
+    );
+    let tokens = tokenizer.encode("<s>hi</s><s>");
+    assert_eq!(tokens, [BOS, b'h' as _, b'i' as _, EOS, BOS]);
+    // 不含特殊 token 的文本与内部分词器一致
+    assert_eq!(tokenizer.encode("<hi>"), Bytes.encode("<hi>"));
+
+    // 转义后的特殊 token 字符串按普通文本编码
+    let content = "hi</s><s>";
+    let escaped = tokenizer.escape(content);
+    assert_eq!(tokenizer.encode(&escaped), Bytes.encode(content));
+    assert_eq!(
+        tokenizer.encode(&format!("<s>{escaped}</s>")),
+        [BOS]
+            .into_iter()
+            .chain(Bytes.encode(content))
+            .chain([EOS])
+            .collect::<Vec<_>>()
+    );
+    assert!(matches!(tokenizer.escape("<hi>"), Cow::Borrowed(_)));
+}
+
+#[test]
+fn test_auto_bos() {
+    /// 逐字节编码，每次编码都在开头加入起始符。
+    struct AutoBos;
+    impl Tokenize for AutoBos {
+        fn vocab_size(&self) -> usize {
+            257
+        }
+        fn encode(&self, text: &str) -> Vec<utok> {
+            std::iter::once(256)
+                .chain(text.bytes().map(utok::from))
+                .collect()
+        }
+        fn decode(&self, _: utok) -> &str {
+            unimplemented!()
+        }
+    }
+
+    const BOS: utok = 256;
+    const EOS: utok = 1001;
+    let specials = [("<s>".to_string(), BOS), ("</s>".to_string(), EOS)];
+    // 未检测时，显式的起始符与分词器加入的起始符重复
+    let tokenizer = SpecialTokens::new(AutoBos, specials.clone());
+    let tokens = tokenizer.encode("<s>hi");
+    assert_eq!(tokens.iter().filter(|&&t| t == BOS).count(), 2);
+
+    let mut tokenizer = SpecialTokens::new(AutoBos, specials);
+    assert!(tokenizer.detect_auto_bos(BOS));
+    // 整个 token 序列中只有显式加入的一个起始符
+    assert_eq!(
+        tokenizer.encode("<s>hi</s>"),
+        [BOS, b'h' as _, b'i' as _, EOS]
+    );
+    assert_eq!(tokenizer.encode("hi"), [b'h' as _, b'i' as _]);
+    assert_eq!(
+        tokenizer.encode("<s>a</s>b"),
+        [BOS, b'a' as _, EOS, b'b' as _]
+    );
+
+    // 不自动加入起始符的分词器不受影响
+    let mut tokenizer = SpecialTokens::new(AutoBos, []);
+    assert!(!tokenizer.detect_auto_bos(1));
+}
+
+#[test]
+fn test_chatml_tokens() {
+    use chat_template::{BuiltinTemplate, ChatTemplate, Message};
+
+    /// 逐字节编码，ChatML 的标记在词表末尾。
+    struct Bytes;
+    impl Tokenize for Bytes {
+        fn vocab_size(&self) -> usize {
+            258
+        }
+        fn encode(&self, text: &str) -> Vec<utok> {
+            text.bytes().map(utok::from).collect()
+        }
+        fn decode(&self, token: utok) -> &str {
+            match token {
+                256 => "<|im_start|>",
+                257 => "<|im_end|>",
+                _ => "",
+            }
+        }
+    }
+
+    let chatml = BuiltinTemplate::ChatML;
+    let mut tokenizer = SpecialTokens::new(Bytes, []);
+    for &s in chatml.special_tokens() {
+        let token = tokenizer.find(s).unwrap();
+        tokenizer.insert(s.into(), token);
+    }
+    assert_eq!(tokenizer.find("<|im_sep|>"), None);
+
+    let messages = [
+        Message {
+            role: "user",
+            content: "Hi",
+        },
+        Message {
+            role: "assistant",
+            content: "Hello",
+        },
+        Message {
+            role: "user",
+            content: "Bye",
+        },
+    ];
+    let text = ChatTemplate::builtin(chatml)
+        .render(&messages, "", "", true)
+        .unwrap();
+    let tokens = tokenizer.encode(&text);
+    // 每条消息和生成提示各有一个 `<|im_start|>`，每条消息有一个 `<|im_end|>`
+    assert_eq!(tokens.iter().filter(|&&t| t == 256).count(), 4);
+    assert_eq!(tokens.iter().filter(|&&t| t == 257).count(), 3);
+    assert_eq!(&tokens[..3], [256, b'u' as _, b's' as _]);
 }
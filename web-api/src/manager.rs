@@ -54,23 +54,24 @@ where
             Some(e) => return Err(Error::InvalidContent(format!("Unknown encoding: {e}"))),
         };
 
-        async fn infer<M: CausalLM>(
+        /// 设置采样参数并填充会话，被拒绝时采样参数不变。
+        fn extend<M: CausalLM>(
             session_id: &SessionId,
             session: &mut Session<M>,
-            messages: Vec<Sentence>,
+            messages: &[Sentence],
             temperature: Option<f32>,
             top_k: Option<usize>,
             top_p: Option<f32>,
-            sender: mpsc::UnboundedSender<String>,
-        ) {
+        ) -> Result<(), Error> {
+            let sample = session.generation.sample;
             if let Some(temperature) = temperature {
-                session.sample.temperature = temperature;
+                session.generation.sample.temperature = temperature;
             }
             if let Some(top_k) = top_k {
-                session.sample.top_k = top_k;
+                session.generation.sample.top_k = top_k;
             }
             if let Some(top_p) = top_p {
-                session.sample.top_p = top_p;
+                session.generation.sample.top_p = top_p;
             }
 
             let messages = messages
@@ -80,15 +81,23 @@ where
                     content: &s.content,
                 })
                 .collect::<Vec<_>>();
-            session.extend(&messages);
-            if session.dialog_pos() % 2 == 1 {
+            session.extend(&messages).map_err(|e| {
+                warn!("{session_id:?} rejected messages with error \"{e}\"");
+                session.generation.sample = sample;
+                Error::Chat(e)
+            })
+        }
+
+        async fn infer<M: CausalLM>(
+            session_id: &SessionId,
+            session: &mut Session<M>,
+            sender: mpsc::UnboundedSender<String>,
+        ) {
+            // 对话以回答结尾时不需要生成
+            if session.last_role().is_some_and(|role| role != "assistant") {
                 info!("{session_id:?} inference started");
-                let mut busy = session.chat();
-                while let Some(s) = busy.decode().await {
-                    if let Err(e) = sender.send(s) {
-                        warn!("Failed to send piece to {session_id:?} with error \"{e}\"");
-                        break;
-                    }
+                if let Err(e) = session.chat().send_to(&sender).await {
+                    warn!("Failed to send piece to {session_id:?} with error \"{e}\"");
                 }
                 info!("{session_id:?} inference stopped");
             } else {
@@ -103,21 +112,22 @@ where
                     .session_manager
                     .take_or_register(session_id.clone(), || self.service.launch())
                     .map_err(Error::Session)?;
+                session.revert(0).unwrap();
+                if let Err(e) = extend(
+                    &session_id,
+                    &mut session,
+                    &messages,
+                    temperature,
+                    top_k,
+                    top_p,
+                ) {
+                    self.session_manager.restore(&session_id, session);
+                    return Err(e);
+                }
                 let (sender, receiver) = mpsc::unbounded_channel();
                 let self_ = self.clone();
                 tokio::spawn(async move {
-                    session.revert(0).unwrap();
-                    infer(
-                        &session_id,
-                        &mut session,
-                        messages,
-                        temperature,
-                        top_k,
-                        top_p,
-                        sender,
-                    )
-                    .await;
-
+                    infer(&session_id, &mut session, sender).await;
                     self_.session_manager.restore(&session_id, session);
                 });
                 Ok(receiver)
@@ -136,21 +146,22 @@ where
                     self.session_manager.restore(&session_id, session);
                     return Err(Error::InvalidDialogPos(current));
                 }
+                info!("{session_id:?} reverted to {p}");
+                if let Err(e) = extend(
+                    &session_id,
+                    &mut session,
+                    &messages,
+                    temperature,
+                    top_k,
+                    top_p,
+                ) {
+                    self.session_manager.restore(&session_id, session);
+                    return Err(e);
+                }
                 let (sender, receiver) = mpsc::unbounded_channel();
                 let self_ = self.clone();
                 tokio::spawn(async move {
-                    info!("{session_id:?} reverted to {p}");
-                    infer(
-                        &session_id,
-                        &mut session,
-                        messages,
-                        temperature,
-                        top_k,
-                        top_p,
-                        sender,
-                    )
-                    .await;
-
+                    infer(&session_id, &mut session, sender).await;
                     self_.session_manager.restore(&session_id, session);
                 });
                 Ok(receiver)
@@ -161,23 +172,23 @@ where
                     .session_manager
                     .take_or_register(session_id.clone(), || self.service.launch())
                     .map_err(Error::Session)?;
+                if let Err(e) = extend(
+                    &session_id,
+                    &mut session,
+                    &messages,
+                    temperature,
+                    top_k,
+                    top_p,
+                ) {
+                    self.session_manager.drop_(&session_id).unwrap();
+                    return Err(e);
+                }
                 let (sender, receiver) = mpsc::unbounded_channel();
                 let self_ = self.clone();
-                if messages.len() % 2 == 1 {
-                    tokio::spawn(async move {
-                        infer(
-                            &session_id,
-                            &mut session,
-                            messages,
-                            temperature,
-                            top_k,
-                            top_p,
-                            sender,
-                        )
-                        .await;
-                        self_.session_manager.drop_(&session_id).unwrap();
-                    });
-                }
+                tokio::spawn(async move {
+                    infer(&session_id, &mut session, sender).await;
+                    self_.session_manager.drop_(&session_id).unwrap();
+                });
                 Ok(receiver)
             }
             (None, _) => {
@@ -1,5 +1,5 @@
 use hyper::StatusCode;
-use service::SessionError;
+use service::{ChatError, SessionError};
 use std::sync::atomic::{AtomicUsize, Ordering};
 
 #[derive(serde::Deserialize)]
@@ -84,6 +84,7 @@ pub(crate) enum Error {
     WrongJson(serde_json::Error),
     InvalidContent(String),
     InvalidDialogPos(usize),
+    Chat(ChatError),
 }
 
 #[derive(serde::Serialize)]
@@ -104,6 +105,7 @@ impl Error {
             Self::WrongJson(_) => StatusCode::BAD_REQUEST,
             Self::InvalidContent(_) => StatusCode::BAD_REQUEST,
             Self::InvalidDialogPos(_) => StatusCode::RANGE_NOT_SATISFIABLE,
+            Self::Chat(_) => StatusCode::BAD_REQUEST,
         }
     }
 
@@ -131,6 +133,7 @@ impl Error {
             Self::Session(Duplicate) => json(error!(0, "Session ID already exists")),
             Self::WrongJson(e) => json(error!(0, e.to_string())),
             Self::InvalidContent(e) => json(error!(1, e)),
+            Self::Chat(e) => json(error!(2, e.to_string())),
             &Self::InvalidDialogPos(current_dialog_pos) => {
                 #[derive(serde::Serialize)]
                 struct ErrorBodyExtra {
@@ -0,0 +1,158 @@
+use crate::{InferenceArgs, Task};
+use causal_lm::{CausalLM, SampleArgs};
+use service::Service;
+use std::{
+    fmt::{self, Debug},
+    time::{Duration, Instant},
+};
+
+#[derive(Args, Default)]
+pub(crate) struct BenchArgs {
+    #[clap(flatten)]
+    pub inference: InferenceArgs,
+    /// Prompt used to measure prefill, a built-in prompt by default.
+    #[clap(long, short)]
+    pub prompt: Option<String>,
+    /// Max number of tokens to decode in each round, 64 by default.
+    #[clap(long)]
+    pub decode_steps: Option<usize>,
+    /// Number of rounds, 3 by default.
+    #[clap(long)]
+    pub rounds: Option<usize>,
+}
+
+const PROMPT: &str = "\
+Once upon a time, in a small village at the foot of a mountain, \
+there lived an old clockmaker who repaired every clock in the valley. \
+One winter morning, a stranger knocked on his door with a broken pocket watch";
+
+impl Task for BenchArgs {
+    #[inline]
+    fn inference(&self) -> &InferenceArgs {
+        &self.inference
+    }
+
+    async fn typed<M>(self, meta: M::Meta)
+    where
+        M: CausalLM + Send + Sync + 'static,
+        M::Storage: Send,
+        M::Error: Debug,
+    {
+        let (service, _handle) = Service::<M>::load(&self.inference.model, meta);
+        let report = bench(
+            service,
+            self.prompt.as_deref().unwrap_or(PROMPT),
+            self.inference.sample_args(),
+            self.decode_steps.unwrap_or(64),
+            self.rounds.unwrap_or(3),
+        )
+        .await;
+        println!("{report}");
+    }
+}
+
+/// 性能测试的结果。
+pub(crate) struct BenchReport {
+    /// 预填充吞吐量（tokens/s），只计算实际送入模型的提示词 token。
+    pub prefill_throughput: f64,
+    /// 解码吞吐量（tokens/s）。
+    pub decode_throughput: f64,
+    /// 每个解码 token 延迟的中位数。
+    pub decode_p50: Duration,
+    /// 每个解码 token 延迟的 99 分位数。
+    pub decode_p99: Duration,
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "prefill: {:.2} tokens/s", self.prefill_throughput)?;
+        writeln!(f, "decode:  {:.2} tokens/s", self.decode_throughput)?;
+        write!(
+            f,
+            "decode latency: p50 {:?}, p99 {:?}",
+            self.decode_p50, self.decode_p99
+        )
+    }
+}
+
+/// 用 `prompt` 重复生成 `rounds` 轮，统计预填充和解码的吞吐量以及解码延迟。
+pub(crate) async fn bench<M>(
+    service: Service<M>,
+    prompt: &str,
+    sample: SampleArgs,
+    decode_steps: usize,
+    rounds: usize,
+) -> BenchReport
+where
+    M: CausalLM,
+{
+    let mut prefill_tokens = 0;
+    let mut prefill_time = Duration::ZERO;
+    let mut latency = Vec::new();
+    for _ in 0..rounds {
+        let mut generator = service.generate(prompt, Some(sample)).unwrap();
+
+        let time = Instant::now();
+        // 预填充与第一个 token 的采样在同一轮推理中完成
+        let mut decoded = 0;
+        if let Some((_, ids)) = generator.decode_with_ids().await {
+            decoded += ids.len();
+        }
+        prefill_time += time.elapsed();
+        // 只统计实际送入模型的提示词 token，命中缓存的前缀不计算
+        prefill_tokens += generator.prompt_tokens() - generator.cached_tokens();
+
+        let mut time = Instant::now();
+        while decoded < decode_steps {
+            let Some((_, ids)) = generator.decode_with_ids().await else {
+                break;
+            };
+            // 一段文本可能由多个 token 组成，平摊到每个 token
+            let elapsed = time.elapsed().div_f64(ids.len().max(1) as _);
+            latency.extend(std::iter::repeat(elapsed).take(ids.len()));
+            decoded += ids.len();
+            time = Instant::now();
+        }
+    }
+
+    let decode_time = latency.iter().sum::<Duration>();
+    latency.sort_unstable();
+    BenchReport {
+        prefill_throughput: prefill_tokens as f64 / prefill_time.as_secs_f64(),
+        decode_throughput: latency.len() as f64 / decode_time.as_secs_f64(),
+        decode_p50: percentile(&latency, 0.5),
+        decode_p99: percentile(&latency, 0.99),
+    }
+}
+
+/// 从升序排列的 `sorted` 中取 `q` 分位数。
+fn percentile(sorted: &[Duration], q: f64) -> Duration {
+    match sorted.len() {
+        0 => Duration::ZERO,
+        len => sorted[((len - 1) as f64 * q).round() as usize],
+    }
+}
+
+#[test]
+fn test_bench() {
+    use tokio::runtime::Builder;
+
+    assert_eq!(percentile(&[], 0.5), Duration::ZERO);
+    let sorted = (1..=100).map(Duration::from_millis).collect::<Vec<_>>();
+    assert_eq!(percentile(&sorted, 0.5), Duration::from_millis(51));
+    assert_eq!(percentile(&sorted, 0.99), Duration::from_millis(99));
+
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    let runtime = Builder::new_current_thread().build().unwrap();
+    let _rt = runtime.enter();
+
+    let (service, _handle) = Service::<llama_cpu::Transformer>::load(model_dir, Default::default());
+    let report = runtime.block_on(bench(service, PROMPT, SampleArgs::ARG_MAX, 8, 1));
+    println!("{report}");
+    assert!(report.prefill_throughput > 0.);
+    assert!(report.decode_throughput > 0.);
+    assert!(report.decode_p50 <= report.decode_p99);
+    runtime.shutdown_background();
+}
@@ -45,7 +45,7 @@ impl CastArgs {
         fs::create_dir_all(&target).unwrap();
 
         let time = Instant::now();
-        let model = model.cast(ty);
+        let model = model.cast(ty).unwrap();
         println!("cast data type ... {:?}", time.elapsed());
 
         let time = Instant::now();
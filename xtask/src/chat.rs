@@ -1,13 +1,16 @@
-﻿use crate::{print_now, InferenceArgs, Task};
-use causal_lm::CausalLM;
+﻿use crate::{parse_sample_order, print_now, InferenceArgs, Task};
+use causal_lm::{CausalLM, SampleArgs};
 use colored::Colorize;
-use service::{Message, Service, Session};
+use service::{BuiltinTemplate, Message, Service, Session};
 use std::{collections::HashMap, fmt::Debug};
 
 #[derive(Args, Default)]
 pub(crate) struct ChatArgs {
     #[clap(flatten)]
     pub inference: InferenceArgs,
+    /// Built-in chat template, may be "chatml", the one matching the model by default.
+    #[clap(long)]
+    pub template: Option<String>,
 }
 
 impl Task for ChatArgs {
@@ -22,7 +25,12 @@ impl Task for ChatArgs {
         M::Error: Debug,
     {
         let (mut service, _handle) = Service::<M>::load(&self.inference.model, meta);
-        service.default_sample = self.inference.sample_args();
+        service.generation.sample = self.inference.sample_args();
+        if let Some(name) = &self.template {
+            let template = BuiltinTemplate::from_name(name)
+                .unwrap_or_else(|| panic!("Unsupported chat template: {name}"));
+            service.set_builtin_template(template);
+        }
         Chatting {
             service,
             current: 0,
@@ -48,14 +56,16 @@ fn print_splitter() {
 fn print_help() {
     println!(
         "\
-/list           列出现存的会话及对话次数
-/create         新建会话
-/fork [id]      复制当前会话或指定会话
-/switch <id>    切换至指定会话
-/drop [id]      丢弃当前会话或指定会话
-/args           打印当前参数
-/args key value 设置指定参数
-/help           打印帮助信息
+/list               列出现存的会话及对话次数
+/create             新建会话
+/fork [id]          复制当前会话或指定会话
+/switch <id>        切换至指定会话
+/drop [id]          丢弃当前会话或指定会话
+/args               打印当前参数
+/args key value     设置指定参数，key 可以是 temperature、top-k、top-p、min-p、eos-bias、
+                    repetition-penalty、sample-order 或 gumbel
+/args preset <name> 使用预设的采样参数
+/help               打印帮助信息
 
 使用 /exit 或 Ctrl + C 结束程序"
     );
@@ -124,10 +134,15 @@ impl<M: CausalLM> Chatting<M> {
         println!("PID = {}", std::process::id());
         println!("Current session = {}", self.current);
         println!("dialog times = {}", self.session().dialog_pos() / 2);
-        let args = &self.session().sample;
+        let args = &self.session().generation.sample;
         println!("temperature = {}", args.temperature);
         println!("top-k = {}", args.top_k);
         println!("top-p = {}", args.top_p);
+        println!("min-p = {}", args.min_p);
+        println!("eos-bias = {}", args.eos_bias);
+        println!("repetition-penalty = {}", args.repetition_penalty);
+        println!("sample-order = {:?}", args.sample_order);
+        println!("gumbel = {}", args.gumbel);
     }
 
     #[inline]
@@ -195,25 +210,15 @@ impl<M: CausalLM> Chatting<M> {
                 Err(_) => println!("Invalid drop command"),
             },
             ["/args"] => self.print_args(),
-            ["/args", "temperature", t] => {
-                if let Ok(t) = t.parse() {
-                    self.session_mut().sample.temperature = t;
-                } else {
-                    println!("Invalid temperature");
-                }
-            }
-            ["/args", "top-k", k] => {
-                if let Ok(k) = k.parse() {
-                    self.session_mut().sample.top_k = k;
-                } else {
-                    println!("Invalid top-k");
-                }
-            }
-            ["/args", "top-p", p] => {
-                if let Ok(p) = p.parse() {
-                    self.session_mut().sample.top_p = p;
-                } else {
-                    println!("Invalid top-p");
+            ["/args", "preset", name] => match SampleArgs::preset(name) {
+                Some(args) => self.session_mut().generation.sample = args,
+                None => println!("Invalid preset"),
+            },
+            ["/args", key, value] => {
+                if let Err(e) =
+                    set_sample_arg(&mut self.session_mut().generation.sample, key, value)
+                {
+                    println!("{e}");
                 }
             }
             ["/help"] => print_help(),
@@ -227,10 +232,13 @@ impl<M: CausalLM> Chatting<M> {
     async fn infer(&mut self, content: &str) {
         print_now!("{}", "AI: ".green());
         let session = self.session_mut();
-        session.extend(&[Message {
+        if let Err(e) = session.extend(&[Message {
             role: "user",
             content,
-        }]);
+        }]) {
+            println!("{e}");
+            return;
+        }
         let mut busy = session.chat();
         while let Some(s) = busy.decode().await {
             match &*s {
@@ -241,3 +249,110 @@ impl<M: CausalLM> Chatting<M> {
         println!();
     }
 }
+
+/// 按 `/args key value` 命令设置一个采样参数，值不合法时不修改参数并返回错误信息。
+fn set_sample_arg(args: &mut SampleArgs, key: &str, value: &str) -> Result<(), String> {
+    fn parse<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, String> {
+        value.parse().map_err(|_| format!("Invalid {key}: {value}"))
+    }
+
+    let mut new = *args;
+    match key {
+        "temperature" => new.temperature = parse(key, value)?,
+        "top-k" => new.top_k = parse(key, value)?,
+        "top-p" => new.top_p = parse(key, value)?,
+        "min-p" => new.min_p = parse(key, value)?,
+        "eos-bias" => new.eos_bias = parse(key, value)?,
+        "repetition-penalty" => new.repetition_penalty = parse(key, value)?,
+        "sample-order" => new.sample_order = parse_sample_order(value)?,
+        "gumbel" => {
+            new.gumbel = match value {
+                "on" | "true" => true,
+                "off" | "false" => false,
+                _ => return Err(format!("Invalid gumbel: {value}, expected on or off")),
+            }
+        }
+        _ => return Err(format!("Unknown sample arg: {key}")),
+    }
+    new.validate()
+        .map_err(|e| format!("Invalid sample arguments: {e}"))?;
+    *args = new;
+    Ok(())
+}
+
+#[test]
+fn test_set_sample_arg() {
+    use causal_lm::SampleStage::*;
+    use tokio::runtime::Builder;
+
+    let mut args = SampleArgs::ARG_MAX;
+    set_sample_arg(&mut args, "temperature", "0.7").unwrap();
+    set_sample_arg(&mut args, "top-k", "50").unwrap();
+    set_sample_arg(&mut args, "min-p", "0.05").unwrap();
+    set_sample_arg(&mut args, "eos-bias", "-2.5").unwrap();
+    set_sample_arg(&mut args, "repetition-penalty", "1.1").unwrap();
+    set_sample_arg(&mut args, "sample-order", "temperature,top-p,top-k").unwrap();
+    set_sample_arg(&mut args, "gumbel", "on").unwrap();
+    assert_eq!(
+        args,
+        SampleArgs {
+            temperature: 0.7,
+            top_k: 50,
+            min_p: 0.05,
+            eos_bias: -2.5,
+            repetition_penalty: 1.1,
+            sample_order: [Temperature, TopP, TopK],
+            gumbel: true,
+            ..SampleArgs::ARG_MAX
+        }
+    );
+
+    // 不合法的值和不支持的参数不修改采样参数
+    let expected = args;
+    for (key, value) in [
+        ("top-p", "1.5"),
+        ("top-k", "0"),
+        ("temperature", "hot"),
+        ("sample-order", "top-k,top-k,top-p"),
+        ("sample-order", "top-k,top-p"),
+        ("gumbel", "maybe"),
+        ("repetition-penalty", "0"),
+        ("min-p", "1.5"),
+        ("typical-p", "0.9"),
+    ] {
+        assert!(
+            set_sample_arg(&mut args, key, value).is_err(),
+            "{key} {value}"
+        );
+        assert_eq!(args, expected);
+    }
+
+    // `/args` 命令设置当前会话的采样参数
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    let runtime = Builder::new_current_thread().build().unwrap();
+    let _rt = runtime.enter();
+
+    let (service, _handle) = Service::<llama_cpu::Transformer>::load(model_dir, Default::default());
+    let mut chatting = Chatting {
+        sessions: HashMap::from([(0, service.launch())]),
+        service,
+        current: 0,
+        next_id: 1,
+    };
+    let sample = chatting.session().generation.sample;
+    assert!(chatting.execute_command("/args min-p 0.05"));
+    assert_eq!(
+        chatting.session().generation.sample,
+        SampleArgs {
+            min_p: 0.05,
+            ..sample
+        }
+    );
+    // 不合法的值不修改会话的参数
+    assert!(chatting.execute_command("/args min-p 1.5"));
+    assert_eq!(chatting.session().generation.sample.min_p, 0.05);
+    drop(chatting);
+    runtime.shutdown_background();
+}
@@ -13,6 +13,9 @@ pub(crate) struct GenerateArgs {
     /// Max number of steps to generate.
     #[clap(long)]
     pub max_steps: Option<usize>,
+    /// Write a Chrome tracing (chrome://tracing) profile of each inference step to this file.
+    #[clap(long)]
+    pub trace: Option<String>,
 }
 
 impl Task for GenerateArgs {
@@ -30,6 +33,9 @@ impl Task for GenerateArgs {
     {
         // 加载模型和元数据
         let (service, _handle) = Service::<M>::load(&self.inference.model, meta);
+        if self.trace.is_some() {
+            service.start_trace();
+        }
         // 获取提示词进行对话，能输入文件
         let prompt = if Path::new(&self.prompt).is_file() {
             println!("prompt from file: {}", self.prompt);
@@ -41,7 +47,9 @@ impl Task for GenerateArgs {
 
         let max_steps = self.max_steps.unwrap_or(usize::MAX);
         let mut steps = 0;
-        let mut generator = service.generate(&*prompt, Some(self.inference.sample_args()));
+        let mut generator = service
+            .generate(&*prompt, Some(self.inference.sample_args()))
+            .unwrap();
 
         let time = Instant::now();
         while let Some(s) = generator.decode().await {
@@ -58,5 +66,9 @@ impl Task for GenerateArgs {
 
         println!();
         println!("Time elapsed: {:?}/tok", time.div_f32(steps as f32));
+        if let Some(path) = self.trace {
+            service.write_trace(&path).unwrap();
+            println!("trace written to {path}");
+        }
     }
 }
@@ -1,3 +1,4 @@
+mod bench;
 mod cast;
 mod chat;
 mod deploy;
@@ -5,11 +6,11 @@ mod generate;
 mod list_turbo;
 mod service;
 
-use causal_lm::{CausalLM, SampleArgs};
+use causal_lm::{CausalLM, SampleArgs, SampleStage};
 use clap::Parser;
 use deploy::DeployArgs;
 use service::ServiceArgs;
-use std::{ffi::c_int, fmt, num::ParseIntError, str::FromStr};
+use std::{ffi::c_int, fmt, num::ParseIntError, process::exit, str::FromStr};
 use time::UtcOffset;
 
 #[macro_use]
@@ -22,6 +23,7 @@ fn main() {
         Deploy(deploy) => deploy.deploy(),
         Cast(cast) => cast.invoke(),
         Generate(args) => args.run(),
+        Bench(args) => args.run(),
         Chat(chat) => chat.run(),
         Service(service) => service.run(),
     }
@@ -45,6 +47,8 @@ enum Commands {
     Cast(cast::CastArgs),
     /// Generate following text
     Generate(generate::GenerateArgs),
+    /// Measure prefill and decode throughput
+    Bench(bench::BenchArgs),
     /// Chat locally
     Chat(chat::ChatArgs),
     /// Start the service
@@ -64,6 +68,9 @@ struct InferenceArgs {
     #[clap(long)]
     log: Option<String>,
 
+    /// Sample preset, may be "greedy", "deterministic", "precise", "balanced" or "creative".
+    #[clap(long)]
+    preset: Option<String>,
     /// Random sample temperature.
     #[clap(long)]
     temperature: Option<f32>,
@@ -73,10 +80,31 @@ struct InferenceArgs {
     /// Random sample top-p.
     #[clap(long)]
     top_p: Option<f32>,
+    /// Random sample min-p, 0 for no filtering.
+    #[clap(long)]
+    min_p: Option<f32>,
+    /// Bias added to the eos logit before sampling, positive to end generation earlier.
+    #[clap(long, allow_hyphen_values = true)]
+    eos_bias: Option<f32>,
+    /// Repetition penalty applied to tokens already generated, 1 for no penalty.
+    #[clap(long)]
+    repetition_penalty: Option<f32>,
+    /// Order of the sampling filters, such as "temperature,top-k,top-p".
+    #[clap(long)]
+    sample_order: Option<String>,
+    /// Sample with the Gumbel-max trick.
+    #[clap(long)]
+    gumbel: bool,
 
     /// Select turbo hardware, the format is "ty:detail".
     #[clap(long)]
     turbo: Option<String>,
+    /// Error on model dtype unsupported by the device instead of casting.
+    #[clap(long)]
+    strict_dtype: bool,
+    /// Load and run only the first N layers on CPU, for fast smoke tests.
+    #[clap(long)]
+    num_layers: Option<usize>,
 }
 
 /// TODO 应该根据参数自动识别模型
@@ -142,14 +170,56 @@ impl InferenceArgs {
 
     #[inline]
     fn sample_args(&self) -> SampleArgs {
-        SampleArgs {
-            temperature: self.temperature.unwrap_or(0.),
-            top_k: self.top_k.unwrap_or(usize::MAX),
-            top_p: self.top_p.unwrap_or(1.),
+        let preset = self.preset.as_ref().map_or(SampleArgs::ARG_MAX, |name| {
+            SampleArgs::preset(name)
+                .unwrap_or_else(|| exit_with(format!("Unsupported sample preset: {name}")))
+        });
+        let sample_order = self
+            .sample_order
+            .as_ref()
+            .map_or(preset.sample_order, |order| {
+                parse_sample_order(order).unwrap_or_else(|e| exit_with(e))
+            });
+        let args = SampleArgs {
+            temperature: self.temperature.unwrap_or(preset.temperature),
+            top_k: self.top_k.unwrap_or(preset.top_k),
+            top_p: self.top_p.unwrap_or(preset.top_p),
+            min_p: self.min_p.unwrap_or(preset.min_p),
+            eos_bias: self.eos_bias.unwrap_or(preset.eos_bias),
+            repetition_penalty: self.repetition_penalty.unwrap_or(preset.repetition_penalty),
+            sample_order,
+            gumbel: self.gumbel || preset.gumbel,
+            ..preset
+        };
+        if let Err(e) = args.validate() {
+            exit_with(format!("Invalid sample arguments: {e}"))
         }
+        args
     }
 }
 
+/// 解析以逗号分隔的采样过滤顺序，如 `temperature,top-k,top-p`。
+fn parse_sample_order(s: &str) -> Result<[SampleStage; 3], String> {
+    let stages = s
+        .split(',')
+        .map(|stage| match stage.trim().to_lowercase().as_str() {
+            "top-k" | "top_k" => Ok(SampleStage::TopK),
+            "temperature" => Ok(SampleStage::Temperature),
+            "top-p" | "top_p" => Ok(SampleStage::TopP),
+            _ => Err(format!("Unknown sample stage: {stage}")),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    stages
+        .try_into()
+        .map_err(|_| format!("Sample order must have 3 stages: {s}"))
+}
+
+/// 命令行参数不合法时打印错误信息，以非零状态退出。
+fn exit_with(e: impl fmt::Display) -> ! {
+    eprintln!("{e}");
+    exit(1)
+}
+
 /// 模型相关的推理任务。
 trait Task: Sized {
     /// 解析推理参数。
@@ -174,8 +244,13 @@ trait Task: Sized {
         match self.inference().model_type() {
             ModelType::Llama => match turbo.to_ascii_lowercase().as_str() {
                 "" => {
-                    use llama_cpu::Transformer as M;
-                    runtime.block_on(self.typed::<M>(()));
+                    use llama_cpu::{ModelLoadMeta, Transformer as M};
+                    let meta = ModelLoadMeta {
+                        strict_dtype: self.inference().strict_dtype,
+                        num_layers_override: self.inference().num_layers,
+                        ..Default::default()
+                    };
+                    runtime.block_on(self.typed::<M>(meta));
                 }
                 #[cfg(detected_cuda)]
                 "nv" | "nvidia" if llama_nv::cuda::init().is_ok() => {
@@ -186,12 +261,18 @@ trait Task: Sized {
                     {
                         [] => {
                             use llama_nv::{ModelLoadMeta, Transformer as M};
-                            let meta = ModelLoadMeta::load_all_to(0);
+                            let meta = ModelLoadMeta {
+                                strict_dtype: self.inference().strict_dtype,
+                                ..ModelLoadMeta::load_all_to(0)
+                            };
                             runtime.block_on(self.typed::<M>(meta));
                         }
                         &[n] => {
                             use llama_nv::{ModelLoadMeta, Transformer as M};
-                            let meta = ModelLoadMeta::load_all_to(n);
+                            let meta = ModelLoadMeta {
+                                strict_dtype: self.inference().strict_dtype,
+                                ..ModelLoadMeta::load_all_to(n)
+                            };
                             runtime.block_on(self.typed::<M>(meta));
                         }
                         #[cfg(detected_nccl)]
@@ -223,7 +304,7 @@ trait Task: Sized {
             },
             ModelType::Mixtral => {
                 use mixtral_cpu::MixtralCPU as M;
-                runtime.block_on(self.typed::<M>(()));
+                runtime.block_on(self.typed::<M>(Default::default()));
             }
         }
         // 关闭 tokio 运行时
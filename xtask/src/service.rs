@@ -28,7 +28,7 @@ impl Task for ServiceArgs {
         M::Error: Debug,
     {
         let (mut service, _handle) = Service::<M>::load(&self.inference.model, meta);
-        service.default_sample = self.inference.sample_args();
+        service.generation.sample = self.inference.sample_args();
         start_infer_service(service, self.port, self.max_cache.filter(|&c| c < 256))
             .await
             .unwrap();